@@ -0,0 +1,29 @@
+use std::env;
+use std::process::Command;
+
+/// Records the git commit and the enabled cargo features for `--version`.
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    let git_commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(git_commit) = git_commit {
+        println!(
+            "cargo:rustc-env=HARDLINK_DEDUP_GIT_COMMIT={}",
+            git_commit.trim()
+        );
+    }
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(str::to_owned))
+        .map(|feature| feature.to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+    println!(
+        "cargo:rustc-env=HARDLINK_DEDUP_FEATURES={}",
+        features.join(",")
+    );
+}
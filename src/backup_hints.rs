@@ -0,0 +1,57 @@
+//! Directories whose backups must preserve hardlinks.
+//!
+//! Backup tools that don't preserve hardlinks (e.g. `rsync` without `-H`) copy every path of a
+//! hardlinked file separately, which undoes the deduplication in the backup. A backup preserves a
+//! hardlink only when it includes both of its paths, so we report the closest directory that
+//! contains both.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// The closest directory containing both files.
+pub(crate) fn common_dir(file1: &Path, file2: &Path) -> PathBuf {
+    file1
+        .parent()
+        .unwrap_or(file1)
+        .components()
+        .zip(file2.parent().unwrap_or(file2).components())
+        .take_while(|(component1, component2)| component1 == component2)
+        .map(|(component, _)| component)
+        .collect()
+}
+
+/// The directories that aren't inside any of the other directories.
+pub(crate) fn outermost_dirs(dirs: &BTreeSet<PathBuf>) -> Vec<PathBuf> {
+    let mut outermost: Vec<PathBuf> = Vec::new();
+    // Sorting puts every directory after the directories that contain it.
+    for dir in dirs {
+        if !outermost.iter().any(|outer| dir.starts_with(outer)) {
+            outermost.push(dir.clone());
+        }
+    }
+    outermost
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outermost_common_dirs() {
+        let dirs = BTreeSet::from([
+            common_dir(Path::new("/a/b/c/f1"), Path::new("/a/b/d/f2")),
+            common_dir(Path::new("/a/b/c/f1"), Path::new("/a/b/c/f3")),
+            common_dir(Path::new("/x/f1"), Path::new("/x/y/f2")),
+            common_dir(Path::new("/xy/f1"), Path::new("/xy/f2")),
+        ]);
+
+        assert_eq!(
+            outermost_dirs(&dirs),
+            vec![
+                PathBuf::from("/a/b"),
+                PathBuf::from("/x"),
+                PathBuf::from("/xy")
+            ]
+        );
+    }
+}
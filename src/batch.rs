@@ -0,0 +1,212 @@
+//! Running several deduplication jobs, each with its own paths and options, from a TOML file.
+//!
+//! ```toml
+//! [[job]]
+//! name = "photos"
+//! paths = ["/srv/photos", "/srv/photo-backups"]
+//! min-age = "1h"
+//! one-file-system = true
+//! report = "/var/log/dedup/photos.json"
+//!
+//! [[job]]
+//! name = "builds"
+//! paths = ["/srv/builds"]
+//! mode = "dry-run"
+//! max-depth = 3
+//! ```
+//!
+//! Jobs run one after another with the default options, overridden by their own. Each
+//! job's summary is printed like a single run prints it and, with `report`, written to a JSON file.
+//! A failing job doesn't stop the remaining ones. The summary of all jobs comes last.
+
+use hardlink_dedup::output::{print_result, print_summary, renderer};
+use hardlink_dedup::units::{parse_duration, parse_timestamp};
+use hardlink_dedup::{dedup_with_renderer, DedupError, DedupOptions, DedupSummary};
+use serde::Deserialize;
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use tracing::{error, info};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobsFile {
+    #[serde(rename = "job", default)]
+    jobs: Vec<Job>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Job {
+    /// Names the job in logs. Defaults to its number.
+    name: Option<String>,
+    paths: Vec<PathBuf>,
+    #[serde(default)]
+    mode: Mode,
+    /// Like --min-age, e.g. "1h".
+    min_age: Option<String>,
+    /// Like --exclude-newer-than, e.g. "2024-01-31".
+    exclude_newer_than: Option<String>,
+    exclude_older_than: Option<String>,
+    max_depth: Option<usize>,
+    min_copies: Option<usize>,
+    one_file_system: Option<bool>,
+    follow_symlinks: Option<bool>,
+    /// Writes the job's summary as JSON to this file.
+    report: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Mode {
+    /// Hardlink duplicates.
+    #[default]
+    Link,
+    /// Only report what would be hardlinked.
+    DryRun,
+}
+
+/// Runs the jobs of the file one after another and prints the summary of all of them.
+pub fn run(jobs_file: &Path, options: &DedupOptions) -> Result<DedupSummary, DedupError> {
+    let jobs = load(jobs_file)?;
+    let mut total = DedupSummary::default();
+    let mut failed_jobs = Vec::new();
+    for (index, job) in jobs.iter().enumerate() {
+        if options.interrupted.load(Ordering::Relaxed) {
+            total.interrupted = true;
+            break;
+        }
+        // The budget is for all the jobs.
+        if total.budget_exhausted {
+            break;
+        }
+        let name = job.name.clone().unwrap_or_else(|| (index + 1).to_string());
+        info!("Running job {}.", name);
+        match run_job(job, options) {
+            Ok(summary) => {
+                total.add(&summary);
+                total.interrupted |= summary.interrupted;
+                total.budget_exhausted |= summary.budget_exhausted;
+                // Resource usage is measured for the whole process so far.
+                total.resources = summary.resources;
+            }
+            Err(err) => {
+                error!("Job {} failed. Error: {}", name, err);
+                failed_jobs.push(name);
+            }
+        }
+    }
+    print_result(options.output, &total, |total| {
+        println!("All jobs:");
+        print_summary(total, options.raw_bytes);
+    });
+    if !failed_jobs.is_empty() {
+        return Err(DedupError::FailedJobs(failed_jobs));
+    }
+    Ok(total)
+}
+
+fn load(jobs_file: &Path) -> Result<Vec<Job>, DedupError> {
+    let text = read_to_string(jobs_file).map_err(|source| DedupError::ReadJobs {
+        path: jobs_file.to_owned(),
+        source,
+    })?;
+    let file: JobsFile = toml::from_str(&text).map_err(|source| DedupError::ParseJobs {
+        path: jobs_file.to_owned(),
+        source,
+    })?;
+    if file.jobs.is_empty() {
+        return Err(DedupError::NoJobs {
+            path: jobs_file.to_owned(),
+        });
+    }
+    Ok(file.jobs)
+}
+
+fn run_job(job: &Job, options: &DedupOptions) -> Result<DedupSummary, DedupError> {
+    let options = job_options(job, options)?;
+    let summary = dedup_with_renderer(&job.paths, &options, renderer(options.output).as_mut())?;
+    if let Some(report) = &job.report {
+        write(
+            report,
+            serde_json::to_string_pretty(&summary).unwrap() + "\n",
+        )
+        .map_err(|source| DedupError::WriteReport {
+            path: report.clone(),
+            source,
+        })?;
+    }
+    Ok(summary)
+}
+
+/// The base options overridden by the job's.
+fn job_options(job: &Job, options: &DedupOptions) -> Result<DedupOptions, DedupError> {
+    let mut options = options.clone();
+    if let Mode::DryRun = job.mode {
+        options.dry_run = true;
+    }
+    if let Some(min_age) = &job.min_age {
+        options.min_age = Some(parse_duration(min_age).map_err(DedupError::InvalidJob)?);
+    }
+    if let Some(newer_than) = &job.exclude_newer_than {
+        options.exclude_newer_than =
+            Some(parse_timestamp(newer_than).map_err(DedupError::InvalidJob)?);
+    }
+    if let Some(older_than) = &job.exclude_older_than {
+        options.exclude_older_than =
+            Some(parse_timestamp(older_than).map_err(DedupError::InvalidJob)?);
+    }
+    if job.max_depth.is_some() {
+        options.max_depth = job.max_depth;
+    }
+    if job.min_copies.is_some() {
+        options.min_copies = job.min_copies;
+    }
+    options.one_file_system = job.one_file_system.unwrap_or(options.one_file_system);
+    options.follow_symlinks = job.follow_symlinks.unwrap_or(options.follow_symlinks);
+    options.own_files.extend(job.report.clone());
+    // A checkpoint belongs to the paths it was saved for, so jobs can't share one.
+    options.checkpoint = None;
+    options.resume = None;
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn override_options_per_job() {
+        let jobs: JobsFile = toml::from_str(
+            r#"
+            [[job]]
+            paths = ["/a"]
+
+            [[job]]
+            name = "b"
+            paths = ["/b"]
+            mode = "dry-run"
+            min-age = "1h"
+            max-depth = 2
+            one-file-system = true
+            "#,
+        )
+        .unwrap();
+        let base = DedupOptions {
+            follow_symlinks: true,
+            ..DedupOptions::default()
+        };
+
+        let first = job_options(&jobs.jobs[0], &base).unwrap();
+        let second = job_options(&jobs.jobs[1], &base).unwrap();
+
+        assert!(!first.dry_run);
+        assert_eq!(first.max_depth, None);
+        assert!(second.dry_run);
+        assert_eq!(second.min_age, Some(std::time::Duration::from_secs(3600)));
+        assert_eq!(second.max_depth, Some(2));
+        assert!(second.one_file_system);
+        assert!(second.follow_symlinks);
+        assert!(toml::from_str::<JobsFile>("[[job]]\npaths = []\nunknown = 1").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! Where the savings are, by directory.
+//!
+//! Every hardlink counts towards the directory that contains its path, cut off at
+//! [`crate::DedupOptions::breakdown_depth`] directories below the deduplicated path it was found
+//! under, so that e.g. depth 1 under `/home` sums up the savings of each user.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+/// The duplicates hardlinked in a directory and its subdirectories.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DirectorySavings {
+    pub dir: PathBuf,
+    /// Paths replaced with hardlinks, or that would be in dry runs.
+    pub duplicates: usize,
+    /// Bytes freed, or that would be in dry runs. A file with several paths counts towards the
+    /// directory of its first path.
+    pub bytes: u64,
+}
+
+pub(crate) struct Breakdown {
+    /// The deduplicated paths. Paths under none of them are cut off below the filesystem root.
+    roots: Vec<PathBuf>,
+    depth: usize,
+    dirs: BTreeMap<PathBuf, (usize, u64)>,
+}
+
+impl Breakdown {
+    pub(crate) fn new(roots: &[PathBuf], depth: usize) -> Breakdown {
+        Breakdown {
+            roots: roots.to_vec(),
+            depth,
+            dirs: BTreeMap::new(),
+        }
+    }
+
+    /// Counts the targets replaced with hardlinks to the same file, which freed `bytes`.
+    pub(crate) fn add<'a>(&mut self, targets: impl IntoIterator<Item = &'a Path>, bytes: u64) {
+        let mut bytes = bytes;
+        for target in targets {
+            let (duplicates, dir_bytes) = self.dirs.entry(self.dir(target)).or_default();
+            *duplicates += 1;
+            *dir_bytes += std::mem::take(&mut bytes);
+        }
+    }
+
+    fn dir(&self, path: &Path) -> PathBuf {
+        let parent = path.parent().unwrap_or(path);
+        let root = self
+            .roots
+            .iter()
+            .filter(|root| parent.starts_with(root))
+            .max_by_key(|root| root.components().count());
+        let (mut dir, relative) = match root {
+            Some(root) => (root.clone(), parent.strip_prefix(root).unwrap_or(parent)),
+            None => (PathBuf::new(), parent),
+        };
+        let mut depth = self.depth;
+        for component in relative.components() {
+            if depth == 0 {
+                break;
+            }
+            if let Component::Normal(_) = component {
+                depth -= 1;
+            }
+            dir.push(component);
+        }
+        dir
+    }
+
+    pub(crate) fn directories(&self) -> Vec<DirectorySavings> {
+        let dirs = self
+            .dirs
+            .iter()
+            .map(|(dir, (duplicates, bytes))| DirectorySavings {
+                dir: dir.clone(),
+                duplicates: *duplicates,
+                bytes: *bytes,
+            });
+        sorted(dirs.collect())
+    }
+}
+
+/// Adds up the savings of the same directories.
+pub(crate) fn merge(a: &[DirectorySavings], b: &[DirectorySavings]) -> Vec<DirectorySavings> {
+    let mut dirs: BTreeMap<&Path, (usize, u64)> = BTreeMap::new();
+    for savings in a.iter().chain(b) {
+        let (duplicates, bytes) = dirs.entry(&savings.dir).or_default();
+        *duplicates += savings.duplicates;
+        *bytes += savings.bytes;
+    }
+    let dirs = dirs
+        .into_iter()
+        .map(|(dir, (duplicates, bytes))| DirectorySavings {
+            dir: dir.to_owned(),
+            duplicates,
+            bytes,
+        });
+    sorted(dirs.collect())
+}
+
+/// The directories that saved the most first.
+fn sorted(mut dirs: Vec<DirectorySavings>) -> Vec<DirectorySavings> {
+    dirs.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.dir.cmp(&b.dir)));
+    dirs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn savings_by_directory() {
+        let roots = [PathBuf::from("/home"), PathBuf::from("/home/shared")];
+        let mut breakdown = Breakdown::new(&roots, 1);
+        breakdown.add([Path::new("/home/alice/photos/a.jpg")], 100);
+        breakdown.add(
+            [Path::new("/home/bob/b.jpg"), Path::new("/home/alice/b.jpg")],
+            300,
+        );
+        breakdown.add([Path::new("/home/shared/docs/c.txt")], 50);
+        breakdown.add([Path::new("/home/notes.txt")], 10);
+        breakdown.add([Path::new("/srv/www/d.html")], 20);
+
+        let dir = |dir: &str, duplicates, bytes| DirectorySavings {
+            dir: PathBuf::from(dir),
+            duplicates,
+            bytes,
+        };
+        assert_eq!(
+            breakdown.directories(),
+            [
+                dir("/home/bob", 1, 300),
+                dir("/home/alice", 2, 100),
+                dir("/home/shared/docs", 1, 50),
+                dir("/srv", 1, 20),
+                dir("/home", 1, 10),
+            ]
+        );
+        assert_eq!(
+            merge(&[dir("/a", 1, 10)], &[dir("/b", 1, 5), dir("/a", 2, 1)]),
+            [dir("/a", 3, 11), dir("/b", 1, 5)]
+        );
+    }
+}
@@ -0,0 +1,39 @@
+//! Information about how this binary was built, so that callers can check its capabilities before
+//! handing it work.
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// `None` if the binary wasn't built from a git checkout.
+    pub git_commit: Option<&'static str>,
+    pub features: Vec<&'static str>,
+    pub backends: Backends,
+}
+
+/// Ways of deduplicating files and of querying the filesystem that this build supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Backends {
+    pub hardlink: bool,
+    pub reflink: bool,
+    pub ioctl: bool,
+    pub io_uring: bool,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: option_env!("HARDLINK_DEDUP_GIT_COMMIT"),
+        features: env!("HARDLINK_DEDUP_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+        backends: Backends {
+            hardlink: true,
+            reflink: crate::reflink::REFLINK_SUPPORTED,
+            ioctl: cfg!(target_os = "linux"),
+            io_uring: cfg!(feature = "io-uring"),
+        },
+    }
+}
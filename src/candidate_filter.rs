@@ -0,0 +1,74 @@
+//! Checks of library users on top of ours before two files with the same contents are hardlinked.
+//!
+//! Files are only hardlinked if they have the same device, size, owner, mode, and contents. Some
+//! files should stay apart even then, e.g. files with different extensions or whose application
+//! tracks them separately. A [`CandidateFilter`] decides which files with the same contents may be
+//! hardlinked to which originals. Such files are split into groups that only hold files the
+//! filters allow hardlinking to the group's original, so that e.g. the `.txt` copies below are
+//! hardlinked to one another rather than left alone because the original is a `.jpg`:
+//!
+//! ```no_run
+//! use hardlink_dedup::{CandidateFilters, Deduper};
+//!
+//! let same_extension = |original: &std::path::Path, target: &std::path::Path| {
+//!     original.extension() == target.extension()
+//! };
+//! Deduper::new()
+//!     .path("/srv/data")
+//!     .candidate_filters(CandidateFilters::new().with(same_extension))
+//!     .run()?;
+//! # Ok::<(), hardlink_dedup::DedupError>(())
+//! ```
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Decides whether a target may be replaced with a hardlink to an original with the same contents.
+/// Closures taking the original and the target are filters too.
+pub trait CandidateFilter: Send + Sync {
+    fn may_link(&self, original: &Path, target: &Path) -> bool;
+}
+
+impl<F: Fn(&Path, &Path) -> bool + Send + Sync> CandidateFilter for F {
+    fn may_link(&self, original: &Path, target: &Path) -> bool {
+        self(original, target)
+    }
+}
+
+/// The filters all of which must allow hardlinking a target to its original.
+#[derive(Clone, Default)]
+pub struct CandidateFilters {
+    filters: Vec<Arc<dyn CandidateFilter>>,
+}
+
+impl CandidateFilters {
+    pub fn new() -> CandidateFilters {
+        CandidateFilters::default()
+    }
+
+    pub fn with(mut self, filter: impl CandidateFilter + 'static) -> CandidateFilters {
+        self.filters.push(Arc::new(filter));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub(crate) fn may_link(&self, original: &Path, target: &Path) -> bool {
+        self.filters
+            .iter()
+            .all(|filter| filter.may_link(original, target))
+    }
+}
+
+impl fmt::Debug for CandidateFilters {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "CandidateFilters({} filters)",
+            self.filters.len()
+        )
+    }
+}
@@ -0,0 +1,70 @@
+//! Checkpoints of which groups of files have been fully processed, so that an interrupted run can
+//! be resumed.
+//!
+//! Groups are identified by the device, size, owner, mode and, if required, modification time their
+//! files share. Hardlinking doesn't change any of these, so a group keeps its identity across runs.
+
+use crate::error::DedupError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{read_to_string, rename, write};
+use std::io;
+use std::path::Path;
+
+/// Device, size, gid, uid, mode, modification time in nanoseconds, and allocated blocks of all files
+/// in a group. The modification time is 0 unless [`crate::DedupOptions::require_same_mtime`] is set,
+/// and the blocks unless [`crate::DedupOptions::group_by_allocated_size`] is.
+pub(crate) type GroupKey = (u64, u64, u32, u32, u32, i64, u64);
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    completed_groups: HashSet<GroupKey>,
+}
+
+impl Checkpoint {
+    pub(crate) fn load(path: &Path) -> Result<Checkpoint, DedupError> {
+        let contents = read_to_string(path).map_err(|source| DedupError::ReadCheckpoint {
+            path: path.to_owned(),
+            source,
+        })?;
+        serde_json::from_str(&contents).map_err(|source| DedupError::ParseCheckpoint {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    /// Replaces the checkpoint file atomically so that a crash never leaves a partial checkpoint.
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        write(&tmp_path, serde_json::to_vec(self)?)?;
+        rename(&tmp_path, path)
+    }
+
+    pub(crate) fn is_completed(&self, group: &GroupKey) -> bool {
+        self.completed_groups.contains(group)
+    }
+
+    pub(crate) fn complete(&mut self, group: GroupKey) {
+        self.completed_groups.insert(group);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn save_and_load() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("state.json");
+        let mut checkpoint = Checkpoint::default();
+        checkpoint.complete((1, 2, 3, 4, 5, 6, 7));
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        assert!(loaded.is_completed(&(1, 2, 3, 4, 5, 6, 7)));
+        assert!(!loaded.is_completed(&(1, 2, 3, 4, 6, 6, 7)));
+    }
+}
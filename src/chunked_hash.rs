@@ -0,0 +1,110 @@
+//! Hashing of large files in chunks.
+//!
+//! Large files with the same size, prefix and tail can still differ anywhere in between. Instead of
+//! hashing them whole, we hash them a chunk at a time and split their group after every chunk, so we
+//! stop reading a file as soon as it differs from all the other files.
+
+use crate::page_cache::{ReadFile, ReadOptions};
+use crate::{group_by, group_file_size, HashAlgorithm};
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Files at least this large are hashed in chunks.
+pub(crate) const CHUNKED_HASH_THRESHOLD: u64 = 64 * 1024 * 1024;
+/// Size of the first chunk.
+pub(crate) const FIRST_CHUNK_BYTES: u64 = 1024 * 1024;
+/// Chunks double in size after every round up to this size, so files that stay the same don't take
+/// too many rounds.
+const MAX_CHUNK_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Groups files of the same size by the hashes of their chunks. Files in the returned groups have
+/// the same hashes of all their chunks, except for files that differ from all the other files,
+/// which are returned alone. Files we fail to hash are added to `unhashed_files` instead.
+pub(crate) fn same_chunked_hash_groups<'a>(
+    files: HashSet<&'a PathBuf>,
+    algorithm: HashAlgorithm,
+    read_options: ReadOptions,
+    first_chunk_bytes: u64,
+    unhashed_files: &mut Vec<&'a PathBuf>,
+) -> Vec<HashSet<&'a PathBuf>> {
+    let size = group_file_size(&files);
+    let mut finished_groups = Vec::new();
+    let mut groups = vec![files];
+    let mut offset = 0;
+    let mut chunk_bytes = first_chunk_bytes.max(1);
+    while !groups.is_empty() {
+        let reached_end = offset + chunk_bytes >= size;
+        let mut next_groups = Vec::new();
+        for group in groups {
+            let chunk_groups = group_by(group.into_iter(), |file| {
+                hash_chunk(file, offset, chunk_bytes, algorithm, read_options)
+                    .map_err(|err| {
+                        unhashed_files.push(file);
+                        warn!(
+                            "Failed to calculate the hash of the contents of {:?} at offset {}. Error: {}",
+                            file, offset, err
+                        )
+                    })
+                    .ok()
+            });
+            for chunk_group in chunk_groups {
+                if reached_end || chunk_group.len() < 2 {
+                    finished_groups.push(chunk_group);
+                } else {
+                    next_groups.push(chunk_group);
+                }
+            }
+        }
+        groups = next_groups;
+        offset += chunk_bytes;
+        chunk_bytes = chunk_bytes.max((chunk_bytes * 2).min(MAX_CHUNK_BYTES));
+    }
+    finished_groups
+}
+
+fn hash_chunk(
+    file: &Path,
+    offset: u64,
+    chunk_bytes: u64,
+    algorithm: HashAlgorithm,
+    read_options: ReadOptions,
+) -> io::Result<Vec<u8>> {
+    let mut open_file = ReadFile::open(file, read_options)?;
+    open_file.seek(SeekFrom::Start(offset))?;
+    algorithm.hash_reader(open_file.take(chunk_bytes), read_options.buffer_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn split_groups_by_chunks() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_dir.path().join("file1");
+        let file2 = tmp_dir.path().join("file2");
+        let file3 = tmp_dir.path().join("file3");
+        let file4 = tmp_dir.path().join("file4");
+        write(&file1, "aaaabbbbcccc").unwrap();
+        write(&file2, "aaaabbbbcccc").unwrap();
+        write(&file3, "aaaabbbbdddd").unwrap();
+        write(&file4, "xaaabbbbcccc").unwrap();
+
+        let groups = same_chunked_hash_groups(
+            HashSet::from([&file1, &file2, &file3, &file4]),
+            HashAlgorithm::Sha256,
+            ReadOptions::default(),
+            4,
+            &mut Vec::new(),
+        );
+
+        assert_eq!(groups.len(), 3);
+        assert!(groups.contains(&HashSet::from([&file1, &file2])));
+        assert!(groups.contains(&HashSet::from([&file3])));
+        assert!(groups.contains(&HashSet::from([&file4])));
+    }
+}
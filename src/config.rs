@@ -0,0 +1,201 @@
+//! Options read from a TOML file, for scheduled runs that would otherwise need long command lines.
+//!
+//! ```toml
+//! paths = ["/srv/photos", "/srv/photo-backups"]
+//! jobs = 4
+//! min-age = "1h"
+//! dry-run = true
+//! reference = ["/srv/golden"]
+//! ```
+//!
+//! Keys are the long names of the command-line options and `paths` the paths to deduplicate.
+//! Flags take booleans, counted flags like `verbose` numbers, and options that can be given several
+//! times arrays. Options given on the command line override the file's, lists included. The file
+//! is `$XDG_CONFIG_HOME/hardlink-dedup.toml` (or `~/.config/hardlink-dedup.toml`) unless --config
+//! names another one. Runs of the `dedup` and `report` subcommands read it too, and runs of the
+//! others don't.
+
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use std::env;
+use std::ffi::OsString;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// The config file read when --config isn't given, if there is one.
+pub fn default_config() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("hardlink-dedup.toml")).filter(|config| config.is_file())
+}
+
+/// The arguments with the options of the config file added in front of the options of the command
+/// (those from `options_start` on), except for those the arguments already give.
+pub fn with_config(
+    command: &Command,
+    matches: &ArgMatches,
+    args: Vec<OsString>,
+    options_start: usize,
+    config: &Path,
+) -> Result<Vec<OsString>, String> {
+    let text = read_to_string(config)
+        .map_err(|err| format!("Failed to read the config {:?}. Error: {}", config, err))?;
+    let table: Table = toml::from_str(&text)
+        .map_err(|err| format!("Invalid config file {:?}. Error: {}", config, err))?;
+    let mut options = Vec::new();
+    let mut paths = Vec::new();
+    for (key, value) in &table {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key) || (arg.is_positional() && arg.get_id() == key))
+            .filter(|arg| !arg.is_hide_set() && key != "config" && key != "no-config")
+            .ok_or_else(|| format!("Unknown option {:?} in the config {:?}.", key, config))?;
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let invalid = |expected: &str| {
+            format!(
+                "Option {:?} in the config {:?} must be {}.",
+                key, config, expected
+            )
+        };
+        let flag = format!("--{}", key);
+        match arg.get_action() {
+            ArgAction::SetTrue => match value {
+                Value::Boolean(true) => options.push(flag.into()),
+                Value::Boolean(false) => {}
+                _ => return Err(invalid("true or false")),
+            },
+            ArgAction::Count => match value {
+                Value::Integer(count) if *count >= 0 => {
+                    options.extend((0..*count).map(|_| flag.clone().into()))
+                }
+                _ => return Err(invalid("a count")),
+            },
+            action => {
+                let values = match value {
+                    Value::Array(values) if action.takes_values() && allows_many(arg) => {
+                        values.iter().collect()
+                    }
+                    Value::Array(_) => return Err(invalid("a single value")),
+                    value => vec![value],
+                };
+                for value in values {
+                    let value = match value {
+                        Value::String(value) => value.clone(),
+                        Value::Integer(value) => value.to_string(),
+                        Value::Float(value) => value.to_string(),
+                        Value::Boolean(value) => value.to_string(),
+                        _ => return Err(invalid("a string, number, or boolean")),
+                    };
+                    if arg.is_positional() {
+                        paths.push(value.into());
+                    } else {
+                        options.push(format!("{}={}", flag, value).into());
+                    }
+                }
+            }
+        }
+    }
+    let mut args = args;
+    let command_args = args.split_off(options_start);
+    Ok(args
+        .into_iter()
+        .chain(options)
+        .chain(command_args)
+        .chain(paths)
+        .collect())
+}
+
+/// Whether the argument can be given several times or takes several values.
+fn allows_many(arg: &clap::Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append)
+        || arg
+            .get_num_args()
+            .is_some_and(|num_args| num_args.max_values() > 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Args;
+    use clap::{CommandFactory, Parser};
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    fn parse(config: &str, args: &[&str]) -> Result<Args, String> {
+        let tmp_dir = tempdir().unwrap();
+        let config_file = tmp_dir.path().join("config.toml");
+        write(&config_file, config).unwrap();
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        let command = Args::command();
+        let matches = command.clone().get_matches_from(&args);
+        let args = with_config(&command, &matches, args, 1, &config_file)?;
+        Ok(Args::parse_from(args))
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let config = r#"
+            paths = ["/a", "/b"]
+            jobs = 3
+            dry-run = true
+            verbose = 2
+            min-age = "1h"
+            reference = ["/golden"]
+        "#;
+
+        let args = parse(config, &["hardlink-dedup"]).unwrap();
+        assert_eq!(args.dedup.paths, [PathBuf::from("/a"), PathBuf::from("/b")]);
+        assert_eq!(args.dedup.jobs, 3);
+        assert!(args.dedup.dry_run);
+        assert_eq!(args.verbose, 2);
+        assert_eq!(args.dedup.reference_dirs, [PathBuf::from("/golden")]);
+
+        let args = parse(config, &["hardlink-dedup", "--jobs", "5", "-v", "/c"]).unwrap();
+        assert_eq!(args.dedup.paths, [PathBuf::from("/c")]);
+        assert_eq!(args.dedup.jobs, 5);
+        assert_eq!(args.verbose, 1);
+        assert!(args.dedup.dry_run);
+    }
+
+    #[test]
+    fn configure_subcommands() {
+        let tmp_dir = tempdir().unwrap();
+        let config_file = tmp_dir.path().join("config.toml");
+        write(&config_file, "jobs = 3\nverbose = 2\npaths = [\"/a\"]").unwrap();
+        let args: Vec<OsString> = ["hardlink-dedup", "report", "-v"]
+            .iter()
+            .map(OsString::from)
+            .collect();
+        let mut command = Args::command();
+        command.build();
+        let matches = command.clone().get_matches_from(&args);
+        let report = command.find_subcommand("report").unwrap();
+        let report_matches = matches.subcommand_matches("report").unwrap();
+        let args = with_config(report, report_matches, args, 2, &config_file).unwrap();
+
+        let args = Args::parse_from(args);
+        assert_eq!(args.verbose, 1);
+        let Some(crate::Command::Report(report)) = args.command else {
+            panic!("Expected the report subcommand.");
+        };
+        assert_eq!(report.jobs, 3);
+        assert_eq!(report.paths, [PathBuf::from("/a")]);
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        let err = parse("no-such-option = 1", &["hardlink-dedup"])
+            .err()
+            .unwrap();
+        assert!(err.starts_with("Unknown option \"no-such-option\""));
+        assert!(parse("dry-run = \"yes\"", &["hardlink-dedup"]).is_err());
+        assert!(parse("jobs = [1, 2]", &["hardlink-dedup"]).is_err());
+        assert!(parse("config = \"/other.toml\"", &["hardlink-dedup"]).is_err());
+        assert!(parse("jobs =", &["hardlink-dedup"]).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+//! Reporting of duplicates on different filesystems.
+//!
+//! Hardlinks can't span filesystems, so deduplication never compares files on different devices.
+//! Reporting their duplicates separately shows how much moving or consolidating the files would
+//! save.
+
+use crate::output::Event;
+use crate::{
+    file_id, group_by, same_hash_groups, same_prefix_groups, DedupContext, FileId,
+    DEFAULT_PREFIX_BYTES,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs::metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Reports groups of files with the same contents on more than one device.
+pub(crate) fn report_cross_device_duplicates(
+    inode_to_paths: &HashMap<FileId, HashSet<PathBuf>>,
+    ctx: &mut DedupContext,
+) {
+    // Hardlinking merged some of the inodes we found, so we look them up again.
+    let mut sizes_and_devices = HashMap::new();
+    let mut seen = HashSet::new();
+    for file in inode_to_paths
+        .values()
+        .flat_map(|paths| paths.iter().next())
+    {
+        if let Ok(file_metadata) = metadata(file) {
+            if seen.insert(file_id(&file_metadata)) {
+                sizes_and_devices.insert(file, (file_metadata.len(), file_metadata.dev()));
+            }
+        }
+    }
+    let size_groups: Vec<_> = group_by(sizes_and_devices.keys().copied(), |file| {
+        Some(sizes_and_devices[file].0)
+    })
+    .filter(|group| device_count(group, &sizes_and_devices) > 1)
+    .collect();
+    for size_group in size_groups {
+        if ctx.interrupted() {
+            break;
+        }
+        let prefix_groups: Vec<_> = same_prefix_groups(
+            size_group,
+            DEFAULT_PREFIX_BYTES,
+            ctx.options.read_options(),
+            &mut ctx.failed_files,
+        )
+        .filter(|group| device_count(group, &sizes_and_devices) > 1)
+        .collect();
+        for prefix_group in prefix_groups {
+            let hash_groups: Vec<_> = same_hash_groups(
+                prefix_group,
+                ctx.hash_pool.as_mut(),
+                None,
+                ctx.options,
+                &mut ctx.failed_files,
+            )
+            .collect();
+            for hash_group in hash_groups {
+                let devices = device_count(&hash_group, &sizes_and_devices);
+                if devices > 1 {
+                    let size = sizes_and_devices[hash_group.iter().next().unwrap()].0;
+                    // Every filesystem still needs one copy.
+                    report_group(&hash_group, size * (devices as u64 - 1), ctx);
+                }
+            }
+        }
+    }
+}
+
+fn device_count(
+    group: &HashSet<&PathBuf>,
+    sizes_and_devices: &HashMap<&PathBuf, (u64, u64)>,
+) -> usize {
+    group
+        .iter()
+        .map(|file| sizes_and_devices[file].1)
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+fn report_group(group: &HashSet<&PathBuf>, bytes: u64, ctx: &mut DedupContext) {
+    let mut files: Vec<&Path> = group.iter().map(|file| file.as_path()).collect();
+    files.sort();
+    ctx.cross_device_groups += 1;
+    ctx.cross_device_bytes += bytes;
+    ctx.emit(Event::CrossDeviceDuplicates {
+        files: &files,
+        bytes,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{dedup_with_options, DedupOptions};
+    use std::fs::{metadata, write};
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::{tempdir, tempdir_in};
+
+    #[test]
+    fn report_duplicates_on_other_filesystem() {
+        let tmp_dir = tempdir().unwrap();
+        let Ok(other_tmp_dir) = tempdir_in("/dev/shm") else {
+            return;
+        };
+        if metadata(tmp_dir.path()).unwrap().dev() == metadata(other_tmp_dir.path()).unwrap().dev()
+        {
+            return;
+        }
+        write(tmp_dir.path().join("file"), "same contents").unwrap();
+        write(other_tmp_dir.path().join("file"), "same contents").unwrap();
+        write(other_tmp_dir.path().join("other"), "diff contents").unwrap();
+
+        let summary = dedup_with_options(
+            &[tmp_dir.path().to_owned(), other_tmp_dir.path().to_owned()],
+            &DedupOptions {
+                report_cross_device: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(summary.cross_device_groups, 1);
+        assert_eq!(summary.cross_device_bytes, 13);
+        assert_eq!(summary.bytes_deduped, 0);
+    }
+}
@@ -0,0 +1,197 @@
+//! A CSV report of what happened to every duplicate file, for loading into spreadsheets.
+//!
+//! Each group of duplicates gets a number. Its kept file has a row with the action `kept`, and every
+//! other file a row with the file it was (or would be) linked to and one of these actions:
+//!
+//! - `linked`, or `would link` in dry runs.
+//! - `already linked`: it already was a hardlink to the kept file.
+//! - `skipped (<reason>)`: it was modified recently, outside the time window, the filesystem was
+//!   full, the user declined, or its directory isn't writable.
+//! - `failed`: hardlinking it failed or was interrupted. The log says why.
+
+use hardlink_dedup::content_hash;
+use hardlink_dedup::output::{Event, Renderer, Status};
+use hardlink_dedup::{DedupError, DedupOptions};
+use std::collections::HashMap;
+use std::fs::{metadata, File, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+const HEADER: [&str; 6] = ["group", "kept", "linked", "size", "hash", "action"];
+
+/// A group of duplicates whose rows are written once the next group starts.
+struct Group {
+    files: Vec<PathBuf>,
+    kept: Option<PathBuf>,
+    actions: HashMap<PathBuf, String>,
+}
+
+/// Renders events with the inner renderer and writes the rows of each group of duplicates.
+pub struct CsvRenderer {
+    inner: Box<dyn Renderer>,
+    writer: csv::Writer<File>,
+    path: PathBuf,
+    options: DedupOptions,
+    groups: usize,
+    group: Option<Group>,
+    /// Set after the first error writing the report. Nothing more is written after it.
+    failed: bool,
+}
+
+impl CsvRenderer {
+    pub fn create(
+        path: &Path,
+        inner: Box<dyn Renderer>,
+        options: &DedupOptions,
+    ) -> Result<CsvRenderer, DedupError> {
+        let create = || -> csv::Result<csv::Writer<File>> {
+            let mut writer = csv::Writer::from_path(path)?;
+            writer.write_record(HEADER)?;
+            writer.flush()?;
+            Ok(writer)
+        };
+        let writer = create().map_err(|source| DedupError::CreateReport {
+            path: path.to_owned(),
+            source,
+        })?;
+        Ok(CsvRenderer {
+            inner,
+            writer,
+            path: path.to_owned(),
+            options: options.clone(),
+            groups: 0,
+            group: None,
+            failed: false,
+        })
+    }
+
+    /// Writes the rows of the current group.
+    fn write_group(&mut self) -> csv::Result<()> {
+        let group = match self.group.take() {
+            Some(group) => group,
+            None => return Ok(()),
+        };
+        let kept = group.kept.as_ref().unwrap_or(&group.files[0]);
+        let kept_metadata = metadata(kept).ok();
+        let size = kept_metadata
+            .as_ref()
+            .map_or(String::new(), |kept_metadata| {
+                kept_metadata.len().to_string()
+            });
+        // The engine doesn't hash pairs of files, which it compares directly, so hash one copy here.
+        let hash = content_hash(kept, &self.options).unwrap_or_else(|err| {
+            warn!("Failed to hash {:?} for the report. Error: {}", kept, err);
+            String::new()
+        });
+        let group_number = self.groups.to_string();
+        let kept_text = kept.to_string_lossy();
+        self.writer
+            .write_record([&group_number, &*kept_text, "", &size, &hash, "kept"])?;
+        for file in group.files.iter().filter(|file| *file != kept) {
+            let action = match group.actions.get(file) {
+                Some(action) => action.as_str(),
+                None if same_inode(file, kept_metadata.as_ref()) => "already linked",
+                None => "failed",
+            };
+            self.writer.write_record([
+                &group_number,
+                &*kept_text,
+                &*file.to_string_lossy(),
+                &size,
+                &hash,
+                action,
+            ])?;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, event: &Event) -> csv::Result<()> {
+        match event {
+            Event::Duplicates { files, .. } => {
+                self.write_group()?;
+                self.groups += 1;
+                self.group = Some(Group {
+                    files: files.iter().map(|file| file.to_path_buf()).collect(),
+                    kept: None,
+                    actions: HashMap::new(),
+                });
+            }
+            Event::Hardlinked {
+                original_file,
+                target,
+                dry_run,
+            } => {
+                let action = if *dry_run { "would link" } else { "linked" };
+                self.set_action(original_file, target, action.to_owned());
+            }
+            Event::Skipped {
+                original_file,
+                target,
+                reason,
+            } => {
+                let reason = serde_json::to_value(reason).unwrap();
+                let action = format!("skipped ({})", reason.as_str().unwrap_or_default());
+                self.set_action(original_file, target, action);
+            }
+            Event::SkippedUnwritable {
+                original_file,
+                targets,
+            } => {
+                for target in *targets {
+                    let action = "skipped (unwritable directory)".to_owned();
+                    self.set_action(original_file, target, action);
+                }
+            }
+            Event::Finished { .. } => {
+                self.write_group()?;
+                self.writer.flush()?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn set_action(&mut self, original_file: &Path, target: &Path, action: String) {
+        if let Some(group) = &mut self.group {
+            group.kept = Some(original_file.to_owned());
+            group.actions.insert(target.to_owned(), action);
+        }
+    }
+}
+
+impl Renderer for CsvRenderer {
+    fn renders(&self, event: &Event) -> bool {
+        self.inner.renders(event)
+            || matches!(
+                event,
+                Event::Duplicates { .. }
+                    | Event::Hardlinked { .. }
+                    | Event::Skipped { .. }
+                    | Event::SkippedUnwritable { .. }
+                    | Event::Finished { .. }
+            )
+    }
+
+    fn render(&mut self, status: &Status, event: &Event) {
+        if self.inner.renders(event) {
+            self.inner.render(status, event);
+        }
+        if self.failed {
+            return;
+        }
+        if let Err(err) = self.record(event) {
+            warn!("Failed to write the report {:?}. Error: {}", self.path, err);
+            self.failed = true;
+        }
+    }
+}
+
+fn same_inode(file: &Path, kept_metadata: Option<&Metadata>) -> bool {
+    match (metadata(file), kept_metadata) {
+        (Ok(file_metadata), Some(kept_metadata)) => {
+            (file_metadata.dev(), file_metadata.ino()) == (kept_metadata.dev(), kept_metadata.ino())
+        }
+        _ => false,
+    }
+}
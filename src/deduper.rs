@@ -0,0 +1,278 @@
+//! A builder for deduplication runs, for code that embeds the library.
+//!
+//! ```no_run
+//! use hardlink_dedup::{Deduper, HashAlgorithm, Prefer};
+//!
+//! let summary = Deduper::new()
+//!     .path("/srv/photos")
+//!     .path("/srv/photo-backups")
+//!     .dry_run(true)
+//!     .jobs(4)
+//!     .hash(HashAlgorithm::Blake3)
+//!     .prefer(Prefer::Oldest)
+//!     .run()?;
+//! println!("Would save {} bytes.", summary.bytes_deduped);
+//! # Ok::<(), hardlink_dedup::DedupError>(())
+//! ```
+//!
+//! Options without a method of their own can be set on [`DedupOptions`] and passed to
+//! [`Deduper::options`].
+
+use crate::output::{Event, Renderer, Status};
+use crate::{
+    dedup_with_options, dedup_with_renderer, CandidateFilters, DedupError, DedupOptions,
+    DedupSummary, HashAlgorithm, Prefer, ProtectedPaths,
+};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The paths to deduplicate and how. Runs with the defaults of [`DedupOptions`] unless told
+/// otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct Deduper {
+    paths: Vec<PathBuf>,
+    options: DedupOptions,
+}
+
+impl Deduper {
+    pub fn new() -> Deduper {
+        Deduper::default()
+    }
+
+    /// Adds a path (a directory or a file) to deduplicate.
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Deduper {
+        self.paths.push(path.into());
+        self
+    }
+
+    /// Adds paths to deduplicate.
+    pub fn paths<P: Into<PathBuf>>(mut self, paths: impl IntoIterator<Item = P>) -> Deduper {
+        self.paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Replaces all options, including those set so far.
+    pub fn options(mut self, options: DedupOptions) -> Deduper {
+        self.options = options;
+        self
+    }
+
+    /// See [`DedupOptions::dry_run`].
+    pub fn dry_run(mut self, dry_run: bool) -> Deduper {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    /// See [`DedupOptions::paranoid`].
+    pub fn paranoid(mut self, paranoid: bool) -> Deduper {
+        self.options.paranoid = paranoid;
+        self
+    }
+
+    /// See [`DedupOptions::jobs`].
+    pub fn jobs(mut self, jobs: usize) -> Deduper {
+        self.options.jobs = jobs;
+        self
+    }
+
+    /// See [`DedupOptions::hash`].
+    pub fn hash(mut self, hash: HashAlgorithm) -> Deduper {
+        self.options.hash = hash;
+        self
+    }
+
+    /// Which file of each group of duplicates to keep. See [`DedupOptions::prefer`].
+    pub fn prefer(mut self, prefer: Prefer) -> Deduper {
+        self.options.prefer = Some(prefer);
+        self
+    }
+
+    /// See [`DedupOptions::min_age`].
+    pub fn min_age(mut self, min_age: Duration) -> Deduper {
+        self.options.min_age = Some(min_age);
+        self
+    }
+
+    /// See [`DedupOptions::min_copies`].
+    pub fn min_copies(mut self, min_copies: usize) -> Deduper {
+        self.options.min_copies = Some(min_copies);
+        self
+    }
+
+    /// See [`DedupOptions::max_depth`].
+    pub fn max_depth(mut self, max_depth: usize) -> Deduper {
+        self.options.max_depth = Some(max_depth);
+        self
+    }
+
+    /// See [`DedupOptions::one_file_system`].
+    pub fn one_file_system(mut self, one_file_system: bool) -> Deduper {
+        self.options.one_file_system = one_file_system;
+        self
+    }
+
+    /// See [`DedupOptions::follow_symlinks`].
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Deduper {
+        self.options.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Adds a directory whose files are never replaced. See [`DedupOptions::reference_dirs`].
+    pub fn reference_dir(mut self, dir: impl Into<PathBuf>) -> Deduper {
+        self.options.reference_dirs.push(dir.into());
+        self
+    }
+
+    /// See [`DedupOptions::protected`].
+    pub fn protect(mut self, protected: ProtectedPaths) -> Deduper {
+        self.options.protected = protected;
+        self
+    }
+
+    /// See [`DedupOptions::candidate_filters`].
+    pub fn candidate_filters(mut self, candidate_filters: CandidateFilters) -> Deduper {
+        self.options.candidate_filters = candidate_filters;
+        self
+    }
+
+    /// Cancels the run when the token is set, e.g. from another thread. The run stops between
+    /// files and returns the summary of what it did so far, with [`DedupSummary::interrupted`] set.
+    /// See [`DedupOptions::interrupted`].
+    pub fn cancel_with(mut self, token: Arc<AtomicBool>) -> Deduper {
+        self.options.interrupted = token;
+        self
+    }
+
+    /// The token that cancels the run when set, the one given to [`Deduper::cancel_with`] or a new
+    /// one.
+    pub fn cancellation_token(&self) -> Arc<AtomicBool> {
+        self.options.interrupted.clone()
+    }
+
+    /// Deduplicates the paths like [`dedup_with_options`].
+    pub fn run(&self) -> Result<DedupSummary, DedupError> {
+        dedup_with_options(&self.paths, &self.options)
+    }
+
+    /// Deduplicates the paths like [`dedup_with_renderer`].
+    pub fn run_with_renderer(
+        &self,
+        renderer: &mut dyn Renderer,
+    ) -> Result<DedupSummary, DedupError> {
+        dedup_with_renderer(&self.paths, &self.options, renderer)
+    }
+
+    /// Deduplicates the paths and passes every event to the observer instead of printing anything:
+    /// the files found, the groups of duplicates, the files hardlinked and skipped, and the
+    /// progress so far in the [`Status`] of each.
+    pub fn run_with_observer(
+        &self,
+        mut observer: impl FnMut(&Status, &Event),
+    ) -> Result<DedupSummary, DedupError> {
+        self.run_with_renderer(&mut observer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{duplicate_files, same, tmp_file};
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+    use tempfile::tempdir;
+
+    #[test]
+    fn build_and_run() {
+        let tmp_dir = tempdir().unwrap();
+        let files = duplicate_files(tmp_dir.path(), 2, "same contents");
+        let deduper = Deduper::new()
+            .paths([tmp_dir.path()])
+            .dry_run(true)
+            .jobs(2)
+            .prefer(Prefer::Newest);
+        assert!(deduper.options.dry_run);
+        assert_eq!(deduper.options.prefer, Some(Prefer::Newest));
+
+        let summary = deduper.run().unwrap();
+        assert_eq!(summary.bytes_deduped, 13);
+        assert!(!same(&files[0], &files[1]));
+
+        Deduper::new().path(tmp_dir.path()).run().unwrap();
+        assert!(same(&files[0], &files[1]));
+    }
+
+    #[test]
+    fn observe_progress() {
+        let tmp_dir = tempdir().unwrap();
+        duplicate_files(tmp_dir.path(), 2, "same contents");
+        let mut scanned = 0;
+        let mut duplicate_bytes = 0;
+        let mut hardlinked = 0;
+        let mut last_status = None;
+        Deduper::new()
+            .path(tmp_dir.path())
+            .run_with_observer(|status, event| {
+                match event {
+                    Event::Scanned { bytes, .. } => scanned += bytes,
+                    Event::Duplicates { bytes, .. } => duplicate_bytes += bytes,
+                    Event::Hardlinked { .. } => hardlinked += 1,
+                    _ => (),
+                }
+                last_status = Some(*status);
+            })
+            .unwrap();
+
+        assert_eq!(scanned, 26);
+        assert_eq!(duplicate_bytes, 13);
+        assert_eq!(hardlinked, 1);
+        let last_status = last_status.unwrap();
+        assert_eq!(last_status.processed_files, 2);
+        assert_eq!(last_status.bytes_deduped, 13);
+    }
+
+    #[test]
+    fn filter_candidates() {
+        let tmp_dir = tempdir().unwrap();
+        let jpg = tmp_file(tmp_dir.path(), "a.jpg", "same contents");
+        let other_jpg = tmp_file(tmp_dir.path(), "b.jpg", "same contents");
+        let txt = tmp_file(tmp_dir.path(), "c.txt", "same contents");
+        let other_txt = tmp_file(tmp_dir.path(), "d.txt", "same contents");
+        let same_extension =
+            |original: &Path, target: &Path| original.extension() == target.extension();
+
+        Deduper::new()
+            .path(tmp_dir.path())
+            .candidate_filters(CandidateFilters::new().with(same_extension))
+            .run()
+            .unwrap();
+
+        assert!(same(&jpg, &other_jpg));
+        assert!(same(&txt, &other_txt));
+        assert!(!same(&jpg, &txt));
+    }
+
+    #[test]
+    fn cancel_run() {
+        let tmp_dir = tempdir().unwrap();
+        let files = duplicate_files(tmp_dir.path(), 2, "same contents");
+        let token = Arc::new(AtomicBool::new(false));
+        let deduper = Deduper::new()
+            .path(tmp_dir.path())
+            .cancel_with(token.clone());
+        assert!(Arc::ptr_eq(&deduper.cancellation_token(), &token));
+
+        let summary = deduper
+            .run_with_observer(|_, event| {
+                if let Event::Scanned { .. } = event {
+                    token.store(true, Ordering::Relaxed);
+                }
+            })
+            .unwrap();
+
+        assert!(summary.interrupted);
+        assert_eq!(summary.bytes_deduped, 0);
+        assert!(!same(&files[0], &files[1]));
+    }
+}
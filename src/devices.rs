@@ -0,0 +1,160 @@
+//! What kind of storage the files are on, so that they're read the way that suits it.
+//!
+//! Spinning disks read one file after the other fastest. Reading several files at once makes their
+//! heads seek back and forth between the files, which can cut their throughput to a fraction. SSDs
+//! on the other hand are only fast with several reads in flight. All files hashed or compared
+//! together are on the same device, so each group is read the way that suits its device: on
+//! spinning disks one file at a time in the order of their inodes, which roughly follows where they
+//! are on the disk, and compared with one other file at a time, and on SSDs several files at a
+//! time. Devices whose kind we can't tell (e.g. network filesystems and platforms other than Linux)
+//! are read one file at a time. `--max-parallel-hashes` sets how many files of each device that
+//! isn't a spinning disk are hashed at a time instead.
+//!
+//! The groups of each device form a queue. While a group is deduplicated, a thread per other device
+//! hashes the upcoming groups of its queue (see [`crate::prefetch`]), so different devices are read
+//! at the same time but each device only as many files at a time as its kind allows.
+
+use std::collections::BTreeMap;
+use std::fs::metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::debug;
+
+/// The kind of storage of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Medium {
+    Rotational,
+    SolidState,
+    Unknown,
+}
+
+/// How many files of a group on an SSD are hashed at a time in this process by default.
+const SOLID_STATE_READERS: usize = 4;
+
+/// How many files of a group on the medium are hashed at a time in this process: `per_device` when
+/// given, except on spinning disks, which are read one file at a time.
+pub(crate) fn hash_readers(medium: Medium, per_device: Option<usize>) -> usize {
+    match (medium, per_device) {
+        (Medium::Rotational, _) => 1,
+        (_, Some(readers)) => readers.max(1),
+        (Medium::SolidState, None) => SOLID_STATE_READERS,
+        (Medium::Unknown, None) => 1,
+    }
+}
+
+/// How many files on the medium are compared at once at most. Spinning disks seek between the
+/// files after each chunk, so they compare two files at a time.
+pub(crate) fn compare_readers(medium: Medium) -> usize {
+    match medium {
+        Medium::Rotational => 2,
+        Medium::SolidState | Medium::Unknown => usize::MAX,
+    }
+}
+
+/// The most memory comparing a group of files on a spinning disk uses for its chunks.
+const ROTATIONAL_COMPARE_BYTES: usize = 64 << 20;
+/// Comparing files on a spinning disk seeks from one file to the next after each chunk, so the
+/// chunks are as large as this when there's memory for them.
+const ROTATIONAL_CHUNK_BYTES: usize = 8 << 20;
+
+/// The media of the devices checked so far.
+static MEDIA: Mutex<BTreeMap<u64, Medium>> = Mutex::new(BTreeMap::new());
+
+/// The medium of the device of the files, which are all on the same device.
+pub(crate) fn files_medium(files: &[&PathBuf]) -> Medium {
+    match files.first().map(metadata) {
+        Some(Ok(file_metadata)) => medium(file_metadata.dev()),
+        _ => Medium::Unknown,
+    }
+}
+
+fn medium(device: u64) -> Medium {
+    let mut media = MEDIA.lock().unwrap_or_else(|err| err.into_inner());
+    *media.entry(device).or_insert_with(|| {
+        let medium = detect_medium(device);
+        debug!("Device {:#x} is {:?}.", device, medium);
+        medium
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn detect_medium(device: u64) -> Medium {
+    let (major, minor) = (libc::major(device), libc::minor(device));
+    // Partitions have no queue of their own, their disk does.
+    for queue in ["queue", "../queue"] {
+        let path = format!("/sys/dev/block/{}:{}/{}/rotational", major, minor, queue);
+        match std::fs::read_to_string(path).as_deref().map(str::trim) {
+            Ok("1") => return Medium::Rotational,
+            Ok("0") => return Medium::SolidState,
+            _ => (),
+        }
+    }
+    Medium::Unknown
+}
+
+#[cfg(not(target_os = "linux"))]
+fn detect_medium(_device: u64) -> Medium {
+    Medium::Unknown
+}
+
+/// The indices of the files in the order of their inodes. Files we can't stat come first.
+pub(crate) fn inode_order(files: &[&PathBuf]) -> Vec<usize> {
+    let mut order: Vec<(u64, usize)> = files
+        .iter()
+        .enumerate()
+        .map(|(index, file)| {
+            (
+                metadata(file).map_or(0, |file_metadata| file_metadata.ino()),
+                index,
+            )
+        })
+        .collect();
+    order.sort_unstable();
+    order.into_iter().map(|(_, index)| index).collect()
+}
+
+/// The size of the chunks to compare `files` files on the medium with, at least `buffer_size`.
+pub(crate) fn compare_chunk_size(medium: Medium, buffer_size: usize, files: usize) -> usize {
+    match medium {
+        Medium::Rotational => {
+            buffer_size.max((ROTATIONAL_COMPARE_BYTES / files.max(1)).min(ROTATIONAL_CHUNK_BYTES))
+        }
+        Medium::SolidState | Medium::Unknown => buffer_size,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tmp_file;
+    use tempfile::tempdir;
+
+    #[test]
+    fn read_in_inode_order() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_file(tmp_dir.path(), "file1", "contents");
+        let file2 = tmp_file(tmp_dir.path(), "file2", "contents");
+        let missing = tmp_dir.path().join("missing");
+        let (first, second) = if metadata(&file1).unwrap().ino() < metadata(&file2).unwrap().ino() {
+            (0, 2)
+        } else {
+            (2, 0)
+        };
+        assert_eq!(inode_order(&[&file1, &missing, &file2]), [1, first, second]);
+        assert_eq!(files_medium(&[&missing]), Medium::Unknown);
+        // Whatever the temporary directory is on, checking it again gives the same answer.
+        assert_eq!(files_medium(&[&file1]), files_medium(&[&file2]));
+
+        assert_eq!(compare_chunk_size(Medium::SolidState, 4096, 100), 4096);
+        assert_eq!(compare_chunk_size(Medium::Rotational, 4096, 2), 8 << 20);
+        assert_eq!(compare_chunk_size(Medium::Rotational, 4096, 64), 1 << 20);
+        assert_eq!(compare_chunk_size(Medium::Rotational, 4096, 1 << 20), 4096);
+        assert_eq!(compare_readers(Medium::Rotational), 2);
+
+        assert_eq!(hash_readers(Medium::SolidState, None), SOLID_STATE_READERS);
+        assert_eq!(hash_readers(Medium::Unknown, None), 1);
+        assert_eq!(hash_readers(Medium::Unknown, Some(8)), 8);
+        assert_eq!(hash_readers(Medium::Rotational, Some(8)), 1);
+    }
+}
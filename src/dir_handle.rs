@@ -0,0 +1,196 @@
+//! Directories opened once and then used for everything done to the files in them.
+//!
+//! Replacing a file takes several steps: linking a temporary file next to it, checking the file
+//! again, and renaming the temporary file over it. Paths are resolved anew at each step, so a
+//! directory swapped for a symlink halfway would send the later steps somewhere else. Through an
+//! open directory every step happens in the directory we opened, and opening it doesn't follow a
+//! symlink in its place unless symlinks are followed anyway.
+
+use std::ffi::{CString, OsStr};
+use std::fs::{File, FileTimes};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+pub(crate) struct DirHandle {
+    dir: File,
+}
+
+impl DirHandle {
+    /// Opens the directory. Fails if the path is a symlink, unless `follow_symlinks` is set.
+    pub(crate) fn open(path: &Path, follow_symlinks: bool) -> io::Result<DirHandle> {
+        let path = if path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            path
+        };
+        let mut flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+        if !follow_symlinks {
+            flags |= libc::O_NOFOLLOW;
+        }
+        let fd = unsafe { libc::open(c_string(path.as_os_str())?.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(DirHandle {
+            dir: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+
+    /// Creates a hardlink to the file at `name` in the directory.
+    pub(crate) fn hard_link(&self, file: &Path, name: &OsStr) -> io::Result<()> {
+        let file = c_string(file.as_os_str())?;
+        let name = c_string(name)?;
+        let fd = self.dir.as_raw_fd();
+        check(unsafe { libc::linkat(libc::AT_FDCWD, file.as_ptr(), fd, name.as_ptr(), 0) })
+    }
+
+    /// Creates a symlink to `file` at `name` in the directory.
+    pub(crate) fn symlink(&self, file: &Path, name: &OsStr) -> io::Result<()> {
+        let file = c_string(file.as_os_str())?;
+        let name = c_string(name)?;
+        check(unsafe { libc::symlinkat(file.as_ptr(), self.dir.as_raw_fd(), name.as_ptr()) })
+    }
+
+    /// The metadata of the file at `name` in the directory. Symlinks aren't followed.
+    pub(crate) fn symlink_metadata(&self, name: &OsStr) -> io::Result<libc::stat> {
+        let name = c_string(name)?;
+        let mut stat = MaybeUninit::<libc::stat>::uninit();
+        let flags = libc::AT_SYMLINK_NOFOLLOW;
+        let fd = self.dir.as_raw_fd();
+        check(unsafe { libc::fstatat(fd, name.as_ptr(), stat.as_mut_ptr(), flags) })?;
+        Ok(unsafe { stat.assume_init() })
+    }
+
+    /// Renames `from` to `to`, both in the directory, replacing `to`.
+    pub(crate) fn rename(&self, from: &OsStr, to: &OsStr) -> io::Result<()> {
+        let (from, to) = (c_string(from)?, c_string(to)?);
+        let fd = self.dir.as_raw_fd();
+        check(unsafe { libc::renameat(fd, from.as_ptr(), fd, to.as_ptr()) })
+    }
+
+    /// Creates a reflink of the file at `name` in the directory and returns it open. Fails unless
+    /// the filesystem can clone files.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn reflink(&self, file: &Path, name: &OsStr) -> io::Result<File> {
+        let source = File::open(file)?;
+        let name = c_string(name)?;
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC;
+        let fd = unsafe { libc::openat(self.dir.as_raw_fd(), name.as_ptr(), flags, 0o600) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let clone = unsafe { File::from_raw_fd(fd) };
+        if unsafe { libc::ioctl(fd, libc::FICLONE, source.as_raw_fd()) } != 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::unlinkat(self.dir.as_raw_fd(), name.as_ptr(), 0) };
+            return Err(err);
+        }
+        Ok(clone)
+    }
+
+    /// Creates a reflink of the file at `name` in the directory and returns it open. Fails unless
+    /// the filesystem can clone files.
+    #[cfg(target_os = "macos")]
+    pub(crate) fn reflink(&self, file: &Path, name: &OsStr) -> io::Result<File> {
+        let (file, name) = (c_string(file.as_os_str())?, c_string(name)?);
+        let dir_fd = self.dir.as_raw_fd();
+        check(unsafe {
+            libc::clonefileat(libc::AT_FDCWD, file.as_ptr(), dir_fd, name.as_ptr(), 0)
+        })?;
+        let fd = unsafe { libc::openat(dir_fd, name.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::unlinkat(dir_fd, name.as_ptr(), 0) };
+            return Err(err);
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub(crate) fn reflink(&self, _file: &Path, _name: &OsStr) -> io::Result<File> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "This platform can't reflink files.",
+        ))
+    }
+
+    pub(crate) fn remove_file(&self, name: &OsStr) -> io::Result<()> {
+        let name = c_string(name)?;
+        check(unsafe { libc::unlinkat(self.dir.as_raw_fd(), name.as_ptr(), 0) })
+    }
+
+    /// Flushes the file at `name` in the directory to disk. Symlinks aren't followed.
+    pub(crate) fn sync_file(&self, name: &OsStr) -> io::Result<()> {
+        let name = c_string(name)?;
+        let flags = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let fd = unsafe { libc::openat(self.dir.as_raw_fd(), name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { File::from_raw_fd(fd) }.sync_all()
+    }
+
+    /// Flushes the directory's entries to disk, e.g. so that a rename in it survives a crash.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.dir.sync_all()
+    }
+
+    /// The access and modification times of the directory itself.
+    pub(crate) fn times(&self) -> io::Result<FileTimes> {
+        let dir_metadata = self.dir.metadata()?;
+        Ok(FileTimes::new()
+            .set_accessed(dir_metadata.accessed()?)
+            .set_modified(dir_metadata.modified()?))
+    }
+
+    pub(crate) fn set_times(&self, times: FileTimes) -> io::Result<()> {
+        self.dir.set_times(times)
+    }
+}
+
+fn c_string(path: &OsStr) -> io::Result<CString> {
+    CString::new(path.as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tmp_file;
+    use std::fs::read_to_string;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn replace_through_dir_handle() {
+        let tmp_dir = tempdir().unwrap();
+        let file = tmp_file(tmp_dir.path(), "file", "contents");
+        let dir = tmp_dir.path().join("dir");
+        tmp_file(&dir, "target", "other contents");
+        symlink(&dir, tmp_dir.path().join("link")).unwrap();
+
+        assert!(DirHandle::open(&tmp_dir.path().join("link"), false).is_err());
+        assert!(DirHandle::open(&tmp_dir.path().join("link"), true).is_ok());
+        let handle = DirHandle::open(&dir, false).unwrap();
+        handle.hard_link(&file, OsStr::new("tmp")).unwrap();
+        assert_eq!(
+            handle.symlink_metadata(OsStr::new("tmp")).unwrap().st_nlink,
+            2
+        );
+        handle
+            .rename(OsStr::new("tmp"), OsStr::new("target"))
+            .unwrap();
+        assert_eq!(read_to_string(dir.join("target")).unwrap(), "contents");
+        handle.remove_file(OsStr::new("target")).unwrap();
+        assert!(handle.symlink_metadata(OsStr::new("target")).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! Detection of files that don't read back the same way twice.
+//!
+//! A file that looks unique only because one of its reads returned bad data points at failing
+//! hardware or bitrot rather than at genuinely unique contents.
+
+use crate::page_cache::ReadOptions;
+use crate::{calculate_hash, HashAlgorithm};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use tracing::warn;
+
+/// Alignment of buffers and reads required by `O_DIRECT` on common filesystems and devices.
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+const DIRECT_IO_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How the second read of a file is performed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SecondRead {
+    /// Read the file again, possibly from the page cache.
+    Cached,
+    /// Drop the file's cached pages before reading it again.
+    DropCaches,
+    /// Read the file again with `O_DIRECT`, bypassing the page cache.
+    Direct,
+}
+
+/// Whether hashing the file twice gives the same hash.
+pub(crate) fn reads_consistently(
+    file: &Path,
+    second_read: SecondRead,
+    algorithm: HashAlgorithm,
+    read_options: ReadOptions,
+) -> io::Result<bool> {
+    let first_hash = calculate_hash(file, algorithm, read_options)?;
+    let buffer_size = read_options.buffer_size;
+    let second_hash = match second_read {
+        SecondRead::Cached => calculate_hash(file, algorithm, read_options)?,
+        SecondRead::DropCaches => calculate_uncached_hash(file, algorithm, buffer_size)?,
+        SecondRead::Direct => calculate_direct_hash(file, algorithm, buffer_size)?,
+    };
+    Ok(first_hash == second_hash)
+}
+
+fn calculate_uncached_hash(
+    file: &Path,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> io::Result<Vec<u8>> {
+    let file_handle = File::open(file)?;
+    let result =
+        unsafe { libc::posix_fadvise(file_handle.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result));
+    }
+    algorithm.hash_reader(file_handle, buffer_size)
+}
+
+fn calculate_direct_hash(
+    file: &Path,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> io::Result<Vec<u8>> {
+    let file_handle = match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(file)
+    {
+        Ok(file_handle) => file_handle,
+        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+            warn!(
+                "The filesystem of {:?} doesn't support O_DIRECT. Dropping its cached pages instead.",
+                file
+            );
+            return calculate_uncached_hash(file, algorithm, buffer_size);
+        }
+        Err(err) => return Err(err),
+    };
+    algorithm.hash_reader(DirectReader::new(file_handle), buffer_size)
+}
+
+/// Reads through a buffer aligned as `O_DIRECT` requires.
+struct DirectReader {
+    file: File,
+    buffer: Vec<u8>,
+    offset: usize,
+    start: usize,
+    end: usize,
+}
+
+impl DirectReader {
+    fn new(file: File) -> DirectReader {
+        let buffer = vec![0; DIRECT_IO_BUFFER_SIZE + DIRECT_IO_ALIGNMENT];
+        let offset = buffer.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+        DirectReader {
+            file,
+            buffer,
+            offset,
+            start: 0,
+            end: 0,
+        }
+    }
+}
+
+impl Read for DirectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.end {
+            let aligned = &mut self.buffer[self.offset..self.offset + DIRECT_IO_BUFFER_SIZE];
+            self.end = self.file.read(aligned)?;
+            self.start = 0;
+        }
+        let available = &self.buffer[self.offset + self.start..self.offset + self.end];
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.start += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn stable_file_reads_consistently() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("file");
+        let contents: Vec<u8> = (0..3 * DIRECT_IO_BUFFER_SIZE / 2)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        File::create(&path).unwrap().write_all(&contents).unwrap();
+        for second_read in [
+            SecondRead::Cached,
+            SecondRead::DropCaches,
+            SecondRead::Direct,
+        ] {
+            assert!(reads_consistently(
+                &path,
+                second_read,
+                HashAlgorithm::Sha256,
+                ReadOptions::default(),
+            )
+            .unwrap());
+        }
+    }
+}
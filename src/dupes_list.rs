@@ -0,0 +1,162 @@
+//! Hardlinking groups of duplicates found by another tool, like `fdupes` and `jdupes`.
+//!
+//! Their output lists each group's paths one per line, with an empty line after each group, which is
+//! also what `--output fdupes` prints. With their `--size` option every group starts with a line like
+//! `1024 bytes each:`, which is skipped. Nothing in the list is trusted: each file is hardlinked only
+//! if it still has the size and contents of the file kept of its group, like in
+//! [`crate::link_selections`], and groups are split by the device, owner and mode of their files.
+
+use crate::error::{DedupError, FailedFiles};
+use crate::output::Renderer;
+use crate::prefer::by_preference;
+use crate::{
+    link_selections, ordered, same_metadata_groups, same_name_groups, DedupOptions, DedupSummary,
+    LinkSelection,
+};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Hardlinks the files of each group in the list to the one kept under [`DedupOptions::prefer`].
+pub fn link_dupes_list(
+    list: &Path,
+    options: &DedupOptions,
+    renderer: &mut dyn Renderer,
+) -> Result<DedupSummary, DedupError> {
+    let text = read(list).map_err(|source| DedupError::ReadDupesList {
+        path: list.to_owned(),
+        source,
+    })?;
+    link_groups(&parse_groups(&text), options, renderer)
+}
+
+/// Hardlinks the files of each group that share their device, size, owner and mode (see
+/// [`DedupOptions::ignore_owner`]) and, if required, name to the one kept under
+/// [`DedupOptions::prefer`], like the groups a scan finds.
+pub(crate) fn link_groups(
+    groups: &[Vec<PathBuf>],
+    options: &DedupOptions,
+    renderer: &mut dyn Renderer,
+) -> Result<DedupSummary, DedupError> {
+    let mut failed_files = FailedFiles::default();
+    let mut selections = Vec::new();
+    for group in groups {
+        let mut metadata_groups: Vec<HashSet<&PathBuf>> =
+            same_metadata_groups(group.iter(), options.key_options(), &mut failed_files).collect();
+        if options.same_name_only {
+            metadata_groups = metadata_groups
+                .into_iter()
+                .flat_map(|group| same_name_groups(group.into_iter()).collect::<Vec<_>>())
+                .collect();
+        }
+        let metadata_groups = ordered(metadata_groups, options);
+        for metadata_group in metadata_groups {
+            let mut files = by_preference(&metadata_group, options.prefer).into_iter();
+            let Some(original) = files.next() else {
+                continue;
+            };
+            let targets: Vec<PathBuf> = files.cloned().collect();
+            if !targets.is_empty() {
+                selections.push(LinkSelection {
+                    original: original.clone(),
+                    targets,
+                });
+            }
+        }
+    }
+    let mut summary = link_selections(&selections, options, renderer)?;
+    summary.add(&DedupSummary {
+        failed_files: failed_files.count(),
+        file_errors: failed_files.into_errors(),
+        ..DedupSummary::default()
+    });
+    Ok(summary)
+}
+
+/// The groups of paths in the list. Paths are bytes, so any path the other tool printed is read back.
+fn parse_groups(text: &[u8]) -> Vec<Vec<PathBuf>> {
+    let mut groups = vec![Vec::new()];
+    for line in text.split(|byte| *byte == b'\n') {
+        if line.is_empty() {
+            groups.push(Vec::new());
+        } else if !is_size_line(line) {
+            let path = PathBuf::from(OsStr::from_bytes(line));
+            groups.last_mut().unwrap().push(path);
+        }
+    }
+    groups.retain(|group| !group.is_empty());
+    groups
+}
+
+/// Whether the line is the size that `fdupes --size` and `jdupes --size` print before each group.
+fn is_size_line(line: &[u8]) -> bool {
+    let Some(digits) = line
+        .strip_suffix(b" bytes each:")
+        .or_else(|| line.strip_suffix(b" byte each:"))
+    else {
+        return false;
+    };
+    !digits.is_empty() && digits.iter().all(u8::is_ascii_digit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::QuietRenderer;
+    use crate::test_utils::{same, tmp_file};
+    use std::fs::{set_permissions, write, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn parse_fdupes_output() {
+        let text = b"13 bytes each:\n/a/x\n/b/x\n\n1 byte each:\n/c\n/d\n/e\n\n";
+        assert_eq!(
+            parse_groups(text),
+            [
+                vec![PathBuf::from("/a/x"), PathBuf::from("/b/x")],
+                vec![
+                    PathBuf::from("/c"),
+                    PathBuf::from("/d"),
+                    PathBuf::from("/e")
+                ],
+            ]
+        );
+        assert_eq!(
+            parse_groups(b"/a\n/b"),
+            [vec![PathBuf::from("/a"), PathBuf::from("/b")]]
+        );
+    }
+
+    #[test]
+    fn link_listed_duplicates() {
+        let tmp_dir = tempdir().unwrap();
+        let a = tmp_file(tmp_dir.path(), "a", "same contents");
+        let b = tmp_file(tmp_dir.path(), "b", "same contents");
+        let changed = tmp_file(tmp_dir.path(), "changed", "same_contents");
+        let private = tmp_file(tmp_dir.path(), "private", "same contents");
+        set_permissions(&private, Permissions::from_mode(0o600)).unwrap();
+        let list = tmp_dir.path().join("dupes.txt");
+        write(
+            &list,
+            format!(
+                "{}\n{}\n{}\n{}\n\n",
+                b.display(),
+                a.display(),
+                changed.display(),
+                private.display()
+            ),
+        )
+        .unwrap();
+
+        let options = DedupOptions::default();
+        let summary = link_dupes_list(&list, &options, &mut QuietRenderer).unwrap();
+
+        assert!(same(&a, &b));
+        assert!(!same(&a, &changed));
+        assert!(!same(&a, &private));
+        assert_eq!(summary.failed_files, 1);
+    }
+}
@@ -0,0 +1,194 @@
+//! What can go wrong in a run.
+//!
+//! Only errors that stop a whole run are [`DedupError`]s. A file that can't be read, compared, or
+//! hardlinked is logged and skipped, and the run goes on. Its [`FileError`] ends up in the summary
+//! of the run.
+
+use colored::Colorize;
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tracing::warn;
+
+/// How many [`FileError`]s a summary keeps. Runs over unreadable trees can fail on millions of
+/// files, which are still all counted.
+pub const MAX_FILE_ERRORS: usize = 1000;
+
+/// Why a run failed before it could deduplicate anything.
+#[derive(Debug, Error)]
+pub enum DedupError {
+    /// One of the paths to deduplicate doesn't exist or can't be accessed.
+    #[error("Failed to access {path:?}. Error: {source}")]
+    Access { path: PathBuf, source: io::Error },
+    /// One of the paths is, or contains, a mount that's unsafe to deduplicate. See
+    /// [`crate::DedupOptions::refuse_foreign_mounts`].
+    #[error("Refusing to deduplicate {path:?}. {reason}")]
+    ForeignMount { path: PathBuf, reason: String },
+    #[error("Failed to read checkpoint {path:?}. Error: {source}")]
+    ReadCheckpoint { path: PathBuf, source: io::Error },
+    #[error("Failed to parse checkpoint {path:?}. Error: {source}")]
+    ParseCheckpoint {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("Failed to open journal {path:?}. Error: {source}")]
+    OpenJournal { path: PathBuf, source: io::Error },
+    #[error("Failed to read journal {path:?}. Error: {source}")]
+    ReadJournal { path: PathBuf, source: io::Error },
+    #[error("Failed to read the list of duplicates {path:?}. Error: {source}")]
+    ReadDupesList { path: PathBuf, source: io::Error },
+    #[error("Failed to write index {path:?}. Error: {source}")]
+    WriteIndex { path: PathBuf, source: io::Error },
+    #[error("Failed to read index {path:?}. Error: {source}")]
+    ReadIndex { path: PathBuf, source: io::Error },
+    #[error("Failed to parse index {path:?}. Error: {source}")]
+    ParseIndex {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// The scanned files didn't fit in memory and writing them to a temporary file failed. See
+    /// [`crate::DedupOptions::max_memory`].
+    #[error("Failed to spill the scanned files to disk. Error: {0}")]
+    Spill(io::Error),
+    #[error("Failed to watch for new files. Error: {0}")]
+    Watch(notify::Error),
+    #[error("Failed to watch {path:?}. Error: {source}")]
+    WatchPath {
+        path: PathBuf,
+        source: notify::Error,
+    },
+    #[error("Invalid --protect pattern {pattern:?}. Error: {source}")]
+    ProtectPattern {
+        pattern: String,
+        source: globset::Error,
+    },
+    #[error("Invalid --protect patterns. Error: {0}")]
+    ProtectPatterns(globset::Error),
+    #[error("Failed to read the patterns {path:?}. Error: {source}")]
+    ReadPatterns { path: PathBuf, source: io::Error },
+    #[error("Failed to read the jobs {path:?}. Error: {source}")]
+    ReadJobs { path: PathBuf, source: io::Error },
+    #[error("Invalid jobs file {path:?}. Error: {source}")]
+    ParseJobs {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("No [[job]] in {path:?}.")]
+    NoJobs { path: PathBuf },
+    /// An option of a job has an invalid value.
+    #[error("{0}")]
+    InvalidJob(String),
+    /// The jobs with these names failed. The other jobs ran.
+    #[error("Jobs {} failed.", .0.join(", "))]
+    FailedJobs(Vec<String>),
+    #[error("Failed to write the report {path:?}. Error: {source}")]
+    WriteReport { path: PathBuf, source: io::Error },
+    #[error("Failed to create the report {path:?}. Error: {source}")]
+    CreateReport { path: PathBuf, source: csv::Error },
+    #[error("Failed to write the statistics to {path:?}. Error: {source}")]
+    WriteStats { path: PathBuf, source: io::Error },
+    #[error("Failed to write the metrics to {path:?}. Error: {source}")]
+    WriteMetrics { path: PathBuf, source: io::Error },
+    #[cfg(feature = "sqlite")]
+    #[error("Failed to export to {path:?}. Error: {source}")]
+    ExportSqlite {
+        path: PathBuf,
+        source: rusqlite::Error,
+    },
+    #[error("Failed to read commands. Error: {0}")]
+    ReadCommands(io::Error),
+    #[error("Failed to set up the terminal. Error: {0}")]
+    SetUpTerminal(io::Error),
+    #[error("Failed to use the terminal. Error: {0}")]
+    Terminal(io::Error),
+}
+
+/// Lets code that reports errors as messages use `?` on the results of runs.
+impl From<DedupError> for String {
+    fn from(err: DedupError) -> String {
+        err.to_string()
+    }
+}
+
+/// A file a run skipped because it failed to read, compare, or hardlink it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileError {
+    pub path: PathBuf,
+    /// What failed, as logged.
+    pub message: String,
+}
+
+/// The files a run failed to process so far: all of them counted, the first [`MAX_FILE_ERRORS`]
+/// kept.
+#[derive(Debug, Default)]
+pub(crate) struct FailedFiles {
+    count: usize,
+    errors: Vec<FileError>,
+}
+
+impl FailedFiles {
+    /// Logs the failure in red and records it.
+    pub(crate) fn add(&mut self, path: &Path, message: String) {
+        warn!("{}", message.red());
+        self.count += 1;
+        if self.errors.len() < MAX_FILE_ERRORS {
+            self.errors.push(FileError {
+                path: path.to_owned(),
+                message,
+            });
+        }
+    }
+
+    /// Records the failures of another part of the run, which logged them already.
+    pub(crate) fn append(&mut self, other: FailedFiles) {
+        self.count += other.count;
+        let room = MAX_FILE_ERRORS.saturating_sub(self.errors.len());
+        self.errors.extend(other.errors.into_iter().take(room));
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.count
+    }
+
+    pub(crate) fn errors(&self) -> &[FileError] {
+        &self.errors
+    }
+
+    pub(crate) fn into_errors(self) -> Vec<FileError> {
+        self.errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_the_first_errors() {
+        let mut failed_files = FailedFiles::default();
+        for index in 0..MAX_FILE_ERRORS {
+            failed_files.add(Path::new("a"), format!("Failure {}", index));
+        }
+        let mut more = FailedFiles::default();
+        more.add(Path::new("b"), "Another failure".to_owned());
+        failed_files.append(more);
+
+        assert_eq!(failed_files.count(), MAX_FILE_ERRORS + 1);
+        let errors = failed_files.into_errors();
+        assert_eq!(errors.len(), MAX_FILE_ERRORS);
+        assert_eq!(errors[0].message, "Failure 0");
+    }
+
+    #[test]
+    fn describe_errors() {
+        let err = DedupError::Access {
+            path: PathBuf::from("/missing"),
+            source: io::Error::from(io::ErrorKind::NotFound),
+        };
+        assert_eq!(
+            String::from(err),
+            "Failed to access \"/missing\". Error: entity not found"
+        );
+    }
+}
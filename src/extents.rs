@@ -0,0 +1,195 @@
+//! Equality proofs from physical extents.
+//!
+//! On copy-on-write filesystems like btrfs, snapshots and reflinked copies are separate inodes that
+//! share all their data blocks. Files that map every byte to the same physical extents have the
+//! same contents, which `FIEMAP` tells us without reading any data. Hardlinking such files frees no
+//! data blocks, only their inodes.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// `_IOWR('f', 11, struct fiemap)`
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
+const FIEMAP_EXTENT_ENCODED: u32 = 0x8;
+const FIEMAP_EXTENT_DATA_ENCRYPTED: u32 = 0x80;
+const FIEMAP_EXTENT_NOT_ALIGNED: u32 = 0x100;
+const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+/// Extents whose physical location doesn't identify their data. Encoded (e.g. compressed) and
+/// encrypted extents may decode differently for different files, so they don't prove anything
+/// either.
+const FIEMAP_EXTENT_UNRELIABLE: u32 = FIEMAP_EXTENT_UNKNOWN
+    | FIEMAP_EXTENT_DELALLOC
+    | FIEMAP_EXTENT_ENCODED
+    | FIEMAP_EXTENT_DATA_ENCRYPTED
+    | FIEMAP_EXTENT_NOT_ALIGNED
+    | FIEMAP_EXTENT_DATA_INLINE;
+/// Heavily fragmented files are hashed instead. Comparing their extents isn't cheap anymore.
+const MAX_EXTENTS: u32 = 1024;
+
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+/// `struct fiemap` followed by its extents.
+#[repr(C)]
+struct FiemapRequest {
+    header: Fiemap,
+    extents: [FiemapExtent; MAX_EXTENTS as usize],
+}
+
+/// The logical offset, physical offset, length, and flags of every extent of a file.
+pub(crate) type Extents = Vec<(u64, u64, u64, u32)>;
+
+/// The extents of a file if all of them are shared with other files and their locations are
+/// reliable. `None` for files that can't share their extents with other files.
+pub(crate) fn shared_extents(file: &Path) -> io::Result<Option<Extents>> {
+    let file_handle = File::open(file)?;
+    let mut request = Box::new(FiemapRequest {
+        header: Fiemap {
+            fm_length: u64::MAX,
+            fm_flags: FIEMAP_FLAG_SYNC,
+            fm_extent_count: MAX_EXTENTS,
+            ..Fiemap::default()
+        },
+        extents: [FiemapExtent::default(); MAX_EXTENTS as usize],
+    });
+    if unsafe { libc::ioctl(file_handle.as_raw_fd(), FS_IOC_FIEMAP, &mut *request) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let extents = &request.extents[..request.header.fm_mapped_extents as usize];
+    let complete = extents
+        .last()
+        .is_some_and(|extent| extent.fe_flags & FIEMAP_EXTENT_LAST != 0);
+    let shareable = extents
+        .iter()
+        .all(|extent| reliably_shared(extent.fe_flags));
+    if !complete || !shareable {
+        return Ok(None);
+    }
+    Ok(Some(
+        extents
+            .iter()
+            .map(|extent| {
+                (
+                    extent.fe_logical,
+                    extent.fe_physical,
+                    extent.fe_length,
+                    extent.fe_flags,
+                )
+            })
+            .collect(),
+    ))
+}
+
+/// For each file, the index of the first file with the same extents, or its own index. Files
+/// without shared extents are their own representatives.
+pub(crate) fn representatives(extents: &[Option<Extents>]) -> Vec<usize> {
+    let mut first_with_extents: HashMap<&Extents, usize> = HashMap::new();
+    extents
+        .iter()
+        .enumerate()
+        .map(|(index, file_extents)| match file_extents {
+            Some(file_extents) => *first_with_extents.entry(file_extents).or_insert(index),
+            None => index,
+        })
+        .collect()
+}
+
+/// Whether an extent with these flags is shared with other files and its location identifies its
+/// data.
+fn reliably_shared(flags: u32) -> bool {
+    flags & FIEMAP_EXTENT_SHARED != 0 && flags & FIEMAP_EXTENT_UNRELIABLE == 0
+}
+
+/// Like [`representatives`], but reads the extents of the files. Files whose extents can't be read
+/// (e.g. on filesystems without `FIEMAP`) are their own representatives.
+pub(crate) fn file_representatives(files: &[&PathBuf]) -> Vec<usize> {
+    let extents: Vec<_> = files
+        .iter()
+        .map(|file| shared_extents(file).ok().flatten())
+        .collect();
+    representatives(&extents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn same_extents_share_representative() {
+        let extents = vec![
+            Some(vec![(
+                0,
+                4096,
+                4096,
+                FIEMAP_EXTENT_SHARED | FIEMAP_EXTENT_LAST,
+            )]),
+            None,
+            Some(vec![(
+                0,
+                8192,
+                4096,
+                FIEMAP_EXTENT_SHARED | FIEMAP_EXTENT_LAST,
+            )]),
+            Some(vec![(
+                0,
+                4096,
+                4096,
+                FIEMAP_EXTENT_SHARED | FIEMAP_EXTENT_LAST,
+            )]),
+            None,
+        ];
+        assert_eq!(representatives(&extents), vec![0, 1, 2, 0, 4]);
+    }
+
+    #[test]
+    fn encoded_and_encrypted_extents_are_unreliable() {
+        assert!(reliably_shared(FIEMAP_EXTENT_SHARED | FIEMAP_EXTENT_LAST));
+        assert!(!reliably_shared(FIEMAP_EXTENT_LAST));
+        assert!(!reliably_shared(
+            FIEMAP_EXTENT_SHARED | FIEMAP_EXTENT_ENCODED
+        ));
+        assert!(!reliably_shared(
+            FIEMAP_EXTENT_SHARED | FIEMAP_EXTENT_DATA_ENCRYPTED
+        ));
+    }
+
+    #[test]
+    fn unshared_file_has_no_shared_extents() {
+        let tmp_dir = tempdir().unwrap();
+        let file = tmp_dir.path().join("file");
+        write(&file, "contents").unwrap();
+        // Filesystems without FIEMAP (e.g. tmpfs) fail instead.
+        if let Ok(extents) = shared_extents(&file) {
+            assert_eq!(extents, None);
+        }
+    }
+}
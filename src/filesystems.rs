@@ -0,0 +1,96 @@
+//! What the filesystems of the files support.
+//!
+//! Some filesystems have no hardlinks at all (e.g. FAT), so hardlinking their files fails once per
+//! file. On others hardlinks work, but less predictably than on local POSIX filesystems. The
+//! filesystem of every device is checked once before deduplicating.
+
+use std::io;
+use std::path::Path;
+
+/// Whether a filesystem supports hardlinks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Support {
+    Hardlinks,
+    /// The filesystem, by name, has no hardlinks.
+    NoHardlinks(&'static str),
+    /// The filesystem, by name, has hardlinks, but they may not work, for the reason.
+    Unreliable(&'static str, &'static str),
+}
+
+/// Filesystem magic numbers, see `linux/magic.h`.
+const MSDOS_SUPER_MAGIC: u32 = 0x4d44;
+const EXFAT_SUPER_MAGIC: u32 = 0x2011_bab0;
+const ISOFS_SUPER_MAGIC: u32 = 0x9660;
+const NFS_SUPER_MAGIC: u32 = 0x6969;
+const SMB_SUPER_MAGIC: u32 = 0x517b;
+const CIFS_SUPER_MAGIC: u32 = 0xff53_4d42;
+const SMB2_SUPER_MAGIC: u32 = 0xfe53_4d42;
+const OVERLAYFS_SUPER_MAGIC: u32 = 0x794c_7630;
+const FUSE_SUPER_MAGIC: u32 = 0x6573_5546;
+
+/// What the filesystem that contains the path supports.
+#[cfg(target_os = "linux")]
+pub(crate) fn filesystem_support(path: &Path) -> io::Result<Support> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The magic numbers are 32 bits, whatever the width of `f_type`.
+    Ok(support(stat.f_type as u32))
+}
+
+/// Filesystem types are only told apart on Linux.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn filesystem_support(_path: &Path) -> io::Result<Support> {
+    Ok(Support::Hardlinks)
+}
+
+fn support(fs_type: u32) -> Support {
+    match fs_type {
+        MSDOS_SUPER_MAGIC => Support::NoHardlinks("FAT"),
+        EXFAT_SUPER_MAGIC => Support::NoHardlinks("exFAT"),
+        ISOFS_SUPER_MAGIC => Support::NoHardlinks("ISO 9660"),
+        NFS_SUPER_MAGIC => Support::Unreliable(
+            "NFS",
+            "Whether hardlinks work depends on the server, and link counts may be cached.",
+        ),
+        SMB_SUPER_MAGIC | CIFS_SUPER_MAGIC | SMB2_SUPER_MAGIC => Support::Unreliable(
+            "SMB",
+            "Whether hardlinks work depends on the server and the mount options.",
+        ),
+        OVERLAYFS_SUPER_MAGIC => Support::Unreliable(
+            "overlayfs",
+            "Files get new inodes when they're copied up from lower layers.",
+        ),
+        FUSE_SUPER_MAGIC => Support::Unreliable(
+            "FUSE",
+            "Whether hardlinks work depends on the FUSE filesystem.",
+        ),
+        _ => Support::Hardlinks,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn support_by_filesystem_type() {
+        assert_eq!(support(0xef53), Support::Hardlinks);
+        assert_eq!(support(MSDOS_SUPER_MAGIC), Support::NoHardlinks("FAT"));
+        assert!(matches!(
+            support(CIFS_SUPER_MAGIC),
+            Support::Unreliable("SMB", _)
+        ));
+        let tmp_dir = tempdir().unwrap();
+        assert!(filesystem_support(tmp_dir.path()).is_ok());
+    }
+}
@@ -0,0 +1,241 @@
+//! Detecting paths whose devices and inodes don't mean what they seem to.
+//!
+//! Hardlinking trusts that two paths with the same device and inode are the same file and that
+//! replacing a path only affects what's under it. Neither holds for files seen through another
+//! process's mount namespace (e.g. a container's files through `/proc/<pid>/root` on the host), on
+//! overlay filesystems, whose inodes change when files are copied up from lower layers, or in the
+//! layer storage of container engines, where layers are shared between images and containers.
+
+use std::fs::{canonicalize, read_link, read_to_string};
+use std::path::{absolute, Component, Path, PathBuf};
+
+/// A mounted filesystem.
+#[derive(Debug, PartialEq, Eq)]
+struct Mount {
+    point: PathBuf,
+    fstype: String,
+}
+
+/// Where container engines keep the layers of images and containers.
+const LAYER_STORAGE_DIRS: [&[&str]; 2] = [
+    &["docker", "overlay2"],
+    &["containers", "storage", "overlay"],
+];
+
+/// The paths at or, unless we stay on one filesystem, under the path whose devices and inodes don't
+/// mean what they seem to, with the reason.
+pub(crate) fn foreign_mounts(path: &Path, one_file_system: bool) -> Vec<(PathBuf, String)> {
+    let mut foreign = Vec::new();
+    if let Some(pid) = absolute(path).ok().as_deref().and_then(proc_root_pid) {
+        if mount_namespace(&pid) != mount_namespace("self") {
+            foreign.push((
+                path.to_owned(),
+                format!(
+                    "It's seen through the mount namespace of process {}, where devices and inodes may belong to other files than in ours.",
+                    pid
+                ),
+            ));
+            return foreign;
+        }
+    }
+    let canonical = match canonicalize(path) {
+        Ok(canonical) => canonical,
+        Err(_) => return foreign,
+    };
+    if in_layer_storage(&canonical) {
+        foreign.push((
+            path.to_owned(),
+            "It's in the layer storage of a container engine, where layers are shared between images and containers.".to_owned(),
+        ));
+        return foreign;
+    }
+    let mounts = read_to_string("/proc/self/mountinfo")
+        .map(|mountinfo| parse_mountinfo(&mountinfo))
+        .unwrap_or_default();
+    if mount_of(&canonical, &mounts).is_some_and(is_overlay) {
+        foreign.push((path.to_owned(), OVERLAY_REASON.to_owned()));
+        return foreign;
+    }
+    if !one_file_system {
+        foreign.extend(
+            mounts
+                .iter()
+                .filter(|mount| mount.point != canonical && mount.point.starts_with(&canonical))
+                .filter(|mount| is_overlay(mount))
+                .map(|mount| (mount.point.clone(), OVERLAY_REASON.to_owned())),
+        );
+    }
+    foreign
+}
+
+const OVERLAY_REASON: &str = "It's on an overlay filesystem, where files get new inodes when they're copied up from lower layers.";
+
+/// The process whose root or working directory the path goes through, like `/proc/42/root/srv`.
+fn proc_root_pid(path: &Path) -> Option<String> {
+    let mut components = path.components();
+    if components.next() != Some(Component::RootDir) {
+        return None;
+    }
+    if components.next() != Some(Component::Normal("proc".as_ref())) {
+        return None;
+    }
+    let pid = components.next()?.as_os_str().to_str()?;
+    let link = components.next()?.as_os_str();
+    let is_pid = !pid.is_empty() && pid.bytes().all(|byte| byte.is_ascii_digit());
+    (is_pid && (link == "root" || link == "cwd")).then(|| pid.to_owned())
+}
+
+fn mount_namespace(pid: &str) -> Option<PathBuf> {
+    read_link(Path::new("/proc").join(pid).join("ns/mnt")).ok()
+}
+
+fn in_layer_storage(path: &Path) -> bool {
+    let components: Vec<_> = path.components().collect();
+    LAYER_STORAGE_DIRS.iter().any(|dirs| {
+        components.windows(dirs.len()).any(|window| {
+            window
+                .iter()
+                .zip(dirs.iter())
+                .all(|(component, dir)| component.as_os_str() == *dir)
+        })
+    })
+}
+
+/// Whether the mount is an overlay filesystem other than the root, which is how containers see
+/// their own files.
+fn is_overlay(mount: &Mount) -> bool {
+    (mount.fstype == "overlay" || mount.fstype == "fuse.fuse-overlayfs")
+        && mount.point != Path::new("/")
+}
+
+/// The mount with the longest mount point that contains the path.
+fn mount_of<'a>(path: &Path, mounts: &'a [Mount]) -> Option<&'a Mount> {
+    mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.point))
+        .max_by_key(|mount| mount.point.components().count())
+}
+
+/// Parses `/proc/self/mountinfo`. Later mounts over the same mount point hide earlier ones, so
+/// they're kept last.
+fn parse_mountinfo(mountinfo: &str) -> Vec<Mount> {
+    let mut mounts: Vec<Mount> = Vec::new();
+    for line in mountinfo.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let separator = match fields.iter().position(|field| *field == "-") {
+            Some(separator) if separator >= 6 && fields.len() > separator + 1 => separator,
+            _ => continue,
+        };
+        let point = PathBuf::from(unescape(fields[4]));
+        mounts.retain(|mount| mount.point != point);
+        mounts.push(Mount {
+            point,
+            fstype: fields[separator + 1].to_owned(),
+        });
+    }
+    mounts
+}
+
+/// Undoes the octal escapes of spaces, tabs, newlines and backslashes in mount points.
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = field;
+    while let Some(index) = rest.find('\\') {
+        unescaped.push_str(&rest[..index]);
+        match rest
+            .get(index + 1..index + 4)
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok())
+        {
+            Some(byte) => {
+                unescaped.push(byte as char);
+                rest = &rest[index + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+21 1 0:20 / / rw,relatime - overlay overlay rw
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+30 22 0:26 / /var/lib/docker/overlay2/abc/merged rw,relatime - overlay overlay rw,lowerdir=/l
+31 22 0:27 / /mnt/my\\040disk rw,relatime shared:5 - xfs /dev/sdb1 rw
+32 22 0:28 / /mnt/my\\040disk rw,relatime shared:6 - overlay overlay rw
+";
+
+    #[test]
+    fn parse_mount_points_and_types() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        assert_eq!(
+            mounts,
+            [
+                Mount {
+                    point: PathBuf::from("/"),
+                    fstype: "ext4".to_owned()
+                },
+                Mount {
+                    point: PathBuf::from("/var/lib/docker/overlay2/abc/merged"),
+                    fstype: "overlay".to_owned()
+                },
+                Mount {
+                    point: PathBuf::from("/mnt/my disk"),
+                    fstype: "overlay".to_owned()
+                },
+            ]
+        );
+        assert!(is_overlay(
+            mount_of(Path::new("/mnt/my disk/a"), &mounts).unwrap()
+        ));
+        assert!(!is_overlay(
+            mount_of(Path::new("/mnt/other"), &mounts).unwrap()
+        ));
+        let container_root = Mount {
+            point: PathBuf::from("/"),
+            fstype: "overlay".to_owned(),
+        };
+        assert!(!is_overlay(&container_root));
+    }
+
+    #[test]
+    fn detect_foreign_paths() {
+        assert_eq!(
+            proc_root_pid(Path::new("/proc/42/root/srv")),
+            Some("42".to_owned())
+        );
+        assert_eq!(
+            proc_root_pid(Path::new("/proc/42/cwd")),
+            Some("42".to_owned())
+        );
+        assert_eq!(proc_root_pid(Path::new("/proc/self/root/srv")), None);
+        assert_eq!(proc_root_pid(Path::new("/srv/proc/42/root")), None);
+        assert!(in_layer_storage(Path::new(
+            "/var/lib/docker/overlay2/abc/diff/etc"
+        )));
+        assert!(in_layer_storage(Path::new(
+            "/home/me/.local/share/containers/storage/overlay/abc"
+        )));
+        assert!(!in_layer_storage(Path::new("/srv/docker/data")));
+    }
+
+    #[test]
+    fn own_mount_namespace_isnt_foreign() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let through_proc = Path::new("/proc")
+            .join(std::process::id().to_string())
+            .join("root")
+            .join(tmp_dir.path().strip_prefix("/").unwrap());
+        assert_eq!(
+            foreign_mounts(&through_proc, true),
+            foreign_mounts(tmp_dir.path(), true)
+        );
+    }
+}
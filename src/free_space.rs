@@ -0,0 +1,92 @@
+//! Free space checks for nearly full filesystems.
+//!
+//! Replacing a file with a hardlink briefly adds a directory entry, which can need a new directory
+//! block and journal space. On a completely full filesystem that fails.
+
+use std::ffi::CString;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Filesystems with less free space than this are considered nearly full.
+const LOW_SPACE_BYTES: u64 = 64 * 1024 * 1024;
+/// Filesystems with fewer free inodes than this are considered nearly full.
+const LOW_SPACE_INODES: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FreeSpace {
+    pub bytes: u64,
+    /// `None` on filesystems that don't have a fixed number of inodes (e.g. btrfs).
+    pub inodes: Option<u64>,
+    pub block_size: u64,
+}
+
+impl FreeSpace {
+    pub(crate) fn is_low(&self) -> bool {
+        self.bytes < LOW_SPACE_BYTES || self.inodes.is_some_and(|inodes| inodes < LOW_SPACE_INODES)
+    }
+
+    /// Whether there's room for the temporary directory entry of a hardlink.
+    pub(crate) fn has_room_for_link(&self) -> bool {
+        self.bytes >= self.block_size && self.inodes != Some(0)
+    }
+}
+
+/// Free space available to unprivileged users on the filesystem that contains the path.
+pub(crate) fn free_space(path: &Path) -> io::Result<FreeSpace> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(FreeSpace {
+        bytes: stat.f_bavail * stat.f_frsize,
+        inodes: if stat.f_files == 0 {
+            None
+        } else {
+            Some(stat.f_favail)
+        },
+        block_size: stat.f_bsize,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn free_space_of_tmp_dir() {
+        let tmp_dir = tempdir().unwrap();
+        let free_space = free_space(tmp_dir.path()).unwrap();
+        assert!(free_space.block_size > 0);
+    }
+
+    #[test]
+    fn nearly_full() {
+        let full = FreeSpace {
+            bytes: 0,
+            inodes: Some(10),
+            block_size: 4096,
+        };
+        assert!(full.is_low());
+        assert!(!full.has_room_for_link());
+        let out_of_inodes = FreeSpace {
+            bytes: LOW_SPACE_BYTES,
+            inodes: Some(0),
+            block_size: 4096,
+        };
+        assert!(out_of_inodes.is_low());
+        assert!(!out_of_inodes.has_room_for_link());
+        let roomy = FreeSpace {
+            bytes: LOW_SPACE_BYTES,
+            inodes: None,
+            block_size: 4096,
+        };
+        assert!(!roomy.is_low());
+        assert!(roomy.has_room_for_link());
+    }
+}
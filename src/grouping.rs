@@ -0,0 +1,234 @@
+//! The stages that classify files into groups of duplicates, without the scanning and linking
+//! around them.
+//!
+//! Each stage splits the files it's given into groups that may hold duplicates: files that share
+//! their metadata, then their first bytes, then their hash, and finally their contents compared
+//! byte by byte. Feed each group of a stage to the next one to find the files with the same contents:
+//!
+//! ```no_run
+//! use hardlink_dedup::grouping::{same_hash_groups, same_metadata_groups, same_prefix_groups, FileInfo};
+//! use hardlink_dedup::DedupOptions;
+//!
+//! let options = DedupOptions::default();
+//! let files: Vec<FileInfo> = ["a", "b", "c"]
+//!     .into_iter()
+//!     .filter_map(|path| FileInfo::new(path).ok())
+//!     .collect();
+//! for metadata_group in same_metadata_groups(&files).groups {
+//!     for prefix_group in same_prefix_groups(metadata_group, &options).groups {
+//!         for hash_group in same_hash_groups(prefix_group, &options).groups {
+//!             println!("{:?}", hash_group);
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Every file ends up in exactly one group, which may be the file alone, or among the failed files.
+//! Files keep the order they were given in within their groups, and groups are ordered by their
+//! first file.
+
+use crate::error::FailedFiles;
+use crate::{
+    group_by, same_content_groups as content_groups, same_hash_groups as hash_groups,
+    same_prefix_groups as prefix_groups, DedupOptions, DEFAULT_PREFIX_BYTES,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs::symlink_metadata;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// A file to classify and the metadata files must share to be hardlinked.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileInfo {
+    pub path: PathBuf,
+    pub dev: u64,
+    pub size: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+}
+
+impl FileInfo {
+    /// The file at the path with its metadata. Symlinks aren't followed.
+    pub fn new(path: impl AsRef<Path>) -> io::Result<FileInfo> {
+        let path = path.as_ref();
+        let file_metadata = symlink_metadata(path)?;
+        Ok(FileInfo {
+            path: path.to_owned(),
+            dev: file_metadata.dev(),
+            size: file_metadata.len(),
+            uid: file_metadata.uid(),
+            gid: file_metadata.gid(),
+            mode: file_metadata.mode(),
+        })
+    }
+}
+
+/// The groups a stage split files into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grouping<'a> {
+    pub groups: Vec<Vec<&'a FileInfo>>,
+    /// Files that couldn't be read. They're in no group, and the log says why.
+    pub failed_files: Vec<&'a FileInfo>,
+}
+
+/// Groups files with the same device, size, owner, group, and mode. Only these can be hardlinked
+/// without changing anyone's view of them. Doesn't touch the filesystem.
+pub fn same_metadata_groups<'a>(files: impl IntoIterator<Item = &'a FileInfo>) -> Grouping<'a> {
+    let files: Vec<&FileInfo> = files.into_iter().collect();
+    let paths: Vec<&PathBuf> = files.iter().map(|file| &file.path).collect();
+    let keys: HashMap<&PathBuf, _> = files
+        .iter()
+        .map(|file| {
+            let key = (file.dev, file.size, file.gid, file.uid, file.mode);
+            (&file.path, key)
+        })
+        .collect();
+    let groups = group_by(paths.into_iter(), |path| keys.get(path).copied());
+    collect_grouping(&files, groups)
+}
+
+/// Groups files with the same first [`DedupOptions::prefix_bytes`] bytes.
+pub fn same_prefix_groups<'a>(
+    files: impl IntoIterator<Item = &'a FileInfo>,
+    options: &DedupOptions,
+) -> Grouping<'a> {
+    let files: Vec<&FileInfo> = files.into_iter().collect();
+    let prefix_bytes = options.prefix_bytes.unwrap_or(DEFAULT_PREFIX_BYTES);
+    let groups = prefix_groups(
+        paths(&files),
+        prefix_bytes,
+        options.read_options(),
+        &mut FailedFiles::default(),
+    );
+    collect_grouping(&files, groups)
+}
+
+/// Groups files with the same hash under [`DedupOptions::hash`]. Files that fail to hash are
+/// compared byte by byte instead.
+pub fn same_hash_groups<'a>(
+    files: impl IntoIterator<Item = &'a FileInfo>,
+    options: &DedupOptions,
+) -> Grouping<'a> {
+    let files: Vec<&FileInfo> = files.into_iter().collect();
+    let groups = hash_groups(
+        paths(&files),
+        None,
+        None,
+        options,
+        &mut FailedFiles::default(),
+    );
+    collect_grouping(&files, groups)
+}
+
+/// Groups files with the same contents by comparing them byte by byte. Files are read a chunk at a
+/// time, up to 16 of them at once, so each file is read once however many files it's compared to
+/// unless there are more of them.
+pub fn same_content_groups<'a>(
+    files: impl IntoIterator<Item = &'a FileInfo>,
+    options: &DedupOptions,
+) -> Grouping<'a> {
+    let files: Vec<&FileInfo> = files.into_iter().collect();
+    let groups = content_groups(
+        &paths(&files),
+        options.read_options(),
+        &mut FailedFiles::default(),
+    );
+    collect_grouping(&files, groups)
+}
+
+fn paths<'a>(files: &[&'a FileInfo]) -> HashSet<&'a PathBuf> {
+    files.iter().map(|file| &file.path).collect()
+}
+
+/// Maps the groups of paths back to the files in the order they were given. Files in no group
+/// failed.
+fn collect_grouping<'a>(
+    files: &[&'a FileInfo],
+    path_groups: impl IntoIterator<Item = HashSet<&'a PathBuf>>,
+) -> Grouping<'a> {
+    let mut group_of: HashMap<&PathBuf, usize> = HashMap::new();
+    for (index, group) in path_groups.into_iter().enumerate() {
+        group_of.extend(group.into_iter().map(|path| (path, index)));
+    }
+    let mut groups: Vec<Vec<&FileInfo>> = Vec::new();
+    let mut group_indexes: HashMap<usize, usize> = HashMap::new();
+    let mut failed_files = Vec::new();
+    for file in files {
+        match group_of.get(&file.path) {
+            Some(index) => {
+                let next_index = groups.len();
+                let group_index = *group_indexes.entry(*index).or_insert(next_index);
+                if group_index == groups.len() {
+                    groups.push(Vec::new());
+                }
+                groups[group_index].push(*file);
+            }
+            None => failed_files.push(*file),
+        }
+    }
+    Grouping {
+        groups,
+        failed_files,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tmp_file;
+    use tempfile::tempdir;
+
+    #[test]
+    fn classify_files_through_all_stages() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = FileInfo::new(tmp_file(tmp_dir.path(), "file1", "same contents")).unwrap();
+        let file2 = FileInfo::new(tmp_file(tmp_dir.path(), "file2", "same contents")).unwrap();
+        let same_prefix =
+            FileInfo::new(tmp_file(tmp_dir.path(), "prefix", "same contentz")).unwrap();
+        let other = FileInfo::new(tmp_file(tmp_dir.path(), "other", "diff contents")).unwrap();
+        let smaller = FileInfo::new(tmp_file(tmp_dir.path(), "smaller", "smaller")).unwrap();
+        let missing = FileInfo {
+            path: tmp_dir.path().join("missing"),
+            ..file1.clone()
+        };
+        let options = DedupOptions {
+            prefix_bytes: Some(4),
+            ..DedupOptions::default()
+        };
+        let files = [
+            file1.clone(),
+            smaller.clone(),
+            file2.clone(),
+            same_prefix.clone(),
+            other.clone(),
+            missing.clone(),
+        ];
+
+        let metadata_groups = same_metadata_groups(&files);
+        assert_eq!(
+            metadata_groups.groups,
+            [
+                vec![&file1, &file2, &same_prefix, &other, &missing],
+                vec![&smaller]
+            ]
+        );
+
+        let prefix_groups = same_prefix_groups(metadata_groups.groups[0].clone(), &options);
+        assert_eq!(
+            prefix_groups.groups,
+            [vec![&file1, &file2, &same_prefix], vec![&other]]
+        );
+        assert_eq!(prefix_groups.failed_files, [&missing]);
+
+        let hash_groups = same_hash_groups(prefix_groups.groups[0].clone(), &options);
+        assert_eq!(
+            hash_groups.groups,
+            [vec![&file1, &file2], vec![&same_prefix]]
+        );
+
+        let content_groups = same_content_groups(prefix_groups.groups[0].clone(), &options);
+        assert_eq!(content_groups, hash_groups);
+    }
+}
@@ -0,0 +1,349 @@
+//! Calculation of hashes in helper processes.
+//!
+//! Reading files on a corrupt filesystem can crash or wedge the reading process. Hash workers are
+//! copies of the current executable started with [`HASH_WORKER_ARG`], [`HASH_ALGORITHM_ARG`],
+//! [`BUFFER_SIZE_ARG`] and, when needed, [`FADVISE_ARG`] and [`BWLIMIT_ARG`]. Each worker runs
+//! with its own file descriptor and memory limits. A worker that crashes or doesn't respond in time
+//! is killed and a fresh one is started in its place.
+//!
+//! Workers read NUL-terminated paths from their stdin and answer each path with a single line on
+//! their stdout: either `ok <hex hash>` or `err <message>`.
+
+use crate::page_cache::ReadOptions;
+use crate::{calculate_hash, HashAlgorithm};
+use std::ffi::OsStr;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::time::Duration;
+use tracing::warn;
+
+/// The command-line argument that makes the executable run as a hash worker.
+pub const HASH_WORKER_ARG: &str = "--hash-worker";
+/// The command-line argument followed by the [`HashAlgorithm::name`] workers hash with.
+pub const HASH_ALGORITHM_ARG: &str = "--hash";
+/// The command-line argument followed by the size in bytes of the buffers workers read files
+/// through.
+pub const BUFFER_SIZE_ARG: &str = "--buffer-size";
+/// The command-line argument followed by the most bytes per second a worker may read.
+pub const BWLIMIT_ARG: &str = "--bwlimit";
+/// The command-line argument that makes workers drop the pages of the files they hashed from the
+/// page cache.
+pub const FADVISE_ARG: &str = "--fadvise";
+
+/// Workers only need their standard streams and the one file they are currently hashing.
+const WORKER_FD_LIMIT: libc::rlim_t = 32;
+
+pub struct HashPool {
+    program: PathBuf,
+    algorithm: HashAlgorithm,
+    read_options: ReadOptions,
+    memory_limit: Option<u64>,
+    timeout: Option<Duration>,
+    workers: Vec<Option<Worker>>,
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl HashPool {
+    pub fn new(
+        size: usize,
+        algorithm: HashAlgorithm,
+        read_options: ReadOptions,
+        memory_limit: Option<u64>,
+        timeout: Option<Duration>,
+    ) -> io::Result<HashPool> {
+        let program = std::env::current_exe()?;
+        // Workers read in parallel, so each gets its share of the limit.
+        let read_options = ReadOptions {
+            bwlimit: read_options
+                .bwlimit
+                .map(|bwlimit| (bwlimit / size.max(1) as u64).max(1)),
+            ..read_options
+        };
+        let mut workers = Vec::with_capacity(size);
+        for _ in 0..size {
+            workers.push(Some(Worker::spawn(
+                &program,
+                algorithm,
+                read_options,
+                memory_limit,
+            )?));
+        }
+        Ok(HashPool {
+            program,
+            algorithm,
+            read_options,
+            memory_limit,
+            timeout,
+            workers,
+        })
+    }
+
+    /// Calculates hashes of the given files with at most `max_parallel` workers at a time. The
+    /// results are in the same order as the files.
+    pub fn hash_files(
+        &mut self,
+        files: &[&PathBuf],
+        max_parallel: Option<usize>,
+    ) -> Vec<io::Result<Vec<u8>>> {
+        let parallel = max_parallel.map_or(self.workers.len(), |max_parallel| {
+            max_parallel.clamp(1, self.workers.len())
+        });
+        let mut hashes = Vec::with_capacity(files.len());
+        for chunk in files.chunks(parallel) {
+            let mut requests = Vec::with_capacity(chunk.len());
+            for (slot, file) in self.workers.iter_mut().zip(chunk) {
+                requests.push(send_request(
+                    slot,
+                    file,
+                    &self.program,
+                    self.algorithm,
+                    self.read_options,
+                    self.memory_limit,
+                ));
+            }
+            for ((slot, file), request) in self.workers.iter_mut().zip(chunk).zip(requests) {
+                hashes.push(request.and_then(|_| receive_hash(slot, file, self.timeout)));
+            }
+        }
+        hashes
+    }
+}
+
+fn send_request(
+    slot: &mut Option<Worker>,
+    file: &Path,
+    program: &Path,
+    algorithm: HashAlgorithm,
+    read_options: ReadOptions,
+    memory_limit: Option<u64>,
+) -> io::Result<()> {
+    if slot.is_none() {
+        *slot = Some(Worker::spawn(
+            program,
+            algorithm,
+            read_options,
+            memory_limit,
+        )?);
+    }
+    let worker = slot.as_mut().unwrap();
+    let result = worker
+        .stdin
+        .write_all(file.as_os_str().as_bytes())
+        .and_then(|_| worker.stdin.write_all(&[0]))
+        .and_then(|_| worker.stdin.flush());
+    if result.is_err() {
+        slot.take();
+    }
+    result
+}
+
+fn receive_hash(
+    slot: &mut Option<Worker>,
+    file: &Path,
+    timeout: Option<Duration>,
+) -> io::Result<Vec<u8>> {
+    let worker = match slot.as_mut() {
+        Some(worker) => worker,
+        None => return Err(io::Error::other("No hash worker.")),
+    };
+    let response = worker.receive(timeout);
+    if let Err(err) = &response {
+        warn!(
+            "Restarting hash worker {} after it failed while hashing {:?}. Error: {}",
+            worker.child.id(),
+            file,
+            err
+        );
+        slot.take();
+    }
+    parse_response(&response?)
+}
+
+impl Worker {
+    fn spawn(
+        program: &Path,
+        algorithm: HashAlgorithm,
+        read_options: ReadOptions,
+        memory_limit: Option<u64>,
+    ) -> io::Result<Worker> {
+        let mut command = Command::new(program);
+        command
+            .arg(HASH_WORKER_ARG)
+            .arg(HASH_ALGORITHM_ARG)
+            .arg(algorithm.name())
+            .arg(BUFFER_SIZE_ARG)
+            .arg(read_options.buffer_size.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        if read_options.drop_caches {
+            command.arg(FADVISE_ARG);
+        }
+        if let Some(bwlimit) = read_options.bwlimit {
+            command.arg(BWLIMIT_ARG).arg(bwlimit.to_string());
+        }
+        unsafe {
+            command.pre_exec(move || {
+                set_limit(libc::RLIMIT_NOFILE as libc::c_int, WORKER_FD_LIMIT)?;
+                if let Some(memory_limit) = memory_limit {
+                    set_limit(libc::RLIMIT_AS as libc::c_int, memory_limit as libc::rlim_t)?;
+                }
+                Ok(())
+            });
+        }
+        let mut child = command.spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(Worker {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    fn receive(&mut self, timeout: Option<Duration>) -> io::Result<String> {
+        if let Some(timeout) = timeout {
+            if self.stdout.buffer().is_empty() && !wait_readable(self.stdout.get_ref(), timeout)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("No response within {:?}.", timeout),
+                ));
+            }
+        }
+        let mut response = String::new();
+        if self.stdout.read_line(&mut response)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The hash worker exited unexpectedly.",
+            ));
+        }
+        Ok(response)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn set_limit(resource: libc::c_int, limit: libc::rlim_t) -> io::Result<()> {
+    let rlimit = libc::rlimit {
+        rlim_cur: limit,
+        rlim_max: limit,
+    };
+    // glibc types the resource as `__rlimit_resource_t` and other C libraries as `c_int`.
+    if unsafe { libc::setrlimit(resource as _, &rlimit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn wait_readable(stdout: &ChildStdout, timeout: Duration) -> io::Result<bool> {
+    let mut poll_fd = libc::pollfd {
+        fd: stdout.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    match unsafe { libc::poll(&mut poll_fd, 1, timeout_millis) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+fn parse_response(response: &str) -> io::Result<Vec<u8>> {
+    let response = response.trim_end_matches('\n');
+    if let Some(hex) = response.strip_prefix("ok ") {
+        return from_hex(hex).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid hash from hash worker: {:?}", hex),
+            )
+        });
+    }
+    let message = response.strip_prefix("err ").unwrap_or(response);
+    Err(io::Error::other(message.to_owned()))
+}
+
+/// Answers hash requests on stdin until stdin is closed. See the module documentation for the
+/// protocol.
+pub fn run_hash_worker(
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+    fadvise: bool,
+    bwlimit: Option<u64>,
+) -> io::Result<()> {
+    let read_options = ReadOptions {
+        buffer_size,
+        drop_caches: fadvise,
+        // Workers hash one file at a time.
+        io_uring: false,
+        bwlimit,
+    };
+    // Interrupting the deduplication from a terminal also interrupts the workers. The parent
+    // decides when to stop and kills its workers itself.
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_IGN) };
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let mut path = Vec::new();
+    loop {
+        path.clear();
+        input.read_until(0, &mut path)?;
+        if path.pop() != Some(0) {
+            return Ok(());
+        }
+        let response =
+            match calculate_hash(Path::new(OsStr::from_bytes(&path)), algorithm, read_options) {
+                Ok(hash) => format!("ok {}\n", to_hex(&hash)),
+                Err(err) => format!("err {}\n", err.to_string().replace('\n', " ")),
+            };
+        output.write_all(response.as_bytes())?;
+        output.flush()?;
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        let bytes = vec![0x00, 0x7f, 0xab, 0xff];
+        assert_eq!(to_hex(&bytes), "007fabff");
+        assert_eq!(from_hex(&to_hex(&bytes)), Some(bytes));
+    }
+
+    #[test]
+    fn parse_error_response() {
+        let err = parse_response("err No such file or directory\n").unwrap_err();
+        assert_eq!(err.to_string(), "No such file or directory");
+    }
+}
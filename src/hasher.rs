@@ -0,0 +1,113 @@
+//! The hash algorithms used to find files with the same contents.
+
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+
+/// Size of the buffers files are read through by default. Large buffers cut down on seeking back and
+/// forth between files on spinning disks.
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Calculates the hash of data fed to it in pieces.
+pub trait Hasher {
+    fn update(&mut self, data: &[u8]);
+
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+/// The hash algorithm used to group files with the same contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum HashAlgorithm {
+    /// SHA-256. Uses the CPU's SHA extensions when available.
+    #[default]
+    Sha256,
+    /// BLAKE3, a cryptographic hash that is several times faster than SHA-256.
+    Blake3,
+    /// The 128-bit variant of XXH3, a very fast non-cryptographic hash. Files with the same XXH3 hash
+    /// can be crafted, so combine it with [`crate::DedupOptions::paranoid`] on untrusted data.
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            HashAlgorithm::Xxh3 => Box::new(xxhash_rust::xxh3::Xxh3::new()),
+        }
+    }
+
+    /// The name of the algorithm as given on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+        }
+    }
+
+    /// Hashes everything the reader returns, reading `buffer_size` bytes at a time.
+    pub fn hash_reader(self, mut reader: impl Read, buffer_size: usize) -> io::Result<Vec<u8>> {
+        let mut hasher = self.hasher();
+        let mut buffer = vec![0; buffer_size.max(1)];
+        loop {
+            let read_bytes = match reader.read(&mut buffer) {
+                Ok(0) => return Ok(hasher.finalize()),
+                Ok(read_bytes) => read_bytes,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            hasher.update(&buffer[..read_bytes]);
+        }
+    }
+}
+
+impl Hasher for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        Digest::finalize(*self).to_vec()
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        blake3::Hasher::finalize(&self).as_bytes().to_vec()
+    }
+}
+
+impl Hasher for xxhash_rust::xxh3::Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh3::Xxh3::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.digest128().to_be_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_pool::to_hex;
+
+    #[test]
+    fn known_hashes() {
+        let hash =
+            |algorithm: HashAlgorithm| to_hex(&algorithm.hash_reader(&b"abc"[..], 2).unwrap());
+        assert_eq!(
+            hash(HashAlgorithm::Sha256),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hash(HashAlgorithm::Blake3),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(hash(HashAlgorithm::Xxh3).len(), 32);
+    }
+}
@@ -0,0 +1,494 @@
+//! Indexes of the hashes of files, computed ahead of deduplicating.
+//!
+//! Indexing only walks the paths and hashes every file, so volumes can be indexed at different times
+//! or on different machines. Deduplicating with indexes then skips the scan: files with the same
+//! size and hash in any of the indexes are hardlinked where they share a device. Files that changed
+//! since they were indexed are left out, and the rest are compared byte by byte before hardlinking,
+//! as with [`crate::link_dupes_list`].
+//!
+//! Incremental runs keep an index of the files they saw as their state. The next run still walks the
+//! paths, but only hashes the files that are new or changed since, and only hardlinks those to
+//! files with the same contents, known or new. Like a full run, it only hashes files with the same
+//! size as another file on their device. Files of groups that didn't all end up hardlinked, e.g.
+//! because hardlinking one of them failed, are left out of the state, so that they're new again
+//! next time.
+
+use crate::dupes_list::link_groups;
+use crate::error::{DedupError, FailedFiles, FileError};
+use crate::hash_pool::to_hex;
+use crate::output::Renderer;
+use crate::{calculate_hashes, find_inode_groups, start_hash_pool, DedupOptions, DedupSummary};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{metadata, read, rename, write, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// What [`index`] did.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct IndexSummary {
+    /// Files hashed into the index. Hardlinks to the same file count once.
+    pub indexed_files: usize,
+    pub indexed_bytes: u64,
+    pub failed_files: usize,
+    /// Why the files in [`IndexSummary::failed_files`] failed, for the first
+    /// [`crate::MAX_FILE_ERRORS`] of them.
+    pub file_errors: Vec<FileError>,
+}
+
+/// What an index or the state of incremental runs holds, see [`cache`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CacheSummary {
+    /// The name of the hash algorithm of the hashes.
+    pub hash: String,
+    /// Files in the cache. Hardlinks to the same file count once.
+    pub files: usize,
+    /// Files with a hash. Incremental runs don't hash files that no other file had the size of.
+    pub hashed_files: usize,
+    pub bytes: u64,
+    /// Files dropped because all their paths disappeared or changed since they were recorded.
+    pub pruned_files: usize,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Index {
+    /// The name of the hash algorithm, see [`crate::HashAlgorithm::name`].
+    hash: String,
+    files: Vec<IndexedFile>,
+}
+
+/// A file and all of its paths under the indexed paths.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct IndexedFile {
+    paths: Vec<PathBuf>,
+    device: u64,
+    inode: u64,
+    size: u64,
+    /// The modification time in nanoseconds since the Unix epoch.
+    modified: i64,
+    /// Left out for files of incremental runs that no other file had the size of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+}
+
+impl IndexedFile {
+    /// Whether the file at the path still looks like it did when it was indexed.
+    fn is_current(&self, file_metadata: &Metadata) -> bool {
+        file_metadata.len() == self.size && modified(file_metadata) == self.modified
+    }
+}
+
+fn modified(file_metadata: &Metadata) -> i64 {
+    file_metadata.mtime() * 1_000_000_000 + file_metadata.mtime_nsec()
+}
+
+/// Hashes the files in the given paths with [`DedupOptions::hash`] and writes their hashes to the
+/// index file, replacing it. The scan filters of the options apply.
+pub fn index(
+    paths: &[PathBuf],
+    options: &DedupOptions,
+    index_file: &Path,
+) -> Result<IndexSummary, DedupError> {
+    let mut failed_files = FailedFiles::default();
+    let (index, _) = scan(paths, options, &Index::default(), true, &mut failed_files)?;
+    save(&index, index_file).map_err(|source| DedupError::WriteIndex {
+        path: index_file.to_owned(),
+        source,
+    })?;
+    Ok(IndexSummary {
+        indexed_files: index.files.len(),
+        indexed_bytes: index.files.iter().map(|file| file.size).sum(),
+        failed_files: failed_files.count(),
+        file_errors: failed_files.into_errors(),
+    })
+}
+
+/// Indexes the files in the given paths, taking the hashes of files that didn't change from the
+/// previous index. Unless `hash_all`, only files with the same size as another file on their device
+/// are hashed. Also returns which of the files are new or changed.
+fn scan(
+    paths: &[PathBuf],
+    options: &DedupOptions,
+    previous: &Index,
+    hash_all: bool,
+    failed_files: &mut FailedFiles,
+) -> Result<(Index, Vec<bool>), DedupError> {
+    for path in paths {
+        std::fs::symlink_metadata(path).map_err(|source| DedupError::Access {
+            path: path.clone(),
+            source,
+        })?;
+    }
+    let known: HashMap<(u64, u64), &IndexedFile> = if previous.hash == options.hash.name() {
+        previous
+            .files
+            .iter()
+            .map(|file| ((file.device, file.inode), file))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let inode_to_paths = find_inode_groups(paths, options, failed_files);
+    let mut files = Vec::new();
+    for file_paths in inode_to_paths.values() {
+        let mut file_paths: Vec<PathBuf> = file_paths.iter().cloned().collect();
+        file_paths.sort();
+        let file_metadata = match metadata(&file_paths[0]) {
+            Ok(file_metadata) => file_metadata,
+            Err(err) => {
+                let path = &file_paths[0];
+                failed_files.add(path, format!("Failed to index {:?}. Error: {}", path, err));
+                continue;
+            }
+        };
+        let known_file = known
+            .get(&(file_metadata.dev(), file_metadata.ino()))
+            .filter(|known_file| known_file.is_current(&file_metadata));
+        files.push((
+            IndexedFile {
+                device: file_metadata.dev(),
+                inode: file_metadata.ino(),
+                size: file_metadata.len(),
+                modified: modified(&file_metadata),
+                hash: known_file.and_then(|known_file| known_file.hash.clone()),
+                paths: file_paths,
+            },
+            known_file.is_none(),
+        ));
+    }
+    let mut sizes: HashMap<(u64, u64), usize> = HashMap::new();
+    for (file, _) in &files {
+        *sizes.entry((file.device, file.size)).or_default() += 1;
+    }
+    // Each device is hashed on its own, so that it's read the way that suits it.
+    let mut unhashed: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+    for (index, (file, _)) in files.iter().enumerate() {
+        if file.hash.is_none() && (hash_all || sizes[&(file.device, file.size)] > 1) {
+            unhashed.entry(file.device).or_default().push(index);
+        }
+    }
+    let mut hash_pool = start_hash_pool(options);
+    let mut failed = HashSet::new();
+    for indices in unhashed.values() {
+        let hashes = {
+            let device_files: Vec<&PathBuf> = indices
+                .iter()
+                .map(|index| &files[*index].0.paths[0])
+                .collect();
+            calculate_hashes(&device_files, hash_pool.as_mut(), options)
+        };
+        for (&index, hash) in indices.iter().zip(hashes) {
+            match hash {
+                Ok(hash) => files[index].0.hash = Some(to_hex(&hash)),
+                Err(err) => {
+                    let path = &files[index].0.paths[0];
+                    failed_files.add(path, format!("Failed to index {:?}. Error: {}", path, err));
+                    failed.insert(index);
+                }
+            }
+        }
+    }
+    let mut files: Vec<(IndexedFile, bool)> = files
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !failed.contains(index))
+        .map(|(_, file)| file)
+        .collect();
+    files.sort_by(|(a, _), (b, _)| a.paths.cmp(&b.paths));
+    let (files, changed) = files.into_iter().unzip();
+    let index = Index {
+        hash: options.hash.name().to_owned(),
+        files,
+    };
+    Ok((index, changed))
+}
+
+/// Deduplicates the files in the given paths that are new or changed since the run that wrote the
+/// state file, and then replaces the state with the files seen now. Without a state file every file
+/// is new. Dry runs and interrupted runs leave the state alone, so that the files they didn't
+/// hardlink are still new next time.
+pub fn dedup_incremental(
+    paths: &[PathBuf],
+    options: &DedupOptions,
+    state_file: &Path,
+    renderer: &mut dyn Renderer,
+) -> Result<DedupSummary, DedupError> {
+    let previous = if state_file.exists() {
+        load(state_file)?
+    } else {
+        Index::default()
+    };
+    let mut failed_files = FailedFiles::default();
+    let (mut index, changed) = scan(paths, options, &previous, false, &mut failed_files)?;
+    info!(
+        "{} of {} files are new or changed since the last run.",
+        changed.iter().filter(|changed| **changed).count(),
+        changed.len()
+    );
+    let mut groups: HashMap<(u64, u64, &str), (Vec<PathBuf>, bool)> = HashMap::new();
+    for (file, changed) in index.files.iter().zip(&changed) {
+        let Some(hash) = &file.hash else {
+            continue;
+        };
+        let (paths, any_changed) = groups.entry((file.device, file.size, hash)).or_default();
+        paths.extend(file.paths.iter().cloned());
+        *any_changed |= *changed;
+    }
+    let mut groups: Vec<Vec<PathBuf>> = groups
+        .into_values()
+        .filter(|(paths, any_changed)| *any_changed && paths.len() > 1)
+        .map(|(paths, _)| paths)
+        .collect();
+    groups.sort();
+    let mut summary = link_groups(&groups, options, renderer)?;
+    summary.add(&DedupSummary {
+        failed_files: failed_files.count(),
+        file_errors: failed_files.into_errors(),
+        ..DedupSummary::default()
+    });
+    if !options.dry_run && !summary.interrupted {
+        let unlinked: HashSet<&PathBuf> = groups
+            .iter()
+            .filter(|group| !all_linked(group))
+            .flatten()
+            .collect();
+        index
+            .files
+            .retain(|file| !file.paths.iter().any(|path| unlinked.contains(path)));
+        save(&index, state_file).map_err(|source| DedupError::WriteIndex {
+            path: state_file.to_owned(),
+            source,
+        })?;
+    }
+    Ok(summary)
+}
+
+/// Reports what an index or the state of incremental runs holds. With `prune`, first drops the
+/// paths that disappeared or changed since they were recorded, and the files left without any, and
+/// replaces the cache with the rest.
+pub fn cache(path: &Path, prune: bool) -> Result<CacheSummary, DedupError> {
+    let mut index = load(path)?;
+    let mut pruned_files = 0;
+    if prune {
+        let files = index.files.len();
+        index.files.retain_mut(|file| {
+            let paths = std::mem::take(&mut file.paths);
+            file.paths = paths
+                .into_iter()
+                .filter(|path| {
+                    metadata(path).is_ok_and(|file_metadata| {
+                        (file_metadata.dev(), file_metadata.ino()) == (file.device, file.inode)
+                            && file.is_current(&file_metadata)
+                    })
+                })
+                .collect();
+            !file.paths.is_empty()
+        });
+        pruned_files = files - index.files.len();
+        save(&index, path).map_err(|source| DedupError::WriteIndex {
+            path: path.to_owned(),
+            source,
+        })?;
+    }
+    Ok(CacheSummary {
+        hash: index.hash,
+        files: index.files.len(),
+        hashed_files: index
+            .files
+            .iter()
+            .filter(|file| file.hash.is_some())
+            .count(),
+        bytes: index.files.iter().map(|file| file.size).sum(),
+        pruned_files,
+    })
+}
+
+/// Whether all the paths are now the same file.
+fn all_linked(paths: &[PathBuf]) -> bool {
+    let file_ids: Result<HashSet<(u64, u64)>, _> = paths
+        .iter()
+        .map(|path| metadata(path).map(|file_metadata| (file_metadata.dev(), file_metadata.ino())))
+        .collect();
+    file_ids.is_ok_and(|file_ids| file_ids.len() == 1)
+}
+
+/// Replaces the index file atomically so that a crash never leaves a partial index.
+fn save(index: &Index, path: &Path) -> std::io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    write(&tmp_path, serde_json::to_vec(index)?)?;
+    rename(&tmp_path, path)
+}
+
+fn load(path: &Path) -> Result<Index, DedupError> {
+    let contents = read(path).map_err(|source| DedupError::ReadIndex {
+        path: path.to_owned(),
+        source,
+    })?;
+    serde_json::from_slice(&contents).map_err(|source| DedupError::ParseIndex {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// Hardlinks the files that have the same size and hash in any of the indexes, without scanning.
+/// Files are only grouped with files hashed with the same algorithm. Files of incremental states
+/// that weren't hashed are left out.
+pub fn link_indexes(
+    index_files: &[PathBuf],
+    options: &DedupOptions,
+    renderer: &mut dyn Renderer,
+) -> Result<DedupSummary, DedupError> {
+    let mut groups: HashMap<(String, u64, String), Vec<PathBuf>> = HashMap::new();
+    for index_file in index_files {
+        let index = load(index_file)?;
+        for file in index.files {
+            let Some(hash) = file.hash.clone() else {
+                continue;
+            };
+            let paths = file.paths.iter().filter(|path| match metadata(path) {
+                Ok(file_metadata) => file.is_current(&file_metadata),
+                Err(_) => false,
+            });
+            let current_paths: Vec<PathBuf> = paths.cloned().collect();
+            if current_paths.len() < file.paths.len() {
+                info!(
+                    "Skipping paths of {:?} that changed or disappeared since they were indexed.",
+                    file.paths
+                );
+            }
+            groups
+                .entry((index.hash.clone(), file.size, hash))
+                .or_default()
+                .extend(current_paths);
+        }
+    }
+    let mut groups: Vec<Vec<PathBuf>> = groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect();
+    groups.sort();
+    link_groups(&groups, options, renderer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::QuietRenderer;
+    use crate::test_utils::{same, set_modified, tmp_file};
+    use std::fs::{set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::UNIX_EPOCH;
+    use tempfile::tempdir;
+
+    #[test]
+    fn index_and_link() {
+        let tmp_dir = tempdir().unwrap();
+        let a = tmp_file(&tmp_dir.path().join("a"), "file", "same contents");
+        let b = tmp_file(&tmp_dir.path().join("b"), "file", "same contents");
+        let changed = tmp_file(&tmp_dir.path().join("b"), "changed", "same contents");
+        let options = DedupOptions::default();
+        let index_a = tmp_dir.path().join("a.index");
+        let index_b = tmp_dir.path().join("b.index");
+
+        let summary = index(&[tmp_dir.path().join("a")], &options, &index_a).unwrap();
+        assert_eq!(summary.indexed_files, 1);
+        assert_eq!(summary.indexed_bytes, 13);
+        index(&[tmp_dir.path().join("b")], &options, &index_b).unwrap();
+        write(&changed, "new contents!").unwrap();
+        set_modified(&changed, UNIX_EPOCH);
+
+        let summary = link_indexes(&[index_a, index_b], &options, &mut QuietRenderer).unwrap();
+        assert_eq!(summary.failed_files, 0);
+        assert!(same(&a, &b));
+        assert!(!same(&a, &changed));
+    }
+
+    #[test]
+    fn only_link_new_files() {
+        let tmp_dir = tempdir().unwrap();
+        let paths = [tmp_dir.path().join("files")];
+        let a = tmp_file(&paths[0], "a", "same contents");
+        let b = tmp_file(&paths[0], "b", "same contents");
+        tmp_file(&paths[0], "unique", "other contents");
+        let state = tmp_dir.path().join("state");
+        let options = DedupOptions::default();
+
+        let summary = dedup_incremental(&paths, &options, &state, &mut QuietRenderer).unwrap();
+        assert_eq!(summary.bytes_deduped, 13);
+        assert!(same(&a, &b));
+
+        let new = tmp_file(&paths[0], "new", "same contents");
+        let mut failed_files = FailedFiles::default();
+        let (index, changed) = scan(
+            &paths,
+            &options,
+            &load(&state).unwrap(),
+            false,
+            &mut failed_files,
+        )
+        .unwrap();
+        assert_eq!(index.files.len(), 3);
+        assert_eq!(changed.iter().filter(|changed| **changed).count(), 1);
+        // The unique file has no other file of its size to hash it for.
+        assert_eq!(
+            index
+                .files
+                .iter()
+                .filter(|file| file.hash.is_none())
+                .count(),
+            1
+        );
+
+        let summary = dedup_incremental(&paths, &options, &state, &mut QuietRenderer).unwrap();
+        assert_eq!(summary.bytes_deduped, 13);
+        assert!(same(&a, &new));
+    }
+
+    #[test]
+    fn keep_unlinked_files_new() {
+        let tmp_dir = tempdir().unwrap();
+        let paths = [tmp_dir.path().join("files")];
+        let a = tmp_file(&paths[0], "a", "same contents");
+        let b = tmp_file(&paths[0], "b", "same contents");
+        set_permissions(&b, Permissions::from_mode(0o600)).unwrap();
+        let state = tmp_dir.path().join("state");
+        let options = DedupOptions::default();
+
+        dedup_incremental(&paths, &options, &state, &mut QuietRenderer).unwrap();
+        assert!(!same(&a, &b));
+
+        let mut failed_files = FailedFiles::default();
+        let (_, changed) = scan(
+            &paths,
+            &options,
+            &load(&state).unwrap(),
+            false,
+            &mut failed_files,
+        )
+        .unwrap();
+        assert_eq!(changed, [true, true]);
+    }
+
+    #[test]
+    fn prune_cache() {
+        let tmp_dir = tempdir().unwrap();
+        let paths = [tmp_dir.path().join("files")];
+        tmp_file(&paths[0], "kept", "contents 1");
+        let removed = tmp_file(&paths[0], "removed", "contents 2");
+        let index_file = tmp_dir.path().join("index");
+        index(&paths, &DedupOptions::default(), &index_file).unwrap();
+        std::fs::remove_file(removed).unwrap();
+
+        let summary = cache(&index_file, false).unwrap();
+        assert_eq!(
+            (summary.files, summary.hashed_files, summary.bytes),
+            (2, 2, 20)
+        );
+        let summary = cache(&index_file, true).unwrap();
+        assert_eq!((summary.files, summary.pruned_files), (1, 1));
+        assert_eq!(load(&index_file).unwrap().files.len(), 1);
+    }
+}
@@ -0,0 +1,67 @@
+//! Confirmation of each group of duplicates before it's hardlinked.
+
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// What the user answered when asked whether to hardlink a group of duplicates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Answer {
+    Yes,
+    No,
+    /// Hardlink this group and all following groups without asking again.
+    All,
+    /// Stop deduplicating.
+    Quit,
+}
+
+/// Asks on stderr whether to replace the targets with hardlinks to the original and reads the
+/// answer from stdin. Closing stdin counts as quitting.
+pub(crate) fn ask(original_file: &Path, targets: &[&Path], size: u64) -> io::Result<Answer> {
+    let mut stderr = io::stderr().lock();
+    writeln!(
+        stderr,
+        "Keep {:?} ({} bytes) and hardlink:",
+        original_file, size
+    )?;
+    for target in targets {
+        writeln!(stderr, "  {:?} ({} bytes)", target, size)?;
+    }
+    let stdin = io::stdin();
+    loop {
+        write!(stderr, "Hardlink? [y]es, [n]o, [a]ll, [q]uit: ")?;
+        stderr.flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            writeln!(stderr)?;
+            return Ok(Answer::Quit);
+        }
+        if let Some(answer) = parse_answer(&line) {
+            return Ok(answer);
+        }
+    }
+}
+
+fn parse_answer(line: &str) -> Option<Answer> {
+    match line.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Some(Answer::Yes),
+        "n" | "no" => Some(Answer::No),
+        "a" | "all" => Some(Answer::All),
+        "q" | "quit" => Some(Answer::Quit),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers() {
+        assert_eq!(parse_answer("y\n"), Some(Answer::Yes));
+        assert_eq!(parse_answer(" No \n"), Some(Answer::No));
+        assert_eq!(parse_answer("a"), Some(Answer::All));
+        assert_eq!(parse_answer("quit"), Some(Answer::Quit));
+        assert_eq!(parse_answer("maybe"), None);
+        assert_eq!(parse_answer(""), None);
+    }
+}
@@ -0,0 +1,210 @@
+//! A journal of replaced files that lets us undo a deduplication.
+//!
+//! Every replaced file is recorded on its own JSON line as soon as it's replaced, so the journal
+//! survives crashes. Undoing copies the contents back into independent files.
+
+use crate::error::{DedupError, FailedFiles, FileError};
+use crate::hash_pool::to_hex;
+use crate::page_cache::ReadOptions;
+use crate::{calculate_hash, HashAlgorithm};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{copy, metadata, remove_file, rename, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{chown, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct JournalEntry {
+    /// The path that was replaced with a hardlink.
+    pub target: PathBuf,
+    /// The file the target now links to.
+    pub original: PathBuf,
+    pub previous_inode: u64,
+    pub size: u64,
+    /// SHA-256 of the contents in hex.
+    pub hash: String,
+    /// Modification time of the replaced file.
+    pub modified: SystemTime,
+}
+
+pub(crate) struct Journal {
+    file: File,
+    /// Hashes of original files, which are usually linked to several times.
+    hashes: HashMap<PathBuf, String>,
+}
+
+impl Journal {
+    pub(crate) fn open(path: &Path) -> io::Result<Journal> {
+        Ok(Journal {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            hashes: HashMap::new(),
+        })
+    }
+
+    /// Records a target that is about to be replaced with a hardlink to the original.
+    pub(crate) fn entry(&mut self, original: &Path, target: &Path) -> io::Result<JournalEntry> {
+        let target_metadata = metadata(target)?;
+        let hash = match self.hashes.get(original) {
+            Some(hash) => hash.clone(),
+            None => {
+                let hash = to_hex(&calculate_hash(
+                    original,
+                    HashAlgorithm::Sha256,
+                    ReadOptions::default(),
+                )?);
+                self.hashes.insert(original.to_owned(), hash.clone());
+                hash
+            }
+        };
+        Ok(JournalEntry {
+            target: target.to_owned(),
+            original: original.to_owned(),
+            previous_inode: target_metadata.ino(),
+            size: target_metadata.len(),
+            hash,
+            modified: target_metadata.modified()?,
+        })
+    }
+
+    pub(crate) fn record(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()
+    }
+}
+
+/// What undoing a journal did.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UndoSummary {
+    pub restored_files: usize,
+    /// Files that are no longer hardlinked or whose contents changed since they were replaced.
+    pub skipped_files: usize,
+    pub failed_files: usize,
+    /// Why the files in [`UndoSummary::failed_files`] failed, for the first
+    /// [`crate::MAX_FILE_ERRORS`] of them.
+    pub file_errors: Vec<FileError>,
+}
+
+/// Turns the files recorded in the journal back into independent copies, most recent first.
+pub fn undo(journal: &Path, dry_run: bool) -> Result<UndoSummary, DedupError> {
+    let entries = read_journal(journal).map_err(|source| DedupError::ReadJournal {
+        path: journal.to_owned(),
+        source,
+    })?;
+    let mut summary = UndoSummary::default();
+    let mut failed_files = FailedFiles::default();
+    for entry in entries.iter().rev() {
+        match restore(entry, dry_run) {
+            Ok(true) => summary.restored_files += 1,
+            Ok(false) => summary.skipped_files += 1,
+            Err(err) => {
+                failed_files.add(
+                    &entry.target,
+                    format!("Failed to restore {:?}. Error: {}", entry.target, err),
+                );
+            }
+        }
+    }
+    summary.failed_files = failed_files.count();
+    summary.file_errors = failed_files.into_errors();
+    Ok(summary)
+}
+
+fn read_journal(journal: &Path) -> io::Result<Vec<JournalEntry>> {
+    BufReader::new(File::open(journal)?)
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// Copies the contents of the target into a new file that replaces it. Returns whether the target
+/// was restored.
+fn restore(entry: &JournalEntry, dry_run: bool) -> io::Result<bool> {
+    let target_metadata = metadata(&entry.target)?;
+    if target_metadata.nlink() < 2 {
+        info!("Skipping {:?}. It's not hardlinked anymore.", entry.target);
+        return Ok(false);
+    }
+    if target_metadata.len() != entry.size
+        || to_hex(&calculate_hash(
+            &entry.target,
+            HashAlgorithm::Sha256,
+            ReadOptions::default(),
+        )?) != entry.hash
+    {
+        warn!(
+            "Skipping {:?}. Its contents changed since it was hardlinked.",
+            entry.target
+        );
+        return Ok(false);
+    }
+    if dry_run {
+        info!("Would restore {:?}.", entry.target);
+        return Ok(true);
+    }
+    let tmp_file = entry
+        .target
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join(Uuid::new_v4().to_string());
+    let copied = copy(&entry.target, &tmp_file)
+        .and_then(|_| {
+            chown(
+                &tmp_file,
+                Some(target_metadata.uid()),
+                Some(target_metadata.gid()),
+            )
+        })
+        .and_then(|_| {
+            File::options()
+                .write(true)
+                .open(&tmp_file)?
+                .set_modified(entry.modified)
+        })
+        .and_then(|_| rename(&tmp_file, &entry.target));
+    if let Err(err) = copied {
+        let _ = remove_file(&tmp_file);
+        return Err(err);
+    }
+    info!("Restored {:?}.", entry.target);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{hard_link, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn record_and_undo() {
+        let tmp_dir = tempdir().unwrap();
+        let original = tmp_dir.path().join("original");
+        let target = tmp_dir.path().join("target");
+        let journal_path = tmp_dir.path().join("journal");
+        write(&original, "same contents").unwrap();
+        write(&target, "same contents").unwrap();
+        let previous_inode = metadata(&target).unwrap().ino();
+
+        let mut journal = Journal::open(&journal_path).unwrap();
+        let entry = journal.entry(&original, &target).unwrap();
+        assert_eq!(entry.previous_inode, previous_inode);
+        journal.record(&entry).unwrap();
+        remove_file(&target).unwrap();
+        hard_link(&original, &target).unwrap();
+
+        let summary = undo(&journal_path, false).unwrap();
+        assert_eq!(summary.restored_files, 1);
+        assert_ne!(
+            metadata(&target).unwrap().ino(),
+            metadata(&original).unwrap().ino()
+        );
+        assert_eq!(std::fs::read(&target).unwrap(), b"same contents");
+    }
+}
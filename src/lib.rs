@@ -1,244 +1,3249 @@
-use log::warn;
-use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
-use std::fs::{hard_link, metadata, remove_file, rename, File};
+mod backup_hints;
+mod breakdown;
+pub mod build_info;
+mod candidate_filter;
+mod checkpoint;
+mod chunked_hash;
+mod cross_device;
+mod deduper;
+mod devices;
+mod dir_handle;
+mod double_read;
+mod dupes_list;
+mod error;
+mod extents;
+mod filesystems;
+mod foreign_mounts;
+mod free_space;
+pub mod grouping;
+mod hash_pool;
+pub mod hasher;
+mod index;
+mod interactive;
+mod journal;
+mod link_max;
+mod low_memory;
+mod near_duplicates;
+mod open_files;
+pub mod output;
+mod page_cache;
+mod parallel_scan;
+mod prefer;
+mod prefetch;
+mod progress;
+pub mod protect;
+mod reflink;
+mod repair;
+mod rusage;
+mod sparse;
+mod stats;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+mod throttle;
+pub mod units;
+#[cfg(feature = "io-uring")]
+mod uring;
+mod verify;
+mod watch;
+mod what_if;
+mod xattrs;
+
+use backup_hints::{common_dir, outermost_dirs};
+use breakdown::Breakdown;
+use checkpoint::{Checkpoint, GroupKey};
+use chunked_hash::{same_chunked_hash_groups, CHUNKED_HASH_THRESHOLD, FIRST_CHUNK_BYTES};
+use cross_device::report_cross_device_duplicates;
+use devices::{
+    compare_chunk_size, compare_readers, files_medium, hash_readers, inode_order, Medium,
+};
+use dir_handle::DirHandle;
+use double_read::reads_consistently;
+use error::FailedFiles;
+use filesystems::{filesystem_support, Support};
+use foreign_mounts::foreign_mounts;
+use free_space::free_space;
+use hash_pool::{to_hex, HashPool};
+use interactive::Answer;
+use journal::Journal;
+use link_max::link_max;
+use low_memory::dedup_in_buckets;
+use near_duplicates::{differing_offsets, read_full, DEFAULT_MAX_DIFFERENCES};
+use open_files::is_open_for_writing;
+use output::{Event, Renderer, SkipReason, Status};
+use page_cache::{ReadFile, ReadOptions};
+use parallel_scan::scan_path_parallel;
+use prefetch::Prefetcher;
+use progress::Progress;
+use reflink::take_metadata;
+use repair::{backup_corrupt_file, choose_corrupt_group};
+use rusage::resource_usage;
+use serde::Serialize;
+use sparse::{allocated_bytes, are_sparse_files_same, is_sparse};
+use stats::{Stage, StageClock};
+use std::cmp::Reverse;
+use std::collections::{hash_map, BTreeSet, HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{canonicalize, metadata, symlink_metadata, File, Metadata};
 use std::io;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, debug_span, info, info_span, warn};
 use uuid::Uuid;
-use walkdir::{DirEntry, DirEntryExt, WalkDir};
+use walkdir::{DirEntry, WalkDir};
+use xattrs::{compares_xattrs, same_xattr_groups, same_xattrs};
 
-pub fn dedup(paths: &Vec<PathBuf>, dry_run: bool, paranoid: bool) {
-    let inode_to_paths = find_inode_groups(paths);
-    let mut ctx = DedupContext::new(&inode_to_paths, dry_run);
-    println!("Processing {} files.", ctx.total);
-    let files = inode_to_paths
-        .values()
-        .map(|file_group| file_group.iter().nth(0))
-        .flatten();
-    for size_group in same_metadata_groups(files) {
-        if exclude_if_unique(
-            &size_group,
-            &mut ctx,
-            "It has unique size, uid, gid, or mode.",
-        ) {
-            continue;
+pub use breakdown::DirectorySavings;
+pub use candidate_filter::{CandidateFilter, CandidateFilters};
+pub use deduper::Deduper;
+pub use double_read::SecondRead;
+pub use dupes_list::link_dupes_list;
+pub use error::{DedupError, FileError, MAX_FILE_ERRORS};
+pub use hash_pool::{
+    run_hash_worker, BUFFER_SIZE_ARG, BWLIMIT_ARG, FADVISE_ARG, HASH_ALGORITHM_ARG, HASH_WORKER_ARG,
+};
+pub use hasher::{HashAlgorithm, Hasher, DEFAULT_BUFFER_SIZE};
+pub use index::{cache, dedup_incremental, index, link_indexes, CacheSummary, IndexSummary};
+pub use journal::{undo, UndoSummary};
+pub use output::OutputFormat;
+pub use prefer::Prefer;
+pub use protect::ProtectedPaths;
+pub use reflink::{Fallback, LinkMode};
+pub use repair::RepairMode;
+pub use rusage::ResourceUsage;
+pub use stats::{RunStats, StageBytes, StageTimes};
+pub use verify::{verify, VerifySummary};
+pub use what_if::{what_if, WhatIfSummary};
+
+/// Options that control how [`dedup_with_options`] deduplicates files.
+#[derive(Debug, Clone, Default)]
+pub struct DedupOptions {
+    /// Don't actually hardlink any files.
+    pub dry_run: bool,
+    /// Always check that files are bit-for-bit equal instead of trusting their hashes.
+    pub paranoid: bool,
+    /// How many bytes from the start of files with the same size to compare before hashing them.
+    /// Defaults to 64.
+    pub prefix_bytes: Option<u64>,
+    /// How many bytes from the end of files with the same prefix to compare before hashing them.
+    /// Defaults to 64. Zero skips the comparison. Skipped when reporting near-duplicates, which can
+    /// differ anywhere.
+    pub tail_bytes: Option<u64>,
+    /// Extensions (without the dot, e.g. `mp3`) of formats whose files differ in their headers more
+    /// often than in their tails, like growing logs and MP3s with leading tags. Files of the same
+    /// size are compared by their tails before their prefixes when any of them has one of these
+    /// extensions.
+    pub tail_first_extensions: Vec<String>,
+    /// Which file of each group of duplicates to keep as the original. Without a policy the file with
+    /// the most links is kept. Ties are broken by lexicographic path order.
+    pub prefer: Option<Prefer>,
+    /// Only hardlink files whose contents have at least this many copies. Hardlinks to the same file
+    /// count as one copy. Defaults to 2.
+    pub min_copies: Option<usize>,
+    /// Leave files that already have at least this many links alone, e.g. files linked across many
+    /// snapshots by previous runs. They're neither hashed nor linked to.
+    pub max_nlink: Option<u64>,
+    /// Only hardlink files with the same name, e.g. the same photo across backup snapshots, so that
+    /// files that are only coincidentally the same stay apart.
+    pub same_name_only: bool,
+    /// Break the savings down by the directories this many levels below the deduplicated paths, see
+    /// [`DedupSummary::directories`].
+    pub breakdown_depth: Option<usize>,
+    /// Process groups of files, and the paths in them, in path order, so that runs over the same
+    /// files print the same output. Otherwise the order depends on hashing.
+    pub deterministic: bool,
+    /// The hash algorithm used to find files with the same contents.
+    pub hash: HashAlgorithm,
+    /// Size of the buffers files are read through when hashing and comparing them. Defaults to
+    /// [`DEFAULT_BUFFER_SIZE`].
+    pub buffer_size: Option<usize>,
+    /// Tell the kernel that files are read sequentially and drop their pages from the page cache
+    /// after hashing or comparing them, so that scanning doesn't evict other processes' pages.
+    pub fadvise: bool,
+    /// Read the prefixes and calculate the hashes of groups of many files through io_uring, which
+    /// saves a syscall per read. Only has an effect when built with the `io-uring` feature and when
+    /// calculating hashes in this process.
+    pub io_uring: bool,
+    /// The most bytes per second to read when hashing and comparing files, shared by all hash
+    /// workers.
+    pub bwlimit: Option<u64>,
+    /// Number of helper processes that calculate hashes. Zero calculates hashes in this process.
+    /// Files of 64 MiB or more are always hashed in this process, in chunks, so that we can stop
+    /// reading them as soon as they differ from all the other files.
+    ///
+    /// Helper processes are started by re-executing the current executable with
+    /// [`HASH_WORKER_ARG`], so the executable must call [`run_hash_worker`] when given that argument.
+    pub hash_workers: usize,
+    /// How many files of each filesystem are hashed at a time, e.g. more for network mounts that
+    /// only get fast with many reads in flight, or fewer for ones that slow down under load. Each
+    /// filesystem is hashed within its own budget, and without hash workers the upcoming files of
+    /// other filesystems are hashed at the same time, so a slow mount doesn't hold up the others.
+    /// Defaults to all hash workers, or to 4 on SSDs and 1 elsewhere without them. Files on
+    /// spinning disks are always hashed one at a time so that they don't seek back and forth.
+    pub max_parallel_hashes: Option<usize>,
+    /// How many threads walk the directories. Each thread reads one directory at a time, at any
+    /// depth. With 0 or 1 the directories are walked in the calling thread.
+    pub jobs: usize,
+    /// Keep the paths of at most about this many bytes' worth of files in memory. The scanned files
+    /// are spilled to temporary files and deduplicated in buckets of files by their size, and their
+    /// first bytes where many files have the same size. Can't be combined with
+    /// [`DedupOptions::watch`], which needs all files in memory.
+    pub max_memory: Option<u64>,
+    /// Maximum size (in bytes) of the address space of each hash worker process.
+    pub hash_worker_memory_limit: Option<u64>,
+    /// How long to wait for a hash worker to hash one file before killing and restarting it.
+    pub hash_worker_timeout: Option<Duration>,
+    /// Skip files modified more recently than this. Files are checked when scanning and again right
+    /// before they are hardlinked.
+    pub min_age: Option<Duration>,
+    /// Skip files modified after this time. Like [`DedupOptions::min_age`], files are checked when
+    /// scanning and again right before they are hardlinked.
+    pub exclude_newer_than: Option<SystemTime>,
+    /// Skip files modified before this time.
+    pub exclude_older_than: Option<SystemTime>,
+    /// Read files that turn out to have unique contents a second time in the given way. Files that
+    /// read back differently are reported as probable hardware or bitrot issues.
+    pub double_read_verify: Option<SecondRead>,
+    /// Don't descend into directories on other filesystems than the paths being deduplicated.
+    pub one_file_system: bool,
+    /// Don't descend into directories with a `CACHEDIR.TAG` or a `.nodedup` file, so their owners can
+    /// opt out of deduplication.
+    pub honor_cachedir_tags: bool,
+    /// Hardlink files with different owners and groups. The files replaced with hardlinks get the
+    /// owner and group of their original.
+    pub ignore_owner: bool,
+    /// Hardlink files with different permissions. The files replaced with hardlinks get the mode of
+    /// their original.
+    pub ignore_mode: bool,
+    /// Whether duplicates are replaced with hardlinks or reflinks. Reflinks keep the owner, mode,
+    /// timestamps, and extended attributes of the files they replace. Files that already share all
+    /// their extents with the original are left alone.
+    pub link_mode: LinkMode,
+    /// What replaces duplicates that can't be hardlinked across mounts or aren't allowed to be.
+    /// Only used with [`LinkMode::Hardlink`].
+    pub fallback: Fallback,
+    /// Flush every replaced file and its directory to disk before counting it as deduplicated, so
+    /// that replacements survive power loss.
+    pub fsync: bool,
+    /// Restore the access and modification times of the directories of the files we replace.
+    /// Replacing a file updates them, which looks like a change to incremental backup tools.
+    pub preserve_dir_mtimes: bool,
+    /// Only hardlink files with the same modification time, like copies made with `rsync --times`.
+    pub require_same_mtime: bool,
+    /// Only hardlink files that occupy the same number of blocks, so that sparse files are only
+    /// hardlinked to files with as many holes.
+    pub group_by_allocated_size: bool,
+    /// Hardlink files with different extended attributes. Replaced files lose theirs for the
+    /// original's, including `user.*` attributes and file capabilities. ACLs and SELinux contexts
+    /// are still compared unless [`DedupOptions::ignore_acls`] and
+    /// [`DedupOptions::ignore_selinux`] are set.
+    pub ignore_xattrs: bool,
+    /// Hardlink files with different POSIX ACLs. Replaced files get the ACL of the original.
+    pub ignore_acls: bool,
+    /// Hardlink files with different SELinux security contexts. Replaced files get the context of
+    /// the original, which changes what confined processes may do with them.
+    pub ignore_selinux: bool,
+    /// Don't hardlink files that another process has open for writing, nor to them.
+    pub skip_open_files: bool,
+    /// Hardlink empty files too. Hardlinking them saves no space, only inodes, and ties together
+    /// files that are likely written to separately later, like lock and marker files.
+    pub include_empty: bool,
+    /// Fail to deduplicate paths seen through another process's mount namespace (like
+    /// `/proc/<pid>/root`), on overlay filesystems, or in the layer storage of container engines,
+    /// where devices and inodes don't mean what they seem to. Without it, such paths are
+    /// deduplicated with a warning.
+    pub refuse_foreign_mounts: bool,
+    /// Directories that are scanned along with the paths but whose files are never replaced. Files
+    /// with the same contents elsewhere are hardlinked to them, preferring them over all other files
+    /// as the original.
+    pub reference_dirs: Vec<PathBuf>,
+    /// Files that may be the original other files are hardlinked to but are never replaced
+    /// themselves, even under the paths being deduplicated. Like reference files, they're preferred
+    /// as the original.
+    pub protected: ProtectedPaths,
+    /// Checks of the caller that must all allow hardlinking a target to its original, on top of
+    /// the files having the same metadata and contents.
+    pub candidate_filters: CandidateFilters,
+    /// Report files with the same size and prefix that differ in at most this many bytes. Such
+    /// files are often one healthy and one silently corrupted copy of the same file.
+    pub report_near_duplicates: Option<usize>,
+    /// Follow symlinks to files and directories. Directories reachable through several paths are
+    /// only scanned once.
+    pub follow_symlinks: bool,
+    /// Only descend this many directory levels below the paths being deduplicated. Files given
+    /// directly are at depth 0.
+    pub max_depth: Option<usize>,
+    /// Replace the corrupted one of two near-duplicates with a hardlink to the healthy one. The
+    /// corrupted contents are kept in a backup file next to the corrupted file. Implies
+    /// [`DedupOptions::report_near_duplicates`].
+    pub repair_from_duplicate: Option<RepairMode>,
+    /// Show a progress bar with an ETA on stderr.
+    pub progress: bool,
+    /// Without a progress bar, print a status line with the files processed per second and the
+    /// bytes hashed and saved to stderr at most this often, e.g. for logs of long runs.
+    pub progress_interval: Option<Duration>,
+    /// How to render what the deduplication did.
+    pub output: OutputFormat,
+    /// Show byte counts in the progress bar and the summary as plain numbers instead of in KiB,
+    /// MiB, GiB and so on.
+    pub raw_bytes: bool,
+    /// Set this flag (e.g. from a signal handler or another thread) to stop early. Walking the paths
+    /// stops at the next file, hardlinks that are being created are finished, no new ones are
+    /// started, and the summary covers what was done so far.
+    pub interrupted: Arc<AtomicBool>,
+    /// Stop like when [`DedupOptions::interrupted`] once this many files have been processed, e.g.
+    /// to spread the deduplication of a large tree over several runs with
+    /// [`DedupOptions::checkpoint`] and [`DedupOptions::resume`].
+    pub max_files: Option<usize>,
+    /// Stop like when [`DedupOptions::interrupted`] once the run took this long, scanning included,
+    /// e.g. to fit it into a maintenance window.
+    pub max_duration: Option<Duration>,
+    /// Set this flag (e.g. from a signal handler) to have the current progress rendered once without
+    /// interrupting the deduplication.
+    pub snapshot_requested: Arc<AtomicBool>,
+    /// Periodically record which groups of files have been fully processed in this file. Dry runs
+    /// don't record any, since they leave the groups as they were.
+    pub checkpoint: Option<PathBuf>,
+    /// Skip groups of files recorded as fully processed in this checkpoint file. Files added to such
+    /// groups since the checkpoint was written are skipped too.
+    pub resume: Option<PathBuf>,
+    /// After deduplicating, keep deduplicating new files under the paths until interrupted through
+    /// [`DedupOptions::interrupted`].
+    pub watch: bool,
+    /// Record every replaced file in this journal so that the deduplication can be undone with
+    /// [`undo`]. Entries are appended to an existing journal.
+    pub journal: Option<PathBuf>,
+    /// Files the caller writes during the run, like reports. They're left out when scanning even if
+    /// they're under the paths, like the journal and checkpoint files, so that writing them never
+    /// changes a file hardlinked to them.
+    pub own_files: Vec<PathBuf>,
+    /// Ask on stderr before hardlinking each group of duplicates and read the answers from stdin.
+    /// Quitting stops the deduplication like [`DedupOptions::interrupted`]. Ignored in dry runs.
+    pub interactive: bool,
+    /// Also compare files on different filesystems and report their duplicates. Hardlinking them is
+    /// impossible, but moving or consolidating them would save space.
+    pub report_cross_device: bool,
+}
+
+impl DedupOptions {
+    fn read_options(&self) -> ReadOptions {
+        ReadOptions {
+            buffer_size: self.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            drop_caches: self.fadvise,
+            io_uring: self.io_uring,
+            bwlimit: self.bwlimit,
         }
-        if dedup_if_pair(&size_group, &mut ctx) {
-            continue;
+    }
+
+    fn key_options(&self) -> KeyOptions {
+        KeyOptions {
+            ignore_owner: self.ignore_owner,
+            ignore_mode: self.ignore_mode,
+            require_same_mtime: self.require_same_mtime,
+            group_by_allocated_size: self.group_by_allocated_size,
         }
-        for prefix_group in same_prefix_groups(size_group) {
-            if exclude_if_unique(&prefix_group, &mut ctx, "It has a unique prefix.") {
-                continue;
+    }
+}
+
+/// Which metadata files must share to be hardlinked, relaxed by [`DedupOptions::ignore_owner`] and
+/// [`DedupOptions::ignore_mode`] and tightened by [`DedupOptions::require_same_mtime`] and
+/// [`DedupOptions::group_by_allocated_size`].
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct KeyOptions {
+    ignore_owner: bool,
+    ignore_mode: bool,
+    require_same_mtime: bool,
+    group_by_allocated_size: bool,
+}
+
+/// How many bytes from the start of files are compared before hashing them by default.
+const DEFAULT_PREFIX_BYTES: u64 = 64;
+/// How many bytes from the end of files are compared before hashing them by default.
+const DEFAULT_TAIL_BYTES: u64 = 64;
+
+/// How a `CACHEDIR.TAG` starts. See https://bford.info/cachedir/.
+const CACHEDIR_TAG_SIGNATURE: &[u8; 43] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// How often the checkpoint file is updated.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What a deduplication run did.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DedupSummary {
+    pub processed_files: usize,
+    /// Bytes freed by replacing files with hardlinks, or that would be in dry runs. Files that stay
+    /// around through hardlinks outside the paths or in unwritable directories free nothing.
+    pub bytes_deduped: usize,
+    /// Bytes of the files replaced with hardlinks, or that would be in dry runs, whether that freed
+    /// them or not. Each file counts once however many of its paths were replaced.
+    pub bytes_linked: u64,
+    /// Bytes that files under the paths already share through hardlinks to each other, which
+    /// [`DedupSummary::bytes_deduped`] doesn't count again.
+    pub bytes_already_linked: u64,
+    /// Pairs of files that differ in only a few bytes.
+    pub near_duplicates: usize,
+    pub repaired_files: usize,
+    /// Files that read back differently when read a second time.
+    pub inconsistent_files: usize,
+    /// Files that we failed to read, compare, or hardlink.
+    pub failed_files: usize,
+    /// Why the files in [`DedupSummary::failed_files`] failed, for the first [`MAX_FILE_ERRORS`]
+    /// of them.
+    pub file_errors: Vec<FileError>,
+    /// Hardlinks that failed, by the step that failed. These files are included in
+    /// [`DedupSummary::failed_files`] too.
+    pub link_failures: LinkFailures,
+    /// Duplicates replaced with a reflink or symlink because hardlinking them failed (see
+    /// [`DedupOptions::fallback`]). These files aren't included in
+    /// [`DedupSummary::failed_files`].
+    pub fallback_links: usize,
+    /// Files that weren't hardlinked because we can't write to their directories.
+    pub unwritable_files: usize,
+    /// Files found to have the same contents as another file without hashing them because they
+    /// share all their extents (e.g. in btrfs snapshots or reflinked copies). Hardlinking them frees
+    /// no data blocks and reflinking them wouldn't save anything either.
+    pub shared_extent_files: usize,
+    /// Groups of files with the same contents on different filesystems. Only counted with
+    /// [`DedupOptions::report_cross_device`].
+    pub cross_device_groups: usize,
+    /// Bytes we'd save if every filesystem kept only one copy of the files in
+    /// [`DedupSummary::cross_device_groups`].
+    pub cross_device_bytes: u64,
+    /// The outermost directories that contain both paths of a hardlink we created. Backups of
+    /// these directories must preserve hardlinks (e.g. `rsync -H`), or the next backup copies the
+    /// deduplicated files again.
+    pub linked_dirs: Vec<PathBuf>,
+    /// The savings by directory, most bytes first. Only broken down with
+    /// [`DedupOptions::breakdown_depth`].
+    pub directories: Vec<DirectorySavings>,
+    /// CPU time, memory and I/O used by the run.
+    pub resources: ResourceUsage,
+    /// What the run looked at to find the duplicates and how long each stage took.
+    pub stats: RunStats,
+    /// Whether the deduplication was stopped early through [`DedupOptions::interrupted`], or by
+    /// running out of its budget.
+    pub interrupted: bool,
+    /// Whether the deduplication was stopped early by [`DedupOptions::max_files`] or
+    /// [`DedupOptions::max_duration`].
+    pub budget_exhausted: bool,
+}
+
+impl DedupSummary {
+    /// Adds what another run did to this summary, e.g. to summarize several runs. Leaves
+    /// [`DedupSummary::resources`], which are measured for the whole process,
+    /// [`DedupSummary::interrupted`] and [`DedupSummary::budget_exhausted`] alone.
+    pub fn add(&mut self, other: &DedupSummary) {
+        self.processed_files += other.processed_files;
+        self.bytes_deduped += other.bytes_deduped;
+        self.bytes_linked += other.bytes_linked;
+        self.bytes_already_linked += other.bytes_already_linked;
+        self.near_duplicates += other.near_duplicates;
+        self.repaired_files += other.repaired_files;
+        self.inconsistent_files += other.inconsistent_files;
+        self.failed_files += other.failed_files;
+        let room = MAX_FILE_ERRORS.saturating_sub(self.file_errors.len());
+        self.file_errors
+            .extend(other.file_errors.iter().take(room).cloned());
+        self.link_failures.source += other.link_failures.source;
+        self.link_failures.temp_link += other.link_failures.temp_link;
+        self.link_failures.rename += other.link_failures.rename;
+        self.link_failures.changed += other.link_failures.changed;
+        self.link_failures.sync += other.link_failures.sync;
+        self.fallback_links += other.fallback_links;
+        self.unwritable_files += other.unwritable_files;
+        self.shared_extent_files += other.shared_extent_files;
+        self.cross_device_groups += other.cross_device_groups;
+        self.cross_device_bytes += other.cross_device_bytes;
+        let linked_dirs = self.linked_dirs.iter().chain(&other.linked_dirs).cloned();
+        self.linked_dirs = outermost_dirs(&linked_dirs.collect());
+        self.directories = breakdown::merge(&self.directories, &other.directories);
+        self.stats.add(&other.stats);
+    }
+}
+
+/// How many hardlinks failed at each step of replacing a file with a hardlink.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LinkFailures {
+    /// We couldn't access the file we were linking to or weren't allowed to link to it.
+    pub source: usize,
+    /// We couldn't create the temporary hardlink in the target's directory.
+    pub temp_link: usize,
+    /// We couldn't rename the temporary hardlink over the target.
+    pub rename: usize,
+    /// The target was no longer the file we compared when we were about to replace it.
+    pub changed: usize,
+    /// The target was replaced, but flushing the replacement to disk failed with
+    /// [`DedupOptions::fsync`].
+    pub sync: usize,
+}
+
+impl LinkFailures {
+    pub fn total(&self) -> usize {
+        self.source + self.temp_link + self.rename + self.changed + self.sync
+    }
+}
+
+/// Deduplicates files in the given paths with the default options. See [`Deduper`] for the others.
+pub fn dedup(paths: &[PathBuf], dry_run: bool, paranoid: bool) -> Result<DedupSummary, DedupError> {
+    Deduper::new()
+        .paths(paths.iter().cloned())
+        .dry_run(dry_run)
+        .paranoid(paranoid)
+        .run()
+}
+
+/// Deduplicates files in the given paths. Fails only if the paths themselves can't be accessed.
+/// Failures to process individual files are logged, counted in [`DedupSummary::failed_files`], and
+/// listed in [`DedupSummary::file_errors`].
+pub fn dedup_with_options(
+    paths: &[PathBuf],
+    options: &DedupOptions,
+) -> Result<DedupSummary, DedupError> {
+    dedup_with_renderer(paths, options, output::renderer(options.output).as_mut())
+}
+
+/// Like [`dedup_with_options`], but renders what happened with the given renderer instead of the
+/// one chosen by [`DedupOptions::output`].
+pub fn dedup_with_renderer(
+    paths: &[PathBuf],
+    options: &DedupOptions,
+    renderer: &mut dyn Renderer,
+) -> Result<DedupSummary, DedupError> {
+    let mut paths = paths.to_vec();
+    for reference_dir in &options.reference_dirs {
+        if !paths.contains(reference_dir) {
+            paths.push(reference_dir.clone());
+        }
+    }
+    let paths = &paths;
+    let (summary, inode_to_paths) = dedup_paths(paths, options, renderer)?;
+    if !options.watch || summary.interrupted {
+        return Ok(summary);
+    }
+    let index = watch::ContentIndex::new(
+        &inode_to_paths,
+        options.hash,
+        options.read_options(),
+        options.key_options(),
+    );
+    drop(inode_to_paths);
+    let mut watch_summary = watch::watch(paths, options, renderer, index)?;
+    watch_summary.processed_files += summary.processed_files;
+    Ok(watch_summary)
+}
+
+/// A file to keep and the files to replace with hardlinks to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkSelection {
+    pub original: PathBuf,
+    pub targets: Vec<PathBuf>,
+}
+
+/// Replaces the targets of each selection with hardlinks to its original, e.g. after choosing which
+/// files to keep from the [`Event::Duplicates`] of a dry run. Targets whose contents no longer match
+/// their original are skipped and counted as failed.
+pub fn link_selections(
+    selections: &[LinkSelection],
+    options: &DedupOptions,
+    renderer: &mut dyn Renderer,
+) -> Result<DedupSummary, DedupError> {
+    let mut failed_files = FailedFiles::default();
+    let mut inode_to_paths: HashMap<FileId, HashSet<PathBuf>> = HashMap::new();
+    for target in selections.iter().flat_map(|selection| &selection.targets) {
+        match metadata(target) {
+            Ok(target_metadata) => {
+                inode_to_paths
+                    .entry(file_id(&target_metadata))
+                    .or_default()
+                    .insert(target.clone());
+            }
+            Err(err) => {
+                failed_files.add(
+                    target,
+                    format!(
+                        "Skipping file {:?}. Failed to fetch its metadata. Error: {}",
+                        target, err
+                    ),
+                );
             }
-            if dedup_if_pair(&prefix_group, &mut ctx) {
+        }
+    }
+    let journal = open_journal(options)?;
+    let mut ctx = DedupContext::new(&inode_to_paths, options, renderer);
+    ctx.failed_files = failed_files;
+    ctx.journal = journal;
+    ctx.emit(Event::Started { files: ctx.total });
+    let mut linked_files = HashSet::new();
+    for selection in selections {
+        for target in &selection.targets {
+            ctx.check_budget();
+            if ctx.interrupted() {
+                break;
+            }
+            ctx.add_processed(1);
+            if let Some(reason) = ctx.keep_reason(target) {
+                warn!(
+                    "Skipping hardlinking {:?} to {:?}. {}",
+                    selection.original, target, reason
+                );
                 continue;
             }
-            for hash_group in same_hash_groups(prefix_group) {
-                if exclude_if_unique(&hash_group, &mut ctx, "It has a unique hash.") {
+            if let Some(target_metadata) = still_same(&selection.original, target, &mut ctx) {
+                let replaced = replace_many_with_hard_link(
+                    &selection.original,
+                    std::iter::once(target),
+                    Some(FileSnapshot::new(&target_metadata)),
+                    &mut ctx,
+                );
+                if replaced.is_empty() {
                     continue;
                 }
-                if paranoid {
-                    same_content_dedup(&hash_group, &mut ctx);
+                // Paths of the same file are replaced one at a time, so only the last one frees it.
+                let saved_bytes = if frees_inode(&target_metadata, 1) {
+                    allocated_bytes(&target_metadata)
                 } else {
-                    hardlink_dedup(hash_group, &mut ctx);
+                    0
+                };
+                ctx.bytes_deduped += saved_bytes as usize;
+                if let Some(breakdown) = &mut ctx.breakdown {
+                    breakdown.add([target.as_path()], saved_bytes);
+                }
+                if linked_files.insert(file_id(&target_metadata)) {
+                    ctx.bytes_linked += target_metadata.len();
                 }
             }
         }
     }
-    println!("Estimated saved bytes: {}", ctx.bytes_deduped);
+    ctx.progress.finish();
+    ctx.hash_pool = None;
+    let summary = ctx.summary();
+    ctx.emit(Event::Finished { summary: &summary });
+    Ok(summary)
 }
 
-struct DedupContext<'a> {
-    dry_run: bool,
-    total: usize,
-    processed: usize,
-    bytes_deduped: usize,
-    inode_to_paths: &'a HashMap<u64, HashSet<PathBuf>>,
+/// Whether the target is a separate file with the same contents as the original. Returns the
+/// target's metadata.
+fn still_same(original_file: &Path, target: &Path, ctx: &mut DedupContext) -> Option<Metadata> {
+    let read_options = ctx.options.read_options();
+    let result = metadata(original_file).and_then(|original_metadata| {
+        let target_metadata = metadata(target)?;
+        if file_id(&original_metadata) == file_id(&target_metadata) {
+            return Ok(None);
+        }
+        if !are_files_same(original_file, target, read_options)? {
+            return Err(io::Error::other(format!(
+                "Its contents no longer match {:?}.",
+                original_file
+            )));
+        }
+        if !same_xattrs(original_file, target, ctx.options)? {
+            return Err(io::Error::other(format!(
+                "Its extended attributes, ACL, or SELinux context differ from {:?}.",
+                original_file
+            )));
+        }
+        Ok(Some(target_metadata))
+    });
+    result.unwrap_or_else(|err| {
+        ctx.failed_files.add(
+            target,
+            format!(
+                "Skipping hardlinking {:?} to {:?}. {}",
+                original_file, target, err
+            ),
+        );
+        None
+    })
 }
 
-impl<'a> DedupContext<'a> {
-    fn new(inode_to_paths: &'a HashMap<u64, HashSet<PathBuf>>, dry_run: bool) -> DedupContext {
-        DedupContext {
-            dry_run: dry_run,
-            total: inode_to_paths.len(),
-            processed: 0,
-            bytes_deduped: 0,
-            inode_to_paths: inode_to_paths,
+/// Deduplicates files in the given paths once. Also returns all paths of all files we found.
+fn dedup_paths(
+    paths: &[PathBuf],
+    options: &DedupOptions,
+    renderer: &mut dyn Renderer,
+) -> Result<(DedupSummary, HashMap<FileId, HashSet<PathBuf>>), DedupError> {
+    for path in paths {
+        symlink_metadata(path).map_err(|source| DedupError::Access {
+            path: path.clone(),
+            source,
+        })?;
+        for (foreign_path, reason) in foreign_mounts(path, options.one_file_system) {
+            if options.refuse_foreign_mounts {
+                return Err(DedupError::ForeignMount {
+                    path: foreign_path,
+                    reason,
+                });
+            }
+            warn!("Deduplicating {:?} anyway. {}", foreign_path, reason);
         }
     }
+    let checkpoint = match &options.resume {
+        Some(resume) => Checkpoint::load(resume)?,
+        None => Checkpoint::default(),
+    };
+    if let Some(max_memory) = options.max_memory {
+        let summary = dedup_in_buckets(paths, max_memory, checkpoint, options, renderer)?;
+        return Ok((summary, HashMap::new()));
+    }
+    let mut failed_files = FailedFiles::default();
+    let started = Instant::now();
+    let inode_to_paths = find_inode_groups(paths, options, &mut failed_files);
+    let scan_time = started.elapsed();
+    let journal = open_journal(options)?;
+    let mut ctx = DedupContext::new(&inode_to_paths, options, renderer);
+    ctx.breakdown = options
+        .breakdown_depth
+        .map(|depth| Breakdown::new(paths, depth));
+    ctx.started = started;
+    ctx.clock.add(Stage::Scan, scan_time);
+    ctx.failed_files = failed_files;
+    ctx.checkpoint = checkpoint;
+    ctx.journal = journal;
+    emit_scanned(&mut ctx);
+    ctx.emit(Event::Started { files: ctx.total });
+    log_hash_algorithm(options);
+    dedup_inode_groups(&mut ctx);
+    let summary = ctx.finish();
+    drop(ctx);
+    Ok((summary, inode_to_paths))
+}
+
+fn log_hash_algorithm(options: &DedupOptions) {
+    match options.hash {
+        HashAlgorithm::Sha256 => info!(
+            "Hashing with the {} implementation of SHA-256.",
+            sha256_implementation()
+        ),
+        algorithm => info!("Hashing with {}.", algorithm.name()),
+    }
 }
 
-impl<'a> std::fmt::Display for DedupContext<'a> {
-    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        let percentage = if self.total == 0 {
-            100.0
+/// Deduplicates the files of the context.
+fn dedup_inode_groups<'a>(ctx: &mut DedupContext<'a>) {
+    let inode_to_paths = ctx.inode_to_paths;
+    let options = ctx.options;
+    // The scan may have used up the time already.
+    ctx.check_budget();
+    ctx.low_space_devices = find_low_space_devices(inode_to_paths);
+    ctx.unlinkable_devices = find_unlinkable_devices(inode_to_paths);
+    ctx.bytes_already_linked += already_linked_bytes(inode_to_paths);
+    let files = inode_to_paths.values().flat_map(|file_group| {
+        if options.deterministic {
+            file_group.iter().min()
         } else {
-            (self.processed as f64) / (self.total as f64) * 100.0
-        };
-        write!(
-            formatter,
-            "{:.2}%{}; {} bytes deduped",
-            percentage,
-            if self.dry_run { "; dry run" } else { "" },
-            self.bytes_deduped,
-        )
+            file_group.iter().next()
+        }
+    });
+    let started = Instant::now();
+    let size_groups: Vec<_> = info_span!("metadata_group").in_scope(|| {
+        same_metadata_groups(files, options.key_options(), &mut ctx.failed_files).collect()
+    });
+    let mut size_groups = ordered(size_groups, options);
+    ctx.clock.add(Stage::Metadata, started.elapsed());
+    if !ctx.low_space_devices.is_empty() {
+        // Free up room on nearly full filesystems as early as possible.
+        size_groups.sort_by_key(|group| Reverse(potential_savings(group)));
+    } else {
+        // The groups of other devices are hashed ahead while those of a device are deduplicated.
+        size_groups.sort_by_cached_key(group_device);
+    }
+    let devices: Vec<u64> = size_groups.iter().map(group_device).collect();
+    ctx.prefetcher = Prefetcher::start(&size_groups, &devices, options);
+    for (index, size_group) in size_groups.into_iter().enumerate() {
+        ctx.check_budget();
+        if ctx.interrupted() {
+            break;
+        }
+        if let Some(prefetcher) = &ctx.prefetcher {
+            prefetcher.begin_group(index, devices[index]);
+        }
+        let key = group_key(&size_group, options.key_options());
+        if key.is_some_and(|key| ctx.checkpoint.is_completed(&key)) {
+            ctx.resumed_files += size_group.len();
+            ctx.add_processed(size_group.len());
+            continue;
+        }
+        ctx.set_current_file(&size_group);
+        ctx.snapshot_files(&size_group);
+        dedup_size_group(size_group, ctx);
+        if let Some(key) = key {
+            if !ctx.interrupted() {
+                ctx.complete_group(key);
+            }
+        }
+    }
+    ctx.prefetcher = None;
+    if options.report_cross_device && !ctx.interrupted() {
+        info_span!("cross_device").in_scope(|| report_cross_device_duplicates(inode_to_paths, ctx));
     }
 }
 
-fn exclude_if_unique<'a>(
-    group: &HashSet<&'a PathBuf>,
-    ctx: &mut DedupContext,
-    uniqueness_msg: &str,
-) -> bool {
-    if group.len() > 1 {
-        return false;
+/// Deduplicates a group of files that share their device, size, owner, and mode.
+fn dedup_size_group<'a>(mut size_group: HashSet<&'a PathBuf>, ctx: &mut DedupContext<'a>) {
+    exclude_many_links(&mut size_group, ctx);
+    if size_group.is_empty() {
+        return;
     }
-    ctx.processed += group.len();
-    println!(
-        "[{}] Excluding {:?} from deduplication. {}",
+    if exclude_if_unique(
+        &size_group,
         ctx,
-        group.iter().nth(0).unwrap(),
-        uniqueness_msg,
+        "It has unique device, size, uid, gid, or mode.",
+    ) {
+        return;
+    }
+    if exclude_if_unlinkable(&size_group, ctx) {
+        return;
+    }
+    if exclude_if_empty(&size_group, ctx) {
+        return;
+    }
+    if !ctx.options.same_name_only {
+        return dedup_name_group(size_group, ctx);
+    }
+    let name_groups = ordered(
+        same_name_groups(size_group.into_iter()).collect(),
+        ctx.options,
     );
-    true
+    for name_group in name_groups {
+        if ctx.interrupted() {
+            break;
+        }
+        if exclude_if_unique(
+            &name_group,
+            ctx,
+            "No other file with its name is the same size.",
+        ) {
+            continue;
+        }
+        dedup_name_group(name_group, ctx);
+    }
 }
 
-/// If we have a pair of same-sized files, it's faster to compare them byte-for-byte
-/// rather than calculate their hashes and compare hashes.
-fn dedup_if_pair<'a>(group: &HashSet<&'a PathBuf>, ctx: &mut DedupContext) -> bool {
-    if group.len() == 2 {
-        same_content_dedup(group, ctx);
-        return true;
+/// Deduplicates files with the same metadata and, if required, name.
+fn dedup_name_group<'a>(name_group: HashSet<&'a PathBuf>, ctx: &mut DedupContext<'a>) {
+    if !compares_xattrs(ctx.options) {
+        return dedup_xattr_group(name_group, ctx);
+    }
+    let xattr_groups = same_xattr_groups(name_group, ctx.options, &mut ctx.failed_files);
+    for xattr_group in ordered(xattr_groups, ctx.options) {
+        if ctx.interrupted() {
+            break;
+        }
+        if exclude_if_unique(
+            &xattr_group,
+            ctx,
+            "It has unique extended attributes, ACL, or SELinux context.",
+        ) {
+            continue;
+        }
+        dedup_xattr_group(xattr_group, ctx);
     }
-    false
 }
 
-fn same_content_dedup<'a>(file_group: &HashSet<&'a PathBuf>, ctx: &mut DedupContext) {
-    for content_group in same_content_groups(file_group) {
-        if exclude_if_unique(&content_group, ctx, "It has unique contents.") {
+/// Deduplicates files with the same metadata, extended attributes, ACLs, and SELinux contexts.
+fn dedup_xattr_group<'a>(size_group: HashSet<&'a PathBuf>, ctx: &mut DedupContext<'a>) {
+    if exclude_if_few_copies(&size_group, ctx) {
+        return;
+    }
+    if dedup_if_pair(&size_group, ctx) {
+        return;
+    }
+    let prefix_bytes = ctx.options.prefix_bytes.unwrap_or(DEFAULT_PREFIX_BYTES);
+    let tail_bytes = sample_tail_bytes(ctx.options);
+    // Files of some formats differ in their headers far more often than in their tails, so their
+    // tails tell them apart sooner. The whole size group is keyed the same way so that copies with
+    // other extensions still end up together.
+    let stages = if tail_bytes > 0 && has_tail_first_extension(&size_group, ctx.options) {
+        [SampleStage::Tail { after: 0 }, SampleStage::Prefix]
+    } else {
+        [
+            SampleStage::Prefix,
+            SampleStage::Tail {
+                after: prefix_bytes,
+            },
+        ]
+    };
+    let first_groups = sample_groups(size_group, stages[0], prefix_bytes, tail_bytes, ctx);
+    for first_group in first_groups {
+        if ctx.interrupted() {
+            break;
+        }
+        ctx.set_current_file(&first_group);
+        if exclude_if_unique(&first_group, ctx, stages[0].unique_reason()) {
             continue;
         }
-        hardlink_dedup(content_group, ctx)
+        if dedup_if_pair(&first_group, ctx) {
+            continue;
+        }
+        let second_groups = sample_groups(first_group, stages[1], prefix_bytes, tail_bytes, ctx);
+        for second_group in second_groups {
+            if ctx.interrupted() {
+                break;
+            }
+            if exclude_if_unique(&second_group, ctx, stages[1].unique_reason()) {
+                continue;
+            }
+            if dedup_if_pair(&second_group, ctx) {
+                continue;
+            }
+            dedup_tail_group(second_group, ctx);
+        }
     }
 }
 
-fn hardlink_dedup<'a>(same_files_group: HashSet<&'a PathBuf>, ctx: &mut DedupContext) {
-    let mut same_files_iterator = same_files_group.iter();
-    if let Some(original_file) = same_files_iterator.next() {
-        ctx.processed += 1;
-        while let Some(other_file) = same_files_iterator.next() {
-            ctx.processed += 1;
-            if let Ok(other_file_metadata) = metadata(other_file) {
-                replace_many_with_hard_link(
-                    &original_file,
-                    ctx.inode_to_paths[&other_file_metadata.ino()].iter(),
-                    ctx,
-                );
-                ctx.bytes_deduped += other_file_metadata.len() as usize;
+/// How many of the last bytes of files are compared before hashing them.
+fn sample_tail_bytes(options: &DedupOptions) -> u64 {
+    // Near-duplicates can differ anywhere, including in their tails.
+    if options.report_near_duplicates.is_some() || options.repair_from_duplicate.is_some() {
+        0
+    } else {
+        options.tail_bytes.unwrap_or(DEFAULT_TAIL_BYTES)
+    }
+}
+
+/// A sample of the bytes of files that are compared before hashing them.
+#[derive(Clone, Copy)]
+enum SampleStage {
+    Prefix,
+    /// Only the bytes after this offset, which an earlier prefix stage already compared.
+    Tail {
+        after: u64,
+    },
+}
+
+impl SampleStage {
+    fn unique_reason(self) -> &'static str {
+        match self {
+            SampleStage::Prefix => "It has a unique prefix.",
+            SampleStage::Tail { .. } => "It has a unique tail.",
+        }
+    }
+}
+
+/// Splits the files into groups with the same sample.
+fn sample_groups<'a>(
+    files: HashSet<&'a PathBuf>,
+    stage: SampleStage,
+    prefix_bytes: u64,
+    tail_bytes: u64,
+    ctx: &mut DedupContext<'a>,
+) -> Vec<HashSet<&'a PathBuf>> {
+    // The files have the same size.
+    let size = group_file_size(&files);
+    let bytes = files.len() as u64
+        * match stage {
+            SampleStage::Prefix => size.min(prefix_bytes),
+            SampleStage::Tail { after } => size.saturating_sub(after).min(tail_bytes),
+        };
+    ctx.stats.bytes_read += bytes;
+    match stage {
+        SampleStage::Prefix => ctx.stats.stage_bytes.prefix += bytes,
+        SampleStage::Tail { .. } => ctx.stats.stage_bytes.tail += bytes,
+    }
+    let started = Instant::now();
+    let groups = match stage {
+        SampleStage::Prefix => debug_span!("prefix_group", files = files.len()).in_scope(|| {
+            same_prefix_groups(
+                files,
+                prefix_bytes,
+                ctx.options.read_options(),
+                &mut ctx.failed_files,
+            )
+            .collect()
+        }),
+        SampleStage::Tail { after } => {
+            debug_span!("tail_group", files = files.len()).in_scope(|| {
+                same_tail_groups(files, after, tail_bytes, &mut ctx.failed_files).collect()
+            })
+        }
+    };
+    let stage = match stage {
+        SampleStage::Prefix => Stage::Prefix,
+        SampleStage::Tail { .. } => Stage::Tail,
+    };
+    ctx.clock.add(stage, started.elapsed());
+    ordered(groups, ctx.options)
+}
+
+/// Whether any of the files has one of [`DedupOptions::tail_first_extensions`].
+fn has_tail_first_extension(files: &HashSet<&PathBuf>, options: &DedupOptions) -> bool {
+    files.iter().any(|file| {
+        file.extension().is_some_and(|extension| {
+            options
+                .tail_first_extensions
+                .iter()
+                .any(|tail_first| extension.eq_ignore_ascii_case(tail_first))
+        })
+    })
+}
+
+/// Deduplicates a group of files that share their metadata and their first and last few bytes.
+fn dedup_tail_group<'a>(tail_group: HashSet<&'a PathBuf>, ctx: &mut DedupContext<'a>) {
+    let (tail_group, shared_extent_files) = split_shared_extents(tail_group, ctx);
+    let bytes = group_bytes(&tail_group);
+    ctx.stats.bytes_hashed += bytes;
+    ctx.stats.bytes_read += bytes;
+    ctx.stats.stage_bytes.hash += bytes;
+    let started = Instant::now();
+    let hash_groups: Vec<_> = debug_span!("hash_group", files = tail_group.len()).in_scope(|| {
+        same_hash_groups(
+            tail_group,
+            ctx.hash_pool.as_mut(),
+            ctx.prefetcher.as_ref(),
+            ctx.options,
+            &mut ctx.failed_files,
+        )
+        .collect()
+    });
+    ctx.clock.add(Stage::Hash, started.elapsed());
+    let mut hash_groups = ordered(hash_groups, ctx.options);
+    for hash_group in &mut hash_groups {
+        let shared: Vec<_> = hash_group
+            .iter()
+            .filter_map(|file| shared_extent_files.get(file))
+            .flatten()
+            .copied()
+            .collect();
+        hash_group.extend(shared);
+    }
+    report_near_duplicates(&mut hash_groups, ctx);
+    for hash_group in hash_groups {
+        if ctx.interrupted() {
+            break;
+        }
+        if exclude_if_inconsistent(&hash_group, ctx) {
+            continue;
+        }
+        if exclude_if_unique(&hash_group, ctx, "It has a unique hash.") {
+            continue;
+        }
+        if ctx.options.paranoid {
+            same_content_dedup(&hash_group, ctx);
+        } else {
+            for candidate_group in candidate_groups(hash_group, ctx) {
+                hardlink_dedup(candidate_group, ctx);
             }
         }
     }
 }
 
-fn replace_many_with_hard_link<'a>(
-    original_file: &Path,
-    targets: impl Iterator<Item = &'a PathBuf>,
-    ctx: &DedupContext,
-) {
-    for target in targets {
-        if ctx.dry_run {
-            println!(
-                "[{}] Would hardlink {:?} to {:?}.",
-                ctx, original_file, target
-            );
+/// Splits off files that share all their extents with another file in the group. Such files have
+/// the same contents as that file, so only that file needs to be hashed. Returns the rest of the
+/// group and the split off files by the file they share their extents with.
+fn split_shared_extents<'a>(
+    group: HashSet<&'a PathBuf>,
+    ctx: &mut DedupContext,
+) -> (HashSet<&'a PathBuf>, HashMap<&'a PathBuf, Vec<&'a PathBuf>>) {
+    let files: Vec<&PathBuf> = group.into_iter().collect();
+    let representatives = extents::file_representatives(&files);
+    let mut rest = HashSet::new();
+    let mut shared_extent_files: HashMap<_, Vec<_>> = HashMap::new();
+    for (&file, representative) in files.iter().zip(representatives) {
+        let other_file = files[representative];
+        if other_file == file {
+            rest.insert(file);
             continue;
         }
-        match replace_with_hard_link(original_file, target) {
-            Ok(_) => println!("[{}] Hardlinked {:?} to {:?}.", ctx, original_file, target),
-            Err(err) => warn!(
-                "Failed to hardlink {:?} to {:?}. Error: {}",
-                original_file, target, err
-            ),
+        ctx.shared_extent_files += 1;
+        ctx.emit(Event::SharedExtents { file, other_file });
+        shared_extent_files
+            .entry(other_file)
+            .or_default()
+            .push(file);
+    }
+    (rest, shared_extent_files)
+}
+
+struct DedupContext<'a> {
+    options: &'a DedupOptions,
+    total: usize,
+    processed: usize,
+    bytes_deduped: usize,
+    bytes_linked: u64,
+    bytes_already_linked: u64,
+    inconsistent_files: usize,
+    near_duplicates: usize,
+    repaired_files: usize,
+    failed_files: FailedFiles,
+    link_failures: LinkFailures,
+    fallback_links: usize,
+    unwritable_files: usize,
+    shared_extent_files: usize,
+    cross_device_groups: usize,
+    cross_device_bytes: u64,
+    /// The closest directories containing both paths of the hardlinks we created.
+    linked_dirs: BTreeSet<PathBuf>,
+    /// The savings by directory, with [`DedupOptions::breakdown_depth`].
+    breakdown: Option<Breakdown>,
+    stats: RunStats,
+    clock: StageClock,
+    /// When the run started, scanning included.
+    started: Instant,
+    inode_to_paths: &'a HashMap<FileId, HashSet<PathBuf>>,
+    hash_pool: Option<HashPool>,
+    progress: Progress,
+    renderer: &'a mut dyn Renderer,
+    /// The file we're processing, for progress snapshots.
+    current_file: Option<&'a Path>,
+    /// The most hardlinks an inode can have on the filesystem of a path, see [`link_max()`].
+    link_max: fn(&Path) -> Option<u64>,
+    /// The files of the group being deduplicated as they were before we read their contents.
+    file_snapshots: HashMap<&'a Path, FileSnapshot>,
+    checkpoint: Checkpoint,
+    last_checkpoint: Instant,
+    journal: Option<Journal>,
+    /// Devices whose filesystems were nearly full when we started.
+    low_space_devices: HashSet<u64>,
+    /// Hashes the upcoming groups of other devices while a group is deduplicated.
+    prefetcher: Option<Prefetcher>,
+    /// Devices whose filesystems don't support hardlinks, with the name of the filesystem.
+    unlinkable_devices: HashMap<u64, &'static str>,
+    /// [`DedupOptions::reference_dirs`] as given and canonicalized, so that files found through
+    /// either path are under them.
+    reference_dirs: Vec<PathBuf>,
+    /// The user answered "all" in interactive mode.
+    confirmed_all: bool,
+    /// The user quit in interactive mode.
+    quit: bool,
+    /// Files of groups that previous runs completed, see [`DedupOptions::resume`].
+    resumed_files: usize,
+    /// We hit [`DedupOptions::max_files`] or [`DedupOptions::max_duration`].
+    budget_exhausted: bool,
+}
+
+/// The part of a [`DedupContext`] that doesn't borrow the files being deduplicated, so that it can
+/// be carried from one bucket of files to the next (see [`low_memory`]).
+struct ContextState {
+    total: usize,
+    processed: usize,
+    bytes_deduped: usize,
+    bytes_linked: u64,
+    bytes_already_linked: u64,
+    inconsistent_files: usize,
+    near_duplicates: usize,
+    repaired_files: usize,
+    failed_files: FailedFiles,
+    link_failures: LinkFailures,
+    fallback_links: usize,
+    unwritable_files: usize,
+    shared_extent_files: usize,
+    cross_device_groups: usize,
+    cross_device_bytes: u64,
+    linked_dirs: BTreeSet<PathBuf>,
+    breakdown: Option<Breakdown>,
+    stats: RunStats,
+    clock: StageClock,
+    started: Instant,
+    hash_pool: Option<HashPool>,
+    progress: Progress,
+    checkpoint: Checkpoint,
+    last_checkpoint: Instant,
+    journal: Option<Journal>,
+    confirmed_all: bool,
+    quit: bool,
+    resumed_files: usize,
+    budget_exhausted: bool,
+}
+
+impl ContextState {
+    fn new(options: &DedupOptions, total: usize) -> ContextState {
+        ContextState {
+            total,
+            processed: 0,
+            bytes_deduped: 0,
+            bytes_linked: 0,
+            bytes_already_linked: 0,
+            inconsistent_files: 0,
+            near_duplicates: 0,
+            repaired_files: 0,
+            failed_files: FailedFiles::default(),
+            link_failures: LinkFailures::default(),
+            fallback_links: 0,
+            unwritable_files: 0,
+            shared_extent_files: 0,
+            cross_device_groups: 0,
+            cross_device_bytes: 0,
+            linked_dirs: BTreeSet::new(),
+            breakdown: options
+                .breakdown_depth
+                .map(|depth| Breakdown::new(&[], depth)),
+            stats: RunStats::default(),
+            clock: StageClock::default(),
+            started: Instant::now(),
+            hash_pool: start_hash_pool(options),
+            progress: Progress::new(options, total),
+            checkpoint: Checkpoint::default(),
+            last_checkpoint: Instant::now(),
+            journal: None,
+            confirmed_all: false,
+            quit: false,
+            resumed_files: 0,
+            budget_exhausted: false,
         }
     }
 }
 
-fn replace_with_hard_link(original_file: &Path, target: &Path) -> Result<(), String> {
-    let tmp_file = target.parent().unwrap().join(Uuid::new_v4().to_string());
-    let _ = hard_link(original_file, &tmp_file).map_err(|err| {
-        format!(
-            "Failed to create temporary hardlink of {:?} at {:?}. Error: {}",
-            original_file, tmp_file, err
-        )
-    })?;
-    rename(&tmp_file, target)
-        .map_err(|err| {
-            format!(
-                "Failed to replace target file {:?} with temporary hardlink {:?}. Error: {}",
-                target, tmp_file, err
-            )
-        })
-        .map_err(|err| {
-            if let Err(inner_err) = remove_file(&tmp_file) {
-                format!(
-                    "After error '{}' also failed to delete temporary file {:?} with error: {}",
-                    err, tmp_file, inner_err
-                )
-            } else {
-                err
+impl<'a> DedupContext<'a> {
+    fn new(
+        inode_to_paths: &'a HashMap<FileId, HashSet<PathBuf>>,
+        options: &'a DedupOptions,
+        renderer: &'a mut dyn Renderer,
+    ) -> DedupContext<'a> {
+        let state = ContextState::new(options, inode_to_paths.len());
+        DedupContext::resume(state, inode_to_paths, options, renderer)
+    }
+
+    /// Continues deduplicating with other files.
+    fn resume(
+        state: ContextState,
+        inode_to_paths: &'a HashMap<FileId, HashSet<PathBuf>>,
+        options: &'a DedupOptions,
+        renderer: &'a mut dyn Renderer,
+    ) -> DedupContext<'a> {
+        DedupContext {
+            options,
+            total: state.total,
+            processed: state.processed,
+            bytes_deduped: state.bytes_deduped,
+            bytes_linked: state.bytes_linked,
+            bytes_already_linked: state.bytes_already_linked,
+            inconsistent_files: state.inconsistent_files,
+            near_duplicates: state.near_duplicates,
+            repaired_files: state.repaired_files,
+            failed_files: state.failed_files,
+            link_failures: state.link_failures,
+            fallback_links: state.fallback_links,
+            unwritable_files: state.unwritable_files,
+            shared_extent_files: state.shared_extent_files,
+            cross_device_groups: state.cross_device_groups,
+            cross_device_bytes: state.cross_device_bytes,
+            linked_dirs: state.linked_dirs,
+            breakdown: state.breakdown,
+            stats: state.stats,
+            clock: state.clock,
+            started: state.started,
+            inode_to_paths,
+            hash_pool: state.hash_pool,
+            progress: state.progress,
+            renderer,
+            current_file: None,
+            link_max,
+            file_snapshots: HashMap::new(),
+            checkpoint: state.checkpoint,
+            last_checkpoint: state.last_checkpoint,
+            journal: state.journal,
+            low_space_devices: HashSet::new(),
+            prefetcher: None,
+            unlinkable_devices: HashMap::new(),
+            reference_dirs: options
+                .reference_dirs
+                .iter()
+                .flat_map(|dir| [Some(dir.clone()), canonicalize(dir).ok()])
+                .flatten()
+                .collect(),
+            confirmed_all: state.confirmed_all,
+            quit: state.quit,
+            resumed_files: state.resumed_files,
+            budget_exhausted: state.budget_exhausted,
+        }
+    }
+
+    /// Stops deduplicating the current files so that [`DedupContext::resume`] can continue with
+    /// others.
+    fn suspend(self) -> ContextState {
+        ContextState {
+            total: self.total,
+            processed: self.processed,
+            bytes_deduped: self.bytes_deduped,
+            bytes_linked: self.bytes_linked,
+            bytes_already_linked: self.bytes_already_linked,
+            inconsistent_files: self.inconsistent_files,
+            near_duplicates: self.near_duplicates,
+            repaired_files: self.repaired_files,
+            failed_files: self.failed_files,
+            link_failures: self.link_failures,
+            fallback_links: self.fallback_links,
+            unwritable_files: self.unwritable_files,
+            shared_extent_files: self.shared_extent_files,
+            cross_device_groups: self.cross_device_groups,
+            cross_device_bytes: self.cross_device_bytes,
+            linked_dirs: self.linked_dirs,
+            breakdown: self.breakdown,
+            stats: self.stats,
+            clock: self.clock,
+            started: self.started,
+            hash_pool: self.hash_pool,
+            progress: self.progress,
+            checkpoint: self.checkpoint,
+            last_checkpoint: self.last_checkpoint,
+            journal: self.journal,
+            confirmed_all: self.confirmed_all,
+            quit: self.quit,
+            resumed_files: self.resumed_files,
+            budget_exhausted: self.budget_exhausted,
+        }
+    }
+
+    fn summary(&self) -> DedupSummary {
+        DedupSummary {
+            processed_files: self.processed,
+            bytes_deduped: self.bytes_deduped,
+            bytes_linked: self.bytes_linked,
+            bytes_already_linked: self.bytes_already_linked,
+            near_duplicates: self.near_duplicates,
+            repaired_files: self.repaired_files,
+            inconsistent_files: self.inconsistent_files,
+            failed_files: self.failed_files.count(),
+            file_errors: self.failed_files.errors().to_vec(),
+            link_failures: self.link_failures,
+            fallback_links: self.fallback_links,
+            unwritable_files: self.unwritable_files,
+            shared_extent_files: self.shared_extent_files,
+            cross_device_groups: self.cross_device_groups,
+            cross_device_bytes: self.cross_device_bytes,
+            linked_dirs: outermost_dirs(&self.linked_dirs),
+            directories: self
+                .breakdown
+                .as_ref()
+                .map_or_else(Vec::new, Breakdown::directories),
+            resources: resource_usage(),
+            stats: RunStats {
+                files_scanned: self.total,
+                stage_times: self.clock.times(),
+                wall_time_ms: self.started.elapsed().as_millis() as u64,
+                ..self.stats.clone()
+            },
+            interrupted: self.interrupted(),
+            budget_exhausted: self.budget_exhausted,
+        }
+    }
+
+    fn status(&self) -> Status {
+        Status {
+            processed_files: self.processed,
+            total_files: self.total,
+            bytes_deduped: self.bytes_deduped,
+            dry_run: self.options.dry_run,
+            raw_bytes: self.options.raw_bytes,
+        }
+    }
+
+    /// Renders the event without garbling the progress bar.
+    fn emit(&mut self, event: Event) {
+        self.count(&event);
+        if self.renderer.renders(&event) {
+            let status = self.status();
+            let renderer = &mut self.renderer;
+            self.progress.suspend(|| renderer.render(&status, &event));
+        }
+    }
+
+    /// Counts the files excluded and skipped for the summary.
+    fn count(&mut self, event: &Event) {
+        let (counts, reason, files) = match event {
+            Event::Excluded { reason, .. } => (&mut self.stats.excluded_files, *reason, 1),
+            Event::Skipped { reason, .. } => {
+                (&mut self.stats.skipped_files, reason.description(), 1)
             }
-        })
+            Event::SkippedUnwritable { targets, .. } => (
+                &mut self.stats.skipped_files,
+                SkipReason::UNWRITABLE_DESCRIPTION,
+                targets.len(),
+            ),
+            Event::Hardlinked { .. } => {
+                self.stats.linked_files += 1;
+                return;
+            }
+            _ => return,
+        };
+        match counts.get_mut(reason) {
+            Some(count) => *count += files,
+            None => {
+                counts.insert(reason.to_owned(), files);
+            }
+        }
+    }
+
+    /// Why the file must never be replaced: any of its paths is under a reference directory or
+    /// protected. `None` for files that may be replaced.
+    fn keep_reason(&self, file: &Path) -> Option<&'static str> {
+        if self.reference_dirs.is_empty() && self.options.protected.is_empty() {
+            return None;
+        }
+        let paths: Vec<&Path> = match metadata(file)
+            .ok()
+            .and_then(|file_metadata| self.inode_to_paths.get(&file_id(&file_metadata)))
+        {
+            Some(paths) => paths.iter().map(PathBuf::as_path).collect(),
+            None => vec![file],
+        };
+        let under_reference =
+            |path: &&Path| self.reference_dirs.iter().any(|dir| path.starts_with(dir));
+        if paths.iter().any(under_reference) {
+            return Some("It's in a reference directory.");
+        }
+        if paths
+            .iter()
+            .any(|path| self.options.protected.is_protected(path))
+        {
+            return Some("It's protected.");
+        }
+        None
+    }
+
+    /// Records the group as fully processed and updates the checkpoint file now and then. Groups of
+    /// dry runs are never processed fully.
+    fn complete_group(&mut self, group: GroupKey) {
+        if self.options.dry_run {
+            return;
+        }
+        self.checkpoint.complete(group);
+        if self.last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+            self.save_checkpoint();
+        }
+    }
+
+    /// Saves the checkpoint and emits the summary.
+    fn finish(&mut self) -> DedupSummary {
+        self.save_checkpoint();
+        self.progress.finish();
+        // Stopped hash workers count towards the resource usage.
+        self.hash_pool = None;
+        let summary = self.summary();
+        self.emit(Event::Finished { summary: &summary });
+        summary
+    }
+
+    fn save_checkpoint(&mut self) {
+        if let Some(path) = &self.options.checkpoint {
+            if let Err(err) = self.checkpoint.save(path) {
+                warn!("Failed to save checkpoint {:?}. Error: {}", path, err);
+            }
+            self.last_checkpoint = Instant::now();
+        }
+    }
+
+    fn interrupted(&self) -> bool {
+        self.quit || self.budget_exhausted || self.options.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Stops the run once it processed [`DedupOptions::max_files`], not counting the files of
+    /// groups completed by previous runs, or took [`DedupOptions::max_duration`]. Checked between
+    /// groups, so that the group in flight is finished.
+    fn check_budget(&mut self) {
+        if self.budget_exhausted {
+            return;
+        }
+        let options = self.options;
+        let processed = self.processed - self.resumed_files;
+        self.budget_exhausted = options.max_files.is_some_and(|max| processed >= max)
+            || options
+                .max_duration
+                .is_some_and(|max| self.started.elapsed() >= max);
+        if self.budget_exhausted {
+            info!(
+                "Stopping after {} of {} files. The run's budget is exhausted.",
+                self.processed, self.total
+            );
+        }
+    }
+
+    /// Whether the user wants the targets hardlinked to the original. Always true unless
+    /// interactive.
+    fn confirmed(&mut self, original_file: &Path, targets: &[&Path]) -> bool {
+        if !self.options.interactive || self.options.dry_run || self.confirmed_all {
+            return true;
+        }
+        let size = metadata(original_file).map_or(0, |file_metadata| file_metadata.len());
+        let answer = self
+            .progress
+            .suspend(|| interactive::ask(original_file, targets, size));
+        match answer {
+            Ok(Answer::Yes) => true,
+            Ok(Answer::No) => false,
+            Ok(Answer::All) => {
+                self.confirmed_all = true;
+                true
+            }
+            Ok(Answer::Quit) => {
+                self.quit = true;
+                false
+            }
+            Err(err) => {
+                warn!("Failed to ask for confirmation. Stopping. Error: {}", err);
+                self.quit = true;
+                false
+            }
+        }
+    }
+
+    fn add_processed(&mut self, count: usize) {
+        self.processed += count;
+        self.progress
+            .update(self.processed, self.stats.bytes_hashed, self.bytes_deduped);
+        self.emit_snapshot_if_requested();
+    }
+
+    /// Remembers the files as they are before their contents are read, so that files that change
+    /// after we compared them aren't linked.
+    fn snapshot_files(&mut self, group: &HashSet<&'a PathBuf>) {
+        self.file_snapshots = group
+            .iter()
+            .filter_map(|file| Some((file.as_path(), FileSnapshot::new(&metadata(file).ok()?))))
+            .collect();
+    }
+
+    /// The error for a file that changed since [`DedupContext::snapshot_files`], if it did.
+    fn changed_since_snapshot(&self, file: &Path, file_metadata: &Metadata) -> Option<String> {
+        let snapshot = self.file_snapshots.get(file)?;
+        (*snapshot != FileSnapshot::new(file_metadata))
+            .then(|| format!("{:?} changed after its contents were compared.", file))
+    }
+
+    /// Remembers the first file of the group as the one we're processing.
+    fn set_current_file(&mut self, group: &HashSet<&'a PathBuf>) {
+        if let Some(file) = group.iter().next() {
+            self.current_file = Some(file);
+        }
+        self.emit_snapshot_if_requested();
+    }
+
+    fn emit_snapshot_if_requested(&mut self) {
+        if self
+            .options
+            .snapshot_requested
+            .swap(false, Ordering::Relaxed)
+        {
+            self.emit(Event::Snapshot {
+                processed_files: self.processed,
+                total_files: self.total,
+                bytes_deduped: self.bytes_deduped,
+                current_file: self.current_file,
+            });
+        }
+    }
+}
+
+/// Whether replacing this many of the file's paths with hardlinks frees it. Its contents stay around
+/// as long as any of its links aren't replaced, including links outside the paths being deduplicated.
+fn frees_inode(file_metadata: &Metadata, replaced_paths: usize) -> bool {
+    file_metadata.nlink() <= replaced_paths as u64
+}
+
+/// Bytes that files share through hardlinks to each other: all but one copy of each file with
+/// several paths.
+fn already_linked_bytes(inode_to_paths: &HashMap<FileId, HashSet<PathBuf>>) -> u64 {
+    inode_to_paths
+        .values()
+        .filter(|paths| paths.len() > 1)
+        .filter_map(|paths| {
+            let file_metadata = metadata(paths.iter().next()?).ok()?;
+            Some(allocated_bytes(&file_metadata) * (paths.len() as u64 - 1))
+        })
+        .sum()
+}
+
+/// The device of a group of files that are all on the same device.
+fn group_device(group: &HashSet<&PathBuf>) -> u64 {
+    group
+        .iter()
+        .next()
+        .and_then(|file| metadata(file).ok())
+        .map_or(0, |file_metadata| file_metadata.dev())
+}
+
+/// The size of each file in a group of files that all have the same size.
+fn group_file_size(group: &HashSet<&PathBuf>) -> u64 {
+    group
+        .iter()
+        .next()
+        .and_then(|file| metadata(file).ok())
+        .map_or(0, |file_metadata| file_metadata.len())
+}
+
+/// The total size of a group of files that all have the same size.
+fn group_bytes(group: &HashSet<&PathBuf>) -> u64 {
+    group_file_size(group) * group.len() as u64
+}
+
+/// How many bytes we'd save if all files in the group turned out to be the same.
+fn potential_savings(group: &HashSet<&PathBuf>) -> u64 {
+    let allocated = group
+        .iter()
+        .next()
+        .and_then(|file| metadata(file).ok())
+        .map_or(0, |file_metadata| allocated_bytes(&file_metadata));
+    allocated * group.len().saturating_sub(1) as u64
+}
+
+/// Checks the free space on the filesystem of every device we found files on and warns about
+/// nearly full ones.
+fn find_low_space_devices(inode_to_paths: &HashMap<FileId, HashSet<PathBuf>>) -> HashSet<u64> {
+    let mut checked_devices = HashSet::new();
+    let mut low_space_devices = HashSet::new();
+    for ((device, _), paths) in inode_to_paths {
+        if !checked_devices.insert(*device) {
+            continue;
+        }
+        let path = match paths.iter().next() {
+            Some(path) => path,
+            None => continue,
+        };
+        match free_space(path) {
+            Ok(space) if space.is_low() => {
+                warn!(
+                    "The filesystem containing {:?} is nearly full ({} bytes and {} inodes free). Replacing files with hardlinks might fail on it. Groups with the largest savings will be processed first.",
+                    path,
+                    space.bytes,
+                    space.inodes.map_or("unlimited".to_owned(), |inodes| inodes.to_string())
+                );
+                low_space_devices.insert(*device);
+            }
+            Ok(_) => (),
+            Err(err) => warn!(
+                "Failed to check free space on the filesystem containing {:?}. Error: {}",
+                path, err
+            ),
+        }
+    }
+    low_space_devices
+}
+
+/// Checks the filesystem of every device we found files on. Warns about filesystems whose hardlinks
+/// may not work and returns the devices whose filesystems have none, with the filesystem's name.
+fn find_unlinkable_devices(
+    inode_to_paths: &HashMap<FileId, HashSet<PathBuf>>,
+) -> HashMap<u64, &'static str> {
+    let mut checked_devices = HashSet::new();
+    let mut unlinkable_devices = HashMap::new();
+    for ((device, _), paths) in inode_to_paths {
+        if !checked_devices.insert(*device) {
+            continue;
+        }
+        let Some(path) = paths.iter().next() else {
+            continue;
+        };
+        match filesystem_support(path) {
+            Ok(Support::Hardlinks) => (),
+            Ok(Support::NoHardlinks(name)) => {
+                warn!(
+                    "The filesystem containing {:?} is {}, which doesn't support hardlinks. Skipping its files.",
+                    path, name
+                );
+                unlinkable_devices.insert(*device, name);
+            }
+            Ok(Support::Unreliable(name, reason)) => warn!(
+                "The filesystem containing {:?} is {}. {} Replacing its files with hardlinks might fail.",
+                path, name, reason
+            ),
+            Err(err) => debug!(
+                "Failed to check the type of the filesystem containing {:?}. Error: {}",
+                path, err
+            ),
+        }
+    }
+    unlinkable_devices
+}
+
+/// Whether there's room for the temporary hardlink next to the target. We only check this on
+/// filesystems that were nearly full when we started.
+fn has_room_for_link(target: &Path, ctx: &DedupContext) -> bool {
+    if ctx.low_space_devices.is_empty() {
+        return true;
+    }
+    match metadata(target) {
+        Ok(target_metadata) if ctx.low_space_devices.contains(&target_metadata.dev()) => {
+            free_space(target).map_or(true, |space| space.has_room_for_link())
+        }
+        _ => true,
+    }
+}
+
+/// Opens the journal unless this is a dry run.
+fn open_journal(options: &DedupOptions) -> Result<Option<Journal>, DedupError> {
+    match &options.journal {
+        Some(journal) if !options.dry_run => {
+            Journal::open(journal)
+                .map(Some)
+                .map_err(|source| DedupError::OpenJournal {
+                    path: journal.clone(),
+                    source,
+                })
+        }
+        _ => Ok(None),
+    }
+}
+
+fn start_hash_pool(options: &DedupOptions) -> Option<HashPool> {
+    if options.hash_workers == 0 {
+        return None;
+    }
+    HashPool::new(
+        options.hash_workers,
+        options.hash,
+        options.read_options(),
+        options.hash_worker_memory_limit,
+        options.hash_worker_timeout,
+    )
+    .map_err(|err| {
+        warn!(
+            "Failed to start hash worker processes. Hashing in this process instead. Error: {}",
+            err
+        )
+    })
+    .ok()
+}
+
+fn exclude_if_unique(
+    group: &HashSet<&PathBuf>,
+    ctx: &mut DedupContext,
+    uniqueness_msg: &str,
+) -> bool {
+    if group.len() > 1 {
+        return false;
+    }
+    ctx.add_processed(group.len());
+    ctx.emit(Event::Excluded {
+        file: group.iter().next().unwrap(),
+        reason: uniqueness_msg,
+    });
+    true
+}
+
+/// Excludes the files of the group that have at least [`DedupOptions::max_nlink`] links.
+fn exclude_many_links(group: &mut HashSet<&PathBuf>, ctx: &mut DedupContext) {
+    let Some(max_nlink) = ctx.options.max_nlink else {
+        return;
+    };
+    let many_links: Vec<&PathBuf> = group
+        .iter()
+        .copied()
+        .filter(|file| metadata(file).is_ok_and(|file_metadata| file_metadata.nlink() >= max_nlink))
+        .collect();
+    ctx.add_processed(many_links.len());
+    let reason = format!("It already has at least {} links.", max_nlink);
+    for file in many_links {
+        group.remove(file);
+        ctx.emit(Event::Excluded {
+            file,
+            reason: &reason,
+        });
+    }
+}
+
+/// Excludes the group if its filesystem doesn't support hardlinks. The files of a group share their
+/// device.
+fn exclude_if_unlinkable(group: &HashSet<&PathBuf>, ctx: &mut DedupContext) -> bool {
+    let device = group.iter().next().and_then(|file| metadata(file).ok());
+    let Some(name) =
+        device.and_then(|file_metadata| ctx.unlinkable_devices.get(&file_metadata.dev()))
+    else {
+        return false;
+    };
+    let reason = format!("Its filesystem ({}) doesn't support hardlinks.", name);
+    ctx.add_processed(group.len());
+    for file in group {
+        ctx.emit(Event::Excluded {
+            file,
+            reason: &reason,
+        });
+    }
+    true
+}
+
+/// Excludes the group if its files are empty, unless [`DedupOptions::include_empty`] is set.
+fn exclude_if_empty(group: &HashSet<&PathBuf>, ctx: &mut DedupContext) -> bool {
+    if ctx.options.include_empty || group_file_size(group) > 0 {
+        return false;
+    }
+    ctx.add_processed(group.len());
+    for file in group {
+        ctx.emit(Event::Excluded {
+            file,
+            reason: "It's empty.",
+        });
+    }
+    true
+}
+
+/// Excludes the group if it has fewer files than [`DedupOptions::min_copies`]. Groups only get
+/// smaller as we compare more of their files, so this can be checked early.
+fn exclude_if_few_copies(group: &HashSet<&PathBuf>, ctx: &mut DedupContext) -> bool {
+    let min_copies = ctx.options.min_copies.unwrap_or(2);
+    if group.len() >= min_copies {
+        return false;
+    }
+    ctx.add_processed(group.len());
+    let reason = format!("Its contents have fewer than {} copies.", min_copies);
+    for file in group {
+        ctx.emit(Event::Excluded {
+            file,
+            reason: &reason,
+        });
+    }
+    true
+}
+
+/// Files that look unique might only look that way because reading them returned bad data. When
+/// requested, we read such files again and exclude those that read back differently.
+fn exclude_if_inconsistent(group: &HashSet<&PathBuf>, ctx: &mut DedupContext) -> bool {
+    let second_read = match ctx.options.double_read_verify {
+        Some(second_read) if group.len() == 1 => second_read,
+        _ => return false,
+    };
+    let file = group.iter().next().unwrap();
+    match reads_consistently(
+        file,
+        second_read,
+        ctx.options.hash,
+        ctx.options.read_options(),
+    ) {
+        Ok(true) => false,
+        Ok(false) => {
+            ctx.add_processed(1);
+            ctx.inconsistent_files += 1;
+            ctx.emit(Event::InconsistentRead { file });
+            ctx.emit(Event::Excluded {
+                file,
+                reason: "It reads back inconsistently.",
+            });
+            true
+        }
+        Err(err) => {
+            ctx.failed_files.add(
+                file,
+                format!("Failed to read {:?} a second time. Error: {}", file, err),
+            );
+            false
+        }
+    }
+}
+
+/// Compares one file of each group with one file of every other group and reports pairs that
+/// differ in only a few bytes. When repairing, groups of corrupted files are repaired and removed.
+fn report_near_duplicates(groups: &mut Vec<HashSet<&PathBuf>>, ctx: &mut DedupContext) {
+    let max_differences = match (
+        ctx.options.report_near_duplicates,
+        ctx.options.repair_from_duplicate,
+    ) {
+        (Some(max_differences), _) => max_differences,
+        (None, Some(_)) => DEFAULT_MAX_DIFFERENCES,
+        (None, None) => return,
+    };
+    // Repaired files are linked to the file the rest of the healthy group is linked to.
+    let representatives: Vec<&PathBuf> = groups
+        .iter()
+        .flat_map(|group| {
+            prefer::by_preference(group, ctx.options.prefer)
+                .first()
+                .copied()
+        })
+        .collect();
+    let mut repaired_groups = HashSet::new();
+    for (index, file) in representatives.iter().enumerate() {
+        for (other_index, other_file) in representatives.iter().enumerate().skip(index + 1) {
+            match differing_offsets(file, other_file, max_differences) {
+                Ok(Some(offsets)) if !offsets.is_empty() => {
+                    ctx.near_duplicates += 1;
+                    ctx.emit(Event::NearDuplicates {
+                        file,
+                        other_file,
+                        offsets: &offsets,
+                    });
+                }
+                Ok(_) => continue,
+                Err(err) => {
+                    ctx.failed_files.add(
+                        other_file,
+                        format!(
+                            "Failed to compare files {:?} and {:?}. Error: {}",
+                            file, other_file, err
+                        ),
+                    );
+                    continue;
+                }
+            }
+            let repair_mode = match ctx.options.repair_from_duplicate {
+                Some(repair_mode) => repair_mode,
+                None => continue,
+            };
+            if repaired_groups.contains(&index) || repaired_groups.contains(&other_index) {
+                continue;
+            }
+            if let Some((healthy, corrupt)) =
+                choose_corrupt_group(repair_mode, groups, index, other_index)
+            {
+                repair_group(representatives[healthy], &groups[corrupt], ctx);
+                repaired_groups.insert(corrupt);
+            }
+        }
+    }
+    let mut index = 0;
+    groups.retain(|_| {
+        index += 1;
+        !repaired_groups.contains(&(index - 1))
+    });
+}
+
+/// Replaces all paths of the corrupted files with hardlinks to the healthy file. Each corrupted
+/// file is backed up first.
+fn repair_group(healthy_file: &Path, corrupt_group: &HashSet<&PathBuf>, ctx: &mut DedupContext) {
+    for corrupt_file in corrupt_group {
+        if ctx.interrupted() {
+            break;
+        }
+        ctx.add_processed(1);
+        if let Some(reason) = ctx.keep_reason(corrupt_file) {
+            warn!(
+                "Not repairing {:?} from {:?}. {}",
+                corrupt_file, healthy_file, reason
+            );
+            continue;
+        }
+        if ctx.options.dry_run {
+            ctx.emit(Event::Repaired {
+                file: corrupt_file,
+                healthy_file,
+                backup: None,
+                dry_run: true,
+            });
+            continue;
+        }
+        let corrupt_metadata = match metadata(corrupt_file) {
+            Ok(corrupt_metadata) => corrupt_metadata,
+            Err(err) => {
+                ctx.failed_files.add(
+                    corrupt_file,
+                    format!(
+                        "Failed to repair {:?}. Failed to fetch its metadata. Error: {}",
+                        corrupt_file, err
+                    ),
+                );
+                continue;
+            }
+        };
+        let inode_to_paths = ctx.inode_to_paths;
+        let Some(corrupt_paths) = inode_to_paths.get(&file_id(&corrupt_metadata)) else {
+            ctx.failed_files.add(
+                corrupt_file,
+                format!(
+                    "Failed to repair {:?}. It changed since it was scanned.",
+                    corrupt_file
+                ),
+            );
+            continue;
+        };
+        match backup_corrupt_file(corrupt_file) {
+            Ok(backup) => ctx.emit(Event::Repaired {
+                file: corrupt_file,
+                healthy_file,
+                backup: Some(&backup),
+                dry_run: false,
+            }),
+            Err(err) => {
+                ctx.failed_files.add(
+                    corrupt_file,
+                    format!(
+                        "Failed to repair {:?}. Failed to back it up. Error: {}",
+                        corrupt_file, err
+                    ),
+                );
+                continue;
+            }
+        }
+        replace_many_with_hard_link(
+            healthy_file,
+            corrupt_paths.iter(),
+            Some(FileSnapshot::new(&corrupt_metadata)),
+            ctx,
+        );
+        ctx.repaired_files += 1;
+    }
+}
+
+/// If we have a pair of same-sized files, it's faster to compare them byte-for-byte
+/// rather than calculate their hashes and compare hashes.
+fn dedup_if_pair(group: &HashSet<&PathBuf>, ctx: &mut DedupContext) -> bool {
+    if group.len() == 2 {
+        same_content_dedup(group, ctx);
+        return true;
+    }
+    false
+}
+
+fn same_content_dedup(file_group: &HashSet<&PathBuf>, ctx: &mut DedupContext) {
+    let bytes = group_bytes(file_group);
+    ctx.stats.bytes_read += bytes;
+    ctx.stats.stage_bytes.compare += bytes;
+    let started = Instant::now();
+    let content_groups = same_content_groups(
+        file_group,
+        ctx.options.read_options(),
+        &mut ctx.failed_files,
+    );
+    let mut content_groups = ordered(content_groups, ctx.options);
+    ctx.clock.add(Stage::Compare, started.elapsed());
+    report_near_duplicates(&mut content_groups, ctx);
+    for content_group in content_groups {
+        if exclude_if_inconsistent(&content_group, ctx) {
+            continue;
+        }
+        if exclude_if_unique(&content_group, ctx, "It has unique contents.") {
+            continue;
+        }
+        for candidate_group in candidate_groups(content_group, ctx) {
+            hardlink_dedup(candidate_group, ctx);
+        }
+    }
+}
+
+/// Splits a group of files with the same contents into groups whose files the candidate filters
+/// all allow hardlinking to the group's original. Each file joins the first group, in the order
+/// originals are picked, whose original may be linked to all the paths of the file, or else starts
+/// a group of its own. Files left in a group of their own are skipped.
+fn candidate_groups<'g>(
+    same_files_group: HashSet<&'g PathBuf>,
+    ctx: &mut DedupContext,
+) -> Vec<HashSet<&'g PathBuf>> {
+    if ctx.options.candidate_filters.is_empty() {
+        return vec![same_files_group];
+    }
+    let mut files = prefer::by_preference(&same_files_group, ctx.options.prefer);
+    files.sort_by_key(|file| ctx.keep_reason(file).is_none());
+    let mut groups: Vec<Vec<&'g PathBuf>> = Vec::new();
+    for file in files {
+        let paths: Vec<&PathBuf> = match metadata(file)
+            .ok()
+            .and_then(|file_metadata| ctx.inode_to_paths.get(&file_id(&file_metadata)))
+        {
+            Some(paths) => paths.iter().collect(),
+            None => vec![file],
+        };
+        let filters = &ctx.options.candidate_filters;
+        match groups
+            .iter_mut()
+            .find(|group| paths.iter().all(|path| filters.may_link(group[0], path)))
+        {
+            Some(group) => group.push(file),
+            None => groups.push(vec![file]),
+        }
+    }
+    let original_file = groups[0][0];
+    let mut candidate_groups = Vec::new();
+    for group in groups {
+        if group.len() > 1 {
+            candidate_groups.push(group.into_iter().collect());
+            continue;
+        }
+        ctx.add_processed(1);
+        if group[0] != original_file {
+            ctx.emit(Event::Skipped {
+                original_file,
+                target: group[0],
+                reason: SkipReason::Filtered,
+            });
+        }
+    }
+    candidate_groups
+}
+
+fn hardlink_dedup(same_files_group: HashSet<&PathBuf>, ctx: &mut DedupContext) {
+    let _span = debug_span!("link", files = same_files_group.len()).entered();
+    if exclude_if_few_copies(&same_files_group, ctx) {
+        return;
+    }
+    ctx.stats.duplicate_groups += 1;
+    emit_duplicates(&same_files_group, ctx);
+    let mut files = prefer::by_preference(&same_files_group, ctx.options.prefer);
+    // The most preferred reference or protected file is the original, and the others are kept.
+    files.sort_by_key(|file| ctx.keep_reason(file).is_none());
+    let mut same_files_iterator = files.into_iter();
+    let original_file = match same_files_iterator.next() {
+        Some(original_file) => original_file,
+        None => return,
+    };
+    ctx.add_processed(1);
+    let original_changed = metadata(original_file).ok().and_then(|original_metadata| {
+        ctx.changed_since_snapshot(original_file, &original_metadata)
+    });
+    if let Some(changed) = original_changed {
+        ctx.add_processed(same_files_iterator.len());
+        ctx.failed_files.add(
+            original_file,
+            format!("Skipping hardlinking its duplicates to it. {}", changed),
+        );
+        return;
+    }
+    // Reflinking a file that already shares all its extents with the original saves nothing.
+    let original_extents = if ctx.options.link_mode == LinkMode::Reflink {
+        extents::shared_extents(original_file).ok().flatten()
+    } else {
+        None
+    };
+    // Check all directories up front so that we skip files we can't replace with one message
+    // rather than failing on each of them.
+    let mut writable_dirs = HashMap::new();
+    let mut unwritable_targets = Vec::new();
+    let mut relinks = Vec::new();
+    for other_file in same_files_iterator {
+        ctx.add_processed(1);
+        if let Some(reason) = ctx.keep_reason(other_file) {
+            debug!("Keeping {:?}. {}", other_file, reason);
+            continue;
+        }
+        if original_extents.is_some()
+            && extents::shared_extents(other_file).ok().flatten() == original_extents
+        {
+            debug!(
+                "Keeping {:?}. It already shares all its extents with {:?}.",
+                other_file, original_file
+            );
+            continue;
+        }
+        match metadata(other_file) {
+            Ok(other_file_metadata) => {
+                if let Some(changed) = ctx.changed_since_snapshot(other_file, &other_file_metadata)
+                {
+                    ctx.failed_files.add(
+                        other_file,
+                        format!(
+                            "Skipping hardlinking {:?} to {:?}. {}",
+                            original_file, other_file, changed
+                        ),
+                    );
+                    continue;
+                }
+                let (mut targets, unwritable): (Vec<&PathBuf>, Vec<&PathBuf>) = ctx.inode_to_paths
+                    [&file_id(&other_file_metadata)]
+                    .iter()
+                    .partition(|target| parent_dir_writable(target, &mut writable_dirs));
+                if ctx.options.deterministic {
+                    targets.sort();
+                }
+                let frees_inode = frees_inode(&other_file_metadata, targets.len());
+                let saved_bytes = if frees_inode {
+                    allocated_bytes(&other_file_metadata) as usize
+                } else {
+                    0
+                };
+                unwritable_targets.extend(unwritable.into_iter().map(PathBuf::as_path));
+                relinks.push((targets, saved_bytes, frees_inode, other_file_metadata));
+            }
+            Err(err) => {
+                ctx.failed_files.add(
+                    other_file,
+                    format!(
+                        "Failed to hardlink {:?} to {:?}. Failed to fetch its metadata. Error: {}",
+                        original_file, other_file, err
+                    ),
+                );
+            }
+        }
+    }
+    if !unwritable_targets.is_empty() {
+        if ctx.options.deterministic {
+            unwritable_targets.sort();
+        }
+        ctx.unwritable_files += unwritable_targets.len();
+        ctx.emit(Event::SkippedUnwritable {
+            original_file,
+            targets: &unwritable_targets,
+        });
+    }
+    let all_targets: Vec<&Path> = relinks
+        .iter()
+        .flat_map(|(targets, ..)| targets.iter().map(|target| target.as_path()))
+        .collect();
+    if all_targets.is_empty() || ctx.interrupted() {
+        return;
+    }
+    if ctx.options.dry_run {
+        ctx.emit(Event::Planned {
+            original_file,
+            targets: &all_targets,
+            bytes: relinks
+                .iter()
+                .map(|(_, saved_bytes, ..)| *saved_bytes as u64)
+                .sum(),
+            inodes: relinks
+                .iter()
+                .filter(|(.., frees_inode, _)| *frees_inode)
+                .count(),
+        });
+    }
+    if !ctx.confirmed(original_file, &all_targets) {
+        if !ctx.interrupted() {
+            for target in all_targets {
+                ctx.emit(Event::Skipped {
+                    original_file,
+                    target,
+                    reason: SkipReason::Declined,
+                });
+            }
+        }
+        return;
+    }
+    let started = Instant::now();
+    let link_max = (ctx.link_max)(original_file);
+    let mut original_file = original_file;
+    let mut original_links =
+        metadata(original_file).map_or(0, |file_metadata| file_metadata.nlink());
+    for (targets, saved_bytes, _, other_file_metadata) in relinks {
+        if ctx.interrupted() {
+            break;
+        }
+        let Some(first_target) = targets.first() else {
+            continue;
+        };
+        let new_links = targets.len() as u64;
+        // Starting a new original is better than failing to link each of the remaining files.
+        if link_max.is_some_and(|link_max| original_links + new_links > link_max) {
+            info!(
+                "{:?} has as many hardlinks as its filesystem allows. Hardlinking its other duplicates to {:?}.",
+                original_file, first_target
+            );
+            original_file = first_target;
+            original_links = other_file_metadata.nlink();
+            continue;
+        }
+        let snapshot = FileSnapshot::new(&other_file_metadata);
+        let replaced = replace_many_with_hard_link(
+            original_file,
+            targets.iter().copied(),
+            Some(snapshot),
+            ctx,
+        );
+        if replaced.is_empty() {
+            continue;
+        }
+        original_links += replaced.len() as u64;
+        // The file keeps its data blocks while any of its paths wasn't replaced.
+        let saved_bytes = if replaced.len() == targets.len() {
+            saved_bytes
+        } else {
+            0
+        };
+        if let Some(breakdown) = &mut ctx.breakdown {
+            breakdown.add(
+                replaced.iter().map(|target| target.as_path()),
+                saved_bytes as u64,
+            );
+        }
+        ctx.bytes_deduped += saved_bytes;
+        ctx.bytes_linked += other_file_metadata.len();
+    }
+    ctx.clock.add(Stage::Link, started.elapsed());
+}
+
+/// Whether another process has the file open for writing. Files we can't check are assumed not to
+/// be.
+fn open_for_writing(file: &Path) -> bool {
+    is_open_for_writing(file).unwrap_or_else(|err| {
+        debug!(
+            "Failed to check whether {:?} is open for writing. Error: {}",
+            file, err
+        );
+        false
+    })
+}
+
+/// Whether we can replace files in the directory that contains the file. Results are cached per
+/// directory.
+fn parent_dir_writable(file: &Path, writable_dirs: &mut HashMap<PathBuf, bool>) -> bool {
+    let dir = match file.parent() {
+        Some(dir) => dir,
+        None => return true,
+    };
+    *writable_dirs
+        .entry(dir.to_owned())
+        .or_insert_with(|| dir_writable(dir))
+}
+
+fn dir_writable(dir: &Path) -> bool {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(c_dir) => unsafe { libc::access(c_dir.as_ptr(), libc::W_OK | libc::X_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+/// Emits every path that was found, if the renderer renders them.
+fn emit_scanned(ctx: &mut DedupContext) {
+    let scanned = Event::Scanned {
+        file: Path::new(""),
+        bytes: 0,
+    };
+    if !ctx.renderer.renders(&scanned) {
+        return;
+    }
+    for paths in ctx.inode_to_paths.values() {
+        let Some(bytes) = paths
+            .iter()
+            .next()
+            .and_then(|file| metadata(file).ok())
+            .map(|file_metadata| file_metadata.len())
+        else {
+            continue;
+        };
+        for file in paths {
+            ctx.emit(Event::Scanned { file, bytes });
+        }
+    }
+}
+
+/// Emits all paths of the files in a group of files with the same contents.
+fn emit_duplicates(same_files_group: &HashSet<&PathBuf>, ctx: &mut DedupContext) {
+    if !ctx.renderer.renders(&Event::Duplicates {
+        files: &[],
+        bytes: 0,
+    }) {
+        return;
+    }
+    let inode_to_paths = ctx.inode_to_paths;
+    let mut files: Vec<&Path> = same_files_group
+        .iter()
+        .filter_map(|file| metadata(file).ok())
+        // Files replaced since the scan have new inodes.
+        .filter_map(|file_metadata| inode_to_paths.get(&file_id(&file_metadata)))
+        .flatten()
+        .map(PathBuf::as_path)
+        .collect();
+    files.sort();
+    let bytes = group_file_size(same_files_group);
+    ctx.emit(Event::Duplicates {
+        files: &files,
+        bytes,
+    });
+}
+
+/// The metadata of the original, if hardlinking the target to it changes the target's owner, group,
+/// or mode. Only files with the same owner and mode are hardlinked without
+/// [`DedupOptions::ignore_owner`] and [`DedupOptions::ignore_mode`], and reflinks keep the target's.
+fn changed_metadata(
+    original_file: &Path,
+    target: &Path,
+    options: &DedupOptions,
+) -> Option<Metadata> {
+    if !options.ignore_owner && !options.ignore_mode || options.link_mode == LinkMode::Reflink {
+        return None;
+    }
+    let original_metadata = metadata(original_file).ok()?;
+    let target_metadata = metadata(target).ok()?;
+    let ownership = |file_metadata: &Metadata| {
+        (
+            file_metadata.uid(),
+            file_metadata.gid(),
+            file_metadata.mode(),
+        )
+    };
+    (ownership(&original_metadata) != ownership(&target_metadata)).then_some(original_metadata)
+}
+
+fn emit_metadata_changed(
+    original_file: &Path,
+    target: &Path,
+    original_metadata: &Metadata,
+    ctx: &mut DedupContext,
+) {
+    ctx.emit(Event::MetadataChanged {
+        original_file,
+        target,
+        uid: original_metadata.uid(),
+        gid: original_metadata.gid(),
+        mode: original_metadata.mode(),
+        dry_run: ctx.options.dry_run,
+    });
+}
+
+/// Replaces the targets, all paths of the same file, with hardlinks to the original file. With
+/// `expected`, they're only replaced if they're still the file as it was compared. Returns the
+/// targets that were replaced, or would be in a dry run. Skipped and failed targets aren't.
+fn replace_many_with_hard_link<'a>(
+    original_file: &Path,
+    targets: impl Iterator<Item = &'a PathBuf>,
+    expected: Option<FileSnapshot>,
+    ctx: &mut DedupContext<'a>,
+) -> Vec<&'a PathBuf> {
+    let mut replaced_targets = Vec::new();
+    for target in targets {
+        if ctx.interrupted() {
+            break;
+        }
+        ctx.current_file = Some(target);
+        let now = SystemTime::now();
+        if modified_recently(original_file, ctx.options, now)
+            || modified_recently(target, ctx.options, now)
+        {
+            ctx.emit(Event::Skipped {
+                original_file,
+                target,
+                reason: SkipReason::ModifiedRecently,
+            });
+            continue;
+        }
+        if modified_outside_time_window(original_file, ctx.options)
+            || modified_outside_time_window(target, ctx.options)
+        {
+            ctx.emit(Event::Skipped {
+                original_file,
+                target,
+                reason: SkipReason::OutsideTimeWindow,
+            });
+            continue;
+        }
+        if ctx.options.skip_open_files
+            && (open_for_writing(original_file) || open_for_writing(target))
+        {
+            ctx.emit(Event::Skipped {
+                original_file,
+                target,
+                reason: SkipReason::OpenForWriting,
+            });
+            continue;
+        }
+        if !has_room_for_link(target, ctx) {
+            ctx.emit(Event::Skipped {
+                original_file,
+                target,
+                reason: SkipReason::FilesystemFull,
+            });
+            continue;
+        }
+        let changed_metadata = changed_metadata(original_file, target, ctx.options);
+        if ctx.options.dry_run {
+            ctx.linked_dirs.insert(common_dir(original_file, target));
+            ctx.emit(Event::Hardlinked {
+                original_file,
+                target,
+                dry_run: true,
+            });
+            if let Some(original_metadata) = changed_metadata {
+                emit_metadata_changed(original_file, target, &original_metadata, ctx);
+            }
+            replaced_targets.push(target);
+            continue;
+        }
+        let journal_entry = match &mut ctx.journal {
+            Some(journal) => match journal.entry(original_file, target) {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    ctx.failed_files.add(
+                        target,
+                        format!(
+                            "Skipping hardlinking {:?} to {:?}. Failed to prepare its journal entry. Error: {}",
+                            original_file, target, err
+                        ),
+                    );
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let (follow_symlinks, preserve_dir_mtimes, fsync) = (
+            ctx.options.follow_symlinks,
+            ctx.options.preserve_dir_mtimes,
+            ctx.options.fsync,
+        );
+        let mut link = Link::from(ctx.options.link_mode);
+        let mut replaced = replace_with_hard_link(
+            original_file,
+            target,
+            expected.as_ref(),
+            follow_symlinks,
+            link,
+            preserve_dir_mtimes,
+            fsync,
+        );
+        let fallback = replaced
+            .as_ref()
+            .err()
+            .and_then(|err| fallback_for(err, ctx.options));
+        if let (Some(fallback), Err(err)) = (fallback, &replaced) {
+            info!(
+                "Failed to hardlink {:?} to {:?}, so replacing it with a {} instead. {}",
+                original_file,
+                target,
+                fallback.noun(),
+                err
+            );
+            link = fallback;
+            replaced = replace_with_hard_link(
+                original_file,
+                target,
+                expected.as_ref(),
+                follow_symlinks,
+                link,
+                preserve_dir_mtimes,
+                fsync,
+            );
+            if replaced.is_ok() {
+                ctx.fallback_links += 1;
+            }
+        }
+        match replaced {
+            Ok(_) => {
+                ctx.linked_dirs.insert(common_dir(original_file, target));
+                if let (Some(journal), Some(entry)) = (&mut ctx.journal, journal_entry) {
+                    if let Err(err) = journal.record(&entry) {
+                        warn!(
+                            "Failed to record hardlinking {:?} to {:?} in the journal. Error: {}",
+                            original_file, target, err
+                        );
+                    }
+                }
+                ctx.emit(Event::Hardlinked {
+                    original_file,
+                    target,
+                    dry_run: false,
+                });
+                // Reflinks keep the metadata of the files they replace.
+                if let Some(original_metadata) = changed_metadata.filter(|_| link != Link::Reflink)
+                {
+                    emit_metadata_changed(original_file, target, &original_metadata, ctx);
+                }
+                replaced_targets.push(target);
+            }
+            Err(err) => {
+                match err {
+                    LinkError::Source(_) => ctx.link_failures.source += 1,
+                    LinkError::TempLink(..) => ctx.link_failures.temp_link += 1,
+                    LinkError::Rename { .. } => ctx.link_failures.rename += 1,
+                    LinkError::Changed { .. } => ctx.link_failures.changed += 1,
+                    LinkError::Sync(_) => ctx.link_failures.sync += 1,
+                }
+                ctx.failed_files.add(
+                    target,
+                    format!(
+                        "Failed to hardlink {:?} to {:?}. {}",
+                        original_file, target, err
+                    ),
+                )
+            }
+        }
+    }
+    replaced_targets
+}
+
+/// What replaces a duplicate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Link {
+    Hardlink,
+    Reflink,
+    Symlink,
+}
+
+impl Link {
+    fn noun(self) -> &'static str {
+        match self {
+            Link::Hardlink => "hardlink",
+            Link::Reflink => "reflink",
+            Link::Symlink => "symlink",
+        }
+    }
+}
+
+impl From<LinkMode> for Link {
+    fn from(link_mode: LinkMode) -> Link {
+        match link_mode {
+            LinkMode::Hardlink => Link::Hardlink,
+            LinkMode::Reflink => Link::Reflink,
+        }
+    }
+}
+
+/// What replaces the target instead, with [`DedupOptions::fallback`], after hardlinking it failed
+/// because it's on another mount than the original or the kernel refused to link to the original.
+fn fallback_for(err: &LinkError, options: &DedupOptions) -> Option<Link> {
+    let (LinkError::Source(err) | LinkError::TempLink(_, err)) = err else {
+        return None;
+    };
+    if options.link_mode != LinkMode::Hardlink
+        || !matches!(err.raw_os_error(), Some(libc::EXDEV | libc::EPERM))
+    {
+        return None;
+    }
+    match options.fallback {
+        Fallback::None => None,
+        Fallback::Reflink => Some(Link::Reflink),
+        Fallback::Symlink => Some(Link::Symlink),
+    }
+}
+
+/// Which step of replacing a file with a hardlink failed.
+#[derive(Debug)]
+enum LinkError {
+    /// We can't access the file we're linking to, or aren't allowed to link to it (e.g. because of
+    /// `fs.protected_hardlinks`).
+    Source(io::Error),
+    /// We can't create the temporary hardlink in the target's directory.
+    TempLink(PathBuf, io::Error),
+    /// We can't rename the temporary hardlink over the target.
+    Rename {
+        tmp_file: PathBuf,
+        err: io::Error,
+        cleanup_err: Option<io::Error>,
+    },
+    /// The target isn't the file we compared anymore.
+    Changed {
+        tmp_file: PathBuf,
+        cleanup_err: Option<io::Error>,
+    },
+    /// The target was replaced, but we can't flush the replacement to disk.
+    Sync(io::Error),
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::Source(err) => write!(
+                formatter,
+                "Failed to access the source file. Error: {}",
+                err
+            ),
+            LinkError::TempLink(tmp_file, err) => write!(
+                formatter,
+                "Failed to create temporary link at {:?}. Error: {}",
+                tmp_file, err
+            ),
+            LinkError::Rename {
+                tmp_file,
+                err,
+                cleanup_err,
+            } => {
+                write!(
+                    formatter,
+                    "Failed to replace the target file with temporary hardlink {:?}. Error: {}",
+                    tmp_file, err
+                )?;
+                write_cleanup_err(formatter, cleanup_err)
+            }
+            LinkError::Changed {
+                tmp_file,
+                cleanup_err,
+            } => {
+                write!(
+                    formatter,
+                    "The target file changed since it was compared, so temporary hardlink {:?} was discarded.",
+                    tmp_file
+                )?;
+                write_cleanup_err(formatter, cleanup_err)
+            }
+            LinkError::Sync(err) => write!(
+                formatter,
+                "The target file was replaced, but flushing it to disk failed. Error: {}",
+                err
+            ),
+        }
+    }
+}
+
+fn write_cleanup_err(
+    formatter: &mut std::fmt::Formatter<'_>,
+    cleanup_err: &Option<io::Error>,
+) -> std::fmt::Result {
+    match cleanup_err {
+        Some(cleanup_err) => write!(
+            formatter,
+            " Also failed to delete the temporary hardlink. Error: {}",
+            cleanup_err
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Replaces the target with a hardlink to the original file, a reflink of it, or a symlink to its
+/// absolute path. With `expected`, the target is only replaced if it's still that file when it's
+/// about to be replaced. The target's directory may only be a symlink with `follow_symlinks` or
+/// `expected`, like the paths given to us are, since only the file we compared is replaced then
+/// wherever the symlink points. With `preserve_dir_times`, the directory keeps its access and
+/// modification times whether the target was replaced or not. With `fsync`, the replacement and
+/// the directory are flushed to disk before returning, so that the replacement survives a crash.
+fn replace_with_hard_link(
+    original_file: &Path,
+    target: &Path,
+    expected: Option<&FileSnapshot>,
+    follow_symlinks: bool,
+    link: Link,
+    preserve_dir_times: bool,
+    fsync: bool,
+) -> Result<(), LinkError> {
+    metadata(original_file).map_err(LinkError::Source)?;
+    let tmp_name = OsString::from(Uuid::new_v4().to_string());
+    let (dir, name) = match (target.parent(), target.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "It isn't a file in a directory.",
+            );
+            return Err(LinkError::TempLink(target.to_owned(), err));
+        }
+    };
+    let tmp_file = dir.join(&tmp_name);
+    let dir = DirHandle::open(dir, follow_symlinks)
+        .or_else(|err| match err.raw_os_error() {
+            // Opening a symlink without following it fails with either.
+            Some(libc::ELOOP | libc::ENOTDIR) if expected.is_some() => DirHandle::open(dir, true),
+            _ => Err(err),
+        })
+        .map_err(|err| LinkError::TempLink(tmp_file.clone(), err))?;
+    let dir_times = if preserve_dir_times {
+        dir.times()
+            .inspect_err(|err| {
+                warn!(
+                    "Failed to read the times of {:?} to preserve them. Error: {}",
+                    target.parent().unwrap_or(target),
+                    err
+                )
+            })
+            .ok()
+    } else {
+        None
+    };
+    let replaced = replace_in_dir(
+        &dir,
+        original_file,
+        name,
+        &tmp_name,
+        tmp_file,
+        expected,
+        link,
+    );
+    if let Some(dir_times) = dir_times {
+        if let Err(err) = dir.set_times(dir_times) {
+            warn!(
+                "Failed to restore the times of {:?}. Error: {}",
+                target.parent().unwrap_or(target),
+                err
+            );
+        }
+    }
+    if fsync && replaced.is_ok() {
+        // A symlink can't be opened to flush it, but it's written along with its directory.
+        if link != Link::Symlink {
+            dir.sync_file(name).map_err(LinkError::Sync)?;
+        }
+        dir.sync().map_err(LinkError::Sync)?;
+    }
+    replaced
+}
+
+/// Creates the link to the original file named `tmp_name` in the directory and renames it over the
+/// file `name`, unless that file isn't `expected` anymore.
+fn replace_in_dir(
+    dir: &DirHandle,
+    original_file: &Path,
+    name: &OsStr,
+    tmp_name: &OsStr,
+    tmp_file: PathBuf,
+    expected: Option<&FileSnapshot>,
+    link: Link,
+) -> Result<(), LinkError> {
+    let linked = match link {
+        Link::Hardlink => dir.hard_link(original_file, tmp_name),
+        Link::Reflink => reflink_as(dir, original_file, name, tmp_name, &tmp_file),
+        Link::Symlink => {
+            std::path::absolute(original_file).and_then(|path| dir.symlink(&path, tmp_name))
+        }
+    };
+    linked.map_err(|err| {
+        if err.raw_os_error() == Some(libc::EPERM) && link == Link::Hardlink {
+            // The kernel refuses to link to files we don't own and can't read and write.
+            LinkError::Source(err)
+        } else {
+            LinkError::TempLink(tmp_file.clone(), err)
+        }
+    })?;
+    if let Some(expected) = expected {
+        let current = dir
+            .symlink_metadata(name)
+            .map(|stat| FileSnapshot::from_stat(&stat));
+        if current.ok().as_ref() != Some(expected) {
+            return Err(LinkError::Changed {
+                cleanup_err: dir.remove_file(tmp_name).err(),
+                tmp_file,
+            });
+        }
+    }
+    dir.rename(tmp_name, name).map_err(|err| LinkError::Rename {
+        cleanup_err: dir.remove_file(tmp_name).err(),
+        tmp_file,
+        err,
+    })
+}
+
+/// Creates a reflink of the original named `tmp_name` in the directory, with the owner, mode,
+/// timestamps, and extended attributes of the file `name` it's about to replace.
+fn reflink_as(
+    dir: &DirHandle,
+    original_file: &Path,
+    name: &OsStr,
+    tmp_name: &OsStr,
+    tmp_file: &Path,
+) -> io::Result<()> {
+    let replaced = dir.symlink_metadata(name)?;
+    let clone = dir.reflink(original_file, tmp_name)?;
+    take_metadata(&clone, &tmp_file.with_file_name(name), &replaced).inspect_err(|_| {
+        let _ = dir.remove_file(tmp_name);
+    })
+}
+
+/// Identifies an inode by its device and inode number. Inode numbers are only unique within a device.
+type FileId = (u64, u64);
+
+/// A file as it was compared: its inode, size, and modification time. A file that still matches was
+/// neither replaced nor written to since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileSnapshot {
+    file_id: FileId,
+    size: u64,
+    mtime: (i64, i64),
+}
+
+impl FileSnapshot {
+    fn new(file_metadata: &Metadata) -> FileSnapshot {
+        FileSnapshot {
+            file_id: file_id(file_metadata),
+            size: file_metadata.len(),
+            mtime: (file_metadata.mtime(), file_metadata.mtime_nsec()),
+        }
+    }
+
+    fn from_stat(stat: &libc::stat) -> FileSnapshot {
+        FileSnapshot {
+            file_id: (stat.st_dev, stat.st_ino),
+            size: stat.st_size as u64,
+            mtime: (stat.st_mtime, stat.st_mtime_nsec),
+        }
+    }
+}
+
+fn file_id(file_metadata: &Metadata) -> FileId {
+    (file_metadata.dev(), file_metadata.ino())
+}
+
+fn find_inode_groups(
+    paths: &[PathBuf],
+    options: &DedupOptions,
+    failed_files: &mut FailedFiles,
+) -> HashMap<FileId, HashSet<PathBuf>> {
+    let mut inode_to_paths = HashMap::new();
+    scan_paths(paths, options, &mut inode_to_paths, failed_files);
+    inode_to_paths
+}
+
+/// Where scanning puts the files it finds.
+trait FileSink: Send {
+    fn add(&mut self, file_id: FileId, size: u64, path: PathBuf);
+}
+
+impl FileSink for HashMap<FileId, HashSet<PathBuf>> {
+    fn add(&mut self, file_id: FileId, _size: u64, path: PathBuf) {
+        self.entry(file_id).or_default().insert(path);
+    }
+}
+
+/// Adds the files under the paths to the sink.
+fn scan_paths(
+    paths: &[PathBuf],
+    options: &DedupOptions,
+    sink: &mut dyn FileSink,
+    failed_files: &mut FailedFiles,
+) {
+    let _span = info_span!("scan").entered();
+    let scan_time = SystemTime::now();
+    for path in paths {
+        let mut stale_paths = Vec::new();
+        if options.jobs > 1 {
+            scan_path_parallel(
+                path,
+                options.jobs,
+                options,
+                scan_time,
+                sink,
+                failed_files,
+                &mut stale_paths,
+            );
+        } else {
+            scan_path(
+                path,
+                options.max_depth,
+                options,
+                scan_time,
+                sink,
+                failed_files,
+                Some(&mut stale_paths),
+                &Mutex::new(HashSet::new()),
+            );
+        }
+        for (stale_path, depth) in stale_paths {
+            info!(
+                "Retrying {:?} after its file handle went stale.",
+                stale_path
+            );
+            scan_path(
+                &stale_path,
+                options
+                    .max_depth
+                    .map(|max_depth| max_depth.saturating_sub(depth)),
+                options,
+                scan_time,
+                sink,
+                failed_files,
+                None,
+                &Mutex::new(HashSet::new()),
+            );
+        }
+    }
+}
+
+/// Whether the scan that started at `scan_time` took all of [`DedupOptions::max_duration`]. The
+/// deduplication that follows stops right away then too.
+fn out_of_time(options: &DedupOptions, scan_time: SystemTime) -> bool {
+    options
+        .max_duration
+        .is_some_and(|max| scan_time.elapsed().is_ok_and(|elapsed| elapsed >= max))
+}
+
+/// Adds the files under the path to the sink. Paths whose file handles go stale (e.g. on
+/// busy NFS exports) are added to `stale_paths` with their depth so they can be retried once. Without
+/// `stale_paths` they are skipped together with everything in them. Directories in `visited_dirs`
+/// aren't walked again when following symlinks.
+#[allow(clippy::too_many_arguments)]
+fn scan_path(
+    path: &Path,
+    max_depth: Option<usize>,
+    options: &DedupOptions,
+    scan_time: SystemTime,
+    sink: &mut dyn FileSink,
+    failed_files: &mut FailedFiles,
+    mut stale_paths: Option<&mut Vec<(PathBuf, usize)>>,
+    visited_dirs: &Mutex<HashSet<FileId>>,
+) {
+    for file in find_files(path, max_depth, options, visited_dirs) {
+        if options.interrupted.load(Ordering::Relaxed) || out_of_time(options, scan_time) {
+            break;
+        }
+        let file = match file {
+            Ok(file) => file,
+            // Symlinks to an ancestor directory are expected when following symlinks.
+            Err(err) if err.loop_ancestor().is_some() => {
+                debug!("Skipping {:?}. {}", err.path().unwrap_or(path), err);
+                continue;
+            }
+            Err(err) if is_stale(&err) => {
+                let stale_path = err.path().unwrap_or(path);
+                match stale_paths.as_deref_mut() {
+                    Some(stale_paths) => stale_paths.push((stale_path.to_owned(), err.depth())),
+                    None => {
+                        failed_files.add(
+                            stale_path,
+                            format!(
+                                "Skipping {:?} and everything in it. Its file handle went stale again. Error: {}",
+                                stale_path, err
+                            ),
+                        );
+                    }
+                }
+                continue;
+            }
+            Err(err) => {
+                let path = err.path().unwrap_or(path);
+                failed_files.add(path, format!("Skipping {:?}. Error: {}", path, err));
+                continue;
+            }
+        };
+        scan_file(&file, options, scan_time, sink, failed_files);
+    }
+}
+
+/// Adds the file found by a walk to the sink, unless the options leave it out.
+fn scan_file(
+    file: &DirEntry,
+    options: &DedupOptions,
+    scan_time: SystemTime,
+    sink: &mut dyn FileSink,
+    failed_files: &mut FailedFiles,
+) {
+    if modified_recently(file.path(), options, scan_time) {
+        info!(
+            "Skipping file {:?}. It was modified less than {:?} ago.",
+            file.path(),
+            options.min_age.unwrap_or_default()
+        );
+        return;
+    }
+    if modified_outside_time_window(file.path(), options) {
+        info!(
+            "Skipping file {:?}. It was modified outside the time window being deduplicated.",
+            file.path()
+        );
+        return;
+    }
+    let file_metadata = match file.metadata() {
+        Ok(file_metadata) => file_metadata,
+        Err(err) => {
+            failed_files.add(
+                file.path(),
+                format!(
+                    "Skipping file {:?}. Failed to fetch its metadata. Error: {}",
+                    file.path(),
+                    err
+                ),
+            );
+            return;
+        }
+    };
+    let file_path = match resolve_symlinked_file(file) {
+        Ok(file_path) => file_path,
+        Err(err) => {
+            failed_files.add(
+                file.path(),
+                format!(
+                    "Skipping file {:?}. Failed to resolve the symlink. Error: {}",
+                    file.path(),
+                    err
+                ),
+            );
+            return;
+        }
+    };
+    if is_own_file(&file_path, options) {
+        info!("Skipping file {:?}. It's written by this run.", file.path());
+        return;
+    }
+    sink.add(file_id(&file_metadata), file_metadata.len(), file_path);
+}
+
+/// Whether the error is a stale file handle, which NFS returns for files and directories removed or
+/// replaced on the server.
+fn is_stale(err: &walkdir::Error) -> bool {
+    err.io_error()
+        .and_then(io::Error::raw_os_error)
+        .is_some_and(|errno| errno == libc::ESTALE)
+}
+
+/// Whether the file was modified less than `min_age` before `now`. Files with modification times in
+/// the future count as recently modified.
+fn modified_recently(file: &Path, options: &DedupOptions, now: SystemTime) -> bool {
+    let min_age = match options.min_age {
+        Some(min_age) => min_age,
+        None => return false,
+    };
+    match metadata(file).and_then(|m| m.modified()) {
+        Ok(mtime) => now.duration_since(mtime).map_or(true, |age| age < min_age),
+        Err(_) => false,
+    }
+}
+
+/// Whether the file was modified after `exclude_newer_than` or before `exclude_older_than`.
+fn modified_outside_time_window(file: &Path, options: &DedupOptions) -> bool {
+    if options.exclude_newer_than.is_none() && options.exclude_older_than.is_none() {
+        return false;
+    }
+    match metadata(file).and_then(|m| m.modified()) {
+        Ok(mtime) => {
+            options
+                .exclude_newer_than
+                .is_some_and(|newest| mtime > newest)
+                || options
+                    .exclude_older_than
+                    .is_some_and(|oldest| mtime < oldest)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Whether the file is the journal, the checkpoint, or one of [`DedupOptions::own_files`].
+fn is_own_file(file: &Path, options: &DedupOptions) -> bool {
+    let mut own_files = options
+        .journal
+        .iter()
+        .chain(&options.checkpoint)
+        .chain(&options.resume)
+        .chain(&options.own_files);
+    own_files.any(|own_file| {
+        own_file.file_name() == file.file_name() && canonical_dir(own_file) == canonical_dir(file)
+    })
+}
+
+/// The canonical path of the file's directory. The file itself may not exist yet.
+fn canonical_dir(file: &Path) -> Option<PathBuf> {
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => canonicalize(dir).ok(),
+        _ => canonicalize(".").ok(),
+    }
 }
 
-fn find_inode_groups(paths: &Vec<PathBuf>) -> HashMap<u64, HashSet<PathBuf>> {
-    let mut inode_to_paths = HashMap::new();
-    for path in paths {
-        for file in find_files(path) {
-            let same_inode_files = inode_to_paths
-                .entry(file.ino())
-                .or_insert_with(|| HashSet::new());
-            same_inode_files.insert(file.path().to_owned());
-        }
+/// The path of a found file. Symlinks are resolved so that we replace the file they point to rather
+/// than the symlink itself.
+fn resolve_symlinked_file(file: &DirEntry) -> io::Result<PathBuf> {
+    if file.path_is_symlink() {
+        canonicalize(file.path())
+    } else {
+        Ok(file.path().to_owned())
     }
-    inode_to_paths
 }
 
-fn find_files(path: &Path) -> impl Iterator<Item = DirEntry> {
+fn find_files<'a>(
+    path: &Path,
+    max_depth: Option<usize>,
+    options: &DedupOptions,
+    visited_dirs: &'a Mutex<HashSet<FileId>>,
+) -> impl Iterator<Item = walkdir::Result<DirEntry>> + 'a {
+    let follow_symlinks = options.follow_symlinks;
+    let honor_cachedir_tags = options.honor_cachedir_tags;
     WalkDir::new(path)
+        .same_file_system(options.one_file_system)
+        .follow_links(follow_symlinks)
+        .max_depth(max_depth.unwrap_or(usize::MAX))
         .into_iter()
-        .flatten()
-        .filter(|entry| entry.file_type().is_file())
+        .filter_entry(move |entry| {
+            if !entry.file_type().is_dir() {
+                return true;
+            }
+            if honor_cachedir_tags {
+                if let Some(marker) = opt_out_marker(entry.path()) {
+                    info!(
+                        "Skipping {:?} and everything in it. It has a {}.",
+                        entry.path(),
+                        marker
+                    );
+                    return false;
+                }
+            }
+            // Symlinks can lead into the same directory through many paths or even form loops.
+            if !follow_symlinks {
+                return true;
+            }
+            match entry.metadata() {
+                Ok(dir_metadata) => visited_dirs.lock().unwrap().insert(file_id(&dir_metadata)),
+                Err(_) => true,
+            }
+        })
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .map_or(true, |entry| entry.file_type().is_file())
+        })
+}
+
+/// The marker file that opts the directory out of deduplication, if it has one: a `.nodedup` file,
+/// or a `CACHEDIR.TAG` with the signature of the Cache Directory Tagging Specification.
+pub(crate) fn opt_out_marker(dir: &Path) -> Option<&'static str> {
+    if symlink_metadata(dir.join(".nodedup")).is_ok() {
+        return Some(".nodedup file");
+    }
+    let mut signature = [0; CACHEDIR_TAG_SIGNATURE.len()];
+    File::open(dir.join("CACHEDIR.TAG"))
+        .and_then(|mut tag| tag.read_exact(&mut signature))
+        .ok()
+        .filter(|()| &signature == CACHEDIR_TAG_SIGNATURE)
+        .map(|()| "CACHEDIR.TAG")
+}
+
+/// Groups files by their name, see [`DedupOptions::same_name_only`]. Files with several paths are
+/// grouped by the name of the path they were found through first.
+fn same_name_groups<'a>(
+    files: impl Iterator<Item = &'a PathBuf>,
+) -> impl Iterator<Item = HashSet<&'a PathBuf>> {
+    group_by(files, |file| file.file_name())
+}
+
+/// Sorts the groups by their first path with [`DedupOptions::deterministic`].
+fn ordered<'a>(
+    mut groups: Vec<HashSet<&'a PathBuf>>,
+    options: &DedupOptions,
+) -> Vec<HashSet<&'a PathBuf>> {
+    if options.deterministic {
+        groups.sort_by_cached_key(|group| group.iter().min().copied());
+    }
+    groups
 }
 
 fn group_by<'a, TKey>(
     unrefined_group: impl Iterator<Item = &'a PathBuf>,
-    to_key: fn(&'a PathBuf) -> Option<TKey>,
-) -> impl Iterator<Item = HashSet<&'a PathBuf>>
+    mut to_key: impl FnMut(&'a PathBuf) -> Option<TKey>,
+) -> hash_map::IntoValues<TKey, HashSet<&'a PathBuf>>
 where
     TKey: std::cmp::Eq + std::hash::Hash,
 {
     let mut groups = HashMap::new();
     for file in unrefined_group {
         if let Some(key) = to_key(file) {
-            let group = groups.entry(key).or_insert_with(|| HashSet::new());
+            let group = groups.entry(key).or_insert_with(HashSet::new);
             group.insert(file);
         }
     }
     groups.into_values()
 }
 
+/// The device, size, owner, mode, and modification time of the file. The owner and mode are left
+/// out, and the modification time is only included, as the key options say.
+fn metadata_key(file_metadata: &Metadata, key_options: KeyOptions) -> GroupKey {
+    let (gid, uid) = if key_options.ignore_owner {
+        (0, 0)
+    } else {
+        (file_metadata.gid(), file_metadata.uid())
+    };
+    // The file type is kept, so nothing but regular files is ever grouped with them.
+    let mode = if key_options.ignore_mode {
+        file_metadata.mode() & libc::S_IFMT
+    } else {
+        file_metadata.mode()
+    };
+    let mtime = if key_options.require_same_mtime {
+        file_metadata.mtime() * 1_000_000_000 + file_metadata.mtime_nsec()
+    } else {
+        0
+    };
+    let allocated = if key_options.group_by_allocated_size {
+        file_metadata.blocks()
+    } else {
+        0
+    };
+    (
+        file_metadata.dev(),
+        file_metadata.len(),
+        gid,
+        uid,
+        mode,
+        mtime,
+        allocated,
+    )
+}
+
+/// The key of a group of files that share their device, size, owner, and mode.
+fn group_key(group: &HashSet<&PathBuf>, key_options: KeyOptions) -> Option<GroupKey> {
+    let file = group.iter().next()?;
+    metadata(file)
+        .ok()
+        .map(|file_metadata| metadata_key(&file_metadata, key_options))
+}
+
 fn same_metadata_groups<'a>(
     files: impl Iterator<Item = &'a PathBuf>,
+    key_options: KeyOptions,
+    failed_files: &mut FailedFiles,
 ) -> impl Iterator<Item = HashSet<&'a PathBuf>> {
-    group_by(files, |file| {
+    group_by(files, move |file| {
         metadata(file)
-            .map(|m| (m.len(), m.gid(), m.uid(), m.mode()))
+            .map(|file_metadata| metadata_key(&file_metadata, key_options))
             .map_err(|err| {
-                warn!(
-                    "Skipping file {:?}. Failed to fetch its metadata. Error: {}",
-                    file, err
+                failed_files.add(
+                    file,
+                    format!(
+                        "Skipping file {:?}. Failed to fetch its metadata. Error: {}",
+                        file, err
+                    ),
                 )
             })
             .ok()
@@ -247,77 +3252,395 @@ fn same_metadata_groups<'a>(
 
 fn same_prefix_groups<'a>(
     files: HashSet<&'a PathBuf>,
+    prefix_bytes: u64,
+    read_options: ReadOptions,
+    failed_files: &mut FailedFiles,
 ) -> impl Iterator<Item = HashSet<&'a PathBuf>> {
-    group_by(files.into_iter(), |file| {
-        read_prefix(file)
+    let files: Vec<&PathBuf> = files.into_iter().collect();
+    let mut prefixes = read_prefixes(&files, prefix_bytes, read_options).into_iter();
+    group_by(files.into_iter(), move |file| {
+        prefixes
+            .next()?
             .map_err(|err| {
-                warn!(
-                    "Skipping file {:?}. Failed to read its first few bytes. Error: {}",
-                    file, err
+                failed_files.add(
+                    file,
+                    format!(
+                        "Skipping file {:?}. Failed to read its first few bytes. Error: {}",
+                        file, err
+                    ),
                 )
             })
             .ok()
     })
 }
 
-fn same_hash_groups<'a>(files: HashSet<&'a PathBuf>) -> impl Iterator<Item = HashSet<&'a PathBuf>> {
-    group_by(files.into_iter(), |file| {
-        calculate_hash(file)
-            .map_err(|err| {
-                warn!(
-                    "Skipping file {:?}. Failed to calculate its hash. Error: {}",
-                    file, err
-                )
+fn same_hash_groups<'a>(
+    files: HashSet<&'a PathBuf>,
+    hash_pool: Option<&mut HashPool>,
+    prefetcher: Option<&Prefetcher>,
+    options: &DedupOptions,
+    failed_files: &mut FailedFiles,
+) -> impl Iterator<Item = HashSet<&'a PathBuf>> {
+    let mut unhashed_files = Vec::new();
+    let chunked = group_file_size(&files) >= CHUNKED_HASH_THRESHOLD;
+    // Hash workers exist to keep files that crash or hang their readers out of this process, so we
+    // don't read the files they failed on here.
+    let retry_unhashed = chunked || hash_pool.is_none();
+    let groups = if chunked {
+        same_chunked_hash_groups(
+            files,
+            options.hash,
+            options.read_options(),
+            FIRST_CHUNK_BYTES,
+            &mut unhashed_files,
+        )
+    } else {
+        let files: Vec<&PathBuf> = files.into_iter().collect();
+        let mut hashes = match prefetcher {
+            Some(prefetcher) => {
+                prefetcher.hashes(&files, |files| calculate_hashes(files, hash_pool, options))
+            }
+            None => calculate_hashes(&files, hash_pool, options),
+        }
+        .into_iter();
+        group_by(files.into_iter(), |file| {
+            hashes
+                .next()?
+                .map_err(|err| {
+                    unhashed_files.push(file);
+                    warn!("Failed to calculate the hash of {:?}. Error: {}", file, err)
+                })
+                .ok()
+        })
+        .collect()
+    };
+    if retry_unhashed {
+        add_by_comparison(groups, unhashed_files, options.read_options(), failed_files)
+    } else {
+        for file in unhashed_files {
+            failed_files.add(
+                file,
+                format!("Skipping file {:?}. Failed to calculate its hash.", file),
+            );
+        }
+        groups
+    }
+    .into_iter()
+}
+
+/// Compares files we failed to hash byte by byte with a file of each group and adds them to the
+/// group of the first one they're the same as. Files that are the same as none of them get their
+/// own groups. Files that we fail to compare too are skipped.
+fn add_by_comparison<'a>(
+    mut groups: Vec<HashSet<&'a PathBuf>>,
+    unhashed_files: Vec<&'a PathBuf>,
+    read_options: ReadOptions,
+    failed_files: &mut FailedFiles,
+) -> Vec<HashSet<&'a PathBuf>> {
+    'files: for file in unhashed_files {
+        for group in &mut groups {
+            let representative = *group.iter().next().unwrap();
+            match are_files_same(file, representative, read_options) {
+                Ok(true) => {
+                    warn!(
+                        "Compared {:?} byte by byte instead of by its hash. It's the same as {:?}.",
+                        file, representative
+                    );
+                    group.insert(file);
+                    continue 'files;
+                }
+                Ok(false) => (),
+                Err(err) => {
+                    failed_files.add(
+                        file,
+                        format!(
+                            "Skipping file {:?}. Failed to calculate its hash or compare it with {:?}. Error: {}",
+                            file, representative, err
+                        ),
+                    );
+                    continue 'files;
+                }
+            }
+        }
+        warn!(
+            "Compared {:?} byte by byte instead of by its hash. Its contents are unique.",
+            file
+        );
+        groups.push(HashSet::from([file]));
+    }
+    groups
+}
+
+/// Calculates hashes of the given files in the same order as the files. The hash pool's workers
+/// hash with the algorithm they were started with.
+fn calculate_hashes(
+    files: &[&PathBuf],
+    hash_pool: Option<&mut HashPool>,
+    options: &DedupOptions,
+) -> Vec<io::Result<Vec<u8>>> {
+    calculate_hashes_on(files, files_medium(files), hash_pool, options)
+}
+
+/// Calculates hashes of the files on the medium, reading them the way that suits it (see
+/// [`devices`]).
+fn calculate_hashes_on(
+    files: &[&PathBuf],
+    medium: Medium,
+    hash_pool: Option<&mut HashPool>,
+    options: &DedupOptions,
+) -> Vec<io::Result<Vec<u8>>> {
+    let read_options = options.read_options();
+    if medium == Medium::Rotational {
+        // One file at a time in the order of their inodes, and then back in the order of the files.
+        let order = inode_order(files);
+        let ordered_files: Vec<&PathBuf> = order.iter().map(|index| files[*index]).collect();
+        let hashes = match hash_pool {
+            Some(hash_pool) => hash_pool.hash_files(&ordered_files, Some(1)),
+            None => ordered_files
+                .iter()
+                .map(|file| calculate_hash(file, options.hash, read_options))
+                .collect(),
+        };
+        let mut hashes: Vec<_> = order.into_iter().zip(hashes).collect();
+        hashes.sort_by_key(|(index, _)| *index);
+        return hashes.into_iter().map(|(_, hash)| hash).collect();
+    }
+    #[cfg(feature = "io-uring")]
+    if hash_pool.is_none()
+        && options.max_parallel_hashes.is_none()
+        && read_options.io_uring
+        && files.len() >= uring::MIN_FILES
+        && uring::available()
+    {
+        match uring::hash_files(files, options.hash, read_options) {
+            Ok(hashes) => return hashes,
+            Err(err) => warn!(
+                "Failed to read files through io_uring. Reading them one at a time instead. Error: {}",
+                err
+            ),
+        }
+    }
+    let readers = hash_readers(medium, options.max_parallel_hashes);
+    match hash_pool {
+        Some(hash_pool) => hash_pool.hash_files(files, options.max_parallel_hashes),
+        None if readers > 1 && files.len() > 1 => {
+            let chunk_size = files.len().div_ceil(readers);
+            std::thread::scope(|scope| {
+                let readers: Vec<_> = files
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            chunk
+                                .iter()
+                                .map(|file| calculate_hash(file, options.hash, read_options))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                readers
+                    .into_iter()
+                    .flat_map(|reader| reader.join().unwrap())
+                    .collect()
             })
-            .ok()
-    })
+        }
+        None => files
+            .iter()
+            .map(|file| calculate_hash(file, options.hash, read_options))
+            .collect(),
+    }
+}
+
+/// How many files are compared at once at most, each with a reader and a chunk in memory.
+const MAX_COMPARE_READERS: usize = 16;
+
+/// Splits the files into groups with the same contents. Files are read a chunk at a time, and a
+/// group splits where the chunks of its files differ, so each file is read only once however many
+/// files it's compared to. Larger groups are compared [`MAX_COMPARE_READERS`] files at a time.
+/// Files that fail to open or read are left out and counted in `failed_files`. On spinning disks
+/// the files are compared one after another with the first one, in the order of their inodes and
+/// in larger chunks.
+fn same_content_groups<'a>(
+    files: &HashSet<&'a PathBuf>,
+    read_options: ReadOptions,
+    failed_files: &mut FailedFiles,
+) -> Vec<HashSet<&'a PathBuf>> {
+    compare_in_batches(files, read_options, MAX_COMPARE_READERS, failed_files)
 }
 
-fn same_content_groups<'a>(files: &HashSet<&'a PathBuf>) -> Vec<HashSet<&'a PathBuf>> {
-    let mut files_remaining = files.clone();
+/// Compares the files at most `max_readers` at a time. With more files than that, the first file
+/// is compared with batches of the others, and the files that differ from it are compared the same
+/// way again.
+fn compare_in_batches<'a>(
+    files: &HashSet<&'a PathBuf>,
+    read_options: ReadOptions,
+    max_readers: usize,
+    failed_files: &mut FailedFiles,
+) -> Vec<HashSet<&'a PathBuf>> {
+    let mut files: Vec<&PathBuf> = files.iter().cloned().collect();
+    let medium = files_medium(&files);
+    let max_readers = max_readers.min(compare_readers(medium)).max(2);
+    if medium == Medium::Rotational {
+        let order = inode_order(&files);
+        files = order.into_iter().map(|index| files[index]).collect();
+    }
     let mut content_groups = Vec::new();
-    while files_remaining.len() > 0 {
-        let file = files_remaining.iter().cloned().nth(0).unwrap();
-        files_remaining.remove(file);
-        let mut content_group = find_equal_files(file, &files_remaining);
-        files_remaining = files_remaining
-            .difference(&content_group)
-            .cloned()
-            .collect();
-        content_group.insert(file);
-        content_groups.push(content_group);
+    while files.len() > max_readers {
+        let representative = files[0];
+        let mut group = HashSet::from([representative]);
+        let mut different = Vec::new();
+        let mut batches = files[1..].chunks(max_readers - 1);
+        for batch in batches.by_ref() {
+            let batch_files = std::iter::once(representative).chain(batch.iter().copied());
+            let batch_groups =
+                compare_in_lockstep(batch_files.collect(), medium, read_options, failed_files);
+            if !batch_groups
+                .iter()
+                .any(|batch_group| batch_group.contains(representative))
+            {
+                // The representative failed, so the rest is compared without it.
+                group.remove(representative);
+                different.extend(group.drain());
+                different.extend(batch_groups.into_iter().flatten());
+                break;
+            }
+            for batch_group in batch_groups {
+                if batch_group.contains(representative) {
+                    group.extend(batch_group);
+                } else {
+                    different.extend(batch_group);
+                }
+            }
+        }
+        different.extend(batches.flatten());
+        if !group.is_empty() {
+            content_groups.push(group);
+        }
+        files = different;
     }
+    content_groups.extend(compare_in_lockstep(
+        files,
+        medium,
+        read_options,
+        failed_files,
+    ));
     content_groups
 }
 
-fn find_equal_files<'a>(file: &Path, other_files: &HashSet<&'a PathBuf>) -> HashSet<&'a PathBuf> {
-    let mut equal_files = HashSet::new();
-    for other_file in other_files.iter().cloned() {
-        match are_files_same(file, other_file) {
-            Ok(true) => {
-                equal_files.insert(other_file);
-            }
-            Ok(false) => (),
-            Err(err) => warn!(
-                "Failed to compare files {:?} and {:?}. Error: {}",
-                file, other_file, err
+/// Compares all the files at once, a chunk at a time.
+fn compare_in_lockstep<'a>(
+    files: Vec<&'a PathBuf>,
+    medium: Medium,
+    read_options: ReadOptions,
+    failed_files: &mut FailedFiles,
+) -> Vec<HashSet<&'a PathBuf>> {
+    let chunk_size = compare_chunk_size(medium, read_options.buffer_size, files.len());
+    let mut content_groups = Vec::new();
+    let mut readers = Vec::new();
+    for file in files {
+        match ReadFile::open(file, read_options) {
+            Ok(open_file) => readers.push(ChunkReader {
+                file,
+                open_file,
+                chunk: vec![0; chunk_size.max(1)],
+                len: 0,
+            }),
+            Err(err) => failed_files.add(
+                file,
+                format!("Failed to open {:?} to compare it. Error: {}", file, err),
             ),
-        };
+        }
+    }
+    let mut groups_to_read = vec![readers];
+    while let Some(group) = groups_to_read.pop() {
+        if group.len() < 2 {
+            content_groups.extend(group.iter().map(|reader| HashSet::from([reader.file])));
+            continue;
+        }
+        let mut split_groups: Vec<Vec<ChunkReader>> = Vec::new();
+        for mut reader in group {
+            if let Err(err) = reader.read_chunk() {
+                failed_files.add(
+                    reader.file,
+                    format!(
+                        "Failed to read {:?} to compare it. Error: {}",
+                        reader.file, err
+                    ),
+                );
+                continue;
+            }
+            match split_groups
+                .iter_mut()
+                .find(|split| split[0].chunk() == reader.chunk())
+            {
+                Some(split_group) => split_group.push(reader),
+                None => split_groups.push(vec![reader]),
+            }
+        }
+        for split_group in split_groups {
+            if split_group[0].len == 0 {
+                // The files ended together, after the same chunks.
+                content_groups.push(split_group.iter().map(|reader| reader.file).collect());
+            } else {
+                groups_to_read.push(split_group);
+            }
+        }
+    }
+    content_groups
+}
+
+/// A file being compared, with the chunk last read from it.
+struct ChunkReader<'a> {
+    file: &'a PathBuf,
+    open_file: ReadFile,
+    chunk: Vec<u8>,
+    len: usize,
+}
+
+impl ChunkReader<'_> {
+    fn read_chunk(&mut self) -> io::Result<()> {
+        self.len = read_full(&mut self.open_file, &mut self.chunk)?;
+        Ok(())
     }
-    equal_files
+
+    /// The last chunk read. Shorter than the buffer at the end of the file, and empty past it.
+    fn chunk(&self) -> &[u8] {
+        &self.chunk[..self.len]
+    }
+}
+
+fn are_files_same(file: &Path, other_file: &Path, read_options: ReadOptions) -> io::Result<bool> {
+    let open_file_1 = ReadFile::open(file, read_options)?;
+    let open_file_2 = ReadFile::open(other_file, read_options)?;
+    let metadata_1 = open_file_1.file().metadata()?;
+    let metadata_2 = open_file_2.file().metadata()?;
+    if metadata_1.len() != metadata_2.len() {
+        return Ok(false);
+    }
+    // Reads at offsets can't be throttled.
+    if read_options.bwlimit.is_some() {
+        return are_readers_same(open_file_1, open_file_2, read_options.buffer_size);
+    }
+    if is_sparse(&metadata_1) || is_sparse(&metadata_2) {
+        return are_sparse_files_same(
+            open_file_1.file(),
+            open_file_2.file(),
+            read_options.buffer_size,
+        );
+    }
+    are_readers_same(open_file_1, open_file_2, read_options.buffer_size)
 }
 
-fn are_files_same(file: &Path, other_file: &Path) -> io::Result<bool> {
-    let open_file_1 = File::open(file)?;
-    let open_file_2 = File::open(other_file)?;
-    let mut reader1 = BufReader::new(open_file_1);
-    let mut reader2 = BufReader::new(open_file_2);
-    let mut buf1 = [0; 4096];
-    let mut buf2 = [0; 4096];
+/// Compares the contents of the readers. Reads can return fewer bytes than asked for at any point,
+/// so each buffer is filled before comparing them.
+fn are_readers_same(file1: impl Read, file2: impl Read, buffer_size: usize) -> io::Result<bool> {
+    let mut reader1 = BufReader::with_capacity(buffer_size, file1);
+    let mut reader2 = BufReader::with_capacity(buffer_size, file2);
+    let mut buf1 = vec![0; buffer_size.max(1)];
+    let mut buf2 = vec![0; buffer_size.max(1)];
     loop {
-        let read_bytes1 = reader1.read(&mut buf1)?;
-        let read_bytes2 = reader2.read(&mut buf2)?;
-        if read_bytes2 != read_bytes2 {
+        let read_bytes1 = read_full(&mut reader1, &mut buf1)?;
+        let read_bytes2 = read_full(&mut reader2, &mut buf2)?;
+        if read_bytes1 != read_bytes2 {
             return Ok(false);
         }
         if read_bytes1 == 0 {
@@ -330,29 +3653,134 @@ fn are_files_same(file: &Path, other_file: &Path) -> io::Result<bool> {
     }
 }
 
-fn read_prefix(file: &Path) -> io::Result<Vec<u8>> {
-    let mut buffer = vec![0; 64];
-    let mut file_handle = File::open(file)?;
-    file_handle.read(&mut buffer[..])?;
+fn same_tail_groups<'a>(
+    files: HashSet<&'a PathBuf>,
+    prefix_bytes: u64,
+    tail_bytes: u64,
+    failed_files: &mut FailedFiles,
+) -> impl Iterator<Item = HashSet<&'a PathBuf>> {
+    group_by(files.into_iter(), move |file| {
+        read_tail(file, prefix_bytes, tail_bytes)
+            .map_err(|err| {
+                failed_files.add(
+                    file,
+                    format!(
+                        "Skipping file {:?}. Failed to read its last few bytes. Error: {}",
+                        file, err
+                    ),
+                )
+            })
+            .ok()
+    })
+}
+
+/// Reads the prefixes of the files in the same order as the files.
+#[cfg_attr(not(feature = "io-uring"), allow(unused_variables))]
+fn read_prefixes(
+    files: &[&PathBuf],
+    prefix_bytes: u64,
+    read_options: ReadOptions,
+) -> Vec<io::Result<Vec<u8>>> {
+    #[cfg(feature = "io-uring")]
+    if read_options.io_uring && files.len() >= uring::MIN_FILES && uring::available() {
+        match uring::read_prefixes(files, prefix_bytes, read_options) {
+            Ok(prefixes) => return prefixes,
+            Err(err) => warn!(
+                "Failed to read files through io_uring. Reading them one at a time instead. Error: {}",
+                err
+            ),
+        }
+    }
+    files
+        .iter()
+        .map(|file| read_prefix(file, prefix_bytes))
+        .collect()
+}
+
+fn read_prefix(file: &Path, prefix_bytes: u64) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    File::open(file)?
+        .take(prefix_bytes)
+        .read_to_end(&mut buffer)?;
     Ok(buffer)
 }
 
-fn calculate_hash(file: &Path) -> io::Result<Vec<u8>> {
-    let mut file_handle = File::open(file)?;
-    let mut hasher = Sha256::new();
-    io::copy(&mut file_handle, &mut hasher)?;
-    Ok(hasher.finalize().to_vec())
+/// Reads up to `tail_bytes` from the end of the file, skipping any bytes the prefix already
+/// covered.
+fn read_tail(file: &Path, prefix_bytes: u64, tail_bytes: u64) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    if tail_bytes == 0 {
+        return Ok(buffer);
+    }
+    let mut open_file = File::open(file)?;
+    let size = open_file.metadata()?.len();
+    if size <= prefix_bytes {
+        return Ok(buffer);
+    }
+    open_file.seek(SeekFrom::Start(
+        size.saturating_sub(tail_bytes).max(prefix_bytes),
+    ))?;
+    open_file.take(tail_bytes).read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// The hash of the file's contents under [`DedupOptions::hash`] in hex, as reports show it.
+pub fn content_hash(file: &Path, options: &DedupOptions) -> io::Result<String> {
+    Ok(to_hex(&calculate_hash(
+        file,
+        options.hash,
+        options.read_options(),
+    )?))
+}
+
+pub(crate) fn calculate_hash(
+    file: &Path,
+    algorithm: HashAlgorithm,
+    read_options: ReadOptions,
+) -> io::Result<Vec<u8>> {
+    algorithm.hash_reader(
+        ReadFile::open(file, read_options)?,
+        read_options.buffer_size,
+    )
+}
+
+/// The SHA-256 implementation that `sha2` picks at runtime. It uses the CPU's SHA extensions when
+/// they're available, which is faster than going through the kernel crypto API (AF_ALG) on
+/// machines without dedicated crypto offload hardware.
+fn sha256_implementation() -> &'static str {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("sha")
+        && is_x86_feature_detected!("sse2")
+        && is_x86_feature_detected!("ssse3")
+        && is_x86_feature_detected!("sse4.1")
+    {
+        return "SHA-NI";
+    }
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("sha2") {
+        return "ARMv8 crypto extensions";
+    }
+    "software"
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::output::QuietRenderer;
+    use crate::test_utils::set_modified;
+    use std::fs::{read_dir, read_to_string};
     use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::UNIX_EPOCH;
     use tempfile::tempdir;
 
     #[test]
     fn same_size_group_empty() {
-        let mut size_groups = same_metadata_groups(std::iter::empty());
+        let mut size_groups = same_metadata_groups(
+            std::iter::empty(),
+            KeyOptions::default(),
+            &mut FailedFiles::default(),
+        );
         assert_eq!(size_groups.next(), None);
     }
 
@@ -360,7 +3788,11 @@ mod tests {
     fn one_same_size() {
         let tmp_dir = tempdir().unwrap();
         let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "contents 1");
-        let mut size_groups = same_metadata_groups(vec![&file1].into_iter());
+        let mut size_groups = same_metadata_groups(
+            vec![&file1].into_iter(),
+            KeyOptions::default(),
+            &mut FailedFiles::default(),
+        );
         assert_eq!(size_groups.next().unwrap(), HashSet::from([&file1]));
         assert_eq!(size_groups.next(), None);
     }
@@ -370,7 +3802,11 @@ mod tests {
         let tmp_dir = tempdir().unwrap();
         let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "contents 1");
         let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "contents 2");
-        let mut size_groups = same_metadata_groups(vec![&file1, &file2].into_iter());
+        let mut size_groups = same_metadata_groups(
+            vec![&file1, &file2].into_iter(),
+            KeyOptions::default(),
+            &mut FailedFiles::default(),
+        );
         assert_eq!(size_groups.next().unwrap(), HashSet::from([&file1, &file2]));
         assert_eq!(size_groups.next(), None);
     }
@@ -381,8 +3817,12 @@ mod tests {
         let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "contents 1");
         let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "contents 2");
         let smaller_file = tmp_file(&tmp_dir.path().join("dir3"), "smaller_file", "smaller");
-        let size_groups: Vec<HashSet<&PathBuf>> =
-            same_metadata_groups(vec![&file1, &file2, &smaller_file].into_iter()).collect();
+        let size_groups: Vec<HashSet<&PathBuf>> = same_metadata_groups(
+            vec![&file1, &file2, &smaller_file].into_iter(),
+            KeyOptions::default(),
+            &mut FailedFiles::default(),
+        )
+        .collect();
         assert!(size_groups.contains(&HashSet::from([&file1, &file2])));
         assert!(size_groups.contains(&HashSet::from([&smaller_file])));
         assert_eq!(size_groups.len(), 2);
@@ -394,55 +3834,539 @@ mod tests {
         let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same prefix");
         let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same prefix");
         let smaller_file = tmp_file(&tmp_dir.path().join("dir3"), "smaller_file", "smaller");
-        let prefix_groups: Vec<HashSet<&PathBuf>> =
-            same_prefix_groups(HashSet::from([&file1, &file2, &smaller_file])).collect();
+        let prefix_groups: Vec<HashSet<&PathBuf>> = same_prefix_groups(
+            HashSet::from([&file1, &file2, &smaller_file]),
+            64,
+            ReadOptions::default(),
+            &mut FailedFiles::default(),
+        )
+        .collect();
         assert!(prefix_groups.contains(&HashSet::from([&file1, &file2])));
         assert!(prefix_groups.contains(&HashSet::from([&smaller_file])));
         assert_eq!(prefix_groups.len(), 2);
     }
 
+    #[test]
+    fn same_prefix_different_tail() {
+        let tmp_dir = tempdir().unwrap();
+        let header = "same header ".repeat(10);
+        let file1 = tmp_file(tmp_dir.path(), "file1", &(header.clone() + "tail 1"));
+        let file2 = tmp_file(tmp_dir.path(), "file2", &(header.clone() + "tail 1"));
+        let file3 = tmp_file(tmp_dir.path(), "file3", &(header + "tail 3"));
+        let tail_groups: Vec<HashSet<&PathBuf>> = same_tail_groups(
+            HashSet::from([&file1, &file2, &file3]),
+            64,
+            4,
+            &mut FailedFiles::default(),
+        )
+        .collect();
+        assert!(tail_groups.contains(&HashSet::from([&file1, &file2])));
+        assert!(tail_groups.contains(&HashSet::from([&file3])));
+        assert_eq!(read_tail(&file3, 64, 4).unwrap(), b"il 3");
+        assert_eq!(read_tail(&file3, 1024, 4).unwrap(), b"");
+    }
+
+    #[test]
+    fn tail_first_for_configured_extensions() {
+        let options = DedupOptions {
+            tail_first_extensions: vec!["mp3".to_owned(), "log".to_owned()],
+            ..DedupOptions::default()
+        };
+        let song = PathBuf::from("music/song.MP3");
+        let log = PathBuf::from("var/app.log");
+        let rotated_log = PathBuf::from("var/app.log.1");
+        let text = PathBuf::from("notes.txt");
+        assert!(has_tail_first_extension(
+            &HashSet::from([&song, &text]),
+            &options
+        ));
+        assert!(has_tail_first_extension(&HashSet::from([&log]), &options));
+        assert!(!has_tail_first_extension(
+            &HashSet::from([&rotated_log, &text]),
+            &options
+        ));
+        assert!(!has_tail_first_extension(
+            &HashSet::from([&song]),
+            &DedupOptions::default()
+        ));
+    }
+
     #[test]
     fn two_same_hash_one_different() {
         let tmp_dir = tempdir().unwrap();
         let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
         let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
         let smaller_file = tmp_file(&tmp_dir.path().join("dir3"), "smaller_file", "smaller");
-        let hash_groups: Vec<HashSet<&PathBuf>> =
-            same_hash_groups(HashSet::from([&file1, &file2, &smaller_file])).collect();
+        let hash_groups: Vec<HashSet<&PathBuf>> = same_hash_groups(
+            HashSet::from([&file1, &file2, &smaller_file]),
+            None,
+            None,
+            &DedupOptions::default(),
+            &mut FailedFiles::default(),
+        )
+        .collect();
         assert!(hash_groups.contains(&HashSet::from([&file1, &file2])));
         assert!(hash_groups.contains(&HashSet::from([&smaller_file])));
         assert_eq!(hash_groups.len(), 2);
     }
 
+    #[test]
+    fn add_unhashed_files_by_comparison() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_file(tmp_dir.path(), "file1", "same content");
+        let file2 = tmp_file(tmp_dir.path(), "file2", "same content");
+        let same_file = tmp_file(tmp_dir.path(), "same_file", "same content");
+        let other_file = tmp_file(tmp_dir.path(), "other_file", "diff content");
+        let missing_file = tmp_dir.path().join("missing_file");
+        let mut failed_files = FailedFiles::default();
+
+        let groups = add_by_comparison(
+            vec![HashSet::from([&file1, &file2])],
+            vec![&same_file, &other_file, &missing_file],
+            ReadOptions::default(),
+            &mut failed_files,
+        );
+
+        assert_eq!(
+            groups,
+            vec![
+                HashSet::from([&file1, &file2, &same_file]),
+                HashSet::from([&other_file])
+            ]
+        );
+        assert_eq!(failed_files.count(), 1);
+    }
+
+    #[test]
+    fn hash_files_on_every_medium() {
+        let tmp_dir = tempdir().unwrap();
+        let files: Vec<PathBuf> = (0..7)
+            .map(|i| {
+                tmp_file(
+                    tmp_dir.path(),
+                    &format!("file{}", i),
+                    &format!("contents {}", i),
+                )
+            })
+            .chain([tmp_dir.path().join("missing")])
+            .collect();
+        let files: Vec<&PathBuf> = files.iter().collect();
+        let options = DedupOptions::default();
+        let hashes = |medium| -> Vec<Option<Vec<u8>>> {
+            calculate_hashes_on(&files, medium, None, &options)
+                .into_iter()
+                .map(Result::ok)
+                .collect()
+        };
+        let expected = hashes(Medium::Unknown);
+        assert_eq!(
+            expected[0],
+            Some(calculate_hash(files[0], options.hash, options.read_options()).unwrap())
+        );
+        assert_eq!(expected[7], None);
+        assert_eq!(hashes(Medium::Rotational), expected);
+        assert_eq!(hashes(Medium::SolidState), expected);
+    }
+
     #[test]
     fn two_same_content_one_different() {
         let tmp_dir = tempdir().unwrap();
         let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
         let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
         let smaller_file = tmp_file(&tmp_dir.path().join("dir3"), "smaller_file", "smaller");
-        let content_groups: Vec<HashSet<&PathBuf>> =
-            same_content_groups(&HashSet::from([&file1, &file2, &smaller_file]));
+        let content_groups: Vec<HashSet<&PathBuf>> = same_content_groups(
+            &HashSet::from([&file1, &file2, &smaller_file]),
+            ReadOptions::default(),
+            &mut FailedFiles::default(),
+        );
         assert!(content_groups.contains(&HashSet::from([&file1, &file2])));
         assert!(content_groups.contains(&HashSet::from([&smaller_file])));
         assert_eq!(content_groups.len(), 2);
     }
 
+    #[test]
+    fn split_content_groups_where_they_differ() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_file(tmp_dir.path(), "file1", "same start, same end");
+        let file2 = tmp_file(tmp_dir.path(), "file2", "same start, same end");
+        let file3 = tmp_file(tmp_dir.path(), "file3", "same start, diff end");
+        let file4 = tmp_file(tmp_dir.path(), "file4", "same start, diff end");
+        let file5 = tmp_file(tmp_dir.path(), "file5", "diff start, same end");
+        let shorter_file = tmp_file(tmp_dir.path(), "shorter_file", "same start, same");
+        let missing = tmp_dir.path().join("missing");
+        let read_options = ReadOptions {
+            buffer_size: 4,
+            ..ReadOptions::default()
+        };
+        let files = HashSet::from([
+            &file1,
+            &file2,
+            &file3,
+            &file4,
+            &file5,
+            &shorter_file,
+            &missing,
+        ]);
+        // All at once, and in batches of two and three files.
+        for max_readers in [MAX_COMPARE_READERS, 2, 3] {
+            let mut failed_files = FailedFiles::default();
+            let content_groups =
+                compare_in_batches(&files, read_options, max_readers, &mut failed_files);
+            assert!(content_groups.contains(&HashSet::from([&file1, &file2])));
+            assert!(content_groups.contains(&HashSet::from([&file3, &file4])));
+            assert!(content_groups.contains(&HashSet::from([&file5])));
+            assert!(content_groups.contains(&HashSet::from([&shorter_file])));
+            assert_eq!(content_groups.len(), 4);
+            assert_eq!(failed_files.count(), 1);
+        }
+    }
+
+    #[test]
+    fn compare_short_reads() {
+        /// Returns at most one byte per read.
+        struct ByteReader<'a>(&'a [u8]);
+        impl Read for ByteReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let read_bytes = self.0.len().min(buf.len()).min(1);
+                buf[..read_bytes].copy_from_slice(&self.0[..read_bytes]);
+                self.0 = &self.0[read_bytes..];
+                Ok(read_bytes)
+            }
+        }
+        let contents = b"same contents";
+        assert!(are_readers_same(ByteReader(contents), &contents[..], 4).unwrap());
+        assert!(!are_readers_same(ByteReader(contents), &b"same content!"[..], 4).unwrap());
+        assert!(!are_readers_same(ByteReader(contents), &b"same"[..], 4).unwrap());
+    }
+
     #[test]
     fn replace_with_hardlink_same() {
         let tmp_dir = tempdir().unwrap();
         let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
         let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
-        let hard_link_result = replace_with_hard_link(&file1, &file2);
+        let hard_link_result =
+            replace_with_hard_link(&file1, &file2, None, false, Link::Hardlink, false, false);
         assert_eq!(hard_link_result.unwrap(), ());
         assert!(same(&file1, &file2));
     }
 
+    #[test]
+    fn replace_with_hardlink_only_unchanged() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_file(tmp_dir.path(), "file1", "same content");
+        let file2 = tmp_file(tmp_dir.path(), "file2", "same content");
+        let snapshot = FileSnapshot::new(&metadata(&file2).unwrap());
+        set_modified(&file2, UNIX_EPOCH);
+
+        assert!(matches!(
+            replace_with_hard_link(
+                &file1,
+                &file2,
+                Some(&snapshot),
+                false,
+                Link::Hardlink,
+                false,
+                false
+            ),
+            Err(LinkError::Changed {
+                cleanup_err: None,
+                ..
+            })
+        ));
+        assert!(!same(&file1, &file2));
+        assert_eq!(read_dir(tmp_dir.path()).unwrap().count(), 2);
+
+        let snapshot = FileSnapshot::new(&metadata(&file2).unwrap());
+        replace_with_hard_link(
+            &file1,
+            &file2,
+            Some(&snapshot),
+            false,
+            Link::Hardlink,
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(same(&file1, &file2));
+    }
+
+    #[test]
+    fn writable_dirs() {
+        let tmp_dir = tempdir().unwrap();
+        assert!(dir_writable(tmp_dir.path()));
+        assert!(!dir_writable(&tmp_dir.path().join("missing")));
+    }
+
+    #[test]
+    fn classify_link_errors() {
+        let tmp_dir = tempdir().unwrap();
+        let file = tmp_file(tmp_dir.path(), "file", "contents");
+        let missing = tmp_dir.path().join("missing");
+        let dir = tmp_dir.path().join("dir");
+        tmp_file(&dir, "file", "contents");
+        assert!(matches!(
+            replace_with_hard_link(&missing, &file, None, false, Link::Hardlink, false, false),
+            Err(LinkError::Source(_))
+        ));
+        assert!(matches!(
+            replace_with_hard_link(
+                &file,
+                &missing.join("file"),
+                None,
+                false,
+                Link::Hardlink,
+                false,
+                false
+            ),
+            Err(LinkError::TempLink(..))
+        ));
+        assert!(matches!(
+            replace_with_hard_link(&file, &dir, None, false, Link::Hardlink, false, false),
+            Err(LinkError::Rename {
+                cleanup_err: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn replace_with_reflink() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_file(tmp_dir.path(), "file1", "same content");
+        let file2 = tmp_file(tmp_dir.path(), "file2", "same content");
+        std::fs::set_permissions(&file2, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let has_xattr = xattr::set(&file2, "user.tag", b"file2").is_ok();
+        let before = metadata(&file2).unwrap();
+
+        match replace_with_hard_link(&file1, &file2, None, false, Link::Reflink, false, false) {
+            Ok(()) => {
+                let after = metadata(&file2).unwrap();
+                assert!(!same(&file1, &file2));
+                assert_eq!(after.mode(), before.mode());
+                assert_eq!(after.modified().unwrap(), before.modified().unwrap());
+                if has_xattr {
+                    assert_eq!(xattr::get(&file2, "user.tag").unwrap().unwrap(), b"file2");
+                }
+            }
+            // The temporary directory's filesystem can't clone files.
+            Err(LinkError::TempLink(..)) => {
+                assert_eq!(file_id(&metadata(&file2).unwrap()), file_id(&before))
+            }
+            Err(err) => panic!("{}", err),
+        }
+        assert_eq!(read_dir(tmp_dir.path()).unwrap().count(), 2);
+        assert_eq!(read_to_string(&file2).unwrap(), "same content");
+    }
+
+    #[test]
+    fn fall_back_to_symlink() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_file(tmp_dir.path(), "file1", "same content");
+        let file2 = tmp_file(tmp_dir.path(), "file2", "same content");
+        replace_with_hard_link(&file1, &file2, None, false, Link::Symlink, false, true).unwrap();
+        assert_eq!(std::fs::read_link(&file2).unwrap(), file1);
+        assert_eq!(read_to_string(&file2).unwrap(), "same content");
+
+        let options = DedupOptions {
+            fallback: Fallback::Symlink,
+            ..DedupOptions::default()
+        };
+        let err = |code| LinkError::TempLink(file2.clone(), io::Error::from_raw_os_error(code));
+        assert_eq!(
+            fallback_for(&err(libc::EXDEV), &options),
+            Some(Link::Symlink)
+        );
+        assert_eq!(fallback_for(&err(libc::ENOSPC), &options), None);
+        let source_err = LinkError::Source(io::Error::from_raw_os_error(libc::EPERM));
+        assert_eq!(fallback_for(&source_err, &options), Some(Link::Symlink));
+        assert_eq!(
+            fallback_for(&err(libc::EXDEV), &DedupOptions::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn preserve_dir_times() {
+        let tmp_dir = tempdir().unwrap();
+        let dir = tmp_dir.path().join("dir");
+        let file1 = tmp_file(tmp_dir.path(), "file1", "same content");
+        let file2 = tmp_file(&dir, "file2", "same content");
+        let file3 = tmp_file(&dir, "file3", "same content");
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::open(&dir).unwrap().set_modified(modified).unwrap();
+
+        replace_with_hard_link(&file1, &file2, None, false, Link::Hardlink, true, false).unwrap();
+        assert!(same(&file1, &file2));
+        assert_eq!(metadata(&dir).unwrap().modified().unwrap(), modified);
+
+        replace_with_hard_link(&file1, &file3, None, false, Link::Hardlink, false, false).unwrap();
+        assert_ne!(metadata(&dir).unwrap().modified().unwrap(), modified);
+    }
+
+    #[test]
+    fn start_new_original_at_link_max() {
+        let tmp_dir = tempdir().unwrap();
+        for index in 0..6 {
+            tmp_file(tmp_dir.path(), &format!("file{}", index), "same content");
+        }
+        let options = DedupOptions::default();
+        let inode_to_paths = find_inode_groups(
+            &[tmp_dir.path().to_owned()],
+            &options,
+            &mut FailedFiles::default(),
+        );
+        let mut renderer = QuietRenderer;
+        let mut ctx = DedupContext::new(&inode_to_paths, &options, &mut renderer);
+        ctx.link_max = |_| Some(3);
+
+        hardlink_dedup(inode_to_paths.values().flatten().collect(), &mut ctx);
+
+        let summary = ctx.finish();
+        assert_eq!(summary.failed_files, 0);
+        assert_eq!(summary.link_failures, LinkFailures::default());
+        let inodes: HashMap<u64, u64> = inode_to_paths
+            .values()
+            .flatten()
+            .map(|file| metadata(file).unwrap())
+            .map(|file_metadata| (file_metadata.ino(), file_metadata.nlink()))
+            .collect();
+        assert_eq!(inodes.len(), 2);
+        assert!(inodes.values().all(|links| *links == 3));
+    }
+
+    #[test]
+    fn skip_files_changed_after_comparing_them() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_file(tmp_dir.path(), "file1", "same content");
+        let file2 = tmp_file(tmp_dir.path(), "file2", "same content");
+        let options = DedupOptions::default();
+        let inode_to_paths = find_inode_groups(
+            &[tmp_dir.path().to_owned()],
+            &options,
+            &mut FailedFiles::default(),
+        );
+        let mut renderer = QuietRenderer;
+        let mut ctx = DedupContext::new(&inode_to_paths, &options, &mut renderer);
+        let group: HashSet<&PathBuf> = inode_to_paths.values().flatten().collect();
+        ctx.snapshot_files(&group);
+
+        set_modified(&file2, UNIX_EPOCH);
+        hardlink_dedup(group, &mut ctx);
+
+        let summary = ctx.finish();
+        assert_eq!(summary.failed_files, 1);
+        assert!(summary.file_errors[0].message.contains("changed after"));
+        assert!(!same(&file1, &file2));
+    }
+
+    #[test]
+    fn only_count_replaced_files() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_file(tmp_dir.path(), "file1", "same content");
+        let file2 = tmp_file(tmp_dir.path(), "file2", "same content");
+        let _writer = File::options().append(true).open(&file2).unwrap();
+        let options = DedupOptions {
+            skip_open_files: true,
+            ..DedupOptions::default()
+        };
+        let summary =
+            dedup_with_renderer(&[tmp_dir.path().to_owned()], &options, &mut QuietRenderer)
+                .unwrap();
+        assert!(!same(&file1, &file2));
+        assert_eq!(summary.bytes_deduped, 0);
+        assert_eq!(summary.bytes_linked, 0);
+
+        let selections = [LinkSelection {
+            original: file1.clone(),
+            targets: vec![file2.clone()],
+        }];
+        let summary = link_selections(&selections, &options, &mut QuietRenderer).unwrap();
+        assert!(!same(&file1, &file2));
+        assert_eq!(summary.bytes_deduped, 0);
+        assert_eq!(summary.bytes_linked, 0);
+    }
+
+    #[test]
+    fn link_selected_files() {
+        let tmp_dir = tempdir().unwrap();
+        let original = tmp_file(tmp_dir.path(), "original", "same content");
+        let same_file = tmp_file(tmp_dir.path(), "same", "same content");
+        let changed_file = tmp_file(tmp_dir.path(), "changed", "same kontent");
+        let selections = [LinkSelection {
+            original: original.clone(),
+            targets: vec![same_file.clone(), changed_file.clone()],
+        }];
+        let summary =
+            link_selections(&selections, &DedupOptions::default(), &mut QuietRenderer).unwrap();
+        assert_eq!(summary.bytes_deduped, 12);
+        assert_eq!(summary.failed_files, 1);
+        assert_eq!(summary.file_errors.len(), 1);
+        assert_eq!(summary.file_errors[0].path, changed_file);
+        assert!(summary.file_errors[0].message.contains("no longer match"));
+        assert!(same(&original, &same_file));
+        assert!(!same(&original, &changed_file));
+    }
+
+    #[test]
+    fn out_of_time_before_start() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
+        let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
+        let options = DedupOptions {
+            output: OutputFormat::Quiet,
+            max_duration: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let summary = dedup_with_options(&[tmp_dir.path().to_owned()], &options).unwrap();
+        assert!(summary.budget_exhausted);
+        assert!(summary.interrupted);
+        assert!(!same(&file1, &file2));
+    }
+
+    #[test]
+    fn interrupted_before_start() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
+        let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
+        let options = DedupOptions {
+            output: OutputFormat::Quiet,
+            interrupted: Arc::new(AtomicBool::new(true)),
+            ..Default::default()
+        };
+        let summary = dedup_with_options(&[tmp_dir.path().to_owned()], &options).unwrap();
+        assert!(summary.interrupted);
+        assert_eq!(summary.bytes_deduped, 0);
+        assert!(!same(&file1, &file2));
+    }
+
+    #[test]
+    fn snapshot_on_request() {
+        struct SnapshotCounter(usize);
+        impl Renderer for SnapshotCounter {
+            fn render(&mut self, _status: &Status, event: &Event) {
+                if let Event::Snapshot { .. } = event {
+                    self.0 += 1;
+                }
+            }
+        }
+        let tmp_dir = tempdir().unwrap();
+        tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
+        tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
+        let options = DedupOptions {
+            snapshot_requested: Arc::new(AtomicBool::new(true)),
+            ..Default::default()
+        };
+        let mut renderer = SnapshotCounter(0);
+        dedup_with_renderer(&[tmp_dir.path().to_owned()], &options, &mut renderer).unwrap();
+        assert_eq!(renderer.0, 1);
+    }
+
     fn tmp_file(dir: &Path, file_name: &str, contents: &str) -> PathBuf {
         std::fs::create_dir_all(dir).unwrap();
         let path = dir.join(file_name);
         let mut file = File::create(&path).unwrap();
         file.write_all(contents.as_bytes()).unwrap();
-        return path;
+        path
     }
 
     pub fn same(file1: &Path, file2: &Path) -> bool {
@@ -0,0 +1,30 @@
+//! The most hardlinks a filesystem allows to one inode.
+//!
+//! ext4 allows 65000 links per inode and many other filesystems similar numbers. Huge groups of
+//! duplicates hit that limit, after which every further link fails with `EMLINK`. Duplicates beyond
+//! the limit are hardlinked to another original instead.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// The most hardlinks an inode can have on the filesystem that contains the path. `None` if the
+/// filesystem has no limit or doesn't tell.
+pub(crate) fn link_max(path: &Path) -> Option<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let link_max = unsafe { libc::pathconf(c_path.as_ptr(), libc::_PC_LINK_MAX) };
+    u64::try_from(link_max).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn read_link_max() {
+        let tmp_dir = tempdir().unwrap();
+        assert!(link_max(tmp_dir.path()).is_none_or(|link_max| link_max > 1));
+        assert_eq!(link_max(&tmp_dir.path().join("missing")), None);
+    }
+}
@@ -0,0 +1,103 @@
+//! Locks that keep overlapping runs from deduplicating the same files at once.
+//!
+//! Two runs replacing the same files race each other's temporary hardlinks and renames. A run takes
+//! an exclusive `flock` on the lockfile, or on each of the paths it deduplicates, and holds it until
+//! it exits. The kernel releases the locks of crashed runs, so stale locks never need cleaning up.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Locks held until dropped.
+pub struct RunLock {
+    _files: Vec<File>,
+}
+
+/// Locks the lockfile, creating it if it's missing, and with `lock_paths` each of the paths. Fails
+/// if another run holds one of the locks, or with `wait` waits until it releases it.
+pub fn acquire(
+    lockfile: Option<&Path>,
+    lock_paths: &[PathBuf],
+    wait: bool,
+) -> Result<RunLock, String> {
+    let mut files = Vec::new();
+    if let Some(lockfile) = lockfile {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lockfile)
+            .map_err(|err| format!("Failed to open the lockfile {:?}. Error: {}", lockfile, err))?;
+        files.push(lock(file, lockfile, wait)?);
+    }
+    // Runs that wait for each other lock the paths in the same order, so they can't deadlock.
+    let mut paths: Vec<PathBuf> = lock_paths
+        .iter()
+        .map(|path| path.canonicalize().unwrap_or_else(|_| path.clone()))
+        .collect();
+    paths.sort();
+    paths.dedup();
+    for path in paths {
+        let file = File::open(&path)
+            .map_err(|err| format!("Failed to open {:?} to lock it. Error: {}", path, err))?;
+        files.push(lock(file, &path, wait)?);
+    }
+    Ok(RunLock { _files: files })
+}
+
+fn lock(file: File, path: &Path, wait: bool) -> Result<File, String> {
+    match flock(&file, false) {
+        Ok(()) => return Ok(file),
+        Err(err) if err.kind() != io::ErrorKind::WouldBlock => {
+            return Err(format!("Failed to lock {:?}. Error: {}", path, err));
+        }
+        Err(_) if !wait => {
+            return Err(format!(
+                "Another run holds the lock on {:?}. Pass --wait-for-lock to wait for it.",
+                path
+            ));
+        }
+        Err(_) => {}
+    }
+    info!("Waiting for another run to release the lock on {:?}.", path);
+    flock(&file, true).map_err(|err| format!("Failed to lock {:?}. Error: {}", path, err))?;
+    Ok(file)
+}
+
+fn flock(file: &File, wait: bool) -> io::Result<()> {
+    let mut operation = libc::LOCK_EX;
+    if !wait {
+        operation |= libc::LOCK_NB;
+    }
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn lock_once_at_a_time() {
+        let tmp_dir = tempdir().unwrap();
+        let lockfile = tmp_dir.path().join("lock");
+        let paths = [tmp_dir.path().to_owned()];
+
+        let held = acquire(Some(&lockfile), &paths, false).unwrap();
+        let err = acquire(Some(&lockfile), &[], false).err().unwrap();
+        assert!(err.starts_with("Another run holds the lock on"));
+        assert!(acquire(None, &paths, false).is_err());
+        drop(held);
+        assert!(acquire(Some(&lockfile), &paths, false).is_ok());
+    }
+}
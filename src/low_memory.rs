@@ -0,0 +1,348 @@
+//! Deduplicating trees with too many files to keep all their paths in memory.
+//!
+//! The scan writes the files it finds to a temporary spill file instead of keeping them in memory.
+//! The spill file is then split into buckets of files by their size, small enough that each bucket's
+//! paths fit in [`DedupOptions::max_memory`]. Files of the same size always land in the same bucket,
+//! so deduplicating one bucket at a time finds the same duplicates as deduplicating all files at
+//! once. Buckets that are too large anyway because too many files have the same size are split
+//! further by the first bytes of their files, which duplicates share too. Files with the same size
+//! and first bytes stay together even if they don't fit, in which case we warn about it.
+
+use crate::breakdown::Breakdown;
+use crate::checkpoint::Checkpoint;
+use crate::error::{DedupError, FailedFiles};
+use crate::output::{Event, Renderer};
+use crate::stats::Stage;
+use crate::{
+    dedup_inode_groups, log_hash_algorithm, open_journal, read_prefix, scan_paths, ContextState,
+    DedupContext, DedupOptions, DedupSummary, FileId, FileSink, DEFAULT_PREFIX_BYTES,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::time::Instant;
+use tracing::warn;
+
+/// Roughly how much memory each path takes up while deduplicating besides the path itself: its
+/// entry in the map from inodes to paths, its `PathBuf`, and its place in the groups of files.
+const PATH_OVERHEAD: u64 = 200;
+
+/// A file found by the scan.
+struct Record {
+    size: u64,
+    file_id: FileId,
+    path: PathBuf,
+}
+
+impl Record {
+    /// Roughly how much memory the file takes up while deduplicating.
+    fn memory(&self) -> u64 {
+        self.path.as_os_str().len() as u64 + PATH_OVERHEAD
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let path = self.path.as_os_str().as_bytes();
+        writer.write_all(&self.size.to_le_bytes())?;
+        writer.write_all(&self.file_id.0.to_le_bytes())?;
+        writer.write_all(&self.file_id.1.to_le_bytes())?;
+        writer.write_all(&(path.len() as u64).to_le_bytes())?;
+        writer.write_all(path)
+    }
+
+    /// `None` at the end of the file.
+    fn read(reader: &mut impl Read) -> io::Result<Option<Record>> {
+        let mut header = [0; 32];
+        match reader.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let field = |index: usize| {
+            u64::from_le_bytes(header[index * 8..(index + 1) * 8].try_into().unwrap())
+        };
+        let mut path = vec![0; field(3) as usize];
+        reader.read_exact(&mut path)?;
+        Ok(Some(Record {
+            size: field(0),
+            file_id: (field(1), field(2)),
+            path: PathBuf::from(OsString::from_vec(path)),
+        }))
+    }
+}
+
+/// The files found by the scan, in a temporary file.
+struct Spill {
+    writer: BufWriter<File>,
+    paths: usize,
+    /// How much memory deduplicating all the files at once would take.
+    memory: u64,
+    /// The first error writing to the spill file. Later files are dropped.
+    error: Option<io::Error>,
+}
+
+impl FileSink for Spill {
+    fn add(&mut self, file_id: FileId, size: u64, path: PathBuf) {
+        if self.error.is_some() {
+            return;
+        }
+        let record = Record {
+            size,
+            file_id,
+            path,
+        };
+        self.paths += 1;
+        self.memory += record.memory();
+        if let Err(err) = record.write(&mut self.writer) {
+            self.error = Some(err);
+        }
+    }
+}
+
+impl Spill {
+    fn new() -> io::Result<Spill> {
+        Ok(Spill {
+            writer: BufWriter::new(tempfile::tempfile()?),
+            paths: 0,
+            memory: 0,
+            error: None,
+        })
+    }
+
+    /// Splits the files into buckets whose paths fit in `max_memory`. Buckets of files of too few
+    /// sizes are split further by the first `prefix_bytes` of the files.
+    fn into_buckets(self, max_memory: u64, prefix_bytes: u64) -> io::Result<Vec<Bucket>> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        let file = self.writer.into_inner().map_err(|err| err.into_error())?;
+        let count = self.memory.div_ceil(max_memory.max(1)).max(1);
+        let mut buckets = Vec::new();
+        for bucket in split_records(file, count, |record| bucket_of(record.size, count))? {
+            if bucket.memory <= max_memory || bucket.paths < 2 {
+                buckets.push(bucket);
+                continue;
+            }
+            let count = bucket.memory.div_ceil(max_memory.max(1));
+            let prefix_buckets = split_records(bucket.file, count, |record| {
+                // Files we can't read are left out when their bucket is deduplicated anyway.
+                let prefix = read_prefix(&record.path, prefix_bytes).unwrap_or_default();
+                prefix_bucket_of(record.size, &prefix, count)
+            })?;
+            for prefix_bucket in prefix_buckets {
+                if prefix_bucket.memory > max_memory {
+                    warn!(
+                        "Deduplicating {} files with the same sizes and first bytes together, which takes about {} bytes, more than the maximum memory.",
+                        prefix_bucket.paths, prefix_bucket.memory
+                    );
+                }
+                buckets.push(prefix_bucket);
+            }
+        }
+        Ok(buckets)
+    }
+}
+
+/// Splits the records in the file into `count` buckets by the bucket index `bucket_of` returns.
+fn split_records(
+    mut file: File,
+    count: u64,
+    mut bucket_of: impl FnMut(&Record) -> usize,
+) -> io::Result<Vec<Bucket>> {
+    file.rewind()?;
+    let mut writers = Vec::new();
+    for _ in 0..count {
+        writers.push((BufWriter::new(tempfile::tempfile()?), 0, 0));
+    }
+    let mut reader = BufReader::new(file);
+    while let Some(record) = Record::read(&mut reader)? {
+        let (writer, paths, memory) = &mut writers[bucket_of(&record)];
+        record.write(writer)?;
+        *paths += 1;
+        *memory += record.memory();
+    }
+    writers
+        .into_iter()
+        .map(|(writer, paths, memory)| {
+            let mut file = writer.into_inner().map_err(|err| err.into_error())?;
+            file.rewind()?;
+            Ok(Bucket {
+                file,
+                paths,
+                memory,
+            })
+        })
+        .collect()
+}
+
+/// Spreads file sizes evenly over the buckets, even though most sizes are small.
+fn bucket_of(size: u64, count: u64) -> usize {
+    (size.wrapping_mul(0x9e37_79b9_7f4a_7c15) % count) as usize
+}
+
+/// Spreads files of the same sizes over the buckets by their first bytes.
+fn prefix_bucket_of(size: u64, prefix: &[u8], count: u64) -> usize {
+    let mut hasher = DefaultHasher::new();
+    (size, prefix).hash(&mut hasher);
+    (hasher.finish() % count) as usize
+}
+
+struct Bucket {
+    file: File,
+    paths: usize,
+    /// Roughly how much memory deduplicating the files of the bucket takes.
+    memory: u64,
+}
+
+impl Bucket {
+    fn read(self) -> io::Result<HashMap<FileId, HashSet<PathBuf>>> {
+        let mut inode_to_paths: HashMap<FileId, HashSet<PathBuf>> = HashMap::new();
+        let mut reader = BufReader::new(self.file);
+        while let Some(record) = Record::read(&mut reader)? {
+            inode_to_paths
+                .entry(record.file_id)
+                .or_default()
+                .insert(record.path);
+        }
+        Ok(inode_to_paths)
+    }
+}
+
+/// Deduplicates the files under the paths one bucket at a time, keeping the paths of at most about
+/// `max_memory` bytes' worth of files in memory.
+pub(crate) fn dedup_in_buckets(
+    paths: &[PathBuf],
+    max_memory: u64,
+    checkpoint: Checkpoint,
+    options: &DedupOptions,
+    renderer: &mut dyn Renderer,
+) -> Result<DedupSummary, DedupError> {
+    let mut failed_files = FailedFiles::default();
+    let mut spill = Spill::new().map_err(DedupError::Spill)?;
+    let started = Instant::now();
+    scan_paths(paths, options, &mut spill, &mut failed_files);
+    let scan_time = started.elapsed();
+    let prefix_bytes = options.prefix_bytes.unwrap_or(DEFAULT_PREFIX_BYTES);
+    let buckets = spill
+        .into_buckets(max_memory, prefix_bytes)
+        .map_err(DedupError::Spill)?;
+    // Until a bucket is read we only know how many paths, rather than files, it has.
+    let mut state = ContextState::new(options, buckets.iter().map(|bucket| bucket.paths).sum());
+    state.failed_files = failed_files;
+    state.breakdown = options
+        .breakdown_depth
+        .map(|depth| Breakdown::new(paths, depth));
+    state.started = started;
+    state.clock.add(Stage::Scan, scan_time);
+    state.checkpoint = checkpoint;
+    state.journal = open_journal(options)?;
+    let no_files = HashMap::new();
+    let mut ctx = DedupContext::resume(state, &no_files, options, renderer);
+    ctx.emit(Event::Started { files: ctx.total });
+    log_hash_algorithm(options);
+    state = ctx.suspend();
+    for bucket in buckets {
+        let bucket_paths = bucket.paths;
+        let inode_to_paths = bucket.read().map_err(DedupError::Spill)?;
+        state.total -= bucket_paths - inode_to_paths.len();
+        state.progress.set_total(state.total);
+        let mut ctx = DedupContext::resume(state, &inode_to_paths, options, renderer);
+        if !ctx.interrupted() {
+            dedup_inode_groups(&mut ctx);
+        }
+        state = ctx.suspend();
+    }
+    let mut ctx = DedupContext::resume(state, &no_files, options, renderer);
+    Ok(ctx.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tmp_file;
+    use std::fs::read_to_string;
+    use tempfile::tempdir;
+
+    #[test]
+    fn split_spill_into_buckets() {
+        let mut spill = Spill::new().unwrap();
+        for (size, ino) in [(10, 1), (10, 2), (20, 3), (30, 4), (10, 1)] {
+            spill.add(
+                (1, ino),
+                size,
+                PathBuf::from(format!("file{}-{}", ino, size)),
+            );
+        }
+        let memory = spill.memory;
+
+        let buckets = spill.into_buckets(memory.div_ceil(3), 64).unwrap();
+
+        // The bucket with the three files of size 10 is too large, so it's split further.
+        assert!(buckets.len() > 3);
+        let mut sizes_seen = HashSet::new();
+        let mut files = 0;
+        for bucket in buckets {
+            let inode_to_paths = bucket.read().unwrap();
+            let sizes: HashSet<u64> = inode_to_paths
+                .values()
+                .flatten()
+                .map(|path| {
+                    path.to_str()
+                        .unwrap()
+                        .split('-')
+                        .nth(1)
+                        .unwrap()
+                        .parse()
+                        .unwrap()
+                })
+                .collect();
+            assert!(sizes.is_disjoint(&sizes_seen));
+            sizes_seen.extend(sizes);
+            files += inode_to_paths.len();
+        }
+        assert_eq!(files, 4);
+        assert_eq!(sizes_seen, HashSet::from([10, 20, 30]));
+    }
+
+    #[test]
+    fn split_files_of_one_size_by_prefix() {
+        let tmp_dir = tempdir().unwrap();
+        let mut spill = Spill::new().unwrap();
+        for (ino, contents) in ["a1", "a2", "b1", "b2", "c1", "c2", "d1", "d2"]
+            .iter()
+            .enumerate()
+        {
+            let file = tmp_file(tmp_dir.path(), contents, &contents[..1]);
+            spill.add((1, ino as u64), 1, file);
+        }
+        let memory = spill.memory;
+
+        let buckets = spill.into_buckets(memory.div_ceil(2), 64).unwrap();
+
+        let prefixes: Vec<HashSet<String>> = buckets
+            .into_iter()
+            .map(|bucket| {
+                let inode_to_paths = bucket.read().unwrap();
+                let prefixes: HashSet<String> = inode_to_paths
+                    .values()
+                    .flatten()
+                    .map(|path| read_to_string(path).unwrap())
+                    .collect();
+                // Both files with each prefix are in the same bucket.
+                assert_eq!(inode_to_paths.len(), prefixes.len() * 2);
+                prefixes
+            })
+            .collect();
+        assert!(
+            prefixes
+                .iter()
+                .filter(|prefixes| !prefixes.is_empty())
+                .count()
+                > 1
+        );
+    }
+}
@@ -1,28 +1,1263 @@
-use clap::Parser;
-use hardlink_dedup::dedup;
-use std::path::PathBuf;
+use clap::error::ErrorKind;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use hardlink_dedup::build_info::build_info;
+use hardlink_dedup::output::{self, print_result};
+use hardlink_dedup::protect;
+use hardlink_dedup::units::{parse_duration, parse_size, parse_timestamp, size_value};
+use hardlink_dedup::{
+    cache, dedup_incremental, dedup_with_renderer, index, link_dupes_list, link_indexes,
+    run_hash_worker, undo, verify, what_if, CandidateFilters, DedupError, DedupOptions,
+    DedupSummary, Fallback, HashAlgorithm, LinkMode, OutputFormat, Prefer, ProtectedPaths,
+    RepairMode, SecondRead,
+};
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::flag;
+use std::ffi::OsString;
+use std::fs::write;
+use std::io::{self, stderr, IsTerminal};
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, warn};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::format::FmtSpan;
+
+mod batch;
+mod config;
+mod csv_report;
+mod lock;
+mod metrics;
+mod sorted_groups;
+#[cfg(feature = "sqlite")]
+mod sqlite_export;
+mod stdio;
+#[cfg(feature = "tui")]
+mod tui;
 
 #[derive(Parser, Debug)]
-#[command(about = "Incrementally hardlinks files with the same contents.")]
+#[command(
+    about = "Incrementally hardlinks files with the same contents.",
+    args_conflicts_with_subcommands = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Print the version, git commit, enabled features and supported backends, then exit.
+    #[arg(long, short = 'V', default_value_t = false)]
+    version: bool,
+
+    /// Print the --version output as JSON.
+    #[arg(long, default_value_t = false, requires = "version")]
+    json: bool,
+
+    /// Read options from this TOML file, with the long names of options as keys and `paths` for the
+    /// paths. Options given on the command line override the file's. Defaults to
+    /// $XDG_CONFIG_HOME/hardlink-dedup.toml or ~/.config/hardlink-dedup.toml if it exists.
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Don't read the default config file.
+    #[arg(
+        long,
+        global = true,
+        default_value_t = false,
+        conflicts_with = "config"
+    )]
+    no_config: bool,
+
+    /// Only print the summary at the end. Warnings are hidden too.
+    #[arg(
+        long,
+        global = true,
+        short = 'q',
+        default_value_t = false,
+        conflicts_with = "verbose"
+    )]
+    quiet: bool,
+
+    /// Print every hardlinked file. Give twice to also print why files were excluded from deduplication.
+    #[arg(long, global = true, short = 'v', action = ArgAction::Count)]
+    verbose: u8,
+
+    /// What to print about the deduplication: `human` log messages and a summary, a single `json`
+    /// document, one JSON object per line (`ndjson`), groups of duplicate files like `fdupes`, the plan
+    /// of a dry run (`list`), or nothing (`quiet`). Machine-readable output only ever goes to stdout and everything else to stderr. `verify`,
+    /// `undo` and --what-if print their result as a JSON object with `json` and `ndjson`.
+    #[arg(long, value_enum, global = true, default_value_t = OutputFormat::Human)]
+    output: OutputFormat,
+
+    /// Print byte counts in the progress bar and the summary as plain numbers instead of in KiB,
+    /// MiB, GiB and so on, for scripts that parse the human output.
+    #[arg(long, global = true, default_value_t = false)]
+    bytes: bool,
+
+    /// Format of the log messages printed to stderr. With `json` every message and every finished pipeline
+    /// stage (with its duration) is a JSON object on its own line.
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    #[command(flatten)]
+    dedup: DedupArgs,
+}
+
+/// The options of deduplication runs, given to `dedup` and `report` or without a subcommand.
+#[derive(clap::Args, Debug)]
+struct DedupArgs {
     /// Don't actually hardlink any files.
     #[arg(long, short = 'n', default_value_t = false)]
     dry_run: bool,
 
-    /// Don't trust the sha-256 hashing algorithm and always check that files are indeed bit-for-bit equal.
+    /// Print the plan for each group of duplicates without hardlinking anything: the file kept under
+    /// --prefer, the files that would be hardlinked to it, and the bytes and inodes that would be
+    /// reclaimed. Short for --dry-run --output list.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["output", "interactive", "watch"])]
+    list: bool,
+
+    /// Print only the N groups of duplicates that would reclaim the most bytes. The groups of dry
+    /// runs are always printed with the most reclaimable bytes first.
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Print the paths of each group of duplicates without hardlinking anything: the file kept
+    /// under --prefer followed by the files that would be hardlinked to it, one path per line and
+    /// an empty line after each group. Short for --dry-run with that output.
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["output", "interactive", "watch", "list"]
+    )]
+    list_duplicates: bool,
+
+    /// Separate the paths printed by --list-duplicates with NUL bytes instead of newlines, for
+    /// `xargs -0` and scripts that handle paths with spaces and newlines.
+    #[arg(
+        long,
+        short = '0',
+        default_value_t = false,
+        requires = "list_duplicates"
+    )]
+    null: bool,
+
+    /// Check that the paths are fully deduplicated without hardlinking anything. Exits with code 3 if
+    /// hardlinking would save more than --check-threshold bytes.
+    #[arg(long, default_value_t = false)]
+    check: bool,
+
+    /// How many bytes hardlinking may still save before --check fails (e.g. 100M).
+    #[arg(long, value_parser = parse_size, default_value = "0", requires = "check")]
+    check_threshold: u64,
+
+    /// Report how much hardlinking would save with and without matching owners and modes, with only
+    /// files of at least 1M, and how much reflinking would save, without changing anything.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["check", "watch", "interactive"])]
+    what_if: bool,
+
+    /// Don't trust the hashing algorithm and always check that files are indeed bit-for-bit equal.
     /// This option is slower.
     #[arg(long, short = 'p', default_value_t = false)]
     paranoid: bool,
 
-    /// Paths (directories or files) to deduplicate. Directories will be recursively traversed. Softlinks won't be followed.
+    /// The hash algorithm used to find files with the same contents. `blake3` is several times faster
+    /// than `sha256`. `xxh3` is faster still but not cryptographic, so combine it with --paranoid on
+    /// untrusted data.
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::Sha256)]
+    hash: HashAlgorithm,
+
+    /// Size of the buffers files are read through when hashing and comparing them (e.g. 4M). Larger
+    /// buffers cut down on seeking on spinning disks.
+    #[arg(long, value_parser = parse_size, default_value = "1M")]
+    buffer_size: u64,
+
+    /// Tell the kernel that files are read sequentially and drop them from the page cache after hashing
+    /// or comparing them, so that scanning large trees doesn't evict other services' cached data.
+    #[arg(long, alias = "drop-caches", default_value_t = false)]
+    fadvise: bool,
+
+    /// The most bytes per second to read when hashing and comparing files (e.g. 50M), so that
+    /// deduplicating doesn't starve other workloads of I/O. Hash workers share the limit.
+    #[arg(long, value_parser = parse_size, value_name = "BYTES")]
+    bwlimit: Option<u64>,
+
+    /// Read files with the idle I/O scheduling class and run at the lowest CPU priority, so that
+    /// scheduled runs only use the disks and CPUs when nothing else needs them. Hash workers inherit
+    /// both.
+    #[arg(long, default_value_t = false)]
+    nice_io: bool,
+
+    /// Read the first bytes and calculate the hashes of groups of many files through io_uring, which
+    /// saves a syscall per read on trees with many small files.
+    #[cfg(feature = "io-uring")]
+    #[arg(long, default_value_t = false)]
+    io_uring: bool,
+
+    /// Which file of each group of duplicates to keep: the `oldest` or `newest` by modification time,
+    /// the one with the `most-links` (or `most-linked`), the `shortest-path`, or the `first-path` in
+    /// path order. Without this option the file with the most links is kept. Files that tie are
+    /// ordered by path, so the same tree is always deduplicated the same way.
+    #[arg(long, visible_alias = "keep", value_enum)]
+    prefer: Option<Prefer>,
+
+    /// Only hardlink files whose contents have at least N copies, e.g. 3 to leave pairs of working
+    /// copies alone. Hardlinks to the same file count as one copy.
+    #[arg(long, value_name = "N", default_value_t = 2, value_parser = clap::value_parser!(u64).range(2..))]
+    min_copies: u64,
+
+    /// Leave files that already have at least N links alone: don't hash them or hardlink other files
+    /// to them. Skips files linked across many snapshots by previous runs.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_nlink: Option<u64>,
+
+    /// Only hardlink files with the same file name, e.g. IMG_1234.JPG across backup snapshots. Files
+    /// that are only coincidentally the same stay apart, and there are far fewer files to compare.
+    #[arg(long, default_value_t = false)]
+    same_name_only: bool,
+
+    /// Process the groups of duplicates, and the files in them, in path order, so that dry runs
+    /// over the same files print the same output and can be diffed.
+    #[arg(long, default_value_t = false)]
+    deterministic: bool,
+
+    /// How many bytes from the start of files with the same size to compare before hashing them (e.g.
+    /// 4K). Larger values reject files with long common headers (tar archives, VM images) earlier.
+    #[arg(long, value_parser = parse_size, default_value = "64")]
+    prefix_bytes: u64,
+
+    /// How many bytes from the end of files with the same prefix to compare before hashing them. 0 skips
+    /// the comparison.
+    #[arg(long, value_parser = parse_size, default_value = "64")]
+    tail_bytes: u64,
+
+    /// Compare files of these formats (comma-separated extensions, e.g. mp3,log) by their last bytes
+    /// before their first bytes. Growing logs and MP3s with leading tags differ in their headers far
+    /// more often than in their tails.
+    #[arg(long, value_name = "EXTENSIONS", value_delimiter = ',')]
+    tail_first_ext: Vec<String>,
+
+    /// Number of helper processes that calculate file hashes. Each helper runs with its own file descriptor
+    /// and memory limits and is restarted if it crashes or hangs. By default hashes are calculated in this process.
+    #[arg(long, default_value_t = 0)]
+    hash_workers: usize,
+
+    /// How many files of each filesystem are hashed at a time. Raise this for network mounts that
+    /// are only fast with many reads in flight, or lower it for ones that slow down under load.
+    /// Defaults to all hash workers, or to 4 files on SSDs and 1 elsewhere without them. Spinning
+    /// disks are always read one file at a time.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_parallel_hashes: Option<u64>,
+
+    /// Number of threads that walk the directories. Walking with many threads is much faster on
+    /// network filesystems and large trees, where most of the walk is spent waiting for metadata.
+    #[arg(long, short = 'j', value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    jobs: u64,
+
+    /// Keep the paths of at most about this many bytes' worth of files in memory (e.g. 2G), for trees
+    /// with tens of millions of files. The scanned files are spilled to temporary files and
+    /// deduplicated in buckets of files by their size, and their first bytes where many files have
+    /// the same size.
+    #[arg(long, value_parser = parse_size, value_name = "BYTES", conflicts_with = "watch")]
+    max_memory: Option<u64>,
+
+    /// Maximum amount of memory that each hash worker process may use (e.g. 512M or 2G).
+    #[arg(long, value_parser = parse_size)]
+    hash_worker_memory_limit: Option<u64>,
+
+    /// How long to wait for a hash worker to hash a file before killing and restarting it (e.g. 30s or 5m).
+    #[arg(long, value_parser = parse_duration)]
+    hash_worker_timeout: Option<Duration>,
+
+    /// Skip files modified more recently than this (e.g. 90s, 10m, 2h). Files still being written to
+    /// shouldn't be hardlinked. Files are checked when scanning and again right before hardlinking.
+    #[arg(long, value_parser = parse_duration)]
+    min_age: Option<Duration>,
+
+    /// Skip files modified after this time, e.g. 2024-03-01, "2024-03-01 12:00:00" (UTC) or @1709294400
+    /// (seconds since the Unix epoch).
+    #[arg(long, value_name = "TIMESTAMP", value_parser = parse_timestamp)]
+    exclude_newer_than: Option<SystemTime>,
+
+    /// Skip files modified before this time. Accepts the same values as --exclude-newer-than.
+    #[arg(long, value_name = "TIMESTAMP", value_parser = parse_timestamp)]
+    exclude_older_than: Option<SystemTime>,
+
+    /// Read files that turn out to have unique contents a second time and report those that read back
+    /// differently as probable hardware or bitrot issues. The second read can bypass the page cache by
+    /// dropping the file's cached pages (the default) or with O_DIRECT, e.g. --double-read-verify=direct.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "drop-caches"
+    )]
+    double_read_verify: Option<SecondRead>,
+
+    /// Don't descend into directories on other filesystems. Files on different filesystems are never
+    /// hardlinked to each other either way.
+    #[arg(long, short = 'x', default_value_t = false)]
+    one_file_system: bool,
+
+    /// Skip directories with a CACHEDIR.TAG (see https://bford.info/cachedir/) or a `.nodedup` file
+    /// and everything in them, so their owners can opt out of deduplication.
+    #[arg(long, default_value_t = false)]
+    honor_cachedir_tags: bool,
+
+    /// Hardlink duplicates with different owners and groups, e.g. across users' home directories.
+    /// Replaced files get the owner and group of the file they're hardlinked to, and each is logged.
+    #[arg(long, default_value_t = false)]
+    ignore_owner: bool,
+
+    /// Hardlink duplicates with different permissions. Replaced files get the mode of the file
+    /// they're hardlinked to, and each is logged.
+    #[arg(long, default_value_t = false)]
+    ignore_mode: bool,
+
+    /// Only hardlink duplicates that also have the same modification time, e.g. to deduplicate
+    /// snapshot trees copied with `rsync --times` without touching files that were rewritten.
+    #[arg(long, default_value_t = false)]
+    require_same_mtime: bool,
+
+    /// Restore the access and modification times of the directories of replaced files, which
+    /// replacing them updates, so that incremental backups don't think the directories changed.
+    #[arg(long, default_value_t = false)]
+    preserve_dir_mtimes: bool,
+
+    /// Flush every replaced file and its directory to disk before counting it as deduplicated, so
+    /// that a power loss can't undo replacements that were reported as done. Slower.
+    #[arg(long, default_value_t = false)]
+    fsync: bool,
+
+    /// Only hardlink duplicates that also occupy the same number of blocks on disk, so that sparse
+    /// files like VM images are only hardlinked to copies with the same holes.
+    #[arg(long, default_value_t = false)]
+    group_by_allocated_size: bool,
+
+    /// Replace duplicates with hardlinks, or with reflinks that share their data blocks but stay
+    /// separate files with their own owner, mode, timestamps and extended attributes. Only btrfs,
+    /// XFS and APFS support reflinks.
+    #[arg(long, value_enum, default_value_t = LinkMode::Hardlink)]
+    mode: LinkMode,
+
+    /// Replace duplicates with reflinks or symlinks instead when hardlinking them fails because
+    /// they're on different mounts or the kernel refuses to link to the original.
+    #[arg(long, value_enum, default_value_t = Fallback::None)]
+    fallback: Fallback,
+
+    /// Hardlink duplicates with different extended attributes. Replaced files lose their own
+    /// attributes, like `user.*` metadata and file capabilities, for those of the file they're
+    /// hardlinked to.
+    #[arg(long, default_value_t = false)]
+    ignore_xattrs: bool,
+
+    /// Hardlink duplicates with different POSIX ACLs. Replaced files get the ACL of the file they're
+    /// hardlinked to. ACLs are compared even with --ignore-xattrs unless this is given.
+    #[arg(long, default_value_t = false)]
+    ignore_acls: bool,
+
+    /// Hardlink duplicates with different SELinux security contexts. Replaced files get the context
+    /// of the file they're hardlinked to. Contexts are compared even with --ignore-xattrs unless this
+    /// is given.
+    #[arg(long, default_value_t = false)]
+    ignore_selinux: bool,
+
+    /// Skip duplicates that another process has open for writing, since replacing a file under an
+    /// active writer loses what it writes afterwards.
+    #[arg(long, default_value_t = false)]
+    skip_open_files: bool,
+
+    /// Hardlink empty files too. They're skipped by default, since hardlinking them saves no space
+    /// and ties together files like locks and markers that are written separately later.
+    #[arg(long, default_value_t = false)]
+    include_empty: bool,
+
+    /// Refuse to deduplicate paths seen through another process's mount namespace (like
+    /// /proc/<pid>/root of a container), on overlay filesystems, or in the layer storage of container
+    /// engines. Their devices and inodes may not be the files they seem to be, so by default we warn
+    /// about them.
+    #[arg(long, default_value_t = false)]
+    refuse_foreign_mounts: bool,
+
+    /// Also scan this directory, but never replace its files: duplicates elsewhere are hardlinked to
+    /// them instead, e.g. to deduplicate backup snapshots against the latest one. Can be given several
+    /// times.
+    #[arg(long = "reference", value_name = "DIR")]
+    reference_dirs: Vec<PathBuf>,
+
+    /// Never replace files whose path matches this glob, though other files may be hardlinked to
+    /// them. Patterns with a `/` match absolute paths (e.g. '/srv/live/**'), and others match file
+    /// and directory names (e.g. '*.sqlite'). Everything in a matching directory is protected. Can be
+    /// given several times.
+    #[arg(long, value_name = "PATTERN")]
+    protect: Vec<String>,
+
+    /// Read more --protect patterns from this file, one per line. Empty lines and lines starting
+    /// with `#` are skipped.
+    #[arg(long, value_name = "FILE")]
+    protect_from: Option<PathBuf>,
+
+    /// Report files with the same size and prefix that differ in at most this many bytes (16 by default),
+    /// e.g. --report-near-duplicates=4. Such files are often a healthy and a silently corrupted copy of
+    /// the same file.
+    #[arg(
+        long,
+        value_name = "MAX_BYTES",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "16"
+    )]
+    report_near_duplicates: Option<usize>,
+
+    /// Follow symlinks to files and directories. The files symlinks point to are deduplicated, the symlinks
+    /// themselves are left alone. Directories reachable through several symlinks are only scanned once.
+    #[arg(long, short = 'L', default_value_t = false)]
+    follow_symlinks: bool,
+
+    /// Only descend this many directory levels below the given paths. With 1 only files directly in the given
+    /// directories are deduplicated.
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// Repair the corrupted one of two near-duplicates (see --report-near-duplicates) by replacing it with a
+    /// hardlink to the healthy one. The corrupted contents are kept in a `.corrupt-backup` file next to it.
+    /// With `majority` (the default) a copy is repaired only if more files agree on the other copy's
+    /// contents. With `interactive` you're asked which copy is healthy, e.g. --repair-from-duplicate=interactive.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "majority"
+    )]
+    repair_from_duplicate: Option<RepairMode>,
+
+    /// Don't look for duplicates but hardlink the groups of duplicates listed in this file, as
+    /// printed by `fdupes`, `jdupes` or --output fdupes: one path per line and an empty line after
+    /// each group. Files are only hardlinked if their size and contents still match. The paths are
+    /// ignored.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["what_if", "stdio", "watch", "interactive"])]
+    dupes_from: Option<PathBuf>,
+
+    /// Don't scan the paths but hardlink the files with the same size and hash in these indexes,
+    /// written by `hardlink-dedup index`. Can be given several times, e.g. with indexes of different
+    /// volumes. Files that changed since they were indexed are skipped, and the rest are only
+    /// hardlinked if their contents still match.
+    #[arg(long = "index", value_name = "FILE", conflicts_with_all = ["what_if", "stdio", "watch", "interactive", "dupes_from"])]
+    indexes: Vec<PathBuf>,
+
+    /// Keep the hashes of the files seen in this state file (creating it if it's missing), and only
+    /// hash the files that are new or changed since the last run and hardlink them to files with the
+    /// same contents. Files that didn't change aren't grouped again, which makes runs over large,
+    /// mostly unchanged trees much faster. Dry runs don't update the state.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["what_if", "stdio", "watch", "interactive", "dupes_from", "indexes", "max_memory"])]
+    incremental: Option<PathBuf>,
+
+    /// Stop after processing N files, finishing the hardlinks in flight, and exit with code 4.
+    /// With --checkpoint, the next run continues with --resume.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_files: Option<u64>,
+
+    /// Stop once the run took this long (e.g. 2h), scanning included, finishing the hardlinks in
+    /// flight, and exit with code 4. With --checkpoint, the next run continues with --resume.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    max_duration: Option<Duration>,
+
+    /// Periodically record which groups of files have been fully processed in this file, so that an
+    /// interrupted run can be continued with --resume. Dry runs process no groups fully.
+    #[arg(long, value_name = "FILE")]
+    checkpoint: Option<PathBuf>,
+
+    /// Skip groups of files that a previous run recorded as fully processed in this --checkpoint file.
+    #[arg(long, value_name = "FILE")]
+    resume: Option<PathBuf>,
+
+    /// After deduplicating, keep running and hardlink new files under the paths to existing files with the
+    /// same contents as soon as they're written. Stop with Ctrl+C.
+    #[arg(long, default_value_t = false, conflicts_with = "check")]
+    watch: bool,
+
+    /// Record every replaced file in this journal (appending to it if it exists), so that the
+    /// deduplication can be undone with `hardlink-dedup undo <JOURNAL>`.
+    #[arg(long, value_name = "FILE")]
+    journal: Option<PathBuf>,
+
+    /// Show each group of duplicates with its sizes and ask before hardlinking it: [y]es, [n]o, [a]ll
+    /// remaining groups, or [q]uit.
+    #[arg(long, short = 'i', default_value_t = false, conflicts_with_all = ["dry_run", "check", "watch"])]
+    interactive: bool,
+
+    /// Also report files with the same contents on different filesystems and how many bytes they
+    /// take up. They can't be hardlinked, but moving or consolidating them would save space.
+    #[arg(long, default_value_t = false)]
+    report_cross_device: bool,
+
+    /// Browse the groups of duplicates in the terminal, choose which file of each group to keep and which
+    /// groups to leave alone, then hardlink the selection.
+    #[cfg(feature = "tui")]
+    #[arg(long, default_value_t = false, conflicts_with_all = ["check", "watch", "interactive", "what_if", "stdio", "dupes_from", "indexes", "incremental"])]
+    tui: bool,
+
+    /// Keep running and take JSON commands on stdin (one per line: `scan`, `status`, `apply-group`
+    /// and `cancel`), printing events as JSON lines on stdout, so that editors and file managers can
+    /// embed the deduplication. Scans default to the given paths.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["check", "watch", "interactive", "what_if"])]
+    stdio: bool,
+
+    /// Write a CSV report with a row for every duplicate file: its group, the kept file, the file
+    /// linked to it, their size and hash, and what was done with it (e.g. linked, would link, already
+    /// linked, or skipped and why).
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["stdio", "what_if"])]
+    report_csv: Option<PathBuf>,
+
+    /// Append the run to this SQLite database (creating it if it's missing): the groups of duplicate
+    /// files, their hashes, and the hardlinks created, for querying past runs with SQL.
+    #[cfg(feature = "sqlite")]
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["stdio", "what_if", "dupes_from", "indexes", "incremental"])]
+    export_sqlite: Option<PathBuf>,
+
+    /// Also write the summary of the run as a JSON document to this file: the bytes saved, the files
+    /// ruled out and skipped by reason, the failed files and hardlinks, and how long each stage took.
+    #[arg(long, value_name = "FILE")]
+    stats_file: Option<PathBuf>,
+
+    /// Break the savings down by directory in the summary: the duplicates hardlinked and bytes
+    /// freed under each directory N levels below the paths (e.g. 1 for each user under /home),
+    /// most bytes first.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    breakdown_depth: Option<u64>,
+
+    /// Also write the bytes saved, files hardlinked, errors and duration of the run to this file in
+    /// the format of node_exporter's textfile collector, e.g.
+    /// /var/lib/node_exporter/hardlink_dedup.prom.
+    #[arg(long, value_name = "FILE")]
+    metrics_textfile: Option<PathBuf>,
+
+    /// After the run, print to stderr how long each stage took (walking the paths, grouping by
+    /// metadata, comparing prefixes and tails, hashing, comparing bytes, and hardlinking) and how
+    /// many files or bytes it processed.
+    #[arg(long, default_value_t = false)]
+    profile: bool,
+
+    /// Hold an exclusive lock on this file (creating it if it's missing) during the run, and refuse
+    /// to start while another run holds it. Keeps overlapping runs, e.g. from cron, apart.
+    #[arg(long, value_name = "FILE")]
+    lockfile: Option<PathBuf>,
+
+    /// Hold an exclusive lock on each of the paths during the run, and refuse to start while another
+    /// run holds a lock on one of them.
+    #[arg(long, default_value_t = false)]
+    lock_paths: bool,
+
+    /// Wait for other runs to release their locks instead of refusing to start.
+    #[arg(long, default_value_t = false)]
+    wait_for_lock: bool,
+
+    /// Don't show the progress bar. The progress bar is only shown when stderr is a terminal.
+    #[arg(long, default_value_t = false)]
+    no_progress: bool,
+
+    /// When stderr isn't a terminal, print a status line with the files processed per second and
+    /// the bytes hashed and saved at most this often instead of a progress bar (e.g. 30s or 5m).
+    /// Defaults to 5s unless --verbose already prints every file.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, conflicts_with = "no_progress")]
+    progress_interval: Option<Duration>,
+
+    /// Run as a hash worker process. Used internally by --hash-workers.
+    #[arg(long, hide = true)]
+    hash_worker: bool,
+
+    /// Paths (directories or files) to deduplicate. Directories will be recursively traversed. Softlinks won't be followed
+    /// unless --follow-symlinks is given.
     /// If no paths are specified nothing will be deduped.
     paths: Vec<PathBuf>,
 }
 
+/// Some files couldn't be read, compared, or hardlinked.
+const EXIT_FAILED_FILES: u8 = 1;
+/// The paths to deduplicate couldn't be accessed. Invalid arguments exit with the same code.
+const EXIT_SETUP_ERROR: u8 = 2;
+/// With --check: hardlinking would save more bytes than allowed. With verify: the audit found
+/// unlinked duplicates or diverged hardlinks.
+const EXIT_NOT_DEDUPLICATED: u8 = 3;
+/// Stopped early by --max-files or --max-duration. Run again with --resume to continue.
+const EXIT_BUDGET_EXHAUSTED: u8 = 4;
+/// Stopped early by SIGINT, SIGTERM, or quitting --interactive.
+const EXIT_INTERRUPTED: u8 = 130;
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Hardlinks files with the same contents under the paths, like running without a subcommand.
+    Dedup(Box<DedupArgs>),
+    /// Reports the duplicates under the paths and how much hardlinking them would save without
+    /// changing anything, like `dedup --dry-run`.
+    Report(Box<DedupArgs>),
+    /// Runs the deduplication jobs of a TOML file one after another and prints the summary of every job
+    /// and of all of them. Each [[job]] has its `paths` and optionally a `name`, a `mode` (link or
+    /// dry-run), the filters `min-age`, `exclude-newer-than`, `exclude-older-than`, `max-depth`,
+    /// `min-copies`, `one-file-system` and `follow-symlinks`, and a `report` file for its summary as
+    /// JSON.
+    Batch { jobs: PathBuf },
+    /// Turns the files recorded in a --journal back into independent copies, most recent first. Files
+    /// whose contents changed since they were hardlinked are left alone.
+    Undo {
+        journal: PathBuf,
+
+        /// Only print which files would be restored.
+        #[arg(long, short = 'n', default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Reports which files are already hardlinked together without changing anything. Exits with code 3
+    /// if files with the same contents aren't hardlinked or if hardlinks to the same file read back
+    /// different contents.
+    Verify {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Don't descend into directories on other filesystems.
+        #[arg(long, short = 'x', default_value_t = false)]
+        one_file_system: bool,
+
+        /// Follow symlinks to files and directories.
+        #[arg(long, short = 'L', default_value_t = false)]
+        follow_symlinks: bool,
+    },
+    /// Hashes the files under the paths without hardlinking anything and writes their hashes to an
+    /// index for `--index`, so that volumes can be indexed at different times or on different
+    /// machines and deduplicated together later.
+    Index {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// The index file to write, replacing it if it exists.
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+
+        /// The hash algorithm. Only files hashed with the same algorithm are deduplicated together.
+        #[arg(long, value_enum, default_value_t = HashAlgorithm::Sha256)]
+        hash: HashAlgorithm,
+
+        /// Don't descend into directories on other filesystems.
+        #[arg(long, short = 'x', default_value_t = false)]
+        one_file_system: bool,
+
+        /// Follow symlinks to files and directories.
+        #[arg(long, short = 'L', default_value_t = false)]
+        follow_symlinks: bool,
+    },
+    /// Reports how many files and bytes an index or the state of --incremental holds, and how many
+    /// of the files are hashed.
+    Cache {
+        file: PathBuf,
+
+        /// First drop the paths that disappeared or changed since they were recorded, and the files
+        /// left without any.
+        #[arg(long, default_value_t = false)]
+        prune: bool,
+    },
+    /// Prints the tab completion script of a shell, e.g. for bash:
+    /// `hardlink-dedup completions bash > /etc/bash_completion.d/hardlink-dedup`.
+    Completions { shell: Shell },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
 fn main() -> ExitCode {
-    let args = Args::parse();
-    env_logger::init();
-    dedup(&args.paths, args.dry_run, args.paranoid);
-    ExitCode::SUCCESS
+    let mut args = parse_args();
+    match args.command.take() {
+        Some(Command::Dedup(dedup)) => args.dedup = *dedup,
+        Some(Command::Report(dedup)) => {
+            args.dedup = *dedup;
+            args.dedup.dry_run = true;
+        }
+        command => args.command = command,
+    }
+    if args.dedup.list || args.dedup.list_duplicates {
+        args.dedup.dry_run = true;
+        args.output = OutputFormat::List;
+    }
+    if args.version {
+        print_version(args.json);
+        return ExitCode::SUCCESS;
+    }
+    init_logger(&args);
+    if args.dedup.hash_worker {
+        return match run_hash_worker(
+            args.dedup.hash,
+            args.dedup.buffer_size as usize,
+            args.dedup.fadvise,
+            args.dedup.bwlimit,
+        ) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(_) => ExitCode::FAILURE,
+        };
+    }
+    if let Some(Command::Completions { shell }) = &args.command {
+        clap_complete::generate(
+            *shell,
+            &mut Args::command(),
+            "hardlink-dedup",
+            &mut io::stdout(),
+        );
+        return ExitCode::SUCCESS;
+    }
+    if let Some(Command::Undo { journal, dry_run }) = &args.command {
+        return run_undo(journal, *dry_run, args.output);
+    }
+    if let Some(Command::Verify {
+        paths,
+        one_file_system,
+        follow_symlinks,
+    }) = &args.command
+    {
+        return run_verify(
+            paths,
+            &DedupOptions {
+                one_file_system: *one_file_system,
+                follow_symlinks: *follow_symlinks,
+                ..DedupOptions::default()
+            },
+            args.output,
+        );
+    }
+    if let Some(Command::Index {
+        paths,
+        out,
+        hash,
+        one_file_system,
+        follow_symlinks,
+    }) = &args.command
+    {
+        return run_index(
+            paths,
+            out,
+            &DedupOptions {
+                hash: *hash,
+                one_file_system: *one_file_system,
+                follow_symlinks: *follow_symlinks,
+                raw_bytes: args.bytes,
+                own_files: vec![out.clone()],
+                ..DedupOptions::default()
+            },
+            args.output,
+        );
+    }
+    if let Some(Command::Cache { file, prune }) = &args.command {
+        return run_cache(file, *prune, args.output, args.bytes);
+    }
+    if args.dedup.nice_io {
+        if let Err(err) = lower_priority() {
+            warn!("Failed to lower the I/O and CPU priority. Error: {}", err);
+        }
+    }
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let snapshot_requested = Arc::new(AtomicBool::new(false));
+    if let Err(err) = handle_signals(&interrupted, &snapshot_requested) {
+        warn!("Failed to set up signal handlers. Error: {}", err);
+    }
+    let protected = match protected_paths(&args) {
+        Ok(protected) => protected,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::from(EXIT_SETUP_ERROR);
+        }
+    };
+    let options = DedupOptions {
+        dry_run: args.dedup.dry_run || args.dedup.check,
+        paranoid: args.dedup.paranoid,
+        prefix_bytes: Some(args.dedup.prefix_bytes),
+        tail_bytes: Some(args.dedup.tail_bytes),
+        tail_first_extensions: args.dedup.tail_first_ext.clone(),
+        prefer: args.dedup.prefer,
+        hash: args.dedup.hash,
+        buffer_size: Some(args.dedup.buffer_size as usize),
+        fadvise: args.dedup.fadvise,
+        bwlimit: args.dedup.bwlimit,
+        #[cfg(feature = "io-uring")]
+        io_uring: args.dedup.io_uring,
+        #[cfg(not(feature = "io-uring"))]
+        io_uring: false,
+        hash_workers: args.dedup.hash_workers,
+        min_copies: Some(args.dedup.min_copies as usize),
+        max_nlink: args.dedup.max_nlink,
+        same_name_only: args.dedup.same_name_only,
+        breakdown_depth: args.dedup.breakdown_depth.map(|depth| depth as usize),
+        deterministic: args.dedup.deterministic,
+        max_parallel_hashes: args.dedup.max_parallel_hashes.map(|hashes| hashes as usize),
+        jobs: args.dedup.jobs as usize,
+        max_memory: args.dedup.max_memory,
+        hash_worker_memory_limit: args.dedup.hash_worker_memory_limit,
+        hash_worker_timeout: args.dedup.hash_worker_timeout,
+        min_age: args.dedup.min_age,
+        exclude_newer_than: args.dedup.exclude_newer_than,
+        exclude_older_than: args.dedup.exclude_older_than,
+        double_read_verify: args.dedup.double_read_verify,
+        one_file_system: args.dedup.one_file_system,
+        honor_cachedir_tags: args.dedup.honor_cachedir_tags,
+        ignore_owner: args.dedup.ignore_owner,
+        ignore_mode: args.dedup.ignore_mode,
+        link_mode: args.dedup.mode,
+        fallback: args.dedup.fallback,
+        require_same_mtime: args.dedup.require_same_mtime,
+        preserve_dir_mtimes: args.dedup.preserve_dir_mtimes,
+        fsync: args.dedup.fsync,
+        group_by_allocated_size: args.dedup.group_by_allocated_size,
+        ignore_xattrs: args.dedup.ignore_xattrs,
+        ignore_acls: args.dedup.ignore_acls,
+        ignore_selinux: args.dedup.ignore_selinux,
+        skip_open_files: args.dedup.skip_open_files,
+        include_empty: args.dedup.include_empty,
+        refuse_foreign_mounts: args.dedup.refuse_foreign_mounts,
+        reference_dirs: args.dedup.reference_dirs.clone(),
+        protected,
+        candidate_filters: CandidateFilters::default(),
+        report_near_duplicates: args.dedup.report_near_duplicates,
+        follow_symlinks: args.dedup.follow_symlinks,
+        max_depth: args.dedup.max_depth,
+        repair_from_duplicate: args.dedup.repair_from_duplicate,
+        progress: !args.dedup.no_progress && stderr().is_terminal(),
+        progress_interval: progress_interval(&args),
+        output: args.output,
+        raw_bytes: args.bytes,
+        interrupted,
+        max_files: args.dedup.max_files.map(|max| max as usize),
+        max_duration: args.dedup.max_duration,
+        snapshot_requested,
+        checkpoint: args.dedup.checkpoint.clone(),
+        resume: args.dedup.resume.clone(),
+        watch: args.dedup.watch,
+        journal: args.dedup.journal.clone(),
+        own_files: own_files(&args),
+        interactive: args.dedup.interactive,
+        report_cross_device: args.dedup.report_cross_device,
+    };
+    let lock_paths = if args.dedup.lock_paths {
+        &args.dedup.paths[..]
+    } else {
+        &[]
+    };
+    let _lock = match lock::acquire(
+        args.dedup.lockfile.as_deref(),
+        lock_paths,
+        args.dedup.wait_for_lock,
+    ) {
+        Ok(lock) => lock,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::from(EXIT_SETUP_ERROR);
+        }
+    };
+    if let Some(Command::Batch { jobs }) = &args.command {
+        return exit_code(&args, batch::run(jobs, &options));
+    }
+    if args.dedup.what_if {
+        return run_what_if(&args.dedup.paths, &options, args.output);
+    }
+    if args.dedup.stdio {
+        return exit_code(&args, stdio::run(&args.dedup.paths, &options));
+    }
+    #[cfg(feature = "tui")]
+    if args.dedup.tui {
+        return exit_code(&args, tui::run(&args.dedup.paths, &options));
+    }
+    let mut renderer = if args.dedup.list_duplicates {
+        let separator = if args.dedup.null { b'\0' } else { b'\n' };
+        Box::new(output::PathListRenderer::new(separator))
+    } else {
+        output::renderer(options.output)
+    };
+    if options.dry_run || args.dedup.top.is_some() {
+        renderer = Box::new(sorted_groups::SortedGroups::new(renderer, args.dedup.top));
+    }
+    if let Some(report) = &args.dedup.report_csv {
+        renderer = match csv_report::CsvRenderer::create(report, renderer, &options) {
+            Ok(csv_renderer) => Box::new(csv_renderer),
+            Err(err) => return exit_code(&args, Err(err)),
+        };
+    }
+    if let Some(list) = &args.dedup.dupes_from {
+        return exit_code(&args, link_dupes_list(list, &options, renderer.as_mut()));
+    }
+    if let Some(state) = &args.dedup.incremental {
+        return exit_code(
+            &args,
+            dedup_incremental(&args.dedup.paths, &options, state, renderer.as_mut()),
+        );
+    }
+    if !args.dedup.indexes.is_empty() {
+        return exit_code(
+            &args,
+            link_indexes(&args.dedup.indexes, &options, renderer.as_mut()),
+        );
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(database) = &args.dedup.export_sqlite {
+        return exit_code(
+            &args,
+            sqlite_export::run(database, &args.dedup.paths, &options, renderer.as_mut()),
+        );
+    }
+    exit_code(
+        &args,
+        dedup_with_renderer(&args.dedup.paths, &options, renderer.as_mut()),
+    )
+}
+
+fn exit_code(args: &Args, result: Result<DedupSummary, DedupError>) -> ExitCode {
+    let result = result.and_then(|summary| {
+        if let Some(stats_file) = &args.dedup.stats_file {
+            write_stats_file(stats_file, &summary)?;
+        }
+        if let Some(textfile) = &args.dedup.metrics_textfile {
+            metrics::write_textfile(textfile, &summary)?;
+        }
+        if args.dedup.profile {
+            output::print_profile(&summary, args.bytes);
+        }
+        Ok(summary)
+    });
+    match result {
+        Ok(summary) if summary.budget_exhausted => ExitCode::from(EXIT_BUDGET_EXHAUSTED),
+        Ok(summary) if summary.interrupted => ExitCode::from(EXIT_INTERRUPTED),
+        Ok(summary) if summary.failed_files > 0 => ExitCode::from(EXIT_FAILED_FILES),
+        Ok(summary)
+            if args.dedup.check && summary.bytes_deduped as u64 > args.dedup.check_threshold =>
+        {
+            error!(
+                "The paths aren't fully deduplicated. Hardlinking would save {} bytes, more than the threshold of {} bytes.",
+                summary.bytes_deduped, args.dedup.check_threshold
+            );
+            ExitCode::from(EXIT_NOT_DEDUPLICATED)
+        }
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::from(EXIT_SETUP_ERROR)
+        }
+    }
+}
+
+/// The patterns of --protect and --protect-from.
+fn protected_paths(args: &Args) -> Result<ProtectedPaths, DedupError> {
+    let mut patterns = args.dedup.protect.clone();
+    if let Some(protect_from) = &args.dedup.protect_from {
+        patterns.extend(protect::read_patterns(protect_from)?);
+    }
+    ProtectedPaths::new(&patterns)
+}
+
+/// The reports, databases and lockfile the run writes, which mustn't be deduplicated when they're
+/// under the paths.
+fn own_files(args: &Args) -> Vec<PathBuf> {
+    #[allow(unused_mut)]
+    let mut own_files: Vec<PathBuf> = args
+        .dedup
+        .report_csv
+        .iter()
+        .chain(&args.dedup.stats_file)
+        .chain(&args.dedup.metrics_textfile)
+        .chain(&args.dedup.lockfile)
+        .chain(&args.dedup.incremental)
+        .cloned()
+        .collect();
+    #[cfg(feature = "sqlite")]
+    if let Some(database) = &args.dedup.export_sqlite {
+        // SQLite keeps its rollback journal next to the database while writing it.
+        let mut rollback_journal = database.as_os_str().to_owned();
+        rollback_journal.push("-journal");
+        own_files.push(database.clone());
+        own_files.push(rollback_journal.into());
+    }
+    own_files
+}
+
+fn write_stats_file(stats_file: &Path, summary: &DedupSummary) -> Result<(), DedupError> {
+    write(
+        stats_file,
+        serde_json::to_string_pretty(summary).unwrap() + "\n",
+    )
+    .map_err(|source| DedupError::WriteStats {
+        path: stats_file.to_owned(),
+        source,
+    })
+}
+
+fn print_version(json: bool) {
+    let info = build_info();
+    if json {
+        println!("{}", serde_json::to_string(&info).unwrap());
+        return;
+    }
+    println!(
+        "hardlink-dedup {} (commit {})",
+        info.version,
+        info.git_commit.unwrap_or("unknown")
+    );
+    if !info.features.is_empty() {
+        println!("features: {}", info.features.join(", "));
+    }
+    let backends = info.backends;
+    let supported: Vec<_> = [
+        ("hardlink", backends.hardlink),
+        ("reflink", backends.reflink),
+        ("ioctl", backends.ioctl),
+        ("io_uring", backends.io_uring),
+    ]
+    .into_iter()
+    .filter_map(|(name, supported)| supported.then_some(name))
+    .collect();
+    println!("backends: {}", supported.join(", "));
+}
+
+fn run_undo(journal: &Path, dry_run: bool, output: OutputFormat) -> ExitCode {
+    match undo(journal, dry_run) {
+        Ok(summary) => {
+            print_result(output, &summary, |summary| {
+                println!("Restored files: {}", summary.restored_files);
+                if summary.skipped_files > 0 {
+                    println!("Skipped files: {}", summary.skipped_files);
+                }
+                if summary.failed_files > 0 {
+                    println!("Files that failed to restore: {}", summary.failed_files);
+                }
+            });
+            if summary.failed_files > 0 {
+                return ExitCode::from(EXIT_FAILED_FILES);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::from(EXIT_SETUP_ERROR)
+        }
+    }
+}
+
+/// The first SIGINT or SIGTERM sets the flag so that the deduplication stops gracefully. The second
+/// one exits immediately. SIGUSR1 requests a progress snapshot.
+fn handle_signals(
+    interrupted: &Arc<AtomicBool>,
+    snapshot_requested: &Arc<AtomicBool>,
+) -> io::Result<()> {
+    for signal in [SIGINT, SIGTERM] {
+        flag::register_conditional_shutdown(signal, EXIT_INTERRUPTED.into(), interrupted.clone())?;
+        flag::register(signal, interrupted.clone())?;
+    }
+    flag::register(SIGUSR1, snapshot_requested.clone())?;
+    Ok(())
+}
+
+/// Moves this process to the idle I/O scheduling class (as `ionice -c 3` does) and to the lowest CPU
+/// priority (as `nice -n 19` does).
+fn lower_priority() -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    let ioprio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Parses the arguments together with the options of the config file they don't give.
+fn parse_args() -> Args {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let mut command = Args::command();
+    // Built, the subcommands take the global options too.
+    command.build();
+    let matches = command.clone().get_matches_from(&args);
+    // Subcommands can't follow options, so those of `dedup` and `report` start right after them.
+    let (dedup_command, dedup_matches, options_start) = match matches.subcommand() {
+        None => (&command, &matches, 1),
+        Some((name @ ("dedup" | "report"), subcommand_matches)) => (
+            command.find_subcommand(name).unwrap(),
+            subcommand_matches,
+            2,
+        ),
+        Some(_) => (&command, &matches, 0),
+    };
+    let config = if options_start == 0 || dedup_matches.get_flag("no_config") {
+        None
+    } else {
+        (dedup_matches.get_one::<PathBuf>("config").cloned()).or_else(config::default_config)
+    };
+    let Some(config) = config else {
+        return Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    };
+    match config::with_config(dedup_command, dedup_matches, args, options_start, &config) {
+        Ok(args) => Args::parse_from(args),
+        Err(err) => command.clone().error(ErrorKind::InvalidValue, err).exit(),
+    }
+}
+
+/// How often to print status lines when there's no progress bar. Never for quiet runs, JSON logs,
+/// or with --no-progress.
+fn progress_interval(args: &Args) -> Option<Duration> {
+    if args.quiet || args.dedup.no_progress || matches!(args.log_format, LogFormat::Json) {
+        return None;
+    }
+    let default = (args.verbose == 0).then_some(Duration::from_secs(5));
+    args.dedup.progress_interval.or(default)
+}
+
+/// Logs to stderr at the level chosen with --quiet and --verbose. `RUST_LOG` overrides the level.
+/// Closing a pipeline stage's span logs how long the stage took.
+fn init_logger(args: &Args) {
+    let level = if args.quiet {
+        LevelFilter::ERROR
+    } else {
+        match args.verbose {
+            0 => LevelFilter::WARN,
+            1 => LevelFilter::INFO,
+            2 => LevelFilter::DEBUG,
+            _ => LevelFilter::TRACE,
+        }
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(stderr)
+        .with_span_events(FmtSpan::CLOSE);
+    // Messages are colored for the terminal they're logged to, which isn't necessarily stdout's.
+    let color = matches!(args.log_format, LogFormat::Text)
+        && stderr().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none_or(|no_color| no_color.is_empty());
+    colored::control::set_override(color);
+    match args.log_format {
+        LogFormat::Text => subscriber
+            .with_timer(())
+            .with_target(false)
+            .with_ansi(color)
+            .init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+fn run_index(
+    paths: &[PathBuf],
+    index_file: &Path,
+    options: &DedupOptions,
+    output: OutputFormat,
+) -> ExitCode {
+    match index(paths, options, index_file) {
+        Ok(summary) => {
+            print_result(output, &summary, |summary| {
+                println!("Indexed files: {}", summary.indexed_files);
+                println!(
+                    "Indexed bytes: {}",
+                    size_value(summary.indexed_bytes, options.raw_bytes)
+                );
+                if summary.failed_files > 0 {
+                    println!("Files that failed to index: {}", summary.failed_files);
+                }
+            });
+            if summary.failed_files > 0 {
+                return ExitCode::from(EXIT_FAILED_FILES);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::from(EXIT_SETUP_ERROR)
+        }
+    }
+}
+
+fn run_cache(file: &Path, prune: bool, output: OutputFormat, raw_bytes: bool) -> ExitCode {
+    match cache(file, prune) {
+        Ok(summary) => {
+            print_result(output, &summary, |summary| {
+                println!("Hash: {}", summary.hash);
+                println!("Files: {}", summary.files);
+                println!("Hashed files: {}", summary.hashed_files);
+                println!("Bytes: {}", size_value(summary.bytes, raw_bytes));
+                if prune {
+                    println!("Pruned files: {}", summary.pruned_files);
+                }
+            });
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::from(EXIT_SETUP_ERROR)
+        }
+    }
+}
+
+fn run_verify(paths: &[PathBuf], options: &DedupOptions, output: OutputFormat) -> ExitCode {
+    match verify(paths, options) {
+        Ok(summary) => {
+            print_result(output, &summary, |summary| {
+                println!("Hardlinked files: {}", summary.hardlinked_files);
+                println!(
+                    "Groups of files with the same contents that aren't hardlinked: {}",
+                    summary.unlinked_duplicates
+                );
+                println!(
+                    "Hardlinked files that read back different contents: {}",
+                    summary.diverged_files
+                );
+                if summary.failed_files > 0 {
+                    println!("Files that failed to verify: {}", summary.failed_files);
+                }
+            });
+            if summary.has_findings() {
+                ExitCode::from(EXIT_NOT_DEDUPLICATED)
+            } else if summary.failed_files > 0 {
+                ExitCode::from(EXIT_FAILED_FILES)
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::from(EXIT_SETUP_ERROR)
+        }
+    }
+}
+
+fn run_what_if(paths: &[PathBuf], options: &DedupOptions, output: OutputFormat) -> ExitCode {
+    match what_if(paths, options) {
+        Ok(summary) => {
+            let size = |bytes: u64| size_value(bytes, options.raw_bytes);
+            print_result(output, &summary, |summary| {
+                println!("Projected saved bytes by policy:");
+                println!(
+                    "  hardlink files with the same owner and mode: {}",
+                    size(summary.hardlink)
+                );
+                println!(
+                    "  hardlink files regardless of owner and mode: {}",
+                    size(summary.hardlink_ignoring_owner)
+                );
+                println!(
+                    "  hardlink files of at least 1M with the same owner and mode: {}",
+                    size(summary.hardlink_min_size_1m)
+                );
+                println!("  reflink files (btrfs, XFS): {}", size(summary.reflink));
+                if summary.failed_files > 0 {
+                    println!("Files that failed to process: {}", summary.failed_files);
+                }
+            });
+            if summary.failed_files > 0 {
+                return ExitCode::from(EXIT_FAILED_FILES);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::from(EXIT_SETUP_ERROR)
+        }
+    }
 }
@@ -0,0 +1,102 @@
+//! Metrics of the run for Prometheus, written in the format of node_exporter's textfile collector.
+//!
+//! The collector reads `*.prom` files from a directory on every scrape, so the file is written next
+//! to its final path and renamed over it. Scrapes never see it half-written.
+
+use hardlink_dedup::{DedupError, DedupSummary};
+use std::fmt::Write;
+use std::fs::{rename, write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Writes the metrics of the run to the file.
+pub fn write_textfile(textfile: &Path, summary: &DedupSummary) -> Result<(), DedupError> {
+    let mut temp_file = textfile.as_os_str().to_owned();
+    temp_file.push(format!(".{}.tmp", std::process::id()));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    write(&temp_file, textfile_contents(summary, now.as_secs()))
+        .and_then(|()| rename(&temp_file, textfile))
+        .map_err(|source| DedupError::WriteMetrics {
+            path: textfile.to_owned(),
+            source,
+        })
+}
+
+fn textfile_contents(summary: &DedupSummary, finished_at: u64) -> String {
+    let metrics = [
+        (
+            "hardlink_dedup_bytes_saved",
+            "gauge",
+            "Bytes saved by replacing duplicates with hardlinks in the last run.",
+            summary.bytes_deduped.to_string(),
+        ),
+        (
+            "hardlink_dedup_files_linked",
+            "gauge",
+            "Files replaced with hardlinks in the last run.",
+            summary.stats.linked_files.to_string(),
+        ),
+        (
+            "hardlink_dedup_errors_total",
+            "counter",
+            "Files that the last run failed to read, compare, or hardlink.",
+            summary.failed_files.to_string(),
+        ),
+        (
+            "hardlink_dedup_run_duration_seconds",
+            "gauge",
+            "Wall time of the last run.",
+            format!("{:.3}", summary.stats.wall_time_ms as f64 / 1000.0),
+        ),
+        (
+            "hardlink_dedup_interrupted",
+            "gauge",
+            "Whether the last run was stopped early.",
+            u8::from(summary.interrupted).to_string(),
+        ),
+        (
+            "hardlink_dedup_last_run_timestamp_seconds",
+            "gauge",
+            "When the last run finished, in seconds since the Unix epoch.",
+            finished_at.to_string(),
+        ),
+    ];
+    let mut contents = String::new();
+    for (name, metric_type, help, value) in metrics {
+        writeln!(contents, "# HELP {} {}", name, help).unwrap();
+        writeln!(contents, "# TYPE {} {}", name, metric_type).unwrap();
+        writeln!(contents, "{} {}", name, value).unwrap();
+    }
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_metrics() {
+        let mut summary = DedupSummary {
+            bytes_deduped: 4096,
+            failed_files: 2,
+            ..DedupSummary::default()
+        };
+        summary.stats.linked_files = 3;
+        summary.stats.wall_time_ms = 1250;
+
+        let contents = textfile_contents(&summary, 1700000000);
+
+        assert!(contents.starts_with(
+            "# HELP hardlink_dedup_bytes_saved Bytes saved by replacing duplicates with hardlinks in the last run.\n\
+             # TYPE hardlink_dedup_bytes_saved gauge\n\
+             hardlink_dedup_bytes_saved 4096\n"
+        ));
+        assert!(contents.contains("\nhardlink_dedup_files_linked 3\n"));
+        assert!(contents.contains("\nhardlink_dedup_errors_total 2\n"));
+        assert!(contents.contains("\nhardlink_dedup_run_duration_seconds 1.250\n"));
+        assert!(contents.contains("\nhardlink_dedup_interrupted 0\n"));
+        assert!(contents.ends_with("\nhardlink_dedup_last_run_timestamp_seconds 1700000000\n"));
+    }
+}
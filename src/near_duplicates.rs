@@ -0,0 +1,86 @@
+//! Detection of files that differ in only a few bytes.
+//!
+//! Two copies of the same file that differ in a handful of bytes are more likely a healthy copy and
+//! a silently corrupted one than two genuinely different files.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How many differing bytes still make two files near-duplicates unless configured otherwise.
+pub const DEFAULT_MAX_DIFFERENCES: usize = 16;
+
+/// Offsets at which the two files differ, or `None` if they differ at more than
+/// `max_differences` offsets or have different lengths.
+pub(crate) fn differing_offsets(
+    file: &Path,
+    other_file: &Path,
+    max_differences: usize,
+) -> io::Result<Option<Vec<u64>>> {
+    let mut reader1 = BufReader::new(File::open(file)?);
+    let mut reader2 = BufReader::new(File::open(other_file)?);
+    let mut buf1 = vec![0; CHUNK_SIZE];
+    let mut buf2 = vec![0; CHUNK_SIZE];
+    let mut offsets = Vec::new();
+    let mut chunk_offset = 0u64;
+    loop {
+        let read_bytes1 = read_full(&mut reader1, &mut buf1)?;
+        let read_bytes2 = read_full(&mut reader2, &mut buf2)?;
+        if read_bytes1 != read_bytes2 {
+            return Ok(None);
+        }
+        if read_bytes1 == 0 {
+            return Ok(Some(offsets));
+        }
+        for (index, (byte1, byte2)) in buf1[..read_bytes1].iter().zip(&buf2).enumerate() {
+            if byte1 != byte2 {
+                if offsets.len() == max_differences {
+                    return Ok(None);
+                }
+                offsets.push(chunk_offset + index as u64);
+            }
+        }
+        chunk_offset += read_bytes1 as u64;
+    }
+}
+
+/// Reads until the buffer is full or the end of the file is reached.
+pub(crate) fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(read_bytes) => total += read_bytes,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn few_differences() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = write_file(tmp_dir.path(), "file1", b"abcdefgh");
+        let file2 = write_file(tmp_dir.path(), "file2", b"abXdefgY");
+        assert_eq!(
+            differing_offsets(&file1, &file2, 2).unwrap(),
+            Some(vec![2, 7])
+        );
+        assert_eq!(differing_offsets(&file1, &file2, 1).unwrap(), None);
+    }
+
+    fn write_file(dir: &Path, name: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path = dir.join(name);
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        path
+    }
+}
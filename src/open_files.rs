@@ -0,0 +1,125 @@
+//! Whether other processes have a file open for writing.
+//!
+//! A file replaced with a hardlink while another process writes to it keeps getting the writes, but
+//! under no path anymore, and the hardlink has contents the writer never meant it to have.
+//!
+//! A read lease can only be taken on files nobody has open for writing, which answers the question
+//! in one call. Leases are only granted to the file's owner and on local filesystems, so otherwise
+//! we look for the file among the open files of all processes in `/proc`.
+
+use std::fs::{metadata, read_dir, read_to_string, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Whether a process other than us has the file open for writing.
+pub(crate) fn is_open_for_writing(file: &Path) -> io::Result<bool> {
+    let opened = File::open(file)?;
+    let fd = opened.as_raw_fd();
+    if unsafe { libc::fcntl(fd, libc::F_SETLEASE, libc::F_RDLCK) } == 0 {
+        unsafe { libc::fcntl(fd, libc::F_SETLEASE, libc::F_UNLCK) };
+        return Ok(false);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EAGAIN) {
+        return Ok(true);
+    }
+    let file_metadata = opened.metadata()?;
+    is_open_for_writing_in_proc(file_metadata.dev(), file_metadata.ino())
+}
+
+/// Whether a process other than us has the inode open for writing according to `/proc`. Processes
+/// whose open files we may not see are skipped.
+fn is_open_for_writing_in_proc(dev: u64, ino: u64) -> io::Result<bool> {
+    let own_pid = std::process::id().to_string();
+    for process in read_dir("/proc")? {
+        let process = process?;
+        let pid = process.file_name();
+        if pid == own_pid.as_str() || !pid.as_encoded_bytes().iter().all(u8::is_ascii_digit) {
+            continue;
+        }
+        let Ok(fds) = read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let is_file = metadata(fd.path()).is_ok_and(|file_metadata| {
+                file_metadata.dev() == dev && file_metadata.ino() == ino
+            });
+            if !is_file {
+                continue;
+            }
+            let fdinfo = process.path().join("fdinfo").join(fd.file_name());
+            if read_to_string(fdinfo).is_ok_and(|fdinfo| opened_for_writing(&fdinfo)) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Whether the flags in the `/proc/<pid>/fdinfo/<fd>` of a file say it's open for writing.
+fn opened_for_writing(fdinfo: &str) -> bool {
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .and_then(|flags| i32::from_str_radix(flags.trim(), 8).ok())
+        .is_some_and(|flags| flags & libc::O_ACCMODE != libc::O_RDONLY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tmp_file;
+    use std::fs::OpenOptions;
+    use std::process::{Command, Stdio};
+    use std::thread::sleep;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn parse_fdinfo_flags() {
+        assert!(opened_for_writing(
+            "pos:\t0\nflags:\t0100001\nmnt_id:\t29\n"
+        ));
+        assert!(opened_for_writing(
+            "pos:\t0\nflags:\t0100002\nmnt_id:\t29\n"
+        ));
+        assert!(!opened_for_writing(
+            "pos:\t0\nflags:\t0100000\nmnt_id:\t29\n"
+        ));
+        assert!(!opened_for_writing("pos:\t0\n"));
+    }
+
+    #[test]
+    fn detect_writers() {
+        let tmp_dir = tempdir().unwrap();
+        let file = tmp_file(tmp_dir.path(), "file", "contents");
+        let file_metadata = metadata(&file).unwrap();
+        let in_proc = || is_open_for_writing_in_proc(file_metadata.dev(), file_metadata.ino());
+        assert!(!is_open_for_writing(&file).unwrap());
+
+        let mut writer = Command::new("sh")
+            .arg("-c")
+            .arg(format!("exec 3>>{:?}; read _", file))
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // Give the shell time to open the file.
+        for _ in 0..100 {
+            if in_proc().unwrap() {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        assert!(in_proc().unwrap());
+        assert!(is_open_for_writing(&file).unwrap());
+        drop(writer.stdin.take());
+        writer.wait().unwrap();
+        assert!(!is_open_for_writing(&file).unwrap());
+
+        // Our own open files don't count.
+        let _own_writer = OpenOptions::new().append(true).open(&file).unwrap();
+        assert!(!in_proc().unwrap());
+    }
+}
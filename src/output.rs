@@ -0,0 +1,890 @@
+//! Rendering of what the deduplication did.
+//!
+//! The engine only emits [`Event`]s. A [`Renderer`] turns them into output, so new output formats
+//! don't need any changes to the engine. Failures are logged separately through `tracing`.
+//!
+//! The `human` format colors hardlinks green, skips yellow and problems red, as decided by
+//! [`colored::control`]: only on terminals, and never with `NO_COLOR` set.
+
+use crate::units::{format_bytes, size_value};
+use crate::{DedupSummary, RunStats};
+use colored::{Color, Colorize};
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use tracing::Level;
+
+/// Something that happened while deduplicating.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A file found under the paths, with its size. Emitted for every path once walking the paths
+    /// finishes, before [`Event::Started`], and only to renderers that render it. Not emitted when
+    /// deduplicating in buckets under [`crate::DedupOptions::max_memory`].
+    Scanned {
+        file: &'a Path,
+        bytes: u64,
+    },
+    Started {
+        files: usize,
+    },
+    Excluded {
+        file: &'a Path,
+        reason: &'a str,
+    },
+    /// Reading the file twice gave different contents.
+    InconsistentRead {
+        file: &'a Path,
+    },
+    NearDuplicates {
+        file: &'a Path,
+        other_file: &'a Path,
+        offsets: &'a [u64],
+    },
+    Repaired {
+        file: &'a Path,
+        healthy_file: &'a Path,
+        /// `None` in dry runs.
+        backup: Option<&'a Path>,
+        dry_run: bool,
+    },
+    /// The file shares all its extents with the other file, which proves that they have the same
+    /// contents without hashing them.
+    SharedExtents {
+        file: &'a Path,
+        other_file: &'a Path,
+    },
+    /// Files with the same contents on different filesystems, which can't be hardlinked. `bytes`
+    /// is how much keeping only one copy on every filesystem would save.
+    CrossDeviceDuplicates {
+        files: &'a [&'a Path],
+        bytes: u64,
+    },
+    /// Paths of files with the same contents, each `bytes` large.
+    Duplicates {
+        files: &'a [&'a Path],
+        bytes: u64,
+    },
+    Skipped {
+        original_file: &'a Path,
+        target: &'a Path,
+        reason: SkipReason,
+    },
+    /// Files with the same contents as the original that we can't replace because we can't write
+    /// to their directories.
+    SkippedUnwritable {
+        original_file: &'a Path,
+        targets: &'a [&'a Path],
+    },
+    Hardlinked {
+        original_file: &'a Path,
+        target: &'a Path,
+        dry_run: bool,
+    },
+    /// The target was hardlinked to an original with another owner, group, or mode, which it has
+    /// now. Only with [`crate::DedupOptions::ignore_owner`] or [`crate::DedupOptions::ignore_mode`].
+    MetadataChanged {
+        original_file: &'a Path,
+        target: &'a Path,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+        dry_run: bool,
+    },
+    /// What a dry run would do with a group of duplicates: keep the original file (chosen under
+    /// [`crate::DedupOptions::prefer`]) and hardlink the targets to it, reclaiming `bytes` and
+    /// `inodes`.
+    Planned {
+        original_file: &'a Path,
+        targets: &'a [&'a Path],
+        bytes: u64,
+        inodes: usize,
+    },
+    /// The current progress, rendered on request.
+    Snapshot {
+        processed_files: usize,
+        total_files: usize,
+        bytes_deduped: usize,
+        current_file: Option<&'a Path>,
+    },
+    Finished {
+        summary: &'a DedupSummary,
+    },
+}
+
+/// Why two files with the same contents weren't hardlinked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    ModifiedRecently,
+    /// One of the files was modified outside the time window being deduplicated.
+    OutsideTimeWindow,
+    FilesystemFull,
+    /// The user answered no in interactive mode.
+    Declined,
+    /// Another process has one of the files open for writing.
+    OpenForWriting,
+    /// A [`crate::CandidateFilter`] doesn't allow hardlinking the files.
+    Filtered,
+}
+
+impl SkipReason {
+    /// How the summary describes files skipped because their directories aren't writable, which
+    /// have an event of their own.
+    pub const UNWRITABLE_DESCRIPTION: &'static str = "directory not writable";
+
+    pub fn description(self) -> &'static str {
+        match self {
+            SkipReason::ModifiedRecently => "modified recently",
+            SkipReason::OutsideTimeWindow => "modified outside the time window",
+            SkipReason::FilesystemFull => "filesystem nearly full",
+            SkipReason::Declined => "declined",
+            SkipReason::OpenForWriting => "open for writing",
+            SkipReason::Filtered => "rejected by a candidate filter",
+        }
+    }
+}
+
+/// How far along the deduplication is when an event is emitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub processed_files: usize,
+    pub total_files: usize,
+    pub bytes_deduped: usize,
+    pub dry_run: bool,
+    /// Show byte counts as plain numbers, like [`crate::DedupOptions::raw_bytes`].
+    pub raw_bytes: bool,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percentage = if self.total_files == 0 {
+            100.0
+        } else {
+            (self.processed_files as f64) / (self.total_files as f64) * 100.0
+        };
+        // As wide as 100.00%, so that the messages after it line up.
+        write!(
+            formatter,
+            "{:>6.2}%{}; {} deduped",
+            percentage,
+            if self.dry_run { "; dry run" } else { "" },
+            format_bytes(self.bytes_deduped as u64, self.raw_bytes),
+        )
+    }
+}
+
+/// Renders the events of a deduplication.
+///
+/// Renderers own stdout: the built-in ones print only their output format there (the human summary,
+/// JSON, or file listings), so pipelines can consume it safely. Everything meant for humans while
+/// the deduplication runs, like log messages, progress and prompts, goes to stderr.
+pub trait Renderer {
+    /// Whether [`Renderer::render`] prints anything for the event. Events that aren't rendered
+    /// don't interrupt the progress bar.
+    fn renders(&self, _event: &Event) -> bool {
+        true
+    }
+
+    fn render(&mut self, status: &Status, event: &Event);
+}
+
+/// Closures observe every event, e.g. to show the progress of a deduplication in a GUI instead of
+/// printing anything.
+impl<F: FnMut(&Status, &Event)> Renderer for F {
+    fn render(&mut self, status: &Status, event: &Event) {
+        self(status, event)
+    }
+}
+
+/// The built-in renderers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Log messages and progress on stderr and a summary on stdout.
+    #[default]
+    Human,
+    /// A single JSON document with all events and the summary on stdout.
+    Json,
+    /// One JSON object per event on stdout.
+    Ndjson,
+    /// Groups of duplicate files separated by empty lines on stdout, like `fdupes` prints them.
+    Fdupes,
+    /// The plan of a dry run on stdout: for each group, the file kept under --prefer, the files that
+    /// would be hardlinked to it, and the bytes and inodes that would be reclaimed.
+    List,
+    /// Nothing.
+    Quiet,
+}
+
+/// Prints the result of a command other than deduplicating, like verifying or undoing. JSON formats
+/// print the result as a single JSON object on stdout, `human` prints it with `print_human`, and the
+/// other formats print nothing.
+pub fn print_result<T: Serialize>(format: OutputFormat, result: &T, print_human: impl FnOnce(&T)) {
+    match format {
+        OutputFormat::Human => print_human(result),
+        OutputFormat::Json | OutputFormat::Ndjson => {
+            println!("{}", serde_json::to_string(result).unwrap())
+        }
+        OutputFormat::Fdupes | OutputFormat::List | OutputFormat::Quiet => (),
+    }
+}
+
+pub fn renderer(format: OutputFormat) -> Box<dyn Renderer> {
+    match format {
+        OutputFormat::Human => Box::new(HumanRenderer),
+        OutputFormat::Json => Box::new(JsonRenderer::default()),
+        OutputFormat::Ndjson => Box::new(NdjsonRenderer),
+        OutputFormat::Fdupes => Box::new(FdupesRenderer),
+        OutputFormat::List => Box::new(ListRenderer),
+        OutputFormat::Quiet => Box::new(QuietRenderer),
+    }
+}
+
+pub struct HumanRenderer;
+
+impl HumanRenderer {
+    /// The level at which the event is logged. `None` for events that aren't logged.
+    fn level(event: &Event) -> Option<Level> {
+        match event {
+            Event::Excluded { .. } | Event::SharedExtents { .. } => Some(Level::DEBUG),
+            Event::InconsistentRead { .. }
+            | Event::NearDuplicates { .. }
+            | Event::MetadataChanged { .. } => Some(Level::WARN),
+            Event::Repaired { .. }
+            | Event::Hardlinked { .. }
+            | Event::Planned { .. }
+            | Event::CrossDeviceDuplicates { .. } => Some(Level::INFO),
+            Event::Skipped {
+                reason:
+                    SkipReason::ModifiedRecently
+                    | SkipReason::OutsideTimeWindow
+                    | SkipReason::Declined
+                    | SkipReason::OpenForWriting
+                    | SkipReason::Filtered,
+                ..
+            } => Some(Level::INFO),
+            Event::Skipped {
+                reason: SkipReason::FilesystemFull,
+                ..
+            } => Some(Level::WARN),
+            Event::SkippedUnwritable { .. } => Some(Level::WARN),
+            Event::Scanned { .. }
+            | Event::Started { .. }
+            | Event::Duplicates { .. }
+            | Event::Snapshot { .. }
+            | Event::Finished { .. } => None,
+        }
+    }
+
+    /// The color of the event's message, so that the links, skips and problems of long runs stand
+    /// apart. `None` for events in the default color.
+    fn color(event: &Event) -> Option<Color> {
+        match event {
+            Event::Hardlinked { .. } | Event::Planned { .. } | Event::Repaired { .. } => {
+                Some(Color::Green)
+            }
+            Event::Skipped { .. } | Event::SkippedUnwritable { .. } => Some(Color::Yellow),
+            Event::InconsistentRead { .. } | Event::NearDuplicates { .. } => Some(Color::Red),
+            _ => None,
+        }
+    }
+
+    fn message(event: &Event, raw_bytes: bool) -> String {
+        match event {
+            Event::Excluded { file, reason } => {
+                format!("Excluding {:?} from deduplication. {}", file, reason)
+            }
+            Event::SharedExtents { file, other_file } => format!(
+                "{:?} shares all its extents with {:?}. They have the same contents.",
+                file, other_file
+            ),
+            Event::CrossDeviceDuplicates { files, bytes } => format!(
+                "{} duplicated across filesystems in {:?}. Hardlinking them is impossible, consider moving or consolidating them.",
+                format_bytes(*bytes, raw_bytes),
+                files
+            ),
+            Event::InconsistentRead { file } => format!(
+                "Reading {:?} twice gave different contents. This is a probable hardware or bitrot issue.",
+                file
+            ),
+            Event::NearDuplicates {
+                file,
+                other_file,
+                offsets,
+            } => format!(
+                "Files {:?} and {:?} differ in only {} byte(s) at offsets {:?}. One of them might be corrupt.",
+                file,
+                other_file,
+                offsets.len(),
+                offsets
+            ),
+            Event::Repaired {
+                file,
+                healthy_file,
+                backup: Some(backup),
+                ..
+            } => format!(
+                "Repairing {:?} from {:?}. Backed up the corrupted contents to {:?}.",
+                file, healthy_file, backup
+            ),
+            Event::Repaired {
+                file, healthy_file, ..
+            } => format!("Would repair {:?} from {:?}.", file, healthy_file),
+            Event::Skipped {
+                original_file,
+                target,
+                reason,
+            } => format!(
+                "Skipping hardlinking {:?} to {:?}. {}",
+                original_file,
+                target,
+                match reason {
+                    SkipReason::ModifiedRecently => "One of them was modified recently.",
+                    SkipReason::OutsideTimeWindow => {
+                        "One of them was modified outside the time window being deduplicated."
+                    }
+                    SkipReason::FilesystemFull => "Its filesystem is full.",
+                    SkipReason::Declined => "Declined interactively.",
+                    SkipReason::OpenForWriting => {
+                        "Another process has one of them open for writing."
+                    }
+                    SkipReason::Filtered => "A candidate filter doesn't allow it.",
+                }
+            ),
+            Event::SkippedUnwritable {
+                original_file,
+                targets,
+            } => format!(
+                "Skipping hardlinking {:?} to {} file(s) in directories we can't write to: {:?}",
+                original_file,
+                targets.len(),
+                targets
+            ),
+            Event::Hardlinked {
+                original_file,
+                target,
+                dry_run,
+            } => format!(
+                "{} {:?} to {:?}.",
+                if *dry_run {
+                    "Would hardlink"
+                } else {
+                    "Hardlinked"
+                },
+                original_file,
+                target
+            ),
+            Event::MetadataChanged {
+                original_file,
+                target,
+                uid,
+                gid,
+                mode,
+                dry_run,
+            } => format!(
+                "{:?} {} the owner {}, group {} and mode {:o} of {:?}.",
+                target,
+                if *dry_run { "would get" } else { "now has" },
+                uid,
+                gid,
+                mode & 0o7777,
+                original_file
+            ),
+            Event::Planned {
+                original_file,
+                targets,
+                bytes,
+                inodes,
+            } => format!(
+                "Would keep {:?} and hardlink {} file(s) to it, reclaiming {} and {} inode(s): {:?}",
+                original_file,
+                targets.len(),
+                format_bytes(*bytes, raw_bytes),
+                inodes,
+                targets
+            ),
+            Event::Scanned { .. }
+            | Event::Started { .. }
+            | Event::Duplicates { .. }
+            | Event::Snapshot { .. }
+            | Event::Finished { .. } => String::new(),
+        }
+    }
+}
+
+impl Renderer for HumanRenderer {
+    fn renders(&self, event: &Event) -> bool {
+        matches!(
+            event,
+            Event::Started { .. } | Event::Snapshot { .. } | Event::Finished { .. }
+        ) || HumanRenderer::level(event).is_some_and(level_enabled)
+    }
+
+    fn render(&mut self, status: &Status, event: &Event) {
+        match event {
+            Event::Started { files } => {
+                if level_enabled(Level::WARN) {
+                    eprintln!("Processing {} files.", files);
+                }
+            }
+            Event::Snapshot { current_file, .. } => match current_file {
+                Some(current_file) => eprintln!("[{}] Processing {:?}.", status, current_file),
+                None => eprintln!("[{}] Scanning.", status),
+            },
+            Event::Finished { summary } => print_summary(summary, status.raw_bytes),
+            _ => {
+                if let Some(level) = HumanRenderer::level(event) {
+                    let message = HumanRenderer::message(event, status.raw_bytes);
+                    let message = match HumanRenderer::color(event) {
+                        Some(color) => message.color(color).to_string(),
+                        None => message,
+                    };
+                    log(level, &format!("[{}] {}", status, message));
+                }
+            }
+        }
+    }
+}
+
+/// Prints the summary of a run like the `human` format does. Byte counts are plain numbers with
+/// `raw_bytes`.
+pub fn print_summary(summary: &DedupSummary, raw_bytes: bool) {
+    let size = |bytes: u64| size_value(bytes, raw_bytes);
+    if summary.budget_exhausted {
+        println!("Stopped at the budget of the run. Only some of the files were processed.");
+    } else if summary.interrupted {
+        println!("Interrupted. Only some of the files were processed.");
+    }
+    if summary.near_duplicates > 0 {
+        println!(
+            "Pairs of files that differ in only a few bytes: {}",
+            summary.near_duplicates
+        );
+    }
+    if summary.repaired_files > 0 {
+        println!("Repaired files: {}", summary.repaired_files);
+    }
+    if summary.inconsistent_files > 0 {
+        println!(
+            "Files that read back differently (probable hardware or bitrot issues): {}",
+            summary.inconsistent_files
+        );
+    }
+    if summary.failed_files > 0 {
+        println!("Files that failed to process: {}", summary.failed_files);
+    }
+    if summary.fallback_links > 0 {
+        println!(
+            "Files replaced with a fallback because hardlinking them failed: {}",
+            summary.fallback_links
+        );
+    }
+    if summary.unwritable_files > 0 {
+        println!(
+            "Files skipped because their directories aren't writable: {}",
+            summary.unwritable_files
+        );
+    }
+    if summary.shared_extent_files > 0 {
+        println!(
+            "Files that already share all their extents with a duplicate: {}",
+            summary.shared_extent_files
+        );
+    }
+    if summary.cross_device_groups > 0 {
+        println!(
+            "Bytes duplicated across filesystems in {} group(s) (hardlinking impossible, consider moving or consolidating): {}",
+            summary.cross_device_groups,
+            size(summary.cross_device_bytes)
+        );
+    }
+    let link_failures = summary.link_failures;
+    if link_failures.total() > 0 {
+        println!(
+            "Failed hardlinks: {} accessing the source, {} creating the temporary hardlink, {} replacing the target, {} changed since comparing, {} flushing to disk",
+            link_failures.source,
+            link_failures.temp_link,
+            link_failures.rename,
+            link_failures.changed,
+            link_failures.sync
+        );
+    }
+    if !summary.linked_dirs.is_empty() {
+        println!("Back up these directories with hardlinks preserved (e.g. `rsync -H`, or `tar` without --hard-dereference) so that backups don't copy the deduplicated files again:");
+        for dir in &summary.linked_dirs {
+            println!("  {}", dir.display());
+        }
+    }
+    if !summary.directories.is_empty() {
+        println!("Savings by directory:");
+        for savings in &summary.directories {
+            println!(
+                "  {:>10}  {:>6} file(s)  {}",
+                size(savings.bytes),
+                savings.duplicates,
+                savings.dir.display()
+            );
+        }
+    }
+    print_stats(&summary.stats, raw_bytes);
+    let resources = summary.resources;
+    println!(
+        "Resource usage: {:.2}s user CPU, {:.2}s system CPU, {} peak memory, {} read, {} written",
+        resources.user_cpu_ms as f64 / 1000.0,
+        resources.system_cpu_ms as f64 / 1000.0,
+        format_bytes(resources.max_rss_bytes, raw_bytes),
+        format_bytes(resources.read_bytes, raw_bytes),
+        format_bytes(resources.write_bytes, raw_bytes)
+    );
+    if summary.bytes_already_linked > 0 {
+        println!(
+            "Bytes already saved by existing hardlinks: {}",
+            size(summary.bytes_already_linked)
+        );
+    }
+    println!("Bytes hardlinked: {}", size(summary.bytes_linked));
+    println!(
+        "Estimated saved bytes: {}",
+        size(summary.bytes_deduped as u64)
+    );
+}
+
+fn print_stats(stats: &RunStats, raw_bytes: bool) {
+    println!("Files scanned: {}", stats.files_scanned);
+    println!("Groups of duplicates: {}", stats.duplicate_groups);
+    if !stats.excluded_files.is_empty() {
+        println!("Files ruled out:");
+        for (reason, files) in &stats.excluded_files {
+            println!("  {} file(s): {}", files, reason);
+        }
+    }
+    if !stats.skipped_files.is_empty() {
+        println!("Duplicates not hardlinked:");
+        for (reason, files) in &stats.skipped_files {
+            println!("  {} file(s): {}", files, reason);
+        }
+    }
+    println!(
+        "Bytes read: {} ({} hashed)",
+        size_value(stats.bytes_read, raw_bytes),
+        size_value(stats.bytes_hashed, raw_bytes)
+    );
+    let seconds = |millis: u64| millis as f64 / 1000.0;
+    let times = stats.stage_times;
+    println!(
+        "Wall time: {:.2}s ({:.2}s scanning, {:.2}s grouping metadata, {:.2}s comparing prefixes, {:.2}s comparing tails, {:.2}s hashing, {:.2}s comparing contents, {:.2}s hardlinking)",
+        seconds(stats.wall_time_ms),
+        seconds(times.scan_ms),
+        seconds(times.metadata_ms),
+        seconds(times.prefix_ms),
+        seconds(times.tail_ms),
+        seconds(times.hash_ms),
+        seconds(times.compare_ms),
+        seconds(times.link_ms),
+    );
+    println!(
+        "Throughput: {}/s",
+        format_bytes(stats.throughput(), raw_bytes)
+    );
+}
+
+/// Prints how long each stage of the run took and how much it processed to stderr, so that it
+/// doesn't mix with the summary in any output format.
+pub fn print_profile(summary: &DedupSummary, raw_bytes: bool) {
+    for line in profile_lines(summary, raw_bytes) {
+        eprintln!("{}", line);
+    }
+}
+
+fn profile_lines(summary: &DedupSummary, raw_bytes: bool) -> Vec<String> {
+    let stats = &summary.stats;
+    let times = stats.stage_times;
+    let bytes = stats.stage_bytes;
+    let files = format!("{} files", stats.files_scanned);
+    let size = |bytes: u64| format_bytes(bytes, raw_bytes);
+    let stages = [
+        ("walk", times.scan_ms, files.clone(), None),
+        ("metadata grouping", times.metadata_ms, files, None),
+        (
+            "prefix",
+            times.prefix_ms,
+            size(bytes.prefix),
+            Some(bytes.prefix),
+        ),
+        ("tail", times.tail_ms, size(bytes.tail), Some(bytes.tail)),
+        ("hashing", times.hash_ms, size(bytes.hash), Some(bytes.hash)),
+        (
+            "byte comparison",
+            times.compare_ms,
+            size(bytes.compare),
+            Some(bytes.compare),
+        ),
+        (
+            "linking",
+            times.link_ms,
+            size(summary.bytes_linked),
+            Some(summary.bytes_linked),
+        ),
+    ];
+    let mut lines = vec![format!(
+        "Profile (wall time {:.2}s):",
+        stats.wall_time_ms as f64 / 1000.0
+    )];
+    for (stage, millis, processed, bytes) in stages {
+        let mut line = format!(
+            "  {:<18} {:>9.2}s  {}",
+            stage,
+            millis as f64 / 1000.0,
+            processed
+        );
+        if let Some(bytes) = bytes.filter(|_| millis > 0) {
+            line += &format!(" ({}/s)", format_bytes(bytes * 1000 / millis, raw_bytes));
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// `tracing`'s macros need levels known at compile time.
+fn level_enabled(level: Level) -> bool {
+    match level {
+        Level::ERROR => tracing::enabled!(Level::ERROR),
+        Level::WARN => tracing::enabled!(Level::WARN),
+        Level::INFO => tracing::enabled!(Level::INFO),
+        Level::DEBUG => tracing::enabled!(Level::DEBUG),
+        Level::TRACE => tracing::enabled!(Level::TRACE),
+    }
+}
+
+fn log(level: Level, message: &str) {
+    match level {
+        Level::ERROR => tracing::error!("{}", message),
+        Level::WARN => tracing::warn!("{}", message),
+        Level::INFO => tracing::info!("{}", message),
+        Level::DEBUG => tracing::debug!("{}", message),
+        Level::TRACE => tracing::trace!("{}", message),
+    }
+}
+
+#[derive(Default)]
+pub struct JsonRenderer {
+    events: Vec<serde_json::Value>,
+}
+
+impl Renderer for JsonRenderer {
+    fn renders(&self, event: &Event) -> bool {
+        !matches!(
+            event,
+            Event::Scanned { .. } | Event::Started { .. } | Event::Snapshot { .. }
+        )
+    }
+
+    fn render(&mut self, _status: &Status, event: &Event) {
+        match event {
+            Event::Scanned { .. } | Event::Started { .. } | Event::Snapshot { .. } => (),
+            Event::Finished { summary } => {
+                let document = serde_json::json!({
+                    "events": self.events,
+                    "summary": summary,
+                });
+                println!("{}", document);
+            }
+            _ => self.events.push(serde_json::to_value(event).unwrap()),
+        }
+    }
+}
+
+pub struct NdjsonRenderer;
+
+impl Renderer for NdjsonRenderer {
+    // Every scanned path would be a line of its own.
+    fn renders(&self, event: &Event) -> bool {
+        !matches!(event, Event::Scanned { .. })
+    }
+
+    fn render(&mut self, _status: &Status, event: &Event) {
+        println!("{}", serde_json::to_string(event).unwrap());
+    }
+}
+
+pub struct FdupesRenderer;
+
+impl Renderer for FdupesRenderer {
+    fn renders(&self, event: &Event) -> bool {
+        matches!(event, Event::Duplicates { .. })
+    }
+
+    fn render(&mut self, _status: &Status, event: &Event) {
+        if let Event::Duplicates { files, .. } = event {
+            for file in files.iter() {
+                println!("{}", file.display());
+            }
+            println!();
+        }
+    }
+}
+
+pub struct ListRenderer;
+
+impl Renderer for ListRenderer {
+    fn renders(&self, event: &Event) -> bool {
+        matches!(event, Event::Planned { .. })
+    }
+
+    fn render(&mut self, _status: &Status, event: &Event) {
+        if let Event::Planned {
+            original_file,
+            targets,
+            bytes,
+            inodes,
+        } = event
+        {
+            println!(
+                "keep {} (reclaims {} bytes, {} inode(s))",
+                original_file.display(),
+                bytes,
+                inodes
+            );
+            for target in targets.iter() {
+                println!("  link {}", target.display());
+            }
+            println!();
+        }
+    }
+}
+
+/// The plan of a dry run as bare paths on stdout: for each group, the file kept under --prefer and
+/// then the files that would be hardlinked to it. Every path ends with the separator and every
+/// group with an empty path, so that with NUL as the separator scripts can read paths that contain
+/// spaces and newlines.
+pub struct PathListRenderer {
+    separator: u8,
+}
+
+impl PathListRenderer {
+    pub fn new(separator: u8) -> PathListRenderer {
+        PathListRenderer { separator }
+    }
+
+    fn write_group(&self, original_file: &Path, targets: &[&Path]) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        for path in std::iter::once(&original_file).chain(targets) {
+            stdout.write_all(path.as_os_str().as_bytes())?;
+            stdout.write_all(&[self.separator])?;
+        }
+        stdout.write_all(&[self.separator])?;
+        stdout.flush()
+    }
+}
+
+impl Renderer for PathListRenderer {
+    fn renders(&self, event: &Event) -> bool {
+        matches!(event, Event::Planned { .. })
+    }
+
+    fn render(&mut self, _status: &Status, event: &Event) {
+        if let Event::Planned {
+            original_file,
+            targets,
+            ..
+        } = event
+        {
+            // Like `println!`, but paths aren't necessarily UTF-8.
+            self.write_group(original_file, targets).unwrap();
+        }
+    }
+}
+
+pub struct QuietRenderer;
+
+impl Renderer for QuietRenderer {
+    fn renders(&self, _event: &Event) -> bool {
+        false
+    }
+
+    fn render(&mut self, _status: &Status, _event: &Event) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_as_json() {
+        let event = Event::Hardlinked {
+            original_file: Path::new("a"),
+            target: Path::new("b"),
+            dry_run: false,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"hardlinked","original_file":"a","target":"b","dry_run":false}"#
+        );
+    }
+
+    #[test]
+    fn profile_of_stages() {
+        let mut summary = DedupSummary {
+            bytes_linked: 100,
+            ..DedupSummary::default()
+        };
+        summary.stats.files_scanned = 3;
+        summary.stats.wall_time_ms = 2500;
+        summary.stats.stage_times.hash_ms = 2000;
+        summary.stats.stage_bytes.hash = 4000;
+        let lines = profile_lines(&summary, true);
+        assert_eq!(lines[0], "Profile (wall time 2.50s):");
+        assert_eq!(lines[1], "  walk                    0.00s  3 files");
+        assert_eq!(
+            lines[5],
+            "  hashing                 2.00s  4000 bytes (2000 bytes/s)"
+        );
+        assert_eq!(lines[7], "  linking                 0.00s  100 bytes");
+    }
+
+    #[test]
+    fn color_by_outcome() {
+        let (original_file, target) = (Path::new("a"), Path::new("b"));
+        let hardlinked = Event::Hardlinked {
+            original_file,
+            target,
+            dry_run: false,
+        };
+        let skipped = Event::Skipped {
+            original_file,
+            target,
+            reason: SkipReason::OpenForWriting,
+        };
+        let inconsistent = Event::InconsistentRead { file: target };
+        assert_eq!(HumanRenderer::color(&hardlinked), Some(Color::Green));
+        assert_eq!(HumanRenderer::color(&skipped), Some(Color::Yellow));
+        assert_eq!(HumanRenderer::color(&inconsistent), Some(Color::Red));
+        let started = Event::Started { files: 1 };
+        assert_eq!(HumanRenderer::color(&started), None);
+    }
+
+    #[test]
+    fn status_with_percentage() {
+        let status = Status {
+            processed_files: 1,
+            total_files: 4,
+            bytes_deduped: 10,
+            dry_run: true,
+            raw_bytes: true,
+        };
+        assert_eq!(status.to_string(), " 25.00%; dry run; 10 bytes deduped");
+        let status = Status {
+            bytes_deduped: 3 * 1024 * 1024,
+            raw_bytes: false,
+            ..status
+        };
+        assert_eq!(status.to_string(), " 25.00%; dry run; 3.0 MiB deduped");
+        let status = Status {
+            processed_files: 4,
+            ..status
+        };
+        assert_eq!(status.to_string(), "100.00%; dry run; 3.0 MiB deduped");
+    }
+}
@@ -0,0 +1,92 @@
+//! Keeping deduplication from evicting everything else from the page cache.
+//!
+//! Hashing and comparing terabytes of files reads them all through the page cache, which pushes out
+//! the pages of other services on the same machine. With [`ReadOptions::drop_caches`] we tell the
+//! kernel that files are read sequentially and drop their pages once we're done with them.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::hasher::DEFAULT_BUFFER_SIZE;
+use crate::throttle::throttle;
+
+/// How files are read when hashing and comparing them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReadOptions {
+    pub(crate) buffer_size: usize,
+    /// Read files with `POSIX_FADV_SEQUENTIAL` and drop their pages from the page cache with
+    /// `POSIX_FADV_DONTNEED` after reading them.
+    pub(crate) drop_caches: bool,
+    /// Read groups of many files through io_uring when the `io-uring` feature is enabled.
+    pub(crate) io_uring: bool,
+    /// The most bytes per second to read from files.
+    pub(crate) bwlimit: Option<u64>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            drop_caches: false,
+            io_uring: false,
+            bwlimit: None,
+        }
+    }
+}
+
+/// A file opened for reading according to [`ReadOptions`]. Reads are throttled to
+/// [`ReadOptions::bwlimit`].
+pub(crate) struct ReadFile {
+    file: File,
+    drop_caches: bool,
+    bwlimit: Option<u64>,
+}
+
+impl ReadFile {
+    pub(crate) fn open(path: &Path, read_options: ReadOptions) -> io::Result<ReadFile> {
+        let file = File::open(path)?;
+        if read_options.drop_caches {
+            advise(&file, libc::POSIX_FADV_SEQUENTIAL);
+        }
+        Ok(ReadFile {
+            file,
+            drop_caches: read_options.drop_caches,
+            bwlimit: read_options.bwlimit,
+        })
+    }
+
+    pub(crate) fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Read for ReadFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read_bytes = self.file.read(buffer)?;
+        if let Some(bwlimit) = self.bwlimit {
+            throttle(read_bytes, bwlimit);
+        }
+        Ok(read_bytes)
+    }
+}
+
+impl Seek for ReadFile {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.file.seek(position)
+    }
+}
+
+impl Drop for ReadFile {
+    fn drop(&mut self) {
+        if self.drop_caches {
+            advise(&self.file, libc::POSIX_FADV_DONTNEED);
+        }
+    }
+}
+
+/// Advice is only a hint, so filesystems that don't support it are read as usual.
+fn advise(file: &File, advice: libc::c_int) {
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+}
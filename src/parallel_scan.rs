@@ -0,0 +1,312 @@
+//! Walking directories with many threads.
+//!
+//! The threads share a queue of directories. Each thread takes a directory, adds the files in it to
+//! the sink, and queues the directories in it for whichever thread is free next. Every directory is
+//! its own piece of work, so all threads stay busy however deep or lopsided the tree is. On network
+//! filesystems most of the walk is spent waiting for `stat` and `readdir` replies, so many reads in
+//! flight finish far sooner than one.
+
+use crate::error::FailedFiles;
+use crate::{
+    file_id, is_stale, opt_out_marker, out_of_time, scan_file, scan_path, DedupOptions, FileId,
+    FileSink,
+};
+use std::collections::HashSet;
+use std::fs::metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::SystemTime;
+use tracing::{debug, info};
+use walkdir::{DirEntry, WalkDir};
+
+/// A directory to read, `depth` levels below the path being scanned.
+struct Walk {
+    dir: PathBuf,
+    depth: usize,
+}
+
+/// The directories waiting to be read, shared by all threads.
+#[derive(Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    walks: Vec<Walk>,
+    /// How many threads are reading a directory, and so may still queue more.
+    busy: usize,
+}
+
+impl Queue {
+    /// The next directory to read, or `None` once all directories have been read.
+    fn take(&self) -> Option<Walk> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(walk) = state.walks.pop() {
+                state.busy += 1;
+                return Some(walk);
+            }
+            if state.busy == 0 {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn push(&self, walk: Walk) {
+        self.state.lock().unwrap().walks.push(walk);
+        self.changed.notify_one();
+    }
+
+    /// Marks a directory taken with [`Queue::take`] as read.
+    fn done(&self) {
+        self.state.lock().unwrap().busy -= 1;
+        self.changed.notify_all();
+    }
+}
+
+/// What one thread failed on.
+#[derive(Default)]
+struct Failed {
+    failed_files: FailedFiles,
+    stale_paths: Vec<(PathBuf, usize)>,
+}
+
+/// A sink shared by all threads.
+struct SharedSink<'a, 'b>(&'a Mutex<&'b mut dyn FileSink>);
+
+impl FileSink for SharedSink<'_, '_> {
+    fn add(&mut self, file_id: FileId, size: u64, path: PathBuf) {
+        self.0.lock().unwrap().add(file_id, size, path);
+    }
+}
+
+/// Adds the files under the directory to the sink like [`scan_path`] does, reading its directories
+/// with `jobs` threads.
+pub(crate) fn scan_path_parallel(
+    path: &Path,
+    jobs: usize,
+    options: &DedupOptions,
+    scan_time: SystemTime,
+    sink: &mut dyn FileSink,
+    failed_files: &mut FailedFiles,
+    stale_paths: &mut Vec<(PathBuf, usize)>,
+) {
+    let visited_dirs = Mutex::new(HashSet::new());
+    let root_device = match root_device(path, options, &visited_dirs) {
+        Some(root_device) => root_device,
+        None => {
+            return scan_path(
+                path,
+                options.max_depth,
+                options,
+                scan_time,
+                sink,
+                failed_files,
+                Some(stale_paths),
+                &visited_dirs,
+            )
+        }
+    };
+    let queue = Queue::default();
+    queue.push(Walk {
+        dir: path.to_owned(),
+        depth: 0,
+    });
+    let sink = Mutex::new(sink);
+    let failed: Vec<Failed> = thread::scope(|scope| {
+        let threads: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut failed = Failed::default();
+                    while let Some(walk) = queue.take() {
+                        let walker = Walker {
+                            root_device,
+                            options,
+                            scan_time,
+                            queue: &queue,
+                            visited_dirs: &visited_dirs,
+                        };
+                        walker.read_dir(&walk, &mut SharedSink(&sink), &mut failed);
+                        queue.done();
+                    }
+                    failed
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect()
+    });
+    for failed in failed {
+        failed_files.append(failed.failed_files);
+        stale_paths.extend(failed.stale_paths);
+    }
+}
+
+/// The device of the directory, or `None` if the path isn't a directory whose reads we can share.
+fn root_device(
+    path: &Path,
+    options: &DedupOptions,
+    visited_dirs: &Mutex<HashSet<FileId>>,
+) -> Option<u64> {
+    if options.max_depth == Some(0) {
+        return None;
+    }
+    let root_metadata = metadata(path).ok().filter(|root| root.is_dir())?;
+    // Opted out directories are skipped by the walk of the directory itself.
+    if options.honor_cachedir_tags && opt_out_marker(path).is_some() {
+        return None;
+    }
+    visited_dirs.lock().unwrap().insert(file_id(&root_metadata));
+    Some(root_metadata.dev())
+}
+
+/// What the threads share while reading directories.
+struct Walker<'a> {
+    root_device: u64,
+    options: &'a DedupOptions,
+    scan_time: SystemTime,
+    queue: &'a Queue,
+    visited_dirs: &'a Mutex<HashSet<FileId>>,
+}
+
+impl Walker<'_> {
+    /// Adds the files in the directory to the sink and queues the directories in it.
+    fn read_dir(&self, walk: &Walk, sink: &mut dyn FileSink, failed: &mut Failed) {
+        let options = self.options;
+        let entries = WalkDir::new(&walk.dir)
+            .follow_links(options.follow_symlinks)
+            .min_depth(1)
+            .max_depth(1);
+        for entry in entries {
+            if options.interrupted.load(Ordering::Relaxed) || out_of_time(options, self.scan_time) {
+                break;
+            }
+            let entry = match entry {
+                Ok(entry) => entry,
+                // Symlinks to an ancestor directory are expected when following symlinks.
+                Err(err) if err.loop_ancestor().is_some() => {
+                    debug!("Skipping {:?}. {}", err.path().unwrap_or(&walk.dir), err);
+                    continue;
+                }
+                Err(err) if is_stale(&err) => {
+                    let stale_path = err.path().unwrap_or(&walk.dir).to_owned();
+                    failed
+                        .stale_paths
+                        .push((stale_path, walk.depth + err.depth()));
+                    continue;
+                }
+                Err(err) => {
+                    let path = err.path().unwrap_or(&walk.dir);
+                    failed
+                        .failed_files
+                        .add(path, format!("Skipping {:?}. Error: {}", path, err));
+                    continue;
+                }
+            };
+            let file_type = entry.file_type();
+            if file_type.is_file() {
+                scan_file(
+                    &entry,
+                    options,
+                    self.scan_time,
+                    sink,
+                    &mut failed.failed_files,
+                );
+            } else if file_type.is_dir() && self.walks_into(&entry, walk.depth + 1) {
+                self.queue.push(Walk {
+                    dir: entry.into_path(),
+                    depth: walk.depth + 1,
+                });
+            }
+        }
+    }
+
+    /// Whether the files in the directory `depth` levels below the path are scanned, like the
+    /// walk of [`scan_path`] decides.
+    fn walks_into(&self, dir: &DirEntry, depth: usize) -> bool {
+        let options = self.options;
+        if options
+            .max_depth
+            .is_some_and(|max_depth| depth >= max_depth)
+        {
+            return false;
+        }
+        if options.one_file_system {
+            match dir.metadata() {
+                Ok(dir_metadata) if dir_metadata.dev() != self.root_device => return false,
+                _ => (),
+            }
+        }
+        if options.honor_cachedir_tags {
+            if let Some(marker) = opt_out_marker(dir.path()) {
+                info!(
+                    "Skipping {:?} and everything in it. It has a {}.",
+                    dir.path(),
+                    marker
+                );
+                return false;
+            }
+        }
+        // Symlinks can lead into the same directory through many paths or even form loops.
+        if !options.follow_symlinks {
+            return true;
+        }
+        match dir.metadata() {
+            Ok(dir_metadata) => self
+                .visited_dirs
+                .lock()
+                .unwrap()
+                .insert(file_id(&dir_metadata)),
+            Err(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::find_inode_groups;
+    use std::fs::{create_dir_all, write};
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn same_files_as_serial_scan() {
+        let tmp_dir = tempdir().unwrap();
+        for dir in ["a/b/c", "d", "e/f", "g/h/i/j/k/l"] {
+            create_dir_all(tmp_dir.path().join(dir)).unwrap();
+            write(tmp_dir.path().join(dir).join("file"), dir).unwrap();
+        }
+        write(tmp_dir.path().join("top"), "top").unwrap();
+        write(tmp_dir.path().join("e/.nodedup"), "").unwrap();
+        symlink(tmp_dir.path(), tmp_dir.path().join("g/h/loop")).unwrap();
+        let paths = [tmp_dir.path().to_owned()];
+
+        for max_depth in [None, Some(0), Some(1), Some(2), Some(5)] {
+            for (follow_symlinks, honor_cachedir_tags) in [(false, false), (true, true)] {
+                let serial_options = DedupOptions {
+                    max_depth,
+                    follow_symlinks,
+                    honor_cachedir_tags,
+                    ..DedupOptions::default()
+                };
+                let parallel_options = DedupOptions {
+                    jobs: 3,
+                    ..serial_options.clone()
+                };
+                assert_eq!(
+                    find_inode_groups(&paths, &parallel_options, &mut FailedFiles::default()),
+                    find_inode_groups(&paths, &serial_options, &mut FailedFiles::default()),
+                );
+            }
+        }
+    }
+}
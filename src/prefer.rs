@@ -0,0 +1,137 @@
+//! Choice of the file the other files in a group of duplicates are hardlinked to.
+//!
+//! The original keeps its inode, so its owner, permissions, and timestamps survive. Without a
+//! [`Prefer`] policy the file with the most links is kept, since its paths needn't be replaced. Files
+//! that tie are ordered by path, so the same tree gives the same plan on every machine.
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs::metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tracing::debug;
+
+/// Which file of a group of duplicates to keep as the original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Prefer {
+    /// The file with the oldest modification time.
+    Oldest,
+    /// The file with the newest modification time.
+    Newest,
+    /// The file with the most hardlinks, which needs the fewest replacements.
+    #[value(alias = "most-linked")]
+    MostLinks,
+    /// The file with the shortest path, e.g. the one closest to the top of the tree.
+    ShortestPath,
+    /// The first file in lexicographic path order, regardless of links.
+    FirstPath,
+}
+
+/// What the policy compares. Files whose metadata can't be read sort last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PolicyKey {
+    Oldest(SystemTime),
+    Newest(Reverse<SystemTime>),
+    MostLinks(Reverse<u64>),
+    ShortestPath(usize),
+    /// Path order only, or the file's metadata couldn't be read.
+    None,
+}
+
+fn policy_key(file: &PathBuf, prefer: Option<Prefer>) -> PolicyKey {
+    match prefer {
+        Some(Prefer::ShortestPath) => return PolicyKey::ShortestPath(file.as_os_str().len()),
+        Some(Prefer::FirstPath) => return PolicyKey::None,
+        _ => (),
+    }
+    let Ok(file_metadata) = metadata(file) else {
+        return PolicyKey::None;
+    };
+    match (prefer, file_metadata.modified()) {
+        (Some(Prefer::Oldest), Ok(modified)) => PolicyKey::Oldest(modified),
+        (Some(Prefer::Newest), Ok(modified)) => PolicyKey::Newest(Reverse(modified)),
+        (Some(Prefer::MostLinks) | None, _) => PolicyKey::MostLinks(Reverse(file_metadata.nlink())),
+        (Some(Prefer::ShortestPath | Prefer::FirstPath), _) | (_, Err(_)) => PolicyKey::None,
+    }
+}
+
+/// The files of the group in order of preference. The first one is the original. Without a policy
+/// files with more links come first. Ties are broken by lexicographic path order.
+pub(crate) fn by_preference<'a>(
+    group: &HashSet<&'a PathBuf>,
+    prefer: Option<Prefer>,
+) -> Vec<&'a PathBuf> {
+    let mut files: Vec<(PolicyKey, &PathBuf)> = group
+        .iter()
+        .map(|file| (policy_key(file, prefer), *file))
+        .collect();
+    files.sort();
+    if let (Some(prefer), [(first_key, first), (second_key, _), ..]) = (prefer, files.as_slice()) {
+        if first_key == second_key && prefer != Prefer::FirstPath {
+            debug!(
+                "Files tie under --prefer {:?}. Keeping {:?}, the first one in path order.",
+                prefer, first
+            );
+        }
+    }
+    files.into_iter().map(|(_, file)| file).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{hard_link, write, File};
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn preferred_order() {
+        let tmp_dir = tempdir().unwrap();
+        let files: Vec<PathBuf> = ["c", "a", "b"]
+            .iter()
+            .map(|name| tmp_dir.path().join(name))
+            .collect();
+        for (age, file) in files.iter().enumerate() {
+            write(file, "contents").unwrap();
+            let modified = SystemTime::now() - Duration::from_secs(age as u64 * 60);
+            File::options()
+                .write(true)
+                .open(file)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        hard_link(&files[0], tmp_dir.path().join("d")).unwrap();
+        let group: HashSet<&PathBuf> = files.iter().collect();
+
+        assert_eq!(
+            by_preference(&group, Some(Prefer::FirstPath)),
+            [&files[1], &files[2], &files[0]]
+        );
+        assert_eq!(
+            by_preference(&group, Some(Prefer::Oldest)),
+            [&files[2], &files[1], &files[0]]
+        );
+        assert_eq!(
+            by_preference(&group, Some(Prefer::Newest)),
+            [&files[0], &files[1], &files[2]]
+        );
+        // "a" and "b" tie with one link each.
+        assert_eq!(
+            by_preference(&group, Some(Prefer::MostLinks)),
+            [&files[0], &files[1], &files[2]]
+        );
+        assert_eq!(
+            by_preference(&group, None),
+            by_preference(&group, Some(Prefer::MostLinks))
+        );
+
+        let nested = tmp_dir.path().join("sub/a");
+        let group: HashSet<&PathBuf> = HashSet::from([&nested, &files[0]]);
+        assert_eq!(
+            by_preference(&group, Some(Prefer::ShortestPath)),
+            [&files[0], &nested]
+        );
+    }
+}
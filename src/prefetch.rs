@@ -0,0 +1,318 @@
+//! Hashing the upcoming groups of other devices while a group is deduplicated.
+//!
+//! Groups are deduplicated one after another, which alone would leave all devices but one idle.
+//! Each device with groups to hash therefore gets a queue of its groups, in the order they're
+//! deduplicated in, and a thread that hashes ahead the files of those groups that will be hashed:
+//! the files that share their first and last bytes with at least two others (pairs are compared
+//! instead). The threads read their devices the way that suits them (see [`crate::devices`]) and
+//! wait while a group of their device is deduplicated, so that a device is read by one thread at a
+//! time. Deduplicating a group takes the hashes of the files that are unchanged since they were
+//! hashed and hashes the rest itself.
+//!
+//! Files are only hashed ahead without hash workers, which keep reading files out of this process.
+
+use crate::chunked_hash::CHUNKED_HASH_THRESHOLD;
+use crate::devices::{files_medium, hash_readers, inode_order, Medium};
+use crate::error::FailedFiles;
+use crate::{
+    calculate_hashes_on, extents, group_file_size, same_prefix_groups, same_tail_groups,
+    sample_tail_bytes, DedupOptions, FileSnapshot, DEFAULT_PREFIX_BYTES,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::metadata;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+/// The groups of a device with their indices in the order they're deduplicated in.
+type Queue = Vec<(usize, Vec<PathBuf>)>;
+
+enum Prefetched {
+    /// The file is being hashed.
+    Pending,
+    Hashed(FileSnapshot, Vec<u8>),
+}
+
+#[derive(Default)]
+struct State {
+    /// The device of the group being deduplicated.
+    reading: Option<u64>,
+    /// The index of the first group that isn't deduplicated yet.
+    next_group: usize,
+    hashes: HashMap<PathBuf, Prefetched>,
+    stopped: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// What a thread does with the next files of a group.
+enum Turn {
+    Hash,
+    /// The group is being or was deduplicated already.
+    Skip,
+    Stop,
+}
+
+/// The threads hashing ahead, one per device.
+pub(crate) struct Prefetcher {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Prefetcher {
+    /// Starts hashing ahead the groups, which are deduplicated in this order and are on the given
+    /// devices, unless they're all on one device.
+    pub(crate) fn start(
+        groups: &[HashSet<&PathBuf>],
+        devices: &[u64],
+        options: &DedupOptions,
+    ) -> Option<Prefetcher> {
+        if options.hash_workers > 0 {
+            return None;
+        }
+        let mut queues: BTreeMap<u64, Queue> = BTreeMap::new();
+        for (index, (group, device)) in groups.iter().zip(devices).enumerate() {
+            let size = group_file_size(group);
+            if group.len() < 3 || size == 0 || size >= CHUNKED_HASH_THRESHOLD {
+                continue;
+            }
+            let files = group.iter().map(|file| (*file).clone()).collect();
+            queues.entry(*device).or_default().push((index, files));
+        }
+        if queues.len() < 2 {
+            return None;
+        }
+        let shared = Arc::new(Shared::default());
+        shared.lock().reading = devices.first().copied();
+        let threads = queues
+            .into_iter()
+            .map(|(device, queue)| {
+                let (shared, options) = (shared.clone(), options.clone());
+                thread::spawn(move || hash_ahead(device, queue, &shared, &options))
+            })
+            .collect();
+        Some(Prefetcher { shared, threads })
+    }
+
+    /// Tells the threads that the group at `index` in the order of the groups, on `device`, is
+    /// being deduplicated.
+    pub(crate) fn begin_group(&self, index: usize, device: u64) {
+        let mut state = self.shared.lock();
+        state.reading = Some(device);
+        state.next_group = index + 1;
+        self.shared.changed.notify_all();
+    }
+
+    /// The hashes of the files in their order, hashing those with `hash` that weren't hashed ahead
+    /// or changed since.
+    pub(crate) fn hashes(
+        &self,
+        files: &[&PathBuf],
+        hash: impl FnOnce(&[&PathBuf]) -> Vec<io::Result<Vec<u8>>>,
+    ) -> Vec<io::Result<Vec<u8>>> {
+        let mut prefetched = Vec::with_capacity(files.len());
+        let mut state = self.shared.lock();
+        for file in files {
+            while matches!(state.hashes.get(*file), Some(Prefetched::Pending)) {
+                state = self
+                    .shared
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(|err| err.into_inner());
+            }
+            prefetched.push(match state.hashes.remove(*file) {
+                Some(Prefetched::Hashed(snapshot, hash)) => Some((snapshot, hash)),
+                _ => None,
+            });
+        }
+        drop(state);
+        let prefetched: Vec<Option<Vec<u8>>> = files
+            .iter()
+            .zip(prefetched)
+            .map(|(file, prefetched)| {
+                let (snapshot, hash) = prefetched?;
+                let file_metadata = metadata(file).ok()?;
+                (FileSnapshot::new(&file_metadata) == snapshot).then_some(hash)
+            })
+            .collect();
+        let unhashed: Vec<&PathBuf> = files
+            .iter()
+            .zip(&prefetched)
+            .filter(|(_, hash)| hash.is_none())
+            .map(|(file, _)| *file)
+            .collect();
+        let mut hashes = if unhashed.is_empty() {
+            Vec::new()
+        } else {
+            hash(&unhashed)
+        }
+        .into_iter();
+        prefetched
+            .into_iter()
+            .map(|prefetched| match prefetched {
+                Some(hash) => Ok(hash),
+                None => hashes.next().unwrap(),
+            })
+            .collect()
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.changed.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Hashes ahead the files of the groups of a device that will be hashed.
+fn hash_ahead(device: u64, queue: Queue, shared: &Shared, options: &DedupOptions) {
+    let prefix_bytes = options.prefix_bytes.unwrap_or(DEFAULT_PREFIX_BYTES);
+    let tail_bytes = sample_tail_bytes(options);
+    for (index, files) in queue {
+        match turn(device, index, &[], shared, options) {
+            Turn::Hash => (),
+            Turn::Skip => continue,
+            Turn::Stop => return,
+        }
+        // Failures are counted when the group is deduplicated.
+        let mut failed_files = FailedFiles::default();
+        let files: HashSet<&PathBuf> = files.iter().collect();
+        let mut hashed_files = Vec::new();
+        let read_options = options.read_options();
+        let prefix_groups: Vec<_> =
+            same_prefix_groups(files, prefix_bytes, read_options, &mut failed_files).collect();
+        for prefix_group in prefix_groups {
+            if prefix_group.len() < 3 {
+                continue;
+            }
+            let tail_groups =
+                same_tail_groups(prefix_group, prefix_bytes, tail_bytes, &mut failed_files);
+            hashed_files.extend(tail_groups.filter(|group| group.len() >= 3).flatten());
+        }
+        // Files that share their extents with another one aren't hashed.
+        let representatives = extents::file_representatives(&hashed_files);
+        let mut hashed_files: Vec<&PathBuf> = representatives
+            .iter()
+            .enumerate()
+            .filter(|(index, representative)| index == *representative)
+            .map(|(index, _)| hashed_files[index])
+            .collect();
+        let medium = files_medium(&hashed_files);
+        if medium == Medium::Rotational {
+            let order = inode_order(&hashed_files);
+            hashed_files = order.into_iter().map(|index| hashed_files[index]).collect();
+        }
+        let batch_size = hash_readers(medium, options.max_parallel_hashes);
+        for batch in hashed_files.chunks(batch_size) {
+            match turn(device, index, batch, shared, options) {
+                Turn::Hash => (),
+                Turn::Skip => break,
+                Turn::Stop => return,
+            }
+            let snapshots: Vec<Option<FileSnapshot>> = batch
+                .iter()
+                .map(|file| metadata(file).ok().map(|m| FileSnapshot::new(&m)))
+                .collect();
+            let hashes = calculate_hashes_on(batch, medium, None, options);
+            let mut state = shared.lock();
+            for ((file, snapshot), hash) in batch.iter().zip(snapshots).zip(hashes) {
+                let unchanged = snapshot.filter(|snapshot| {
+                    metadata(file).is_ok_and(|m| FileSnapshot::new(&m) == *snapshot)
+                });
+                match (unchanged, hash) {
+                    (Some(snapshot), Ok(hash)) => {
+                        state
+                            .hashes
+                            .insert((*file).clone(), Prefetched::Hashed(snapshot, hash));
+                    }
+                    _ => {
+                        state.hashes.remove(*file);
+                    }
+                }
+            }
+            shared.changed.notify_all();
+        }
+    }
+}
+
+/// Waits until the device isn't read by the deduplication, and then marks the files as being
+/// hashed, unless the group at `index` was reached by the deduplication already.
+fn turn(
+    device: u64,
+    index: usize,
+    files: &[&PathBuf],
+    shared: &Shared,
+    options: &DedupOptions,
+) -> Turn {
+    let mut state = shared.lock();
+    loop {
+        if state.stopped || options.interrupted.load(Ordering::Relaxed) {
+            return Turn::Stop;
+        }
+        if index < state.next_group {
+            return Turn::Skip;
+        }
+        if state.reading != Some(device) {
+            break;
+        }
+        state = shared
+            .changed
+            .wait(state)
+            .unwrap_or_else(|err| err.into_inner());
+    }
+    for file in files {
+        state.hashes.insert((*file).clone(), Prefetched::Pending);
+    }
+    Turn::Hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tmp_file;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn use_unchanged_hashes() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_file(tmp_dir.path(), "file1", "contents");
+        let file2 = tmp_file(tmp_dir.path(), "file2", "contents");
+        let file3 = tmp_file(tmp_dir.path(), "file3", "contents");
+        let prefetcher = Prefetcher {
+            shared: Arc::new(Shared::default()),
+            threads: Vec::new(),
+        };
+        for file in [&file1, &file2] {
+            let snapshot = FileSnapshot::new(&metadata(file).unwrap());
+            let hashed = Prefetched::Hashed(snapshot, b"prefetched".to_vec());
+            prefetcher.shared.lock().hashes.insert(file.clone(), hashed);
+        }
+        write(&file2, "changed contents").unwrap();
+
+        let mut hashed_here = Vec::new();
+        let hashes = prefetcher.hashes(&[&file1, &file2, &file3], |files| {
+            hashed_here.extend(files.iter().map(|file| (*file).clone()));
+            files.iter().map(|_| Ok(b"hashed".to_vec())).collect()
+        });
+        let hashes: Vec<Vec<u8>> = hashes.into_iter().map(Result::unwrap).collect();
+        assert_eq!(hashes, [&b"prefetched"[..], b"hashed", b"hashed"]);
+        assert_eq!(hashed_here, [file2, file3]);
+        assert!(prefetcher.shared.lock().hashes.is_empty());
+    }
+}
@@ -0,0 +1,89 @@
+//! Progress on stderr: an interactive progress bar, or status lines at most every
+//! [`crate::DedupOptions::progress_interval`] when stderr isn't a terminal.
+
+use crate::units::format_bytes;
+use crate::DedupOptions;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::time::{Duration, Instant};
+
+const TEMPLATE: &str =
+    "{wide_bar} {pos}/{len} files ({per_sec}), {msg} [{elapsed_precise}, ETA {eta_precise}]";
+
+/// A progress bar that is only shown when enabled. Lines printed through [`Progress::suspend`]
+/// don't garble the bar.
+pub(crate) struct Progress {
+    bar: Option<ProgressBar>,
+    /// How often to print a status line without a bar.
+    interval: Option<Duration>,
+    started: Instant,
+    last_line: Instant,
+    total: usize,
+    raw_bytes: bool,
+}
+
+impl Progress {
+    pub(crate) fn new(options: &DedupOptions, total: usize) -> Progress {
+        let bar = if options.progress {
+            let bar =
+                ProgressBar::with_draw_target(Some(total as u64), ProgressDrawTarget::stderr());
+            bar.set_style(ProgressStyle::with_template(TEMPLATE).unwrap());
+            Some(bar)
+        } else {
+            None
+        };
+        Progress {
+            bar,
+            interval: options.progress_interval,
+            started: Instant::now(),
+            last_line: Instant::now(),
+            total,
+            raw_bytes: options.raw_bytes,
+        }
+    }
+
+    pub(crate) fn update(&mut self, processed: usize, bytes_hashed: u64, bytes_deduped: usize) {
+        let bytes = format!(
+            "{} hashed, {} saved",
+            format_bytes(bytes_hashed, self.raw_bytes),
+            format_bytes(bytes_deduped as u64, self.raw_bytes)
+        );
+        match (&self.bar, self.interval) {
+            (Some(bar), _) => {
+                bar.set_position(processed as u64);
+                bar.set_message(bytes);
+            }
+            (None, Some(interval)) if self.last_line.elapsed() >= interval => {
+                self.last_line = Instant::now();
+                let seconds = self.started.elapsed().as_secs_f64();
+                eprintln!(
+                    "{}/{} files ({:.1} files/s), {}",
+                    processed,
+                    self.total,
+                    processed as f64 / seconds,
+                    bytes
+                );
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn set_total(&mut self, total: usize) {
+        self.total = total;
+        if let Some(bar) = &self.bar {
+            bar.set_length(total as u64);
+        }
+    }
+
+    pub(crate) fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.bar {
+            Some(bar) => bar.suspend(f),
+            None => f(),
+        }
+    }
+
+    pub(crate) fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
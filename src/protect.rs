@@ -0,0 +1,106 @@
+//! Paths that are never replaced, even when they're under the paths being deduplicated.
+//!
+//! Patterns are globs. A pattern with a `/` is matched against the absolute path, like
+//! `/srv/live/**` or `/srv/*/db`, and a pattern without one against each file and directory name,
+//! like `*.sqlite` or `live-data`. Everything under a matching directory is protected too.
+
+use crate::DedupError;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::fs::read_to_string;
+use std::path::{absolute, Path};
+
+/// Globs of protected paths. Protected files can be the original other files are hardlinked to, but
+/// are never replaced themselves.
+#[derive(Debug, Clone, Default)]
+pub struct ProtectedPaths {
+    paths: GlobSet,
+    names: GlobSet,
+}
+
+impl ProtectedPaths {
+    pub fn new(patterns: &[String]) -> Result<ProtectedPaths, DedupError> {
+        let mut paths = GlobSetBuilder::new();
+        let mut names = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = glob(pattern)?;
+            if pattern.contains('/') {
+                paths.add(glob);
+            } else {
+                names.add(glob);
+            }
+        }
+        let build = |builder: GlobSetBuilder| builder.build().map_err(DedupError::ProtectPatterns);
+        Ok(ProtectedPaths {
+            paths: build(paths)?,
+            names: build(names)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.names.is_empty()
+    }
+
+    /// Whether the path or any directory it's in matches a pattern.
+    pub fn is_protected(&self, path: &Path) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let path = absolute(path).unwrap_or_else(|_| path.to_owned());
+        path.ancestors().any(|ancestor| {
+            self.paths.is_match(ancestor)
+                || ancestor
+                    .file_name()
+                    .is_some_and(|name| self.names.is_match(name))
+        })
+    }
+}
+
+fn glob(pattern: &str) -> Result<Glob, DedupError> {
+    GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|source| DedupError::ProtectPattern {
+            pattern: pattern.to_owned(),
+            source,
+        })
+}
+
+/// Reads patterns from a file with one pattern per line. Empty lines and lines starting with `#`
+/// are skipped.
+pub fn read_patterns(file: &Path) -> Result<Vec<String>, DedupError> {
+    let text = read_to_string(file).map_err(|source| DedupError::ReadPatterns {
+        path: file.to_owned(),
+        source,
+    })?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_paths_and_names() {
+        let protected = ProtectedPaths::new(&[
+            "/srv/live/**".to_owned(),
+            "/data/*/db".to_owned(),
+            "*.sqlite".to_owned(),
+            "keep-me".to_owned(),
+        ])
+        .unwrap();
+
+        assert!(protected.is_protected(Path::new("/srv/live/a/b")));
+        assert!(protected.is_protected(Path::new("/data/x/db/file")));
+        assert!(!protected.is_protected(Path::new("/data/x/y/db/file")));
+        assert!(protected.is_protected(Path::new("/home/me/app.sqlite")));
+        assert!(protected.is_protected(Path::new("/home/keep-me/file")));
+        assert!(!protected.is_protected(Path::new("/srv/other/file")));
+        assert!(!ProtectedPaths::default().is_protected(Path::new("/srv/live/a")));
+        assert!(ProtectedPaths::new(&["[".to_owned()]).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! Reflinks: copies that share their data blocks with the original until either of them is written.
+//!
+//! Unlike hardlinks, reflinked files stay separate files that keep their own owner, mode,
+//! timestamps, and extended attributes, and writing to one leaves the other alone. Btrfs and XFS on
+//! Linux clone files through the `FICLONE` ioctl and APFS on macOS through `clonefile(2)`. Other
+//! filesystems can't clone files, and replacing files with reflinks fails on them.
+//!
+//! On Linux POSIX ACLs are extended attributes, so clones keep the ACLs of the files they replace
+//! too. macOS keeps ACLs apart from extended attributes, and `clonefile(2)` gives the clone the
+//! ACL of the original, which it keeps.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use xattr::FileExt;
+
+/// How files with the same contents are deduplicated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LinkMode {
+    /// Replace the duplicates with hardlinks to the original.
+    #[default]
+    Hardlink,
+    /// Replace the duplicates with reflinks of the original, which keep their own metadata.
+    Reflink,
+}
+
+/// What replaces a duplicate when hardlinking it fails because the kernel won't link across
+/// mounts (`EXDEV`) or refuses to link to the file (`EPERM`, e.g. `fs.protected_hardlinks`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Fallback {
+    /// Leave the duplicate alone and count it as failed.
+    #[default]
+    None,
+    /// Replace the duplicate with a reflink of the original.
+    Reflink,
+    /// Replace the duplicate with a symlink to the original's absolute path. Deleting or moving
+    /// the original breaks the symlink.
+    Symlink,
+}
+
+/// Whether this platform can reflink files at all.
+pub(crate) const REFLINK_SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "macos"));
+
+/// Makes the clone look like the file it replaces: its owner, mode, extended attributes, and
+/// timestamps.
+pub(crate) fn take_metadata(
+    clone: &File,
+    replaced_file: &Path,
+    replaced: &libc::stat,
+) -> io::Result<()> {
+    let fd = clone.as_raw_fd();
+    check(unsafe { libc::fchown(fd, replaced.st_uid, replaced.st_gid) })?;
+    check(unsafe { libc::fchmod(fd, replaced.st_mode & 0o7777) })?;
+    // Changing the owner drops file capabilities, so the attributes are taken after it.
+    take_xattrs(clone, replaced_file)?;
+    let times = [
+        libc::timespec {
+            tv_sec: replaced.st_atime,
+            tv_nsec: replaced.st_atime_nsec as _,
+        },
+        libc::timespec {
+            tv_sec: replaced.st_mtime,
+            tv_nsec: replaced.st_mtime_nsec as _,
+        },
+    ];
+    check(unsafe { libc::futimens(fd, times.as_ptr()) })
+}
+
+/// Gives the clone the extended attributes of the replaced file instead of any that `clonefile(2)`
+/// copied from the original. Attributes the clone already has are left alone, so that e.g. an
+/// SELinux context it got from its directory needn't be relabelled.
+fn take_xattrs(clone: &File, replaced_file: &Path) -> io::Result<()> {
+    let replaced_names: Vec<_> = xattr::list(replaced_file)?.collect();
+    for name in clone.list_xattr()? {
+        if !replaced_names.contains(&name) {
+            clone.remove_xattr(&name)?;
+        }
+    }
+    for name in replaced_names {
+        if let Some(value) = xattr::get(replaced_file, &name)? {
+            if clone.get_xattr(&name)?.as_ref() != Some(&value) {
+                clone.set_xattr(&name, &value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! Repair of corrupted copies from their healthy near-duplicates.
+//!
+//! Given two groups of files that differ in only a few bytes, we have to decide which group holds
+//! the healthy contents. Corrupted copies are backed up before they are replaced.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::hard_link;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// How to decide which of two near-duplicates is the corrupted one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RepairMode {
+    /// Repair a copy only if strictly more files agree on the other copy's contents.
+    Majority,
+    /// Ask which copy is healthy.
+    Interactive,
+}
+
+/// Returns the indices of the healthy and the corrupted group, or `None` if we can't tell.
+pub(crate) fn choose_corrupt_group(
+    mode: RepairMode,
+    groups: &[HashSet<&PathBuf>],
+    first: usize,
+    second: usize,
+) -> Option<(usize, usize)> {
+    match mode {
+        RepairMode::Majority => {
+            if groups[first].len() > groups[second].len() {
+                Some((first, second))
+            } else if groups[second].len() > groups[first].len() {
+                Some((second, first))
+            } else {
+                None
+            }
+        }
+        RepairMode::Interactive => {
+            let first_file = groups[first].iter().next()?;
+            let second_file = groups[second].iter().next()?;
+            match ask_which_is_healthy(first_file, second_file) {
+                Some(true) => Some((first, second)),
+                Some(false) => Some((second, first)),
+                None => None,
+            }
+        }
+    }
+}
+
+/// Asks on stderr whether the first (`true`) or the second (`false`) file is the healthy one and reads
+/// the answer from stdin.
+fn ask_which_is_healthy(first_file: &Path, second_file: &Path) -> Option<bool> {
+    let stdin = io::stdin();
+    loop {
+        eprint!(
+            "Which copy is healthy? 1) {:?} 2) {:?} s) skip [1/2/s]: ",
+            first_file, second_file
+        );
+        io::stderr().flush().ok()?;
+        let mut answer = String::new();
+        if stdin.lock().read_line(&mut answer).ok()? == 0 {
+            return None;
+        }
+        match answer.trim() {
+            "1" => return Some(true),
+            "2" => return Some(false),
+            "s" | "S" => return None,
+            _ => continue,
+        }
+    }
+}
+
+/// Keeps the corrupted contents around by hardlinking them next to the corrupted file.
+pub(crate) fn backup_corrupt_file(file: &Path) -> io::Result<PathBuf> {
+    let backup = backup_path(file);
+    hard_link(file, &backup)?;
+    Ok(backup)
+}
+
+pub(crate) fn backup_path(file: &Path) -> PathBuf {
+    let mut backup_name = file.file_name().map(OsString::from).unwrap_or_default();
+    backup_name.push(".corrupt-backup");
+    file.with_file_name(backup_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn majority_decides() {
+        let (a, b, c) = (PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c"));
+        let groups = vec![HashSet::from([&a, &b]), HashSet::from([&c])];
+        assert_eq!(
+            choose_corrupt_group(RepairMode::Majority, &groups, 0, 1),
+            Some((0, 1))
+        );
+        assert_eq!(
+            choose_corrupt_group(RepairMode::Majority, &groups, 1, 0),
+            Some((0, 1))
+        );
+    }
+
+    #[test]
+    fn no_majority_in_ties() {
+        let (a, b) = (PathBuf::from("a"), PathBuf::from("b"));
+        let groups = vec![HashSet::from([&a]), HashSet::from([&b])];
+        assert_eq!(
+            choose_corrupt_group(RepairMode::Majority, &groups, 0, 1),
+            None
+        );
+    }
+
+    #[test]
+    fn backup_next_to_file() {
+        assert_eq!(
+            backup_path(Path::new("dir/file.txt")),
+            PathBuf::from("dir/file.txt.corrupt-backup")
+        );
+    }
+}
@@ -0,0 +1,68 @@
+//! How much CPU time, memory and I/O a run used.
+
+use serde::Serialize;
+use std::fs::read_to_string;
+use std::time::Duration;
+
+/// Resources used by this process and its hash workers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ResourceUsage {
+    /// CPU time spent in user mode, in milliseconds.
+    pub user_cpu_ms: u64,
+    /// CPU time spent in the kernel, in milliseconds.
+    pub system_cpu_ms: u64,
+    /// The peak resident set size of this process or of its largest hash worker, in bytes.
+    pub max_rss_bytes: u64,
+    /// Bytes this process read from storage, as opposed to from the page cache. Hash workers'
+    /// reads aren't included.
+    pub read_bytes: u64,
+    /// Bytes this process caused to be written to storage.
+    pub write_bytes: u64,
+}
+
+/// Resources used so far. Hash workers only count once they have exited.
+pub(crate) fn resource_usage() -> ResourceUsage {
+    let own = rusage(libc::RUSAGE_SELF);
+    let workers = rusage(libc::RUSAGE_CHILDREN);
+    let (read_bytes, write_bytes) = io_bytes().unwrap_or_default();
+    ResourceUsage {
+        user_cpu_ms: millis(own.ru_utime) + millis(workers.ru_utime),
+        system_cpu_ms: millis(own.ru_stime) + millis(workers.ru_stime),
+        // Linux reports the maximum resident set size in kibibytes.
+        max_rss_bytes: own.ru_maxrss.max(workers.ru_maxrss) as u64 * 1024,
+        read_bytes,
+        write_bytes,
+    }
+}
+
+fn rusage(who: libc::c_int) -> libc::rusage {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(who, &mut usage) };
+    usage
+}
+
+fn millis(time: libc::timeval) -> u64 {
+    (Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64))
+        .as_millis() as u64
+}
+
+/// `read_bytes` and `write_bytes` from `/proc/self/io`, which isn't available on all kernels.
+fn io_bytes() -> Option<(u64, u64)> {
+    let io = read_to_string("/proc/self/io").ok()?;
+    let field = |name: &str| {
+        io.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(": ")?.parse().ok())
+    };
+    Some((field("read_bytes")?, field("write_bytes")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measure_own_usage() {
+        let usage = resource_usage();
+        assert!(usage.max_rss_bytes > 0);
+    }
+}
@@ -0,0 +1,130 @@
+//! Groups of duplicates ordered by how much deduplicating them saves.
+//!
+//! Groups are found in no particular order, so the groups of a dry run are held back until it's
+//! finished and then passed on with the most reclaimable bytes first. A plan reclaims the bytes it
+//! says it does, and a group of duplicates its size times its extra copies. Groups with the same
+//! savings keep the order they were found in.
+
+use hardlink_dedup::output::{Event, Renderer, Status};
+use std::path::{Path, PathBuf};
+
+enum Group {
+    Duplicates {
+        files: Vec<PathBuf>,
+        bytes: u64,
+    },
+    Planned {
+        original_file: PathBuf,
+        targets: Vec<PathBuf>,
+        bytes: u64,
+        inodes: usize,
+    },
+}
+
+impl Group {
+    fn reclaimable_bytes(&self) -> u64 {
+        match self {
+            Group::Duplicates { files, bytes } => {
+                bytes.saturating_mul(files.len().saturating_sub(1) as u64)
+            }
+            Group::Planned { bytes, .. } => *bytes,
+        }
+    }
+}
+
+/// Renders events with the inner renderer, except for the groups, which are rendered sorted when the
+/// run finishes. Only the first `top` groups of duplicates and the first `top` plans are rendered.
+pub struct SortedGroups {
+    inner: Box<dyn Renderer>,
+    top: Option<usize>,
+    groups: Vec<Group>,
+}
+
+impl SortedGroups {
+    pub fn new(inner: Box<dyn Renderer>, top: Option<usize>) -> SortedGroups {
+        SortedGroups {
+            inner,
+            top,
+            groups: Vec::new(),
+        }
+    }
+
+    fn render_groups(&mut self, status: &Status) {
+        let mut groups = std::mem::take(&mut self.groups);
+        groups.sort_by_key(|group| std::cmp::Reverse(group.reclaimable_bytes()));
+        let top = self.top.unwrap_or(usize::MAX);
+        let (mut duplicates, mut plans) = (0, 0);
+        for group in &groups {
+            let rendered = match group {
+                Group::Duplicates { .. } => &mut duplicates,
+                Group::Planned { .. } => &mut plans,
+            };
+            if *rendered == top {
+                continue;
+            }
+            *rendered += 1;
+            match group {
+                Group::Duplicates { files, bytes } => {
+                    let files: Vec<&Path> = files.iter().map(PathBuf::as_path).collect();
+                    self.inner.render(
+                        status,
+                        &Event::Duplicates {
+                            files: &files,
+                            bytes: *bytes,
+                        },
+                    );
+                }
+                Group::Planned {
+                    original_file,
+                    targets,
+                    bytes,
+                    inodes,
+                } => {
+                    let targets: Vec<&Path> = targets.iter().map(PathBuf::as_path).collect();
+                    self.inner.render(
+                        status,
+                        &Event::Planned {
+                            original_file,
+                            targets: &targets,
+                            bytes: *bytes,
+                            inodes: *inodes,
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Renderer for SortedGroups {
+    fn renders(&self, event: &Event) -> bool {
+        self.inner.renders(event) || matches!(event, Event::Finished { .. })
+    }
+
+    fn render(&mut self, status: &Status, event: &Event) {
+        match event {
+            Event::Duplicates { files, bytes } => self.groups.push(Group::Duplicates {
+                files: files.iter().map(|file| file.to_path_buf()).collect(),
+                bytes: *bytes,
+            }),
+            Event::Planned {
+                original_file,
+                targets,
+                bytes,
+                inodes,
+            } => self.groups.push(Group::Planned {
+                original_file: original_file.to_path_buf(),
+                targets: targets.iter().map(|target| target.to_path_buf()).collect(),
+                bytes: *bytes,
+                inodes: *inodes,
+            }),
+            Event::Finished { .. } => {
+                self.render_groups(status);
+                if self.inner.renders(event) {
+                    self.inner.render(status, event);
+                }
+            }
+            _ => self.inner.render(status, event),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Sparse files, like VM images, whose sizes are far larger than the blocks they occupy.
+//!
+//! Holes take no space and read as zeros. Hardlinking a sparse file only frees the blocks it
+//! occupies, and the bytes in holes that both files share needn't be read to compare them.
+//! `SEEK_DATA` and `SEEK_HOLE` tell where the data is. Filesystems without them report the whole
+//! file as data.
+
+use std::fs::{File, Metadata};
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+
+/// The bytes the file occupies on disk, but no more than its size. Dense files occupy their size
+/// rounded up to whole blocks, which hardlinking them frees too, but estimates stay in file sizes.
+pub(crate) fn allocated_bytes(file_metadata: &Metadata) -> u64 {
+    file_metadata.len().min(file_metadata.blocks() * 512)
+}
+
+pub(crate) fn is_sparse(file_metadata: &Metadata) -> bool {
+    allocated_bytes(file_metadata) < file_metadata.len()
+}
+
+/// Compares files of the same size by reading only the ranges where either of them has data. The
+/// rest are holes in both, so zeros in both.
+pub(crate) fn are_sparse_files_same(
+    file1: &File,
+    file2: &File,
+    buffer_size: usize,
+) -> io::Result<bool> {
+    let mut ranges = data_ranges(file1)?;
+    ranges.extend(data_ranges(file2)?);
+    let mut buf1 = vec![0; buffer_size.max(1)];
+    let mut buf2 = vec![0; buffer_size.max(1)];
+    for range in merge(ranges) {
+        let mut offset = range.start;
+        while offset < range.end {
+            let chunk = (range.end - offset).min(buf1.len() as u64) as usize;
+            file1.read_exact_at(&mut buf1[..chunk], offset)?;
+            file2.read_exact_at(&mut buf2[..chunk], offset)?;
+            if buf1[..chunk] != buf2[..chunk] {
+                return Ok(false);
+            }
+            offset += chunk as u64;
+        }
+    }
+    Ok(true)
+}
+
+/// The ranges of the file that hold data, in order.
+fn data_ranges(file: &File) -> io::Result<Vec<Range<u64>>> {
+    let size = file.metadata()?.len();
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let err = io::Error::last_os_error();
+            // There's no data after the offset.
+            if err.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(err);
+        }
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let end = (end as u64).min(size);
+        ranges.push(start as u64..end);
+        offset = end;
+    }
+    Ok(ranges)
+}
+
+/// Sorts the ranges and merges the overlapping and adjacent ones.
+fn merge(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use tempfile::tempdir;
+
+    #[test]
+    fn merge_ranges() {
+        assert_eq!(merge(vec![5..8, 0..2, 1..3, 8..9]), vec![0..3, 5..9]);
+        assert_eq!(merge(Vec::new()), Vec::<Range<u64>>::new());
+    }
+
+    #[test]
+    fn compare_sparse_files() {
+        let tmp_dir = tempdir().unwrap();
+        let create = |name: &str, data: &[(u64, &[u8])]| {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .read(true)
+                .write(true)
+                .open(tmp_dir.path().join(name))
+                .unwrap();
+            file.set_len(1 << 20).unwrap();
+            for (offset, bytes) in data {
+                file.write_all_at(bytes, *offset).unwrap();
+            }
+            file
+        };
+        let sparse1 = create("sparse1", &[(100_000, b"data")]);
+        let sparse2 = create("sparse2", &[(100_000, b"data")]);
+        let zeros = create("zeros", &[(100_000, b"data"), (500_000, &[0; 4096])]);
+        let other = create("other", &[(100_000, b"data"), (900_000, b"more")]);
+
+        assert!(is_sparse(&sparse1.metadata().unwrap()));
+        assert!(are_sparse_files_same(&sparse1, &sparse2, 1024).unwrap());
+        assert!(are_sparse_files_same(&sparse1, &zeros, 1024).unwrap());
+        assert!(!are_sparse_files_same(&sparse1, &other, 1024).unwrap());
+    }
+}
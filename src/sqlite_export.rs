@@ -0,0 +1,262 @@
+//! Exporting runs to an SQLite database, for querying the history of deduplications with SQL.
+//!
+//! Every run appends to the database:
+//!
+//! - `runs`: when the run started and finished, its paths, whether it was a dry run, and how many
+//!   bytes it deduplicated.
+//! - `content_groups`: the groups of files with the same contents the run found.
+//! - `files`: the paths in each group, with their directories, devices and inodes.
+//! - `hashes`: the hash of each group's contents.
+//! - `links`: the hardlinks the run created, or would have created in a dry run.
+//!
+//! For example, the directories with the most duplicates over all runs:
+//!
+//! ```sql
+//! SELECT dir, COUNT(*) FROM files GROUP BY dir ORDER BY COUNT(*) DESC LIMIT 10;
+//! ```
+
+use hardlink_dedup::output::{Event, Renderer, Status};
+use hardlink_dedup::{content_hash, dedup_with_renderer, DedupError, DedupOptions, DedupSummary};
+use rusqlite::{params, Connection};
+use std::fs::metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    started_at INTEGER NOT NULL,
+    finished_at INTEGER,
+    paths TEXT NOT NULL,
+    dry_run INTEGER NOT NULL,
+    bytes_deduped INTEGER,
+    failed_files INTEGER,
+    interrupted INTEGER
+);
+CREATE TABLE IF NOT EXISTS content_groups (
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    size INTEGER NOT NULL,
+    files INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS files (
+    id INTEGER PRIMARY KEY,
+    group_id INTEGER NOT NULL REFERENCES content_groups(id),
+    path TEXT NOT NULL,
+    dir TEXT NOT NULL,
+    device INTEGER,
+    inode INTEGER
+);
+CREATE TABLE IF NOT EXISTS hashes (
+    group_id INTEGER PRIMARY KEY REFERENCES content_groups(id),
+    algorithm TEXT NOT NULL,
+    hash TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS links (
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    original TEXT NOT NULL,
+    target TEXT NOT NULL,
+    dry_run INTEGER NOT NULL,
+    linked_at INTEGER NOT NULL
+);
+";
+
+/// Renders events with the inner renderer and records the groups and links in the database.
+struct SqliteRenderer<'a> {
+    inner: &'a mut dyn Renderer,
+    connection: &'a Connection,
+    run_id: i64,
+    options: &'a DedupOptions,
+    /// The first error writing to the database. Nothing more is recorded after it.
+    error: Option<rusqlite::Error>,
+}
+
+impl Renderer for SqliteRenderer<'_> {
+    fn renders(&self, event: &Event) -> bool {
+        self.inner.renders(event)
+            || matches!(event, Event::Duplicates { .. } | Event::Hardlinked { .. })
+    }
+
+    fn render(&mut self, status: &Status, event: &Event) {
+        if self.inner.renders(event) {
+            self.inner.render(status, event);
+        }
+        if self.error.is_some() {
+            return;
+        }
+        let recorded = match event {
+            Event::Duplicates { files, .. } => self.record_group(files),
+            Event::Hardlinked {
+                original_file,
+                target,
+                dry_run,
+            } => self
+                .connection
+                .execute(
+                    "INSERT INTO links (run_id, original, target, dry_run, linked_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        self.run_id,
+                        text(original_file),
+                        text(target),
+                        dry_run,
+                        now(),
+                    ],
+                )
+                .map(|_| ()),
+            _ => Ok(()),
+        };
+        if let Err(err) = recorded {
+            warn!("Failed to export to the SQLite database. Error: {}", err);
+            self.error = Some(err);
+        }
+    }
+}
+
+impl SqliteRenderer<'_> {
+    fn record_group(&self, files: &[&Path]) -> rusqlite::Result<()> {
+        let size = files
+            .iter()
+            .find_map(|file| metadata(file).ok())
+            .map_or(0, |file_metadata| file_metadata.len());
+        self.connection.execute(
+            "INSERT INTO content_groups (run_id, size, files) VALUES (?1, ?2, ?3)",
+            params![self.run_id, size as i64, files.len() as i64],
+        )?;
+        let group_id = self.connection.last_insert_rowid();
+        for file in files {
+            let file_metadata = metadata(file).ok();
+            self.connection.execute(
+                "INSERT INTO files (group_id, path, dir, device, inode) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    group_id,
+                    text(file),
+                    text(file.parent().unwrap_or(Path::new(""))),
+                    file_metadata.as_ref().map(|file_metadata| file_metadata.dev() as i64),
+                    file_metadata.as_ref().map(|file_metadata| file_metadata.ino() as i64),
+                ],
+            )?;
+        }
+        // The engine doesn't hash pairs of files, which it compares directly, so hash one copy here.
+        match content_hash(files[0], self.options) {
+            Ok(hash) => {
+                self.connection.execute(
+                    "INSERT INTO hashes (group_id, algorithm, hash) VALUES (?1, ?2, ?3)",
+                    params![group_id, self.options.hash.name(), hash],
+                )?;
+            }
+            Err(err) => warn!(
+                "Failed to hash {:?} for the SQLite database. Error: {}",
+                files[0], err
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Deduplicates the paths like `dedup_with_renderer` and appends the run to the database.
+pub fn run(
+    database: &Path,
+    paths: &[PathBuf],
+    options: &DedupOptions,
+    inner: &mut dyn Renderer,
+) -> Result<DedupSummary, DedupError> {
+    let database_error = |source| DedupError::ExportSqlite {
+        path: database.to_owned(),
+        source,
+    };
+    let connection = Connection::open(database).map_err(database_error)?;
+    connection.execute_batch(SCHEMA).map_err(database_error)?;
+    connection
+        .execute(
+            "INSERT INTO runs (started_at, paths, dry_run) VALUES (?1, ?2, ?3)",
+            params![
+                now(),
+                serde_json::to_string(&paths.iter().map(|path| text(path)).collect::<Vec<_>>())
+                    .unwrap(),
+                options.dry_run
+            ],
+        )
+        .map_err(database_error)?;
+    let run_id = connection.last_insert_rowid();
+    // One transaction for the whole run is far faster than one per row.
+    connection.execute_batch("BEGIN").map_err(database_error)?;
+    let mut renderer = SqliteRenderer {
+        inner,
+        connection: &connection,
+        run_id,
+        options,
+        error: None,
+    };
+    let result = dedup_with_renderer(paths, options, &mut renderer);
+    let export_error = renderer.error.take();
+    if let Ok(summary) = &result {
+        connection
+            .execute(
+                "UPDATE runs SET finished_at = ?1, bytes_deduped = ?2, failed_files = ?3, interrupted = ?4 WHERE id = ?5",
+                params![
+                    now(),
+                    summary.bytes_deduped as i64,
+                    summary.failed_files as i64,
+                    summary.interrupted,
+                    run_id
+                ],
+            )
+            .map_err(database_error)?;
+    }
+    connection.execute_batch("COMMIT").map_err(database_error)?;
+    match export_error {
+        Some(err) => Err(database_error(err)),
+        None => result,
+    }
+}
+
+fn text(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hardlink_dedup::output::QuietRenderer;
+    use hardlink_dedup::test_utils::{duplicate_files, tmp_file};
+    use tempfile::tempdir;
+
+    #[test]
+    fn export_groups_and_links() {
+        let tmp_dir = tempdir().unwrap();
+        let tree = tmp_dir.path().join("tree");
+        duplicate_files(&tree, 3, "same contents");
+        tmp_file(&tree, "unique", "other contents");
+        let paths = [tree];
+        let database = tmp_dir.path().join("dups.db");
+        let options = DedupOptions::default();
+
+        for _ in 0..2 {
+            run(&database, &paths, &options, &mut QuietRenderer).unwrap();
+        }
+
+        let connection = Connection::open(&database).unwrap();
+        let count =
+            |query: &str| -> i64 { connection.query_row(query, [], |row| row.get(0)).unwrap() };
+        assert_eq!(
+            count("SELECT COUNT(*) FROM runs WHERE finished_at IS NOT NULL"),
+            2
+        );
+        assert_eq!(count("SELECT SUM(bytes_deduped) FROM runs"), 26);
+        // The second run finds the files hardlinked, so it doesn't report them again.
+        assert_eq!(count("SELECT COUNT(*) FROM content_groups"), 1);
+        assert_eq!(count("SELECT size FROM content_groups"), 13);
+        assert_eq!(count("SELECT COUNT(DISTINCT dir) FROM files"), 3);
+        assert_eq!(count("SELECT COUNT(*) FROM hashes"), 1);
+        assert_eq!(count("SELECT COUNT(*) FROM links WHERE dry_run = 0"), 2);
+    }
+}
@@ -0,0 +1,163 @@
+//! Counters of what each stage of a run did and how long it took, for the summary.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The stages of the deduplication, in the order files go through them.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Stage {
+    Scan,
+    Metadata,
+    Prefix,
+    Tail,
+    Hash,
+    Compare,
+    Link,
+}
+
+/// Wall time spent in each stage, in milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct StageTimes {
+    /// Walking the directories.
+    pub scan_ms: u64,
+    /// Grouping files by their device, size, owner, and mode.
+    pub metadata_ms: u64,
+    /// Comparing the first bytes of files with the same size.
+    pub prefix_ms: u64,
+    /// Comparing the last bytes of files with the same prefix.
+    pub tail_ms: u64,
+    pub hash_ms: u64,
+    /// Comparing files byte by byte, for pairs and with [`crate::DedupOptions::paranoid`].
+    pub compare_ms: u64,
+    /// Replacing files with hardlinks.
+    pub link_ms: u64,
+}
+
+/// Bytes read in each stage that reads file contents.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct StageBytes {
+    pub prefix: u64,
+    pub tail: u64,
+    pub hash: u64,
+    pub compare: u64,
+}
+
+/// What a run looked at to find the duplicates.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RunStats {
+    /// Files found under the paths. Hardlinks to the same file count once.
+    pub files_scanned: usize,
+    /// Groups of files with the same contents.
+    pub duplicate_groups: usize,
+    /// Files found to have no duplicates, or left out of deduplication, by why.
+    pub excluded_files: BTreeMap<String, usize>,
+    /// Files replaced with hardlinks, or that would have been in dry runs.
+    pub linked_files: usize,
+    /// Duplicates that weren't hardlinked, by why.
+    pub skipped_files: BTreeMap<String, usize>,
+    /// Bytes read to compare and hash files.
+    pub bytes_read: u64,
+    /// Bytes of the files that were hashed. Also counted in [`RunStats::bytes_read`].
+    pub bytes_hashed: u64,
+    pub stage_times: StageTimes,
+    /// Bytes read in each stage. They add up to [`RunStats::bytes_read`].
+    pub stage_bytes: StageBytes,
+    /// Wall time of the whole run, in milliseconds.
+    pub wall_time_ms: u64,
+}
+
+impl RunStats {
+    /// Bytes read per second of the run's wall time.
+    pub fn throughput(&self) -> u64 {
+        if self.wall_time_ms == 0 {
+            return 0;
+        }
+        self.bytes_read * 1000 / self.wall_time_ms
+    }
+
+    pub(crate) fn add(&mut self, other: &RunStats) {
+        self.files_scanned += other.files_scanned;
+        self.duplicate_groups += other.duplicate_groups;
+        self.linked_files += other.linked_files;
+        for (reason, files) in &other.excluded_files {
+            *self.excluded_files.entry(reason.clone()).or_default() += files;
+        }
+        for (reason, files) in &other.skipped_files {
+            *self.skipped_files.entry(reason.clone()).or_default() += files;
+        }
+        self.bytes_read += other.bytes_read;
+        self.bytes_hashed += other.bytes_hashed;
+        let times = &mut self.stage_times;
+        let other_times = &other.stage_times;
+        times.scan_ms += other_times.scan_ms;
+        times.metadata_ms += other_times.metadata_ms;
+        times.prefix_ms += other_times.prefix_ms;
+        times.tail_ms += other_times.tail_ms;
+        times.hash_ms += other_times.hash_ms;
+        times.compare_ms += other_times.compare_ms;
+        times.link_ms += other_times.link_ms;
+        let bytes = &mut self.stage_bytes;
+        bytes.prefix += other.stage_bytes.prefix;
+        bytes.tail += other.stage_bytes.tail;
+        bytes.hash += other.stage_bytes.hash;
+        bytes.compare += other.stage_bytes.compare;
+        self.wall_time_ms += other.wall_time_ms;
+    }
+}
+
+/// Adds up the time spent in each stage. Stages take many short turns, so the time is kept
+/// precisely until the end.
+#[derive(Debug, Default)]
+pub(crate) struct StageClock {
+    durations: [Duration; 7],
+}
+
+impl StageClock {
+    pub(crate) fn add(&mut self, stage: Stage, elapsed: Duration) {
+        self.durations[stage as usize] += elapsed;
+    }
+
+    pub(crate) fn times(&self) -> StageTimes {
+        let millis = |stage: Stage| self.durations[stage as usize].as_millis() as u64;
+        StageTimes {
+            scan_ms: millis(Stage::Scan),
+            metadata_ms: millis(Stage::Metadata),
+            prefix_ms: millis(Stage::Prefix),
+            tail_ms: millis(Stage::Tail),
+            hash_ms: millis(Stage::Hash),
+            compare_ms: millis(Stage::Compare),
+            link_ms: millis(Stage::Link),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_up_stage_times_and_stats() {
+        let mut clock = StageClock::default();
+        for _ in 0..4 {
+            clock.add(Stage::Hash, Duration::from_micros(600));
+        }
+        clock.add(Stage::Scan, Duration::from_millis(5));
+        let mut stats = RunStats {
+            bytes_read: 3000,
+            wall_time_ms: 1500,
+            excluded_files: BTreeMap::from([("It has a unique hash.".to_owned(), 1)]),
+            stage_times: clock.times(),
+            ..RunStats::default()
+        };
+        assert_eq!(stats.stage_times.hash_ms, 2);
+        assert_eq!(stats.stage_times.scan_ms, 5);
+        assert_eq!(stats.throughput(), 2000);
+
+        stats.add(&stats.clone());
+
+        assert_eq!(stats.excluded_files["It has a unique hash."], 2);
+        assert_eq!(stats.stage_times.hash_ms, 4);
+        assert_eq!(stats.throughput(), 2000);
+    }
+}
@@ -0,0 +1,257 @@
+//! Long-running mode controlled through stdin, for embedding in editors and file managers.
+//!
+//! Every line on stdin is a JSON command:
+//!
+//! - `{"command": "scan", "paths": [...]}` finds duplicates in the paths (the command-line paths
+//!   when omitted) without changing anything. Each group of duplicates is numbered.
+//! - `{"command": "status"}` reports whether a scan is running and how far along it is.
+//! - `{"command": "apply-group", "group": 3, "keep": "..."}` hardlinks the files of a group to the
+//!   kept file (the group's first file when omitted).
+//! - `{"command": "cancel"}` stops the running scan.
+//!
+//! Every line on stdout is a JSON object with an `event` field: the events of scans and of applied
+//! groups as `--output ndjson` prints them (duplicates carry their `group` number too), `status`
+//! replies, and `error`s for commands that couldn't be carried out. Closing stdin, SIGINT, and
+//! SIGTERM cancel the running scan and exit.
+
+use hardlink_dedup::output::{Event, Renderer, Status};
+use hardlink_dedup::{
+    dedup_with_renderer, link_selections, DedupError, DedupOptions, DedupSummary, LinkSelection,
+    MAX_FILE_ERRORS,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs::metadata;
+use std::io::{self, BufRead};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often waiting for a command checks whether a signal arrived.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case", deny_unknown_fields)]
+enum Command {
+    Scan {
+        #[serde(default)]
+        paths: Vec<PathBuf>,
+    },
+    Status,
+    ApplyGroup {
+        group: usize,
+        keep: Option<PathBuf>,
+    },
+    Cancel,
+}
+
+/// A scan running on its own thread, with the flag that cancels it.
+struct Scan {
+    thread: JoinHandle<()>,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// What the running or last scan found.
+#[derive(Default)]
+struct Session {
+    /// Cleared before printing the scan's last event, so that clients can apply groups as soon as
+    /// they see it.
+    scanning: bool,
+    groups: Vec<Vec<PathBuf>>,
+    status: Option<Status>,
+}
+
+/// Prints events as JSON lines and numbers the groups of duplicates.
+struct StdioRenderer {
+    session: Arc<Mutex<Session>>,
+}
+
+impl Renderer for StdioRenderer {
+    fn renders(&self, event: &Event) -> bool {
+        !matches!(event, Event::Scanned { .. })
+    }
+
+    fn render(&mut self, status: &Status, event: &Event) {
+        let mut session = self.session.lock().unwrap();
+        session.status = Some(*status);
+        if let Event::Finished { .. } = event {
+            session.scanning = false;
+        }
+        let mut value = serde_json::to_value(event).unwrap();
+        if let Event::Duplicates { files, .. } = event {
+            value["group"] = session.groups.len().into();
+            session
+                .groups
+                .push(files.iter().map(|file| file.to_path_buf()).collect());
+        }
+        send(&value);
+    }
+}
+
+/// Answers commands from stdin until it is closed or a signal sets [`DedupOptions::interrupted`].
+/// Returns the combined summary of the applied groups.
+pub fn run(paths: &[PathBuf], options: &DedupOptions) -> Result<DedupSummary, DedupError> {
+    let session = Arc::new(Mutex::new(Session::default()));
+    let mut scan: Option<Scan> = None;
+    let mut summary = DedupSummary::default();
+    let lines = read_lines();
+    while !options.interrupted.load(Ordering::Relaxed) {
+        let line = match lines.recv_timeout(SIGNAL_POLL_INTERVAL) {
+            Ok(line) => line.map_err(DedupError::ReadCommands)?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let command = match serde_json::from_str::<Command>(&line) {
+            Ok(command) => command,
+            Err(err) => {
+                send_error(&format!("Invalid command {:?}. Error: {}", line, err));
+                continue;
+            }
+        };
+        let scanning = session.lock().unwrap().scanning;
+        match command {
+            Command::Scan { .. } | Command::ApplyGroup { .. } if scanning => {
+                send_error("A scan is already running.");
+            }
+            Command::Scan { paths: scan_paths } => {
+                let scan_paths = if scan_paths.is_empty() {
+                    paths.to_vec()
+                } else {
+                    scan_paths
+                };
+                if let Some(scan) = scan.take() {
+                    let _ = scan.thread.join();
+                }
+                *session.lock().unwrap() = Session {
+                    scanning: true,
+                    ..Session::default()
+                };
+                scan = Some(start_scan(scan_paths, options, &session));
+            }
+            Command::Status => {
+                let session = session.lock().unwrap();
+                send(&json!({
+                    "event": "status",
+                    "scanning": scanning,
+                    "processed_files": session.status.map_or(0, |status| status.processed_files),
+                    "total_files": session.status.map_or(0, |status| status.total_files),
+                    "groups": session.groups.len(),
+                }));
+            }
+            Command::ApplyGroup { group, keep } => {
+                let files = session.lock().unwrap().groups.get(group).cloned();
+                let selection = match files.ok_or_else(|| format!("No group {}.", group)) {
+                    Ok(files) => selection(&files, keep),
+                    Err(err) => Err(err),
+                };
+                let applied = selection.and_then(|selection| {
+                    let mut renderer = StdioRenderer {
+                        session: session.clone(),
+                    };
+                    link_selections(&[selection], options, &mut renderer).map_err(String::from)
+                });
+                match applied {
+                    Ok(applied) => {
+                        summary.bytes_deduped += applied.bytes_deduped;
+                        summary.failed_files += applied.failed_files;
+                        let room = MAX_FILE_ERRORS.saturating_sub(summary.file_errors.len());
+                        summary
+                            .file_errors
+                            .extend(applied.file_errors.into_iter().take(room));
+                        summary.processed_files += applied.processed_files;
+                    }
+                    Err(err) => send_error(&err),
+                }
+            }
+            Command::Cancel => {
+                if let Some(scan) = &scan {
+                    scan.cancelled.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+    if let Some(scan) = scan {
+        scan.cancelled.store(true, Ordering::Relaxed);
+        let _ = scan.thread.join();
+    }
+    summary.interrupted = options.interrupted.load(Ordering::Relaxed);
+    Ok(summary)
+}
+
+/// Reads the lines of stdin on a thread of their own, so that waiting for a command doesn't keep
+/// signals from being noticed.
+fn read_lines() -> Receiver<io::Result<String>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Scans the paths for duplicates without changing anything. Cancelling the scan leaves
+/// [`DedupOptions::interrupted`], which signals set, alone.
+fn start_scan(paths: Vec<PathBuf>, options: &DedupOptions, session: &Arc<Mutex<Session>>) -> Scan {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let scan_options = DedupOptions {
+        dry_run: true,
+        journal: None,
+        progress: false,
+        progress_interval: None,
+        interrupted: cancelled.clone(),
+        ..options.clone()
+    };
+    let mut renderer = StdioRenderer {
+        session: session.clone(),
+    };
+    let session = session.clone();
+    let thread = thread::spawn(move || {
+        if let Err(err) = dedup_with_renderer(&paths, &scan_options, &mut renderer) {
+            session.lock().unwrap().scanning = false;
+            send_error(&err.to_string());
+        }
+    });
+    Scan { thread, cancelled }
+}
+
+/// Links the files of a group to the kept file. Files already hardlinked to it are left alone.
+fn selection(files: &[PathBuf], keep: Option<PathBuf>) -> Result<LinkSelection, String> {
+    let original = match keep {
+        Some(keep) if !files.contains(&keep) => {
+            return Err(format!("{:?} isn't in the group.", keep))
+        }
+        Some(keep) => keep,
+        None => files[0].clone(),
+    };
+    let original_metadata = metadata(&original)
+        .map_err(|err| format!("Failed to access {:?}. Error: {}", original, err))?;
+    let targets = files
+        .iter()
+        .filter(|file| {
+            metadata(file).map_or(true, |file_metadata| {
+                (file_metadata.dev(), file_metadata.ino())
+                    != (original_metadata.dev(), original_metadata.ino())
+            })
+        })
+        .cloned()
+        .collect();
+    Ok(LinkSelection { original, targets })
+}
+
+fn send(value: &Value) {
+    println!("{}", value);
+}
+
+fn send_error(message: &str) {
+    send(&json!({"event": "error", "message": message}));
+}
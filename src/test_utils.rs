@@ -0,0 +1,55 @@
+//! Helpers for integration tests against real filesystems.
+//!
+//! Only available with the `test-utils` feature. The helpers panic on any IO error, which fails the
+//! test that called them.
+
+use std::fs::{create_dir_all, metadata, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Creates the file `file_name` with the given contents in `dir`. Creates `dir` if it's missing.
+pub fn tmp_file(dir: &Path, file_name: &str, contents: &str) -> PathBuf {
+    create_dir_all(dir).unwrap();
+    let path = dir.join(file_name);
+    let mut file = File::create(&path).unwrap();
+    file.write_all(contents.as_bytes()).unwrap();
+    path
+}
+
+/// Creates `count` files with the same contents, each in its own directory `dir1`, `dir2`, ... under
+/// `root`. The files are named `file1`, `file2`, ...
+pub fn duplicate_files(root: &Path, count: usize, contents: &str) -> Vec<PathBuf> {
+    (1..=count)
+        .map(|i| {
+            tmp_file(
+                &root.join(format!("dir{}", i)),
+                &format!("file{}", i),
+                contents,
+            )
+        })
+        .collect()
+}
+
+/// Sets the modification time of the file.
+pub fn set_modified(file: &Path, modified: SystemTime) {
+    OpenOptions::new()
+        .write(true)
+        .open(file)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+}
+
+/// Whether both paths are hardlinks to the same inode.
+pub fn same(file1: &Path, file2: &Path) -> bool {
+    let metadata1 = metadata(file1).unwrap();
+    let metadata2 = metadata(file2).unwrap();
+    metadata1.ino() == metadata2.ino()
+}
+
+/// Whether all paths are hardlinks to the same inode.
+pub fn all_same(files: &[PathBuf]) -> bool {
+    files.windows(2).all(|pair| same(&pair[0], &pair[1]))
+}
@@ -0,0 +1,56 @@
+//! Limiting how fast we read files.
+//!
+//! All reads of this process share one budget of bytes per second, so hashing and comparing files
+//! together never read faster than the limit.
+
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Reads that happened longer ago than this don't count towards the current rate. Otherwise a
+/// long pause (e.g. while walking directories) would allow a burst of reads at full speed.
+const WINDOW: Duration = Duration::from_secs(1);
+
+struct Budget {
+    started: Instant,
+    bytes: u64,
+}
+
+static BUDGET: Mutex<Option<Budget>> = Mutex::new(None);
+
+/// Accounts for reading `bytes` and sleeps long enough to stay below `bytes_per_second`.
+pub(crate) fn throttle(bytes: usize, bytes_per_second: u64) {
+    let delay = {
+        let mut budget = BUDGET.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+        let budget = budget.get_or_insert(Budget {
+            started: now,
+            bytes: 0,
+        });
+        let allowed = Duration::from_secs_f64(budget.bytes as f64 / bytes_per_second.max(1) as f64);
+        if now.duration_since(budget.started) > allowed + WINDOW {
+            budget.started = now;
+            budget.bytes = 0;
+        }
+        budget.bytes += bytes as u64;
+        let allowed = Duration::from_secs_f64(budget.bytes as f64 / bytes_per_second.max(1) as f64);
+        allowed.saturating_sub(now.duration_since(budget.started))
+    };
+    if !delay.is_zero() {
+        sleep(delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleep_to_stay_below_limit() {
+        let started = Instant::now();
+        for _ in 0..4 {
+            throttle(1000, 10_000);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+}
@@ -0,0 +1,296 @@
+//! Terminal browser of duplicate groups.
+//!
+//! A dry run finds the groups of files with the same contents. The groups are listed by how many
+//! bytes hardlinking them would reclaim. The user picks which file of each group to keep and which
+//! groups to leave alone, then the selection is hardlinked.
+
+use hardlink_dedup::output::{renderer, Event, Renderer, Status};
+use hardlink_dedup::units::format_size;
+use hardlink_dedup::{
+    dedup_with_renderer, link_selections, DedupError, DedupOptions, DedupSummary, LinkSelection,
+};
+use ratatui::crossterm::event::{self, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs::metadata;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const HELP: &str =
+    "↑/↓ move, →/← expand/collapse, space select group or keep file, x hardlink selected, q quit";
+
+/// Finds duplicates in the paths, lets the user choose what to hardlink, and hardlinks it.
+pub fn run(paths: &[PathBuf], options: &DedupOptions) -> Result<DedupSummary, DedupError> {
+    let mut collector = DuplicatesCollector::default();
+    let scan_options = DedupOptions {
+        dry_run: true,
+        journal: None,
+        ..options.clone()
+    };
+    let scan_summary = dedup_with_renderer(paths, &scan_options, &mut collector)?;
+    if scan_summary.interrupted {
+        return Ok(scan_summary);
+    }
+    let mut groups: Vec<Group> = collector
+        .groups
+        .iter()
+        .filter_map(|files| Group::new(files))
+        .collect();
+    groups.sort_by_key(|group| Reverse(group.reclaimable));
+    let mut terminal = ratatui::try_init().map_err(DedupError::SetUpTerminal)?;
+    let browse_result = browse(&mut terminal, &mut groups);
+    ratatui::restore();
+    let scan_failures = DedupSummary {
+        failed_files: scan_summary.failed_files,
+        file_errors: scan_summary.file_errors,
+        ..DedupSummary::default()
+    };
+    if !browse_result.map_err(DedupError::Terminal)? {
+        return Ok(scan_failures);
+    }
+    let selections: Vec<LinkSelection> = groups
+        .iter()
+        .filter(|group| group.selected)
+        .map(Group::selection)
+        .collect();
+    let mut summary = link_selections(&selections, options, renderer(options.output).as_mut())?;
+    summary.add(&scan_failures);
+    Ok(summary)
+}
+
+#[derive(Default)]
+struct DuplicatesCollector {
+    groups: Vec<Vec<PathBuf>>,
+}
+
+impl Renderer for DuplicatesCollector {
+    fn renders(&self, event: &Event) -> bool {
+        matches!(event, Event::Duplicates { .. })
+    }
+
+    fn render(&mut self, _status: &Status, event: &Event) {
+        if let Event::Duplicates { files, .. } = event {
+            self.groups
+                .push(files.iter().map(|file| file.to_path_buf()).collect());
+        }
+    }
+}
+
+struct GroupFile {
+    path: PathBuf,
+    size: u64,
+    inode: (u64, u64),
+}
+
+/// Files with the same contents.
+struct Group {
+    files: Vec<GroupFile>,
+    /// The index of the file the other files are hardlinked to.
+    keep: usize,
+    selected: bool,
+    expanded: bool,
+    /// Bytes that hardlinking all files in the group frees.
+    reclaimable: u64,
+}
+
+impl Group {
+    /// `None` if fewer than two separate files remain.
+    fn new(paths: &[PathBuf]) -> Option<Group> {
+        let files: Vec<GroupFile> = paths
+            .iter()
+            .filter_map(|path| {
+                let file_metadata = metadata(path).ok()?;
+                Some(GroupFile {
+                    path: path.clone(),
+                    size: file_metadata.len(),
+                    inode: (file_metadata.dev(), file_metadata.ino()),
+                })
+            })
+            .collect();
+        let inodes: HashSet<_> = files.iter().map(|file| file.inode).collect();
+        if inodes.len() < 2 {
+            return None;
+        }
+        let reclaimable = files[0].size * (inodes.len() as u64 - 1);
+        Some(Group {
+            files,
+            keep: 0,
+            selected: true,
+            expanded: false,
+            reclaimable,
+        })
+    }
+
+    fn kept(&self) -> &GroupFile {
+        &self.files[self.keep]
+    }
+
+    fn selection(&self) -> LinkSelection {
+        let kept = self.kept();
+        LinkSelection {
+            original: kept.path.clone(),
+            targets: self
+                .files
+                .iter()
+                .filter(|file| file.inode != kept.inode)
+                .map(|file| file.path.clone())
+                .collect(),
+        }
+    }
+}
+
+/// A line in the list: a group, or a file of an expanded group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Row {
+    Group(usize),
+    File(usize, usize),
+}
+
+fn rows(groups: &[Group]) -> Vec<Row> {
+    let mut rows = Vec::new();
+    for (group_index, group) in groups.iter().enumerate() {
+        rows.push(Row::Group(group_index));
+        if group.expanded {
+            rows.extend(
+                (0..group.files.len()).map(|file_index| Row::File(group_index, file_index)),
+            );
+        }
+    }
+    rows
+}
+
+/// Lets the user edit the selection. Returns whether to hardlink the selected groups.
+fn browse(terminal: &mut DefaultTerminal, groups: &mut [Group]) -> io::Result<bool> {
+    let mut list_state = ListState::default().with_selected(Some(0));
+    loop {
+        let rows = rows(groups);
+        terminal.draw(|frame| draw(frame, groups, &rows, &mut list_state))?;
+        let key = match event::read()? {
+            event::Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+        let cursor = list_state
+            .selected()
+            .unwrap_or(0)
+            .min(rows.len().saturating_sub(1));
+        let row = rows.get(cursor).copied();
+        match (key.code, row) {
+            (KeyCode::Char('q') | KeyCode::Esc, _) => return Ok(false),
+            (KeyCode::Char('x'), _) => return Ok(true),
+            (KeyCode::Up | KeyCode::Char('k'), _) => list_state.select_previous(),
+            (KeyCode::Down | KeyCode::Char('j'), _) => list_state.select_next(),
+            (KeyCode::Right | KeyCode::Char('l') | KeyCode::Enter, Some(Row::Group(group))) => {
+                groups[group].expanded = !groups[group].expanded;
+            }
+            (KeyCode::Left | KeyCode::Char('h'), Some(Row::Group(group) | Row::File(group, _))) => {
+                groups[group].expanded = false;
+                let group_row = rows.iter().position(|row| *row == Row::Group(group));
+                list_state.select(group_row);
+            }
+            (KeyCode::Char(' '), Some(Row::Group(group))) => {
+                groups[group].selected = !groups[group].selected;
+            }
+            (KeyCode::Char(' '), Some(Row::File(group, file))) => {
+                groups[group].keep = file;
+                groups[group].selected = true;
+            }
+            _ => (),
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, groups: &[Group], rows: &[Row], list_state: &mut ListState) {
+    let [list_area, help_area] =
+        Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+    let selected_bytes: u64 = groups
+        .iter()
+        .filter(|group| group.selected)
+        .map(|group| group.reclaimable)
+        .sum();
+    let items: Vec<ListItem> = rows
+        .iter()
+        .map(|row| ListItem::new(row_text(groups, *row)))
+        .collect();
+    let list = List::new(items)
+        .block(Block::bordered().title(format!(
+            " {} groups of duplicates, {} selected ",
+            groups.len(),
+            format_size(selected_bytes)
+        )))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(list, list_area, list_state);
+    frame.render_widget(Paragraph::new(HELP), help_area);
+}
+
+fn row_text(groups: &[Group], row: Row) -> String {
+    match row {
+        Row::Group(group_index) => {
+            let group = &groups[group_index];
+            format!(
+                "[{}] {} in {} files, keeping {}",
+                if group.selected { "x" } else { " " },
+                format_size(group.reclaimable),
+                group.files.len(),
+                display(&group.kept().path)
+            )
+        }
+        Row::File(group_index, file_index) => {
+            let group = &groups[group_index];
+            let file = &group.files[file_index];
+            let marker = if file_index == group.keep {
+                "keep"
+            } else if file.inode == group.kept().inode {
+                "same"
+            } else {
+                "link"
+            };
+            format!(
+                "      {} {} ({})",
+                marker,
+                display(&file.path),
+                format_size(file.size)
+            )
+        }
+    }
+}
+
+fn display(path: &Path) -> String {
+    path.display().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{hard_link, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn group_selection() {
+        let tmp_dir = tempdir().unwrap();
+        let paths: Vec<PathBuf> = ["a", "b", "c"]
+            .iter()
+            .map(|name| tmp_dir.path().join(name))
+            .collect();
+        write(&paths[0], "contents").unwrap();
+        hard_link(&paths[0], &paths[1]).unwrap();
+        write(&paths[2], "contents").unwrap();
+
+        let mut group = Group::new(&paths).unwrap();
+        assert_eq!(group.reclaimable, 8);
+        assert_eq!(group.selection().targets, vec![paths[2].clone()]);
+        group.keep = 2;
+        assert_eq!(
+            group.selection(),
+            LinkSelection {
+                original: paths[2].clone(),
+                targets: vec![paths[0].clone(), paths[1].clone()],
+            }
+        );
+        assert!(Group::new(&paths[..2]).is_none());
+    }
+}
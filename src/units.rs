@@ -0,0 +1,212 @@
+//! Parsing of human-friendly values given on the command line, and formatting of sizes for output.
+//!
+//! Every size accepts values like `4096`, `512K`, `10M` or `1.5G`, every duration accepts values
+//! like `30`, `90s`, `15m` or `1.5h`, and every timestamp accepts values like `2024-03-01`,
+//! `2024-03-01 12:00:00` or `@1709294400`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Parses sizes like `512K`, `10M` or `1.5G`. Units are powers of 1024 and may be followed by `B`
+/// or `iB` (e.g. `10MB` or `10MiB`). A number without a unit is in bytes.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let (number, unit) = split_number(text).ok_or_else(|| {
+        format!(
+            "Invalid size {:?}. Expected a value like 512K, 10M or 1.5G.",
+            text
+        )
+    })?;
+    let unit_upper = unit.to_ascii_uppercase();
+    let unit_prefix = unit_upper
+        .strip_suffix("IB")
+        .or_else(|| unit_upper.strip_suffix('B'))
+        .unwrap_or(&unit_upper);
+    let exponent = match unit_prefix {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        "P" => 5,
+        _ => {
+            return Err(format!(
+                "Invalid size unit {:?} in {:?}. Expected one of K, M, G, T or P.",
+                unit, text
+            ))
+        }
+    };
+    let bytes = number * 1024f64.powi(exponent);
+    if bytes > u64::MAX as f64 {
+        return Err(format!("Size {:?} is too large.", text));
+    }
+    Ok(bytes.round() as u64)
+}
+
+/// Parses durations like `90s`, `15m`, `1.5h` or `1d`. A number without a unit is in seconds.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let (number, unit) = split_number(text).ok_or_else(|| {
+        format!(
+            "Invalid duration {:?}. Expected a value like 90s, 15m or 2h.",
+            text
+        )
+    })?;
+    let unit_seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "Invalid duration unit {:?} in {:?}. Expected one of s, m, h or d.",
+                unit, text
+            ))
+        }
+    };
+    Duration::try_from_secs_f64(number * unit_seconds as f64)
+        .map_err(|_| format!("Duration {:?} is too large.", text))
+}
+
+/// Parses timestamps like `2024-03-01`, `2024-03-01 12:00:00` or `2024-03-01T12:00:00Z` in UTC, or
+/// `@1709294400` in seconds since the Unix epoch.
+pub fn parse_timestamp(text: &str) -> Result<SystemTime, String> {
+    let text = text.trim();
+    let invalid = || {
+        format!(
+            "Invalid timestamp {:?}. Expected a value like 2024-03-01, 2024-03-01 12:00:00 or @1709294400.",
+            text
+        )
+    };
+    if let Some(seconds) = text.strip_prefix('@') {
+        let seconds: u64 = seconds.parse().map_err(|_| invalid())?;
+        return UNIX_EPOCH
+            .checked_add(Duration::from_secs(seconds))
+            .ok_or_else(|| format!("Timestamp {:?} is too large.", text));
+    }
+    let date_time = if text.len() == "2024-03-01".len() {
+        format!("{} 00:00:00", text)
+    } else {
+        text.to_owned()
+    };
+    humantime::parse_rfc3339_weak(&date_time).map_err(|_| invalid())
+}
+
+/// Formats sizes like `512 B`, `1.5 KiB` or `10.0 GiB`, in the largest unit that the size has at
+/// least one of.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Formats sizes as plain numbers like `1536 bytes` with `raw`, for scripts, or like
+/// [`format_size`] otherwise.
+pub fn format_bytes(bytes: u64, raw: bool) -> String {
+    if raw {
+        format!("{} bytes", bytes)
+    } else {
+        format_size(bytes)
+    }
+}
+
+/// Formats sizes after labels that already say they're in bytes: as plain numbers with `raw`, or
+/// like [`format_size`] otherwise.
+pub fn size_value(bytes: u64, raw: bool) -> String {
+    if raw {
+        bytes.to_string()
+    } else {
+        format_size(bytes)
+    }
+}
+
+/// Splits text like `1.5G` into a non-negative number and its unit.
+fn split_number(text: &str) -> Option<(f64, &str)> {
+    let text = text.trim();
+    let unit_start = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(unit_start);
+    let number: f64 = number.parse().ok()?;
+    Some((number, unit.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_with_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+    }
+
+    #[test]
+    fn invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("10y").is_err());
+        assert!(parse_duration("-5s").is_err());
+    }
+
+    #[test]
+    fn timestamps() {
+        let march_first = UNIX_EPOCH + Duration::from_secs(1709251200);
+        assert_eq!(parse_timestamp("2024-03-01"), Ok(march_first));
+        assert_eq!(
+            parse_timestamp("2024-03-01 12:00:00"),
+            Ok(march_first + Duration::from_secs(12 * 60 * 60))
+        );
+        assert_eq!(
+            parse_timestamp("2024-03-01T12:00:00Z"),
+            Ok(march_first + Duration::from_secs(12 * 60 * 60))
+        );
+        assert_eq!(parse_timestamp("@1709251200"), Ok(march_first));
+        assert!(parse_timestamp("yesterday").is_err());
+        assert!(parse_timestamp("@-5").is_err());
+        assert_eq!(
+            parse_timestamp("@18446744073709551615"),
+            Err("Timestamp \"@18446744073709551615\" is too large.".to_owned())
+        );
+    }
+
+    #[test]
+    fn sizes_with_units() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("10M"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("10MiB"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("10mb"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("1.5G"), Ok(3 * 512 * 1024 * 1024));
+    }
+
+    #[test]
+    fn format_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(10 * 1024 * 1024), "10.0 MiB");
+        assert_eq!(format_size(u64::MAX), "16.0 EiB");
+        assert_eq!(format_bytes(1536, true), "1536 bytes");
+        assert_eq!(format_bytes(1536, false), "1.5 KiB");
+        assert_eq!(size_value(1536, true), "1536");
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("1.2.3M").is_err());
+        assert!(parse_size("99999P").is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//! Reading many files at once through io_uring.
+//!
+//! With many small files, a syscall per read dominates the time it takes to compare them. io_uring
+//! lets us queue the reads of many files and wait for all of them with a single syscall. Files are
+//! still opened and closed one by one.
+
+use crate::page_cache::{ReadFile, ReadOptions};
+use crate::throttle::throttle;
+use crate::HashAlgorithm;
+use io_uring::{opcode, types, IoUring};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tracing::warn;
+
+/// How many files are read at a time.
+const QUEUE_DEPTH: usize = 64;
+/// Groups with fewer files than this are read one file at a time. Setting up a ring isn't worth it
+/// for them.
+pub(crate) const MIN_FILES: usize = 8;
+
+/// Whether the kernel lets us use io_uring. Warns once if it doesn't.
+pub(crate) fn available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| match IoUring::new(1) {
+        Ok(_) => true,
+        Err(err) => {
+            warn!(
+                "io_uring isn't available. Reading files one at a time instead. Error: {}",
+                err
+            );
+            false
+        }
+    })
+}
+
+/// Reads up to `prefix_bytes` from the start of every file. The results are in the same order as
+/// the files. Fails as a whole only if the ring fails.
+pub(crate) fn read_prefixes(
+    files: &[&PathBuf],
+    prefix_bytes: u64,
+    read_options: ReadOptions,
+) -> io::Result<Vec<io::Result<Vec<u8>>>> {
+    let mut prefixes = vec![Vec::new(); files.len()];
+    let chunk_bytes = read_options.buffer_size.min(prefix_bytes as usize);
+    let results = read_files(
+        files,
+        read_options,
+        chunk_bytes,
+        prefix_bytes,
+        |index, data| prefixes[index].extend_from_slice(data),
+    )?;
+    Ok(results
+        .into_iter()
+        .zip(prefixes)
+        .map(|(result, prefix)| result.map(|_| prefix))
+        .collect())
+}
+
+/// Calculates the hashes of the files. The results are in the same order as the files. Fails as a
+/// whole only if the ring fails.
+pub(crate) fn hash_files(
+    files: &[&PathBuf],
+    algorithm: HashAlgorithm,
+    read_options: ReadOptions,
+) -> io::Result<Vec<io::Result<Vec<u8>>>> {
+    let mut hashers: Vec<_> = files.iter().map(|_| algorithm.hasher()).collect();
+    let results = read_files(
+        files,
+        read_options,
+        read_options.buffer_size,
+        u64::MAX,
+        |index, data| hashers[index].update(data),
+    )?;
+    Ok(results
+        .into_iter()
+        .zip(hashers)
+        .map(|(result, hasher)| result.map(|_| hasher.finalize()))
+        .collect())
+}
+
+/// A file being read.
+struct Slot {
+    index: usize,
+    file: ReadFile,
+    buffer: Vec<u8>,
+    offset: u64,
+}
+
+/// Reads up to `limit` bytes of the files, `chunk_bytes` at a time and `QUEUE_DEPTH` files at a time,
+/// and passes the contents of each file to `consume` in order. Returns the result of reading each
+/// file.
+fn read_files(
+    files: &[&PathBuf],
+    read_options: ReadOptions,
+    chunk_bytes: usize,
+    limit: u64,
+    mut consume: impl FnMut(usize, &[u8]),
+) -> io::Result<Vec<io::Result<()>>> {
+    let mut results: Vec<io::Result<()>> = files.iter().map(|_| Ok(())).collect();
+    if limit == 0 || chunk_bytes == 0 {
+        return Ok(results);
+    }
+    let mut ring = IoUring::new(QUEUE_DEPTH as u32)?;
+    let mut slots: Vec<Option<Slot>> = (0..QUEUE_DEPTH).map(|_| None).collect();
+    let mut next_file = 0;
+    let mut active = 0;
+    loop {
+        for (slot_index, slot) in slots.iter_mut().enumerate() {
+            while slot.is_none() && next_file < files.len() {
+                let index = next_file;
+                next_file += 1;
+                match ReadFile::open(files[index], read_options) {
+                    Ok(file) => {
+                        let new_slot = slot.insert(Slot {
+                            index,
+                            file,
+                            buffer: vec![0; chunk_bytes],
+                            offset: 0,
+                        });
+                        queue_read(&mut ring, slot_index, new_slot, limit);
+                        active += 1;
+                    }
+                    Err(err) => results[index] = Err(err),
+                }
+            }
+        }
+        if active == 0 {
+            return Ok(results);
+        }
+        if let Err(err) = ring.submit_and_wait(1) {
+            // The kernel may still write into the buffers of queued reads.
+            std::mem::forget(slots);
+            return Err(err);
+        }
+        let completions: Vec<_> = ring
+            .completion()
+            .map(|completion| (completion.user_data() as usize, completion.result()))
+            .collect();
+        for (slot_index, result) in completions {
+            let slot = slots[slot_index].as_mut().unwrap();
+            if result == -libc::EINTR || result == -libc::EAGAIN {
+                queue_read(&mut ring, slot_index, slot, limit);
+                continue;
+            }
+            if result < 0 {
+                results[slot.index] = Err(io::Error::from_raw_os_error(-result));
+                slots[slot_index] = None;
+                active -= 1;
+                continue;
+            }
+            let read_bytes = result as usize;
+            if let Some(bwlimit) = read_options.bwlimit {
+                throttle(read_bytes, bwlimit);
+            }
+            consume(slot.index, &slot.buffer[..read_bytes]);
+            slot.offset += read_bytes as u64;
+            if read_bytes == 0 || slot.offset >= limit {
+                slots[slot_index] = None;
+                active -= 1;
+            } else {
+                queue_read(&mut ring, slot_index, slot, limit);
+            }
+        }
+    }
+}
+
+fn queue_read(ring: &mut IoUring, slot_index: usize, slot: &mut Slot, limit: u64) {
+    let length = (slot.buffer.len() as u64).min(limit - slot.offset) as u32;
+    let read = opcode::Read::new(
+        types::Fd(slot.file.file().as_raw_fd()),
+        slot.buffer.as_mut_ptr(),
+        length,
+    )
+    .offset(slot.offset)
+    .build()
+    .user_data(slot_index as u64);
+    // Safety: the buffer stays in its slot until the read completes, and there are never more
+    // queued reads than slots, so the submission queue can't overflow.
+    unsafe { ring.submission().push(&read) }.expect("The submission queue is full.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculate_hash;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn read_many_files() {
+        if !available() {
+            return;
+        }
+        let tmp_dir = tempdir().unwrap();
+        let mut files: Vec<PathBuf> = (0..100)
+            .map(|index| tmp_dir.path().join(format!("file{}", index)))
+            .collect();
+        for (index, file) in files.iter().enumerate() {
+            write(file, format!("contents of file {}", index).repeat(index)).unwrap();
+        }
+        files.push(tmp_dir.path().join("missing"));
+        let files: Vec<&PathBuf> = files.iter().collect();
+        let read_options = ReadOptions {
+            buffer_size: 7,
+            ..ReadOptions::default()
+        };
+
+        let prefixes = read_prefixes(&files, 10, read_options).unwrap();
+        let hashes = hash_files(&files, HashAlgorithm::Sha256, read_options).unwrap();
+
+        for (index, file) in files[..100].iter().enumerate() {
+            let contents = std::fs::read(file).unwrap();
+            assert_eq!(
+                prefixes[index].as_ref().unwrap(),
+                &contents[..contents.len().min(10)]
+            );
+            assert_eq!(
+                hashes[index].as_ref().unwrap(),
+                &calculate_hash(file, HashAlgorithm::Sha256, read_options).unwrap()
+            );
+        }
+        assert!(prefixes[100].is_err());
+        assert!(hashes[100].is_err());
+    }
+}
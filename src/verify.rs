@@ -0,0 +1,160 @@
+//! Auditing of files that were already deduplicated.
+//!
+//! Verifying doesn't change anything. It reports which files are hardlinked together and flags
+//! files with the same contents that aren't hardlinked, as well as hardlinks to the same inode that
+//! read back different contents, which only happens on a corrupt filesystem.
+
+use crate::error::{DedupError, FailedFiles, FileError};
+use crate::page_cache::ReadOptions;
+use crate::{
+    calculate_hash, find_inode_groups, same_hash_groups, same_metadata_groups, same_prefix_groups,
+    DedupOptions, HashAlgorithm, DEFAULT_PREFIX_BYTES,
+};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct VerifySummary {
+    /// Files with more than one path among the verified paths.
+    pub hardlinked_files: usize,
+    /// Groups of separate files with the same contents, i.e. files that deduplicating would link.
+    pub unlinked_duplicates: usize,
+    /// Files whose paths read back different contents.
+    pub diverged_files: usize,
+    pub failed_files: usize,
+    /// Why the files in [`VerifySummary::failed_files`] failed, for the first
+    /// [`crate::MAX_FILE_ERRORS`] of them.
+    pub file_errors: Vec<FileError>,
+}
+
+impl VerifySummary {
+    /// Whether the audit found anything suspicious.
+    pub fn has_findings(&self) -> bool {
+        self.unlinked_duplicates > 0 || self.diverged_files > 0
+    }
+}
+
+/// Audits the files in the given paths without changing them. Only [`DedupOptions::follow_symlinks`],
+/// [`DedupOptions::one_file_system`] and the age and time window filters apply.
+pub fn verify(paths: &[PathBuf], options: &DedupOptions) -> Result<VerifySummary, DedupError> {
+    for path in paths {
+        std::fs::symlink_metadata(path).map_err(|source| DedupError::Access {
+            path: path.clone(),
+            source,
+        })?;
+    }
+    let mut summary = VerifySummary::default();
+    let mut failed_files = FailedFiles::default();
+    let inode_to_paths = find_inode_groups(paths, options, &mut failed_files);
+    for file_paths in inode_to_paths.values().filter(|paths| paths.len() > 1) {
+        summary.hardlinked_files += 1;
+        info!("Files {:?} are hardlinked together.", file_paths);
+        if !reads_same_through_all_paths(
+            file_paths,
+            options.hash,
+            options.read_options(),
+            &mut failed_files,
+        ) {
+            summary.diverged_files += 1;
+        }
+    }
+    let files = inode_to_paths
+        .values()
+        .flat_map(|file_group| file_group.iter().next());
+    let metadata_groups: Vec<_> =
+        same_metadata_groups(files, options.key_options(), &mut failed_files).collect();
+    for metadata_group in metadata_groups.into_iter().filter(|group| group.len() > 1) {
+        let prefix_groups: Vec<_> = same_prefix_groups(
+            metadata_group,
+            DEFAULT_PREFIX_BYTES,
+            options.read_options(),
+            &mut failed_files,
+        )
+        .collect();
+        for prefix_group in prefix_groups.into_iter().filter(|group| group.len() > 1) {
+            let hash_groups: Vec<_> =
+                same_hash_groups(prefix_group, None, None, options, &mut failed_files).collect();
+            for hash_group in hash_groups.into_iter().filter(|group| group.len() > 1) {
+                summary.unlinked_duplicates += 1;
+                warn!(
+                    "Files {:?} have the same contents but aren't hardlinked.",
+                    hash_group
+                );
+            }
+        }
+    }
+    summary.failed_files = failed_files.count();
+    summary.file_errors = failed_files.into_errors();
+    Ok(summary)
+}
+
+/// Whether every path of a file reads back the same contents. Paths that fail to read are counted
+/// as failed and otherwise ignored.
+fn reads_same_through_all_paths(
+    file_paths: &HashSet<PathBuf>,
+    algorithm: HashAlgorithm,
+    read_options: ReadOptions,
+    failed_files: &mut FailedFiles,
+) -> bool {
+    let mut first_hash: Option<(&PathBuf, Vec<u8>)> = None;
+    for file_path in file_paths {
+        let hash = match calculate_hash(file_path, algorithm, read_options) {
+            Ok(hash) => hash,
+            Err(err) => {
+                failed_files.add(
+                    file_path,
+                    format!(
+                        "Skipping file {:?}. Failed to calculate its hash. Error: {}",
+                        file_path, err
+                    ),
+                );
+                continue;
+            }
+        };
+        match &first_hash {
+            None => first_hash = Some((file_path, hash)),
+            Some((first_path, first)) if *first != hash => {
+                warn!(
+                    "Hardlinks {:?} and {:?} read back different contents. This is a probable filesystem corruption.",
+                    first_path, file_path
+                );
+                return false;
+            }
+            Some(_) => (),
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{hard_link, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn verify_hardlinks_and_duplicates() {
+        let tmp_dir = tempdir().unwrap();
+        let file1 = tmp_dir.path().join("file1");
+        write(&file1, "same contents").unwrap();
+        hard_link(&file1, tmp_dir.path().join("file2")).unwrap();
+        write(tmp_dir.path().join("file3"), "same contents").unwrap();
+        write(tmp_dir.path().join("file4"), "other contents").unwrap();
+
+        let summary = verify(&[tmp_dir.path().to_owned()], &DedupOptions::default()).unwrap();
+
+        assert_eq!(
+            summary,
+            VerifySummary {
+                hardlinked_files: 1,
+                unlinked_duplicates: 1,
+                diverged_files: 0,
+                failed_files: 0,
+                file_errors: Vec::new(),
+            }
+        );
+        assert!(summary.has_findings());
+    }
+}
@@ -0,0 +1,340 @@
+//! Watching the deduplicated paths for new files.
+//!
+//! After the initial pass we keep an index of the files we've seen by their device, size, owner and
+//! mode. New files are only compared with the indexed files that share these, so we never rescan the
+//! whole tree.
+
+use crate::checkpoint::GroupKey;
+use crate::error::{DedupError, FailedFiles};
+use crate::output::{Event, Renderer, Status};
+use crate::page_cache::ReadOptions;
+use crate::rusage::resource_usage;
+use crate::sparse::allocated_bytes;
+use crate::xattrs::same_xattrs;
+use crate::{
+    are_files_same, calculate_hash, file_id, frees_inode, is_own_file, metadata_key, open_journal,
+    replace_many_with_hard_link, DedupContext, DedupOptions, DedupSummary, FileId, FileSnapshot,
+    HashAlgorithm, KeyOptions,
+};
+use notify::event::{AccessKind, AccessMode, CreateKind, ModifyKind, RenameMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::fs::{metadata, symlink_metadata, Metadata};
+use std::io;
+use std::mem::take;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+/// How long to collect new files before deduplicating them together.
+const BATCH_DELAY: Duration = Duration::from_secs(2);
+/// How often to check whether we were interrupted.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Files we've seen, grouped by their device, size, owner and mode.
+pub(crate) struct ContentIndex {
+    groups: HashMap<GroupKey, Vec<PathBuf>>,
+    /// Hashes of indexed files and the modification times they were calculated at.
+    hashes: HashMap<PathBuf, (SystemTime, Vec<u8>)>,
+    algorithm: HashAlgorithm,
+    read_options: ReadOptions,
+    key_options: KeyOptions,
+}
+
+impl ContentIndex {
+    /// Indexes one path of every inode.
+    pub(crate) fn new(
+        inode_to_paths: &HashMap<FileId, HashSet<PathBuf>>,
+        algorithm: HashAlgorithm,
+        read_options: ReadOptions,
+        key_options: KeyOptions,
+    ) -> ContentIndex {
+        let mut index = ContentIndex {
+            groups: HashMap::new(),
+            hashes: HashMap::new(),
+            algorithm,
+            read_options,
+            key_options,
+        };
+        for file in inode_to_paths
+            .values()
+            .flat_map(|paths| paths.iter().next())
+        {
+            if let Ok(file_metadata) = metadata(file) {
+                index.add(file.clone(), &file_metadata);
+            }
+        }
+        index
+    }
+
+    fn add(&mut self, file: PathBuf, file_metadata: &Metadata) {
+        self.groups
+            .entry(metadata_key(file_metadata, self.key_options))
+            .or_default()
+            .push(file);
+    }
+
+    /// Finds an indexed file with the same contents (and, if required, name) as the given file.
+    /// Files without one are indexed. Returns `None` for files that are already hardlinked to an
+    /// indexed file too.
+    pub(crate) fn find_same(
+        &mut self,
+        file: &Path,
+        file_metadata: &Metadata,
+        paranoid: bool,
+        same_name_only: bool,
+    ) -> io::Result<Option<PathBuf>> {
+        let candidates = self
+            .groups
+            .get(&metadata_key(file_metadata, self.key_options))
+            .cloned()
+            .unwrap_or_default();
+        let mut hash = None;
+        for candidate in candidates {
+            if same_name_only && candidate.file_name() != file.file_name() {
+                continue;
+            }
+            let candidate_metadata = match metadata(&candidate) {
+                Ok(candidate_metadata) => candidate_metadata,
+                Err(_) => continue,
+            };
+            if file_id(&candidate_metadata) == file_id(file_metadata) {
+                return Ok(None);
+            }
+            if hash.is_none() {
+                hash = Some(calculate_hash(file, self.algorithm, self.read_options)?);
+            }
+            let candidate_hash = match self.hash(&candidate, &candidate_metadata) {
+                Ok(candidate_hash) => candidate_hash,
+                Err(_) => continue,
+            };
+            if hash.as_ref() == Some(&candidate_hash)
+                && (!paranoid || are_files_same(file, &candidate, self.read_options)?)
+            {
+                return Ok(Some(candidate));
+            }
+        }
+        self.add(file.to_owned(), file_metadata);
+        Ok(None)
+    }
+
+    fn hash(&mut self, file: &Path, file_metadata: &Metadata) -> io::Result<Vec<u8>> {
+        let modified = file_metadata.modified()?;
+        if let Some((hashed_at, hash)) = self.hashes.get(file) {
+            if *hashed_at == modified {
+                return Ok(hash.clone());
+            }
+        }
+        let hash = calculate_hash(file, self.algorithm, self.read_options)?;
+        self.hashes
+            .insert(file.to_owned(), (modified, hash.clone()));
+        Ok(hash)
+    }
+}
+
+/// Deduplicates new files under the paths until interrupted.
+pub(crate) fn watch(
+    paths: &[PathBuf],
+    options: &DedupOptions,
+    renderer: &mut dyn Renderer,
+    mut index: ContentIndex,
+) -> Result<DedupSummary, DedupError> {
+    let (sender, receiver) = channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(DedupError::Watch)?;
+    for path in paths {
+        watcher
+            .watch(path, RecursiveMode::Recursive)
+            .map_err(|source| DedupError::WatchPath {
+                path: path.clone(),
+                source,
+            })?;
+    }
+    info!("Watching for new files.");
+    let batch_options = DedupOptions {
+        progress: false,
+        progress_interval: None,
+        hash_workers: 0,
+        ..options.clone()
+    };
+    let mut summary = DedupSummary::default();
+    let mut new_files = HashSet::new();
+    let mut batch_started = None;
+    while !options.interrupted.load(Ordering::Relaxed) {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) if is_new_file(&event.kind) => {
+                new_files.extend(event.paths);
+                batch_started.get_or_insert_with(Instant::now);
+            }
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => (),
+            Ok(Err(err)) => warn!("Failed to watch for new files. Error: {}", err),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if batch_started.is_some_and(|started: Instant| started.elapsed() >= BATCH_DELAY) {
+            let batch = dedup_new_files(take(&mut new_files), &mut index, &batch_options, renderer);
+            summary.add(&batch);
+            batch_started = None;
+        }
+    }
+    summary.resources = resource_usage();
+    // Interrupting is how watching normally ends, so the summary doesn't count as interrupted.
+    let event = Event::Finished { summary: &summary };
+    if renderer.renders(&event) {
+        let status = Status {
+            processed_files: summary.processed_files,
+            total_files: summary.processed_files,
+            bytes_deduped: summary.bytes_deduped,
+            dry_run: options.dry_run,
+            raw_bytes: options.raw_bytes,
+        };
+        renderer.render(&status, &event);
+    }
+    Ok(summary)
+}
+
+/// Files that were written and closed, created (e.g. as hardlinks), or moved into a watched path.
+fn is_new_file(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Create(CreateKind::File)
+            | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+    )
+}
+
+/// Hardlinks new files to indexed files with the same contents.
+pub(crate) fn dedup_new_files(
+    new_files: HashSet<PathBuf>,
+    index: &mut ContentIndex,
+    options: &DedupOptions,
+    renderer: &mut dyn Renderer,
+) -> DedupSummary {
+    let mut failed_files = FailedFiles::default();
+    let mut inode_to_paths: HashMap<FileId, HashSet<PathBuf>> = HashMap::new();
+    for file in new_files {
+        if is_own_file(&file, options) {
+            continue;
+        }
+        match symlink_metadata(&file) {
+            Ok(file_metadata) if file_metadata.is_file() => {
+                inode_to_paths
+                    .entry(file_id(&file_metadata))
+                    .or_default()
+                    .insert(file);
+            }
+            Ok(_) => (),
+            // Temporary files (including our own temporary hardlinks) are often gone by now.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => {
+                failed_files.add(
+                    &file,
+                    format!(
+                        "Skipping file {:?}. Failed to fetch its metadata. Error: {}",
+                        file, err
+                    ),
+                );
+            }
+        }
+    }
+    let mut ctx = DedupContext::new(&inode_to_paths, options, renderer);
+    ctx.failed_files = failed_files;
+    ctx.journal = open_journal(options).unwrap_or_else(|err| {
+        warn!("{}", err);
+        None
+    });
+    for paths in inode_to_paths.values() {
+        let file = paths.iter().next().unwrap();
+        ctx.add_processed(paths.len());
+        if ctx.keep_reason(file).is_some() {
+            continue;
+        }
+        let file_metadata = match metadata(file) {
+            Ok(file_metadata) => file_metadata,
+            Err(_) => continue,
+        };
+        if file_metadata.len() == 0 && !options.include_empty {
+            continue;
+        }
+        match index.find_same(
+            file,
+            &file_metadata,
+            options.paranoid,
+            options.same_name_only,
+        ) {
+            Ok(Some(original_file))
+                if !same_xattrs(&original_file, file, options).unwrap_or(false) =>
+            {
+                info!(
+                    "Skipping hardlinking {:?} to {:?}. Their extended attributes, ACLs, or SELinux contexts differ.",
+                    original_file, file
+                );
+            }
+            Ok(Some(original_file)) => {
+                let replaced = replace_many_with_hard_link(
+                    &original_file,
+                    paths.iter(),
+                    Some(FileSnapshot::new(&file_metadata)),
+                    &mut ctx,
+                );
+                if replaced.len() == paths.len() && frees_inode(&file_metadata, paths.len()) {
+                    ctx.bytes_deduped += allocated_bytes(&file_metadata) as usize;
+                }
+                if !replaced.is_empty() {
+                    ctx.bytes_linked += file_metadata.len();
+                }
+            }
+            Ok(None) => (),
+            Err(err) => {
+                ctx.failed_files.add(
+                    file,
+                    format!(
+                        "Skipping file {:?}. Failed to compare it with existing files. Error: {}",
+                        file, err
+                    ),
+                );
+            }
+        }
+    }
+    ctx.summary()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::QuietRenderer;
+    use std::fs::write;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn dedup_new_file_against_index() {
+        let tmp_dir = tempdir().unwrap();
+        let old_file = tmp_dir.path().join("old");
+        let new_file = tmp_dir.path().join("new");
+        let other_file = tmp_dir.path().join("other");
+        write(&old_file, "same contents").unwrap();
+        let old_metadata = metadata(&old_file).unwrap();
+        let inode_to_paths =
+            HashMap::from([(file_id(&old_metadata), HashSet::from([old_file.clone()]))]);
+        let mut index = ContentIndex::new(
+            &inode_to_paths,
+            HashAlgorithm::Sha256,
+            ReadOptions::default(),
+            KeyOptions::default(),
+        );
+        write(&new_file, "same contents").unwrap();
+        write(&other_file, "diff contents").unwrap();
+
+        let summary = dedup_new_files(
+            HashSet::from([new_file.clone(), other_file.clone()]),
+            &mut index,
+            &DedupOptions::default(),
+            &mut QuietRenderer,
+        );
+
+        assert_eq!(summary.bytes_deduped, 13);
+        assert_eq!(metadata(&new_file).unwrap().ino(), old_metadata.ino());
+        assert_ne!(metadata(&other_file).unwrap().ino(), old_metadata.ino());
+    }
+}
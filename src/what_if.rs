@@ -0,0 +1,161 @@
+//! Projected savings under different deduplication policies.
+//!
+//! One scan finds all files with the same contents regardless of their owner and mode, and then adds
+//! up how much each policy would save. This shows which settings are worth it without rerunning the
+//! deduplication with each of them.
+
+use crate::error::{DedupError, FailedFiles, FileError};
+use crate::sparse::allocated_bytes;
+use crate::{
+    find_inode_groups, group_by, same_hash_groups, same_prefix_groups, DedupOptions,
+    DEFAULT_PREFIX_BYTES,
+};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+/// Files smaller than this are left out of [`WhatIfSummary::hardlink_min_size_1m`].
+const MIN_SIZE: u64 = 1024 * 1024;
+
+/// Bytes that each policy would save.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct WhatIfSummary {
+    /// Hardlinking files that share their owner and mode, which is what deduplicating does.
+    pub hardlink: u64,
+    /// Hardlinking all files with the same contents regardless of their owner and mode. The kept
+    /// file's owner and mode would apply to all of them.
+    pub hardlink_ignoring_owner: u64,
+    /// Hardlinking only files of at least 1 MiB that share their owner and mode.
+    pub hardlink_min_size_1m: u64,
+    /// Reflinking all files with the same contents. Reflinked files keep their own owner and mode,
+    /// but only filesystems like btrfs and XFS support reflinks.
+    pub reflink: u64,
+    pub failed_files: usize,
+    /// Why the files in [`WhatIfSummary::failed_files`] failed, for the first
+    /// [`crate::MAX_FILE_ERRORS`] of them.
+    pub file_errors: Vec<FileError>,
+}
+
+/// Projects how much deduplicating the given paths would save under each policy without changing
+/// anything. The same options apply as in [`crate::verify`].
+pub fn what_if(paths: &[PathBuf], options: &DedupOptions) -> Result<WhatIfSummary, DedupError> {
+    for path in paths {
+        std::fs::symlink_metadata(path).map_err(|source| DedupError::Access {
+            path: path.clone(),
+            source,
+        })?;
+    }
+    let mut summary = WhatIfSummary::default();
+    let mut failed_files = FailedFiles::default();
+    let inode_to_paths = find_inode_groups(paths, options, &mut failed_files);
+    let files = inode_to_paths
+        .values()
+        .flat_map(|file_group| file_group.iter().next());
+    let size_groups: Vec<_> = group_by(files, |file| {
+        metadata(file)
+            .map(|file_metadata| (file_metadata.dev(), file_metadata.len()))
+            .map_err(|err| {
+                failed_files.add(
+                    file,
+                    format!(
+                        "Skipping file {:?}. Failed to fetch its metadata. Error: {}",
+                        file, err
+                    ),
+                )
+            })
+            .ok()
+    })
+    .collect();
+    for size_group in size_groups.into_iter().filter(|group| group.len() > 1) {
+        let prefix_groups: Vec<_> = same_prefix_groups(
+            size_group,
+            DEFAULT_PREFIX_BYTES,
+            options.read_options(),
+            &mut failed_files,
+        )
+        .collect();
+        for prefix_group in prefix_groups.into_iter().filter(|group| group.len() > 1) {
+            let hash_groups: Vec<_> =
+                same_hash_groups(prefix_group, None, None, options, &mut failed_files).collect();
+            for hash_group in hash_groups.into_iter().filter(|group| group.len() > 1) {
+                add_savings(&mut summary, hash_group);
+            }
+        }
+    }
+    summary.failed_files = failed_files.count();
+    summary.file_errors = failed_files.into_errors();
+    Ok(summary)
+}
+
+/// Adds what each policy would save on a group of files with the same contents.
+fn add_savings(summary: &mut WhatIfSummary, group: HashSet<&PathBuf>) {
+    let owner_groups: Vec<_> = group_by(group.iter().copied(), |file| {
+        metadata(file)
+            .map(|file_metadata| {
+                (
+                    file_metadata.uid(),
+                    file_metadata.gid(),
+                    file_metadata.mode(),
+                    file_metadata.len(),
+                )
+            })
+            .ok()
+    })
+    .collect();
+    let first_metadata = owner_groups
+        .first()
+        .and_then(|owner_group| owner_group.iter().next())
+        .and_then(|file| metadata(file).ok());
+    let size = first_metadata
+        .as_ref()
+        .map_or(0, |file_metadata| file_metadata.len());
+    // Sparse files only free the blocks they occupy.
+    let allocated = first_metadata.as_ref().map_or(0, allocated_bytes);
+    let files: usize = owner_groups.iter().map(HashSet::len).sum();
+    let hardlink: u64 = owner_groups
+        .iter()
+        .map(|owner_group| allocated * (owner_group.len() as u64 - 1))
+        .sum();
+    let all_files = allocated * (files.saturating_sub(1) as u64);
+    summary.hardlink += hardlink;
+    summary.hardlink_ignoring_owner += all_files;
+    if size >= MIN_SIZE {
+        summary.hardlink_min_size_1m += hardlink;
+    }
+    summary.reflink += all_files;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{set_permissions, write, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn savings_by_policy() {
+        let tmp_dir = tempdir().unwrap();
+        for file in ["file1", "file2", "file3"] {
+            write(tmp_dir.path().join(file), "same contents").unwrap();
+        }
+        set_permissions(tmp_dir.path().join("file1"), Permissions::from_mode(0o644)).unwrap();
+        set_permissions(tmp_dir.path().join("file2"), Permissions::from_mode(0o644)).unwrap();
+        set_permissions(tmp_dir.path().join("file3"), Permissions::from_mode(0o600)).unwrap();
+
+        let summary = what_if(&[tmp_dir.path().to_owned()], &DedupOptions::default()).unwrap();
+
+        assert_eq!(
+            summary,
+            WhatIfSummary {
+                hardlink: 13,
+                hardlink_ignoring_owner: 26,
+                hardlink_min_size_1m: 0,
+                reflink: 26,
+                failed_files: 0,
+                file_errors: Vec::new(),
+            }
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Extended attributes of files that are about to be hardlinked.
+//!
+//! All paths of an inode share its extended attributes, so a file replaced with a hardlink loses
+//! its own `user.*` attributes, file capabilities, ACLs, and security labels for the original's.
+//! Files are only hardlinked to files with the same attributes unless the caller opts out.
+//!
+//! POSIX ACLs and SELinux security contexts are extended attributes too. `acl_get_file` reads the
+//! access ACL from [`ACL_XATTR`] and `getfilecon` the context from [`SELINUX_XATTR`], so they're
+//! compared the same way, but can be ignored separately.
+
+use crate::error::FailedFiles;
+use crate::{group_by, DedupOptions};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The attribute that holds the access ACL of a file, unless it only has the permissions of its
+/// mode.
+const ACL_XATTR: &str = "system.posix_acl_access";
+/// The attribute that holds the SELinux security context of a file.
+const SELINUX_XATTR: &str = "security.selinux";
+
+/// Whether files must have the same extended attributes, ACLs, or SELinux contexts to be
+/// hardlinked.
+pub(crate) fn compares_xattrs(options: &DedupOptions) -> bool {
+    !options.ignore_xattrs || !options.ignore_acls || !options.ignore_selinux
+}
+
+/// The names and values of the file's extended attributes, without the ACL, the SELinux context, or
+/// the other attributes if the options ignore them.
+pub(crate) fn read_xattrs(
+    file: &Path,
+    options: &DedupOptions,
+) -> io::Result<BTreeMap<OsString, Vec<u8>>> {
+    let mut xattrs = BTreeMap::new();
+    for name in xattr::list(file)? {
+        let ignored = if name == ACL_XATTR {
+            options.ignore_acls
+        } else if name == SELINUX_XATTR {
+            options.ignore_selinux
+        } else {
+            options.ignore_xattrs
+        };
+        if ignored {
+            continue;
+        }
+        // Attributes removed since listing them are gone for good.
+        if let Some(value) = xattr::get(file, &name)? {
+            xattrs.insert(name, value);
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Whether the files have the same extended attributes, ACLs, and SELinux contexts, as far as the
+/// options compare them.
+pub(crate) fn same_xattrs(
+    file: &Path,
+    other_file: &Path,
+    options: &DedupOptions,
+) -> io::Result<bool> {
+    if !compares_xattrs(options) {
+        return Ok(true);
+    }
+    Ok(read_xattrs(file, options)? == read_xattrs(other_file, options)?)
+}
+
+/// Groups the files by their extended attributes, ACLs, and SELinux contexts. Files whose attributes
+/// can't be read are left out and counted in `failed_files`.
+pub(crate) fn same_xattr_groups<'a>(
+    files: HashSet<&'a PathBuf>,
+    options: &DedupOptions,
+    failed_files: &mut FailedFiles,
+) -> Vec<HashSet<&'a PathBuf>> {
+    group_by(files.into_iter(), |file| {
+        read_xattrs(file, options)
+            .map_err(|err| {
+                failed_files.add(
+                    file,
+                    format!(
+                        "Skipping file {:?}. Failed to read its extended attributes. Error: {}",
+                        file, err
+                    ),
+                )
+            })
+            .ok()
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tmp_file;
+    use tempfile::tempdir;
+
+    #[test]
+    fn group_by_xattrs() {
+        let tmp_dir = tempdir().unwrap();
+        let plain = tmp_file(tmp_dir.path(), "plain", "same contents");
+        let tagged1 = tmp_file(tmp_dir.path(), "tagged1", "same contents");
+        let tagged2 = tmp_file(tmp_dir.path(), "tagged2", "same contents");
+        let other = tmp_file(tmp_dir.path(), "other", "same contents");
+        for file in [&tagged1, &tagged2] {
+            if xattr::set(file, "user.origin", b"camera").is_err() {
+                // The temporary directory's filesystem doesn't support user attributes.
+                return;
+            }
+        }
+        xattr::set(&other, "user.origin", b"scanner").unwrap();
+        let missing = tmp_dir.path().join("missing");
+
+        let mut failed_files = FailedFiles::default();
+        let mut groups = same_xattr_groups(
+            HashSet::from([&plain, &tagged1, &tagged2, &other, &missing]),
+            &DedupOptions::default(),
+            &mut failed_files,
+        );
+        groups.sort_by_key(HashSet::len);
+
+        assert_eq!(failed_files.count(), 1);
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[2], HashSet::from([&tagged1, &tagged2]));
+        let options = DedupOptions::default();
+        assert!(same_xattrs(&tagged1, &tagged2, &options).unwrap());
+        assert!(!same_xattrs(&plain, &tagged1, &options).unwrap());
+    }
+
+    #[test]
+    fn compare_acls_separately() {
+        let tmp_dir = tempdir().unwrap();
+        let file = tmp_file(tmp_dir.path(), "file", "same contents");
+        let shared_file = tmp_file(tmp_dir.path(), "shared", "same contents");
+        // Read access for the user with uid 1000 on top of the mode's permissions.
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [
+            (0x01u16, 6u16, u32::MAX),
+            (0x02, 4, 1000),
+            (0x04, 4, u32::MAX),
+            (0x10, 4, u32::MAX),
+            (0x20, 4, u32::MAX),
+        ] {
+            acl.extend(tag.to_le_bytes());
+            acl.extend(perm.to_le_bytes());
+            acl.extend(id.to_le_bytes());
+        }
+        if xattr::set(&shared_file, ACL_XATTR, &acl).is_err() {
+            // The temporary directory's filesystem doesn't support ACLs.
+            return;
+        }
+
+        let ignore_xattrs = DedupOptions {
+            ignore_xattrs: true,
+            ..DedupOptions::default()
+        };
+        assert!(!same_xattrs(&file, &shared_file, &ignore_xattrs).unwrap());
+        let ignore_acls = DedupOptions {
+            ignore_acls: true,
+            ..DedupOptions::default()
+        };
+        assert!(same_xattrs(&file, &shared_file, &ignore_acls).unwrap());
+    }
+}
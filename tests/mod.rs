@@ -1,12 +1,14 @@
-pub mod test_utils;
-
 use assert_cmd::prelude::*;
+use hardlink_dedup::test_utils::{all_same, duplicate_files, same, set_modified, tmp_file};
 use nix::unistd::{chown, getgroups, Gid};
-use std::fs::{metadata, set_permissions};
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::process::Command;
+use predicates::prelude::*;
+use std::fs::{create_dir, hard_link, metadata, read_to_string, set_permissions, symlink_metadata};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{symlink, FileExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::process::{Command, Stdio};
+use std::time::{Duration, UNIX_EPOCH};
 use tempfile::tempdir;
-use test_utils::{same, tmp_file};
 
 #[test]
 fn no_dedup_different_size_files() {
@@ -14,7 +16,7 @@ fn no_dedup_different_size_files() {
     let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "contents 1");
     let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "smaller 2");
 
-    dedup(&[&file1.to_str().unwrap(), &file2.to_str().unwrap()]).success();
+    dedup(&[file1.to_str().unwrap(), file2.to_str().unwrap()]).success();
 
     assert!(
         !same(&file1, &file2),
@@ -30,7 +32,7 @@ fn no_dedup_different_files() {
     let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "contents 1");
     let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "contents 2");
 
-    dedup(&[&file1.to_str().unwrap(), &file2.to_str().unwrap()]).success();
+    dedup(&[file1.to_str().unwrap(), file2.to_str().unwrap()]).success();
 
     assert!(
         !same(&file1, &file2),
@@ -46,7 +48,7 @@ fn dedup_same_files() {
     let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents");
     let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
 
-    dedup(&[&file1.to_str().unwrap(), &file2.to_str().unwrap()]).success();
+    dedup(&[file1.to_str().unwrap(), file2.to_str().unwrap()]).success();
 
     assert!(
         same(&file1, &file2),
@@ -56,6 +58,18 @@ fn dedup_same_files() {
     );
 }
 
+#[test]
+fn dedup_with_fsync() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
+
+    dedup(&["--fsync", tmp_dir.path().to_str().unwrap()])
+        .stdout(predicates::str::contains("Failed hardlinks").not());
+
+    assert!(same(&file1, &file2));
+}
+
 #[test]
 fn dedup_same_3_files() {
     let tmp_dir = tempdir().unwrap();
@@ -63,7 +77,7 @@ fn dedup_same_3_files() {
     let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
     let file3 = tmp_file(&tmp_dir.path().join("dir3"), "file3", "same contents");
 
-    dedup(&[&tmp_dir.path().to_str().unwrap()]).success();
+    dedup(&[tmp_dir.path().to_str().unwrap()]).success();
 
     assert!(
         same(&file1, &file2),
@@ -99,7 +113,7 @@ fn dedup_by_prefix() {
         "same size, but different prefix 1234",
     );
 
-    dedup(&[&tmp_dir.path().to_str().unwrap()]).success();
+    dedup(&[tmp_dir.path().to_str().unwrap()]).success();
 
     assert!(
         same(&file1, &file2),
@@ -124,8 +138,8 @@ fn no_dedup_on_dry_run() {
 
     dedup(&[
         "--dry-run",
-        &file1.to_str().unwrap(),
-        &file2.to_str().unwrap(),
+        file1.to_str().unwrap(),
+        file2.to_str().unwrap(),
     ])
     .success();
 
@@ -137,6 +151,100 @@ fn no_dedup_on_dry_run() {
     );
 }
 
+#[test]
+fn dedup_and_report_subcommands() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(tmp_dir.path(), 2, "same contents");
+    let path = tmp_dir.path().to_str().unwrap();
+
+    dedup(&["report", "-v", path])
+        .success()
+        .stdout(predicate::str::contains("Estimated saved bytes: 13"))
+        .stderr(predicate::str::contains("Would hardlink"));
+    assert!(!same(&files[0], &files[1]));
+
+    dedup(&["dedup", "--output", "json", path]).success();
+    assert!(same(&files[0], &files[1]));
+}
+
+#[test]
+fn dedup_listed_duplicates() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(&tmp_dir.path().join("files"), 2, "same contents");
+    let list = tmp_dir.path().join("fdupes.txt");
+    std::fs::write(
+        &list,
+        format!(
+            "13 bytes each:\n{}\n{}\n\n",
+            files[0].display(),
+            files[1].display()
+        ),
+    )
+    .unwrap();
+
+    dedup(&["--dupes-from", list.to_str().unwrap()])
+        .success()
+        .stdout(predicate::str::contains("Estimated saved bytes: 13"));
+    assert!(same(&files[0], &files[1]));
+}
+
+#[test]
+fn dedup_with_indexes() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("vol1"), "file", "same contents");
+    let file2 = tmp_file(&tmp_dir.path().join("vol2"), "file", "same contents");
+    let index1 = tmp_dir.path().join("vol1.index");
+    let index2 = tmp_dir.path().join("vol2.index");
+
+    for (dir, index) in [("vol1", &index1), ("vol2", &index2)] {
+        dedup(&[
+            "index",
+            "--hash",
+            "blake3",
+            "--out",
+            index.to_str().unwrap(),
+            tmp_dir.path().join(dir).to_str().unwrap(),
+        ])
+        .success()
+        .stdout(predicate::str::contains("Indexed files: 1"));
+    }
+    assert!(!same(&file1, &file2));
+
+    dedup(&[
+        "--index",
+        index1.to_str().unwrap(),
+        "--index",
+        index2.to_str().unwrap(),
+    ])
+    .success()
+    .stdout(predicate::str::contains("Estimated saved bytes: 13"));
+    assert!(same(&file1, &file2));
+}
+
+#[test]
+fn dedup_incrementally() {
+    let tmp_dir = tempdir().unwrap();
+    let dir = tmp_dir.path().join("files");
+    let files = duplicate_files(&dir, 2, "same contents");
+    let state = tmp_dir.path().join("state.json");
+    let args = [
+        "-v",
+        "--incremental",
+        state.to_str().unwrap(),
+        dir.to_str().unwrap(),
+    ];
+
+    dedup(&args)
+        .success()
+        .stderr(predicate::str::contains("2 of 2 files are new or changed"));
+    assert!(same(&files[0], &files[1]));
+    let new = tmp_file(&dir, "new", "same contents");
+    dedup(&args)
+        .success()
+        .stderr(predicate::str::contains("1 of 2 files are new or changed"));
+    assert!(same(&files[0], &new));
+}
+
 #[test]
 fn dedup_paranoid() {
     let tmp_dir = tempdir().unwrap();
@@ -144,7 +252,7 @@ fn dedup_paranoid() {
     let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
     let file3 = tmp_file(&tmp_dir.path().join("dir3"), "file3", "same contents");
 
-    dedup(&["--paranoid", &tmp_dir.path().to_str().unwrap()]).success();
+    dedup(&["--paranoid", tmp_dir.path().to_str().unwrap()]).success();
 
     assert!(
         same(&file1, &file2),
@@ -162,55 +270,1635 @@ fn dedup_paranoid() {
 }
 
 #[test]
-fn dedup_only_same_permissions() {
+fn dedup_with_other_hash_algorithms() {
+    for algorithm in ["blake3", "xxh3"] {
+        let tmp_dir = tempdir().unwrap();
+        // Longer than the prefix that is compared before hashing.
+        let contents = "same prefix ".repeat(10);
+        let files = duplicate_files(tmp_dir.path(), 3, &(contents.clone() + "1"));
+        tmp_file(&tmp_dir.path().join("dir4"), "file4", &(contents + "2"));
+
+        dedup(&[
+            "--hash",
+            algorithm,
+            "--hash-workers",
+            "2",
+            "--max-parallel-hashes",
+            "1",
+            tmp_dir.path().to_str().unwrap(),
+        ]);
+        assert!(all_same(&files));
+        assert!(!same(&files[0], &tmp_dir.path().join("dir4/file4")));
+    }
+}
+
+#[test]
+fn keep_first_path_on_ties() {
     let tmp_dir = tempdir().unwrap();
-    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
-    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
-    let old_file1_mode = metadata(&file1).unwrap().permissions().mode();
-    let mut file2_permissions = metadata(&file2).unwrap().permissions();
-    file2_permissions.set_mode(0o100750);
-    set_permissions(&file2, file2_permissions).expect("could not set permissions");
+    let files = duplicate_files(tmp_dir.path(), 3, "same contents");
+    for file in &files {
+        set_modified(file, UNIX_EPOCH);
+    }
+    let kept_inode = metadata(&files[0]).unwrap().ino();
 
-    dedup(&[&tmp_dir.path().to_str().unwrap()]).success();
+    dedup(&["--prefer", "oldest", tmp_dir.path().to_str().unwrap()]);
 
-    assert_eq!(metadata(&file2).unwrap().permissions().mode(), 0o100750);
-    assert_eq!(
-        metadata(&file1).unwrap().permissions().mode(),
-        old_file1_mode
+    for file in &files {
+        assert_eq!(metadata(file).unwrap().ino(), kept_inode);
+    }
+}
+
+#[test]
+fn keep_shortest_path() {
+    let tmp_dir = tempdir().unwrap();
+    let nested = tmp_file(&tmp_dir.path().join("a/b"), "file", "same contents");
+    let top = tmp_file(tmp_dir.path(), "z", "same contents");
+    let kept_inode = metadata(&top).unwrap().ino();
+
+    dedup(&["--keep", "shortest-path", tmp_dir.path().to_str().unwrap()]);
+
+    assert_eq!(metadata(&nested).unwrap().ino(), kept_inode);
+}
+
+#[test]
+fn skip_files_with_many_links() {
+    let tmp_dir = tempdir().unwrap();
+    let linked = tmp_file(tmp_dir.path(), "linked", "same contents");
+    hard_link(&linked, tmp_dir.path().join("linked2")).unwrap();
+    let b = tmp_file(tmp_dir.path(), "b", "same contents");
+    let c = tmp_file(tmp_dir.path(), "c", "same contents");
+    let path = tmp_dir.path().to_str().unwrap();
+
+    dedup(&["--max-nlink", "2", path]).success();
+    assert!(same(&b, &c));
+    assert!(!same(&linked, &b));
+
+    // The file with the most links is kept.
+    let kept_inode = metadata(&b).unwrap().ino();
+    dedup(&[path]).success();
+    assert_eq!(metadata(&linked).unwrap().ino(), kept_inode);
+}
+
+#[test]
+fn dedup_same_names_only() {
+    let tmp_dir = tempdir().unwrap();
+    let photo1 = tmp_file(
+        &tmp_dir.path().join("snap1"),
+        "IMG_1234.JPG",
+        "same contents",
+    );
+    let photo2 = tmp_file(
+        &tmp_dir.path().join("snap2"),
+        "IMG_1234.JPG",
+        "same contents",
+    );
+    let other = tmp_file(&tmp_dir.path().join("snap2"), "notes.txt", "same contents");
+
+    dedup(&["--same-name-only", tmp_dir.path().to_str().unwrap()]).success();
+
+    assert!(same(&photo1, &photo2));
+    assert!(!same(&photo1, &other));
+}
+
+#[test]
+fn break_down_savings_by_directory() {
+    let tmp_dir = tempdir().unwrap();
+    tmp_file(&tmp_dir.path().join("a"), "file", "same contents");
+    tmp_file(&tmp_dir.path().join("b/x"), "file", "same contents");
+    tmp_file(&tmp_dir.path().join("b/y"), "file", "same contents");
+    tmp_file(&tmp_dir.path().join("c"), "file", "same contents");
+    let path = tmp_dir.path().to_str().unwrap();
+
+    dedup(&["--breakdown-depth", "1", "--bytes", path])
+        .stdout(predicates::str::contains(format!(
+            "Savings by directory:\n          26       2 file(s)  {path}/b\n"
+        )))
+        .stdout(predicates::str::contains(format!(
+            "/b\n          13       1 file(s)  {path}/c\n"
+        )));
+}
+
+#[test]
+fn link_to_reference_dirs() {
+    let tmp_dir = tempdir().unwrap();
+    // After the snapshot in path order, which would otherwise keep the snapshot's file.
+    let latest = tmp_dir.path().join("z-latest");
+    let reference_file = tmp_file(&latest, "z", "same contents");
+    let reference_copy = tmp_file(&latest, "zz", "same contents");
+    let reference_inode = metadata(&reference_file).unwrap().ino();
+    let snapshot = tmp_dir.path().join("snapshot");
+    let old_files = duplicate_files(&snapshot, 2, "same contents");
+
+    dedup(&[
+        "--reference",
+        latest.to_str().unwrap(),
+        snapshot.to_str().unwrap(),
+    ]);
+
+    for file in &old_files {
+        assert_eq!(metadata(file).unwrap().ino(), reference_inode);
+    }
+    assert_eq!(metadata(&reference_file).unwrap().ino(), reference_inode);
+    assert!(!same(&reference_file, &reference_copy));
+}
+
+#[test]
+fn protect_matching_files() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(&tmp_dir.path().join("data"), 2, "same contents");
+    // Last in path order, which would otherwise be replaced.
+    let protected_file = tmp_file(&tmp_dir.path().join("live"), "db.keep", "same contents");
+    let protected_copy = tmp_file(&tmp_dir.path().join("live"), "z.keep", "same contents");
+    let protected_inode = metadata(&protected_file).unwrap().ino();
+    let patterns = tmp_file(
+        &tmp_dir.path().join("config"),
+        "protect",
+        "# Live data\n\n*.keep\n",
+    );
+
+    dedup(&[
+        "--protect-from",
+        patterns.to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+    ]);
+
+    for file in &files {
+        assert_eq!(metadata(file).unwrap().ino(), protected_inode);
+    }
+    assert_eq!(metadata(&protected_file).unwrap().ino(), protected_inode);
+    assert!(!same(&protected_file, &protected_copy));
+}
+
+#[test]
+fn skip_opted_out_dirs() {
+    for jobs in ["1", "3"] {
+        let tmp_dir = tempdir().unwrap();
+        let files = duplicate_files(&tmp_dir.path().join("data"), 2, "same contents");
+        let cache = tmp_dir.path().join("cache");
+        tmp_file(
+            &cache,
+            "CACHEDIR.TAG",
+            "Signature: 8a477f597d28d172789f06886806bc55\n",
+        );
+        let cached_file = tmp_file(&cache.join("sub"), "file", "same contents");
+        let opted_out_file = tmp_file(&tmp_dir.path().join("mine"), "file", "same contents");
+        tmp_file(&tmp_dir.path().join("mine"), ".nodedup", "");
+        let bad_tag = tmp_dir.path().join("bad-tag");
+        tmp_file(&bad_tag, "CACHEDIR.TAG", "Not a signature");
+        let untagged_file = tmp_file(&bad_tag, "file", "same contents");
+
+        dedup(&[
+            "--honor-cachedir-tags",
+            "--jobs",
+            jobs,
+            tmp_dir.path().to_str().unwrap(),
+            cache.to_str().unwrap(),
+        ]);
+
+        assert!(all_same(&files));
+        assert!(same(&files[0], &untagged_file));
+        assert!(!same(&files[0], &cached_file));
+        assert!(!same(&files[0], &opted_out_file));
+    }
+}
+
+#[test]
+fn skip_files_open_for_writing() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(&tmp_dir.path().join("dir1"), 2, "same contents");
+    let open_file = tmp_file(&tmp_dir.path().join("dir2"), "open", "same contents");
+    let mut writer = Command::new("sh")
+        .arg("-c")
+        .arg(format!("exec 3>>{:?}; echo opened; read _", open_file))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut opened = String::new();
+    BufReader::new(writer.stdout.take().unwrap())
+        .read_line(&mut opened)
+        .unwrap();
+
+    dedup(&["--skip-open-files", tmp_dir.path().to_str().unwrap()]);
+    drop(writer.stdin.take());
+    writer.wait().unwrap();
+
+    assert!(all_same(&files));
+    assert!(!same(&files[0], &open_file));
+}
+
+#[test]
+fn leave_content_with_few_copies() {
+    let tmp_dir = tempdir().unwrap();
+    let pair = duplicate_files(&tmp_dir.path().join("pair"), 2, "pair contents");
+    let triple = duplicate_files(&tmp_dir.path().join("triple"), 3, "triple contents");
+
+    dedup(&["--min-copies", "3", tmp_dir.path().to_str().unwrap()]);
+
+    assert!(!same(&pair[0], &pair[1]));
+    assert!(all_same(&triple));
+}
+
+#[test]
+fn dedup_with_hash_workers() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
+    let file3 = tmp_file(&tmp_dir.path().join("dir3"), "file3", "same contents");
+
+    dedup(&[
+        "--hash-workers",
+        "2",
+        "--hash-worker-timeout",
+        "10s",
+        "--hash-worker-memory-limit",
+        "1G",
+        tmp_dir.path().to_str().unwrap(),
+    ])
+    .success();
+
+    assert!(
+        same(&file1, &file2),
+        "Files {:?} and {:?} should have been deduped.",
+        file1,
+        file2,
+    );
+
+    assert!(
+        same(&file1, &file3),
+        "Files {:?} and {:?} should have been deduped.",
+        file1,
+        file3,
+    );
+}
+
+#[test]
+fn dedup_with_fadvise() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(tmp_dir.path(), 3, "same contents");
+
+    dedup(&[
+        "--fadvise",
+        "--paranoid",
+        "--hash-workers",
+        "1",
+        tmp_dir.path().to_str().unwrap(),
+    ]);
+
+    assert!(all_same(&files));
+}
+
+#[test]
+fn dedup_with_bwlimit() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(tmp_dir.path(), 3, "same contents");
+
+    dedup(&[
+        "--bwlimit",
+        "1M",
+        "--paranoid",
+        "--hash-workers",
+        "2",
+        tmp_dir.path().to_str().unwrap(),
+    ])
+    .success();
+
+    assert!(all_same(&files));
+}
+
+#[test]
+fn dedup_tail_first_extensions() {
+    let tmp_dir = tempdir().unwrap();
+    let song1 = tmp_file(tmp_dir.path(), "song1.mp3", "TAG1 same audio");
+    let song2 = tmp_file(tmp_dir.path(), "song2.MP3", "TAG1 same audio");
+    let retagged = tmp_file(tmp_dir.path(), "song3.mp3", "TAG2 same audio");
+    let copy = tmp_file(tmp_dir.path(), "copy.bin", "TAG1 same audio");
+
+    dedup(&[
+        "--tail-first-ext",
+        "ogg,mp3",
+        tmp_dir.path().to_str().unwrap(),
+    ])
+    .success();
+
+    assert!(all_same(&[song1.clone(), song2, copy]));
+    assert!(!same(&song1, &retagged));
+}
+
+#[test]
+fn dedup_with_parallel_walk() {
+    let tmp_dir = tempdir().unwrap();
+    let files: Vec<_> = ["a", "b/c", "d/e/f"]
+        .iter()
+        .map(|dir| tmp_file(&tmp_dir.path().join(dir), "file", "same contents"))
+        .collect();
+
+    dedup(&["--jobs", "4", tmp_dir.path().to_str().unwrap()]);
+
+    assert!(all_same(&files));
+}
+
+#[test]
+fn dedup_with_max_memory() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(&tmp_dir.path().join("a"), 3, "same contents");
+    let other_files = duplicate_files(
+        &tmp_dir.path().join("b"),
+        2,
+        "other contents of another size",
+    );
+
+    dedup(&["--max-memory", "1K", tmp_dir.path().to_str().unwrap()])
+        .stdout(predicates::str::contains("Estimated saved bytes: 56"));
+
+    assert!(all_same(&files));
+    assert!(all_same(&other_files));
+}
+
+#[test]
+fn dedup_with_nice_io() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(tmp_dir.path(), 3, "same contents");
+
+    dedup(&["--nice-io", tmp_dir.path().to_str().unwrap()])
+        .stderr(predicates::str::contains("Failed to lower").not());
+
+    assert!(all_same(&files));
+}
+
+#[test]
+fn hint_dirs_to_back_up_with_hardlinks() {
+    let tmp_dir = tempdir().unwrap();
+    tmp_file(&tmp_dir.path().join("a/dir1"), "file1", "same contents");
+    tmp_file(&tmp_dir.path().join("a/dir2"), "file2", "same contents");
+
+    dedup(&[tmp_dir.path().to_str().unwrap()]).stdout(predicates::str::contains(format!(
+        "  {}\n",
+        tmp_dir.path().join("a").display()
+    )));
+}
+
+#[test]
+fn no_dedup_recently_modified_files() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
+
+    dedup(&["--min-age", "1h", tmp_dir.path().to_str().unwrap()]).success();
+
+    assert!(
+        !same(&file1, &file2),
+        "Files {:?} and {:?} should not have been deduped.",
+        file1,
+        file2,
     );
+}
+
+#[test]
+fn no_dedup_different_files_with_double_read_verify() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "contents 1");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "contents 2");
+    let file3 = tmp_file(&tmp_dir.path().join("dir3"), "file3", "contents 3");
+
+    dedup(&["--double-read-verify", tmp_dir.path().to_str().unwrap()])
+        .success()
+        .stderr(predicates::str::contains("twice gave different contents").not());
+
     assert!(!same(&file1, &file2));
+    assert!(!same(&file1, &file3));
 }
 
 #[test]
-fn dedup_only_same_gid() {
+fn dedup_one_file_system() {
     let tmp_dir = tempdir().unwrap();
-    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
-    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
-    let old_file1_gid = Gid::from_raw(metadata(&file2).unwrap().gid());
-    let old_file2_gid = Gid::from_raw(metadata(&file2).unwrap().gid());
-    let new_gid = getgroups()
-        .unwrap()
-        .into_iter()
-        .filter(|gid| gid != &old_file2_gid)
-        .next()
-        .expect("Could not find another group to use for testing.");
-    chown(&file2, None, Some(new_gid)).expect("could not change group");
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
 
-    dedup(&[&tmp_dir.path().to_str().unwrap()]).success();
+    dedup(&["--one-file-system", tmp_dir.path().to_str().unwrap()]).success();
 
-    assert_eq!(Gid::from_raw(metadata(&file2).unwrap().gid()), new_gid);
-    assert_eq!(
-        Gid::from_raw(metadata(&file1).unwrap().gid()),
-        old_file1_gid
+    assert!(
+        same(&file1, &file2),
+        "Files {:?} and {:?} should have been deduped.",
+        file1,
+        file2,
     );
+}
+
+#[test]
+fn report_near_duplicates() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents 1");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents 2");
+
+    dedup(&["--report-near-duplicates", tmp_dir.path().to_str().unwrap()])
+        .success()
+        .stderr(predicates::str::contains(
+            "differ in only 1 byte(s) at offsets [14]",
+        ));
+
     assert!(!same(&file1, &file2));
 }
 
-fn dedup(paths: &[&str]) -> assert_cmd::assert::Assert {
+#[test]
+fn dedup_through_symlinks() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
+    let links_dir = tmp_dir.path().join("links");
+    create_dir(&links_dir).unwrap();
+    symlink(tmp_dir.path().join("dir1"), links_dir.join("dir1")).unwrap();
+    symlink(&file2, links_dir.join("file2")).unwrap();
+    symlink(&links_dir, links_dir.join("loop")).unwrap();
+
+    dedup(&["--follow-symlinks", links_dir.to_str().unwrap()]).success();
+
+    assert!(
+        same(&file1, &file2),
+        "Files {:?} and {:?} should have been deduped.",
+        file1,
+        file2,
+    );
+    assert!(symlink_metadata(links_dir.join("file2"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+}
+
+#[test]
+fn dedup_in_symlinked_path() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(&tmp_dir.path().join("dir"), 2, "same contents");
+    let link = tmp_dir.path().join("link");
+    symlink(tmp_dir.path().join("dir"), &link).unwrap();
+
+    dedup(&[link.to_str().unwrap()]);
+
+    assert!(all_same(&files));
+}
+
+#[test]
+fn no_dedup_through_symlinks_by_default() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
+    let links_dir = tmp_dir.path().join("links");
+    create_dir(&links_dir).unwrap();
+    symlink(tmp_dir.path().join("dir1"), links_dir.join("dir1")).unwrap();
+    symlink(&file2, links_dir.join("file2")).unwrap();
+
+    dedup(&[links_dir.to_str().unwrap()]).success();
+
+    assert!(!same(&file1, &file2));
+}
+
+#[test]
+fn dedup_up_to_max_depth() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(tmp_dir.path(), "file1", "same contents");
+    let file2 = tmp_file(tmp_dir.path(), "file2", "same contents");
+    let file3 = tmp_file(&tmp_dir.path().join("dir1"), "file3", "same contents");
+
+    dedup(&["--max-depth", "1", tmp_dir.path().to_str().unwrap()]).success();
+
+    assert!(
+        same(&file1, &file2),
+        "Files {:?} and {:?} should have been deduped.",
+        file1,
+        file2,
+    );
+    assert!(
+        !same(&file1, &file3),
+        "Files {:?} and {:?} should not have been deduped.",
+        file1,
+        file3,
+    );
+}
+
+#[test]
+fn repair_from_majority() {
+    let tmp_dir = tempdir().unwrap();
+    let healthy_contents = "same contents ".repeat(10) + "1";
+    let corrupt_contents = "same contents ".repeat(10) + "2";
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", &healthy_contents);
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", &healthy_contents);
+    let corrupt_file = tmp_file(&tmp_dir.path().join("dir3"), "file3", &corrupt_contents);
+
+    dedup(&["--repair-from-duplicate", tmp_dir.path().to_str().unwrap()]).success();
+
+    assert!(
+        same(&file1, &corrupt_file),
+        "Files {:?} and {:?} should have been repaired.",
+        file1,
+        corrupt_file,
+    );
+    assert!(same(&file1, &file2));
+    assert_eq!(
+        read_to_string(tmp_dir.path().join("dir3/file3.corrupt-backup")).unwrap(),
+        corrupt_contents
+    );
+}
+
+#[test]
+fn verbose_lists_hardlinked_files() {
+    let tmp_dir = tempdir().unwrap();
+    tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents");
+    tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
+    tmp_file(&tmp_dir.path().join("dir3"), "file3", "other contents");
+
+    dedup(&["-v", "--dry-run", tmp_dir.path().to_str().unwrap()])
+        .success()
+        .stderr(predicates::str::contains("Would hardlink"))
+        .stderr(predicates::str::contains("Excluding").not());
+    dedup(&["-vv", "--dry-run", tmp_dir.path().to_str().unwrap()])
+        .success()
+        .stderr(predicates::str::contains("Excluding"));
+    dedup(&[tmp_dir.path().to_str().unwrap()])
+        .success()
+        .stderr(predicates::str::contains("Hardlinked").not());
+}
+
+#[test]
+fn quiet_only_prints_summary() {
+    let tmp_dir = tempdir().unwrap();
+    tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents");
+    tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
+
+    dedup(&["-q", tmp_dir.path().to_str().unwrap()])
+        .success()
+        .stdout(predicates::str::contains("Estimated saved bytes: 13"))
+        .stderr(predicates::str::is_empty());
+}
+
+#[test]
+fn exit_code_for_missing_path() {
+    let tmp_dir = tempdir().unwrap();
+    dedup_with_any_exit_code(&[tmp_dir.path().join("missing").to_str().unwrap()])
+        .code(2)
+        .stderr(predicates::str::contains("Failed to access"));
+}
+
+#[test]
+fn batch_jobs() {
+    let tmp_dir = tempdir().unwrap();
+    let linked = duplicate_files(&tmp_dir.path().join("linked"), 2, "same contents");
+    let planned = duplicate_files(&tmp_dir.path().join("planned"), 2, "same contents");
+    let report = tmp_dir.path().join("planned.json");
+    let jobs = tmp_dir.path().join("jobs.toml");
+    std::fs::write(
+        &jobs,
+        format!(
+            "[[job]]\npaths = [{:?}]\n\n[[job]]\nname = \"planned\"\npaths = [{:?}]\nmode = \"dry-run\"\nreport = {:?}\n",
+            tmp_dir.path().join("linked"),
+            tmp_dir.path().join("planned"),
+            report,
+        ),
+    )
+    .unwrap();
+
+    dedup(&["batch", jobs.to_str().unwrap()])
+        .success()
+        .stdout(predicates::str::contains("All jobs:\n"))
+        .stdout(predicates::str::ends_with("Estimated saved bytes: 26 B\n"));
+
+    assert!(all_same(&linked));
+    assert!(!all_same(&planned));
+    assert!(read_to_string(&report)
+        .unwrap()
+        .contains("\"bytes_deduped\": 13"));
+}
+
+#[test]
+fn refuse_container_layer_storage() {
+    let tmp_dir = tempdir().unwrap();
+    let layer = tmp_dir.path().join("var/lib/docker/overlay2/layer1/diff");
+    let files = duplicate_files(&layer, 2, "same contents");
+
+    dedup_with_any_exit_code(&["--refuse-foreign-mounts", layer.to_str().unwrap()])
+        .code(2)
+        .stderr(predicates::str::contains(
+            "layer storage of a container engine",
+        ));
+    assert!(!all_same(&files));
+
+    dedup(&[layer.to_str().unwrap()])
+        .success()
+        .stderr(predicates::str::contains(
+            "layer storage of a container engine",
+        ));
+    assert!(all_same(&files));
+}
+
+#[test]
+fn exit_code_for_failed_files() {
+    let tmp_dir = tempdir().unwrap();
+    tmp_file(tmp_dir.path(), "file1", "same contents");
+    tmp_file(tmp_dir.path(), "file2", "same contents");
+    symlink(
+        tmp_dir.path().join("missing"),
+        tmp_dir.path().join("dangling"),
+    )
+    .unwrap();
+
+    dedup_with_any_exit_code(&["--follow-symlinks", tmp_dir.path().to_str().unwrap()])
+        .code(1)
+        .stdout(predicates::str::contains("Files that failed to process: 1"));
+    assert!(same(
+        &tmp_dir.path().join("file1"),
+        &tmp_dir.path().join("file2")
+    ));
+}
+
+#[test]
+fn report_file_errors() {
+    let tmp_dir = tempdir().unwrap();
+    duplicate_files(tmp_dir.path(), 2, "same contents");
+    let dangling = tmp_dir.path().join("dangling");
+    symlink(tmp_dir.path().join("missing"), &dangling).unwrap();
+
+    let output = dedup_with_any_exit_code(&[
+        "--follow-symlinks",
+        "--output",
+        "json",
+        tmp_dir.path().to_str().unwrap(),
+    ])
+    .code(1)
+    .get_output()
+    .stdout
+    .clone();
+    let summary = &serde_json::from_slice::<serde_json::Value>(&output).unwrap()["summary"];
+    let file_errors = summary["file_errors"].as_array().unwrap();
+    assert_eq!(file_errors.len(), 1);
+    assert_eq!(file_errors[0]["path"], dangling.to_str().unwrap());
+    assert!(file_errors[0]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with(&format!("Skipping {:?}.", dangling)));
+}
+
+#[test]
+fn check_fully_deduplicated() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(tmp_dir.path(), "file1", "same contents");
+    let file2 = tmp_file(tmp_dir.path(), "file2", "same contents");
+
+    dedup_with_any_exit_code(&["--check", tmp_dir.path().to_str().unwrap()])
+        .code(3)
+        .stderr(predicates::str::contains("aren't fully deduplicated"));
+    assert!(!same(&file1, &file2));
+    dedup(&[
+        "--check",
+        "--check-threshold=13",
+        tmp_dir.path().to_str().unwrap(),
+    ]);
+
+    dedup(&[tmp_dir.path().to_str().unwrap()]);
+    dedup(&["--check", tmp_dir.path().to_str().unwrap()]);
+}
+
+#[test]
+fn resume_from_checkpoint() {
+    let tmp_dir = tempdir().unwrap();
+    let files_dir = tmp_dir.path().join("files");
+    let file1 = tmp_file(&files_dir, "file1", "same contents");
+    let file2 = tmp_file(&files_dir, "file2", "same contents");
+    let checkpoint = tmp_dir.path().join("state.json");
+
+    dedup(&[
+        "--dry-run",
+        "--checkpoint",
+        checkpoint.to_str().unwrap(),
+        files_dir.to_str().unwrap(),
+    ]);
+    // The dry run linked nothing, so resuming from its checkpoint skips nothing.
+    dedup(&[
+        "--resume",
+        checkpoint.to_str().unwrap(),
+        files_dir.to_str().unwrap(),
+    ]);
+    assert!(same(&file1, &file2));
+}
+
+#[test]
+fn stop_at_max_files_and_resume() {
+    let tmp_dir = tempdir().unwrap();
+    let files_dir = tmp_dir.path().join("files");
+    let a = duplicate_files(&files_dir.join("a"), 2, "same contents");
+    let b = duplicate_files(&files_dir.join("b"), 2, "other contents!");
+    let checkpoint = tmp_dir.path().join("state.json");
+    let args = [
+        "--max-files",
+        "2",
+        "--checkpoint",
+        checkpoint.to_str().unwrap(),
+        files_dir.to_str().unwrap(),
+    ];
+
+    dedup_with_any_exit_code(&args)
+        .code(4)
+        .stdout(predicates::str::contains(
+            "Stopped at the budget of the run.",
+        ));
+    assert!(same(&a[0], &a[1]) != same(&b[0], &b[1]));
+
+    dedup(&[
+        "--resume",
+        checkpoint.to_str().unwrap(),
+        files_dir.to_str().unwrap(),
+    ]);
+    assert!(same(&a[0], &a[1]));
+    assert!(same(&b[0], &b[1]));
+}
+
+#[test]
+fn print_completions() {
+    dedup(&["completions", "bash"]).stdout(predicates::str::contains("--dry-run"));
+    dedup(&["completions", "zsh"]).stdout(predicates::str::contains("#compdef hardlink-dedup"));
+    dedup(&["completions", "fish"]).stdout(predicates::str::contains("complete -c hardlink-dedup"));
+}
+
+#[test]
+fn undo_from_journal() {
+    let tmp_dir = tempdir().unwrap();
+    let files_dir = tmp_dir.path().join("files");
+    let file1 = tmp_file(&files_dir, "file1", "same contents");
+    let file2 = tmp_file(&files_dir, "file2", "same contents");
+    let journal = tmp_dir.path().join("journal.ndjson");
+
+    dedup(&[
+        "--journal",
+        journal.to_str().unwrap(),
+        files_dir.to_str().unwrap(),
+    ]);
+    assert!(same(&file1, &file2));
+
+    dedup(&["undo", journal.to_str().unwrap()])
+        .stdout(predicates::str::contains("Restored files: 1"));
+    assert!(!same(&file1, &file2));
+    assert_eq!(read_to_string(&file1).unwrap(), "same contents");
+    assert_eq!(read_to_string(&file2).unwrap(), "same contents");
+}
+
+#[test]
+fn no_dedup_files_newer_than_timestamp() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
+
+    dedup(&[
+        "--exclude-newer-than",
+        "2000-01-01",
+        tmp_dir.path().to_str().unwrap(),
+    ]);
+    assert!(!same(&file1, &file2));
+    dedup(&[
+        "--exclude-older-than",
+        "2000-01-01",
+        tmp_dir.path().to_str().unwrap(),
+    ]);
+    assert!(same(&file1, &file2));
+}
+
+#[test]
+fn no_dedup_files_older_than_timestamp() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(tmp_dir.path(), 3, "same contents");
+    set_modified(&files[0], UNIX_EPOCH);
+
+    dedup(&[
+        "--exclude-older-than",
+        "2000-01-01",
+        tmp_dir.path().to_str().unwrap(),
+    ]);
+    assert!(!same(&files[0], &files[1]));
+    assert!(all_same(&files[1..]));
+}
+
+#[test]
+fn verify_reports_unlinked_duplicates() {
+    let tmp_dir = tempdir().unwrap();
+    duplicate_files(tmp_dir.path(), 2, "same contents");
+    let path = tmp_dir.path().to_str().unwrap();
+
+    dedup_with_any_exit_code(&["verify", path])
+        .code(3)
+        .stdout(predicate::str::contains(
+            "Groups of files with the same contents that aren't hardlinked: 1",
+        ));
+    dedup(&[path]);
+    dedup(&["verify", path]).stdout(predicate::str::contains("Hardlinked files: 1"));
+}
+
+#[test]
+fn interactive_confirmation() {
+    let tmp_dir = tempdir().unwrap();
+    let declined = duplicate_files(&tmp_dir.path().join("declined"), 2, "declined contents");
+    let confirmed = duplicate_files(&tmp_dir.path().join("confirmed"), 2, "other contents");
+
+    let mut child = Command::cargo_bin("hardlink-dedup")
+        .unwrap()
+        .args(["--interactive", tmp_dir.path().to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    // Groups are processed in no particular order, so answer based on the prompt.
+    for _ in 0..2 {
+        let mut prompt = Vec::new();
+        while !prompt.ends_with(b"[q]uit:") {
+            stderr.read_until(b':', &mut prompt).unwrap();
+        }
+        let answer = if String::from_utf8_lossy(&prompt).contains("declined") {
+            "n\n"
+        } else {
+            "y\n"
+        };
+        stdin.write_all(answer.as_bytes()).unwrap();
+    }
+    drop(stdin);
+
+    assert!(child.wait().unwrap().success());
+    assert!(!same(&declined[0], &declined[1]));
+    assert!(same(&confirmed[0], &confirmed[1]));
+}
+
+#[test]
+fn stdio_scan_and_apply_group() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(tmp_dir.path(), 2, "same contents");
+
+    let mut child = Command::cargo_bin("hardlink-dedup")
+        .unwrap()
+        .arg("--stdio")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut next_event = |name: &str| loop {
+        let line = stdout.next().unwrap().unwrap();
+        let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+        if event["event"] == name {
+            return event;
+        }
+    };
+    let scan = serde_json::json!({"command": "scan", "paths": [tmp_dir.path()]});
+    writeln!(stdin, "{}", scan).unwrap();
+    let duplicates = next_event("duplicates");
+    assert_eq!(duplicates["group"], 0);
+    next_event("finished");
+    assert!(!same(&files[0], &files[1]));
+    writeln!(stdin, r#"{{"command": "apply-group", "group": 0}}"#).unwrap();
+    next_event("hardlinked");
+    writeln!(stdin, r#"{{"command": "status"}}"#).unwrap();
+    assert_eq!(next_event("status")["groups"], 1);
+    drop(stdin);
+
+    assert!(child.wait().unwrap().success());
+    assert!(same(&files[0], &files[1]));
+}
+
+#[test]
+fn stdio_stops_on_sigterm() {
+    let mut child = Command::cargo_bin("hardlink-dedup")
+        .unwrap()
+        .arg("--stdio")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    // Signals are handled once commands are answered.
+    writeln!(stdin, r#"{{"command": "status"}}"#).unwrap();
+    stdout.next().unwrap().unwrap();
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+
+    // Stdin is still open.
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+    drop(stdin);
+}
+
+#[test]
+fn list_plan() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(tmp_dir.path(), "file1", "same contents");
+    let file2 = tmp_file(tmp_dir.path(), "file2", "same contents");
+    let file3 = tmp_dir.path().join("file3");
+    std::fs::hard_link(&file2, &file3).unwrap();
+
+    dedup(&[
+        "--list",
+        "--prefer",
+        "first-path",
+        tmp_dir.path().to_str().unwrap(),
+    ])
+    .stdout(predicates::str::starts_with(format!(
+        "keep {} (reclaims 13 bytes, 1 inode(s))\n",
+        file1.display()
+    )))
+    .stdout(predicates::str::contains(format!(
+        "  link {}\n",
+        file2.display()
+    )))
+    .stdout(predicates::str::contains(format!(
+        "  link {}\n",
+        file3.display()
+    )));
+
+    assert!(!same(&file1, &file2));
+}
+
+#[test]
+fn list_plan_deterministically() {
+    let tmp_dir = tempdir().unwrap();
+    for copy in ["a", "b", "c"] {
+        for file in ["f1", "f2", "f3", "f4"] {
+            tmp_file(
+                &tmp_dir.path().join(copy),
+                file,
+                &format!("contents of {file}"),
+            );
+        }
+    }
+    let dir = tmp_dir.path().display();
+    let mut expected = String::new();
+    for file in ["f1", "f2", "f3", "f4"] {
+        expected += &format!("keep {dir}/a/{file} (reclaims 28 bytes, 2 inode(s))\n");
+        expected += &format!("  link {dir}/b/{file}\n  link {dir}/c/{file}\n\n");
+    }
+
+    for _ in 0..2 {
+        dedup(&[
+            "--list",
+            "--deterministic",
+            tmp_dir.path().to_str().unwrap(),
+        ])
+        .stdout(predicates::str::diff(expected.clone()));
+    }
+}
+
+#[test]
+fn list_biggest_groups_first() {
+    let tmp_dir = tempdir().unwrap();
+    for (contents, copies) in [
+        ("a".repeat(10), 2),
+        ("b".repeat(100), 2),
+        ("c".repeat(60), 3),
+    ] {
+        for copy in 0..copies {
+            tmp_file(
+                tmp_dir.path(),
+                &format!("{}{}", &contents[..1], copy),
+                &contents,
+            );
+        }
+    }
+    let dir = tmp_dir.path().display();
+    let mut expected = format!("keep {dir}/c0 (reclaims 120 bytes, 2 inode(s))\n");
+    expected += &format!("  link {dir}/c1\n  link {dir}/c2\n\n");
+    expected += &format!("keep {dir}/b0 (reclaims 100 bytes, 1 inode(s))\n  link {dir}/b1\n\n");
+
+    dedup(&[
+        "--list",
+        "--deterministic",
+        tmp_dir.path().to_str().unwrap(),
+    ])
+    .stdout(predicates::str::diff(format!(
+        "{expected}keep {dir}/a0 (reclaims 10 bytes, 1 inode(s))\n  link {dir}/a1\n\n"
+    )));
+    dedup(&[
+        "--list",
+        "--deterministic",
+        "--top",
+        "2",
+        tmp_dir.path().to_str().unwrap(),
+    ])
+    .stdout(predicates::str::diff(expected));
+}
+
+#[test]
+fn list_duplicates_separated_by_nul() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(tmp_dir.path(), "a file", "same contents");
+    let file2 = tmp_file(tmp_dir.path(), "b\nfile", "same contents");
+    tmp_file(tmp_dir.path(), "other", "other contents");
+
+    dedup(&[
+        "--list-duplicates",
+        "-0",
+        "--prefer",
+        "first-path",
+        tmp_dir.path().to_str().unwrap(),
+    ])
+    .stdout(predicates::str::diff(format!(
+        "{}\0{}\0\0",
+        file1.display(),
+        file2.display()
+    )));
+    dedup(&["--list-duplicates", tmp_dir.path().to_str().unwrap()])
+        .stdout(predicates::str::ends_with("\n\n"));
+
+    assert!(!same(&file1, &file2));
+}
+
+#[test]
+fn report_csv() {
+    let tmp_dir = tempdir().unwrap();
+    let report_dir = tempdir().unwrap();
+    let report = report_dir.path().join("report.csv");
+    let file1 = tmp_file(tmp_dir.path(), "file1", "same contents");
+    let file2 = tmp_file(tmp_dir.path(), "file2", "same contents");
+    let file3 = tmp_dir.path().join("file3");
+    std::fs::hard_link(&file1, &file3).unwrap();
+    tmp_file(tmp_dir.path(), "file4", "other contents");
+
+    dedup(&[
+        "--report-csv",
+        report.to_str().unwrap(),
+        "--prefer",
+        "most-links",
+        tmp_dir.path().to_str().unwrap(),
+    ])
+    .success();
+
+    let rows: Vec<Vec<String>> = read_to_string(&report)
+        .unwrap()
+        .lines()
+        .map(|line| line.split(',').map(str::to_owned).collect())
+        .collect();
+    assert_eq!(
+        rows[0],
+        ["group", "kept", "linked", "size", "hash", "action"]
+    );
+    let hash = &rows[1][4];
+    assert_eq!(hash.len(), 64);
+    // Either path of the file with the most links can be kept.
+    let kept = rows[1][1].clone();
+    let other_link = if kept == file1.display().to_string() {
+        &file3
+    } else {
+        &file1
+    };
+    let row = |linked: &str, action: &str| {
+        ["1", &kept, linked, "13", hash, action]
+            .map(str::to_owned)
+            .to_vec()
+    };
+    let mut expected = vec![
+        row(&file2.display().to_string(), "linked"),
+        row(&other_link.display().to_string(), "already linked"),
+    ];
+    expected.sort();
+    assert_eq!(rows[1], row("", "kept"));
+    assert_eq!(rows[2..], expected);
+}
+
+#[test]
+fn fdupes_output() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(tmp_dir.path(), "file1", "same contents");
+    let file2 = tmp_file(tmp_dir.path(), "file2", "same contents");
+    tmp_file(tmp_dir.path(), "file3", "other contents");
+
+    dedup(&[
+        "--dry-run",
+        "--output",
+        "fdupes",
+        tmp_dir.path().to_str().unwrap(),
+    ])
+    .stdout(format!("{}\n{}\n\n", file1.display(), file2.display()));
+}
+
+#[test]
+fn ndjson_output() {
+    let tmp_dir = tempdir().unwrap();
+    tmp_file(tmp_dir.path(), "file1", "same contents");
+    tmp_file(tmp_dir.path(), "file2", "same contents");
+
+    dedup(&["--output", "ndjson", tmp_dir.path().to_str().unwrap()])
+        .stdout(predicates::str::contains(
+            r#"{"event":"started","files":2}"#,
+        ))
+        .stdout(predicates::str::contains(r#"{"event":"hardlinked","#))
+        .stdout(predicates::str::contains(r#""bytes_deduped":13"#));
+}
+
+#[test]
+fn only_machine_output_on_stdout() {
+    let tmp_dir = tempdir().unwrap();
+    duplicate_files(tmp_dir.path(), 2, "same contents");
+    let path = tmp_dir.path().to_str().unwrap();
+
+    let output = dedup(&["-vv", "--output", "ndjson", "--dry-run", path])
+        .get_output()
+        .stdout
+        .clone();
+    for line in String::from_utf8(output).unwrap().lines() {
+        serde_json::from_str::<serde_json::Value>(line).unwrap();
+    }
+    let output = dedup_with_any_exit_code(&["verify", "--output", "json", path])
+        .code(3)
+        .get_output()
+        .stdout
+        .clone();
+    let summary: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(summary["unlinked_duplicates"], 1);
+}
+
+#[test]
+fn report_summary_statistics() {
+    let tmp_dir = tempdir().unwrap();
+    duplicate_files(tmp_dir.path(), 3, "same contents");
+    tmp_file(tmp_dir.path(), "other", "diff contents");
+    tmp_file(tmp_dir.path(), "small", "small");
+    let path = tmp_dir.path().to_str().unwrap();
+
+    dedup(&["--dry-run", "--bytes", path])
+        .stdout(predicates::str::contains("Bytes read: 91 (39 hashed)\n"))
+        .stdout(predicates::str::contains(" bytes/s\n"))
+        .stdout(predicates::str::ends_with("Estimated saved bytes: 26\n"));
+    dedup(&[path])
+        .stdout(predicates::str::contains("Files scanned: 5\n"))
+        .stdout(predicates::str::contains("Groups of duplicates: 1\n"))
+        .stdout(predicates::str::contains(
+            "  1 file(s): It has unique device, size, uid, gid, or mode.\n",
+        ))
+        .stdout(predicates::str::contains(
+            "  1 file(s): It has a unique prefix.\n",
+        ))
+        // The prefixes of the four files of the same size, then the three duplicates in full.
+        .stdout(predicates::str::contains(
+            "Bytes read: 91 B (39 B hashed)\n",
+        ))
+        .stdout(predicates::str::contains("s hashing, "))
+        .stdout(predicates::str::contains("Throughput: "));
+}
+
+#[test]
+fn write_stats_file() {
+    let tmp_dir = tempdir().unwrap();
+    let data_dir = tmp_dir.path().join("data");
+    create_dir(&data_dir).unwrap();
+    duplicate_files(&data_dir, 3, "same contents");
+    tmp_file(&data_dir, "other", "diff contents");
+    let stats_file = tmp_dir.path().join("stats.json");
+
+    dedup(&[
+        "--stats-file",
+        stats_file.to_str().unwrap(),
+        data_dir.to_str().unwrap(),
+    ]);
+
+    let stats: serde_json::Value =
+        serde_json::from_str(&read_to_string(&stats_file).unwrap()).unwrap();
+    assert_eq!(stats["bytes_deduped"], 26);
+    assert_eq!(stats["failed_files"], 0);
+    assert_eq!(stats["stats"]["files_scanned"], 4);
+    assert_eq!(
+        stats["stats"]["excluded_files"]["It has a unique prefix."],
+        1
+    );
+}
+
+#[test]
+fn read_options_from_config() {
+    let tmp_dir = tempdir().unwrap();
+    let data_dir = tmp_dir.path().join("data");
+    create_dir(&data_dir).unwrap();
+    let files = duplicate_files(&data_dir, 2, "same contents");
+    let config_dir = tmp_dir.path().join("config");
+    tmp_file(
+        &config_dir,
+        "hardlink-dedup.toml",
+        &format!("paths = [{:?}]\ndry-run = true\n", data_dir),
+    );
+
+    Command::cargo_bin("hardlink-dedup")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", &config_dir)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Files scanned: 2"));
+    assert!(!same(&files[0], &files[1]));
+
+    Command::cargo_bin("hardlink-dedup")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", &config_dir)
+        .arg("--no-config")
+        .arg(&data_dir)
+        .assert()
+        .success();
+    assert!(same(&files[0], &files[1]));
+}
+
+#[test]
+fn dedup_sparse_files() {
+    let tmp_dir = tempdir().unwrap();
+    let data_dir = tmp_dir.path().join("data");
+    create_dir(&data_dir).unwrap();
+    let sparse_file = |name: &str| {
+        let path = data_dir.join(name);
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_len(1 << 20).unwrap();
+        file.write_all_at(b"data", 100_000).unwrap();
+        path
+    };
+    let sparse1 = sparse_file("sparse1");
+    let sparse2 = sparse_file("sparse2");
+    let mut contents = vec![0; 1 << 20];
+    contents[100_000..100_004].copy_from_slice(b"data");
+    let dense = data_dir.join("dense");
+    std::fs::write(&dense, &contents).unwrap();
+    let stats_file = tmp_dir.path().join("stats.json");
+
+    dedup(&[
+        "--group-by-allocated-size",
+        "--stats-file",
+        stats_file.to_str().unwrap(),
+        data_dir.to_str().unwrap(),
+    ]);
+    assert!(same(&sparse1, &sparse2));
+    assert!(!same(&sparse1, &dense));
+    // Only the blocks the sparse file occupied were freed.
+    let stats: serde_json::Value =
+        serde_json::from_str(&read_to_string(&stats_file).unwrap()).unwrap();
+    let bytes_deduped = stats["bytes_deduped"].as_u64().unwrap();
+    assert!(bytes_deduped > 0 && bytes_deduped < 1 << 20);
+
+    dedup(&[data_dir.to_str().unwrap()]);
+    assert!(all_same(&[sparse1, sparse2, dense]));
+}
+
+#[test]
+fn skip_empty_files_unless_included() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(tmp_dir.path(), 3, "");
+
+    dedup(&[tmp_dir.path().to_str().unwrap()]);
+    assert!(!same(&files[0], &files[1]));
+
+    dedup(&["--include-empty", tmp_dir.path().to_str().unwrap()]);
+    assert!(all_same(&files));
+}
+
+#[test]
+fn refuse_to_run_while_locked() {
+    let tmp_dir = tempdir().unwrap();
+    let data_dir = tmp_dir.path().join("data");
+    create_dir(&data_dir).unwrap();
+    let files = duplicate_files(&data_dir, 2, "same contents");
+    let lockfile = data_dir.join("lock");
+    let lock = std::fs::File::create(&lockfile).unwrap();
+    assert_eq!(unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) }, 0);
+
+    dedup_with_any_exit_code(&[
+        "--lockfile",
+        lockfile.to_str().unwrap(),
+        data_dir.to_str().unwrap(),
+    ])
+    .code(2)
+    .stderr(predicates::str::contains("Another run holds the lock"));
+    assert!(!same(&files[0], &files[1]));
+
+    drop(lock);
+    dedup(&[
+        "--lockfile",
+        lockfile.to_str().unwrap(),
+        data_dir.to_str().unwrap(),
+    ]);
+    assert!(same(&files[0], &files[1]));
+}
+
+#[test]
+fn print_progress_lines() {
+    let tmp_dir = tempdir().unwrap();
+    duplicate_files(tmp_dir.path(), 3, "same contents");
+    let path = tmp_dir.path().to_str().unwrap();
+
+    dedup(&["--progress-interval", "0s", "--bytes", path])
+        .stderr(predicates::str::contains("3/3 files ("))
+        .stderr(predicates::str::contains(" files/s), 39 bytes hashed, "));
+    dedup(&["--no-progress", path]).stderr(predicates::str::contains("files/s").not());
+}
+
+#[test]
+fn print_profile() {
+    let tmp_dir = tempdir().unwrap();
+    duplicate_files(tmp_dir.path(), 3, "same contents");
+
+    let output = dedup(&["--profile", "--bytes", tmp_dir.path().to_str().unwrap()]);
+
+    let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("Profile (wall time "));
+    assert!(stderr.contains("  walk "));
+    assert!(stderr.contains("s  3 files"));
+    assert!(stderr.contains("s  39 bytes"));
+    assert!(stderr.contains("s  26 bytes"));
+}
+
+#[test]
+fn write_metrics_textfile() {
+    let tmp_dir = tempdir().unwrap();
+    let data_dir = tmp_dir.path().join("data");
+    create_dir(&data_dir).unwrap();
+    duplicate_files(&data_dir, 3, "same contents");
+    let textfile = tmp_dir.path().join("hardlink_dedup.prom");
+
+    dedup(&[
+        "--metrics-textfile",
+        textfile.to_str().unwrap(),
+        data_dir.to_str().unwrap(),
+    ]);
+
+    let metrics = read_to_string(&textfile).unwrap();
+    assert!(metrics.contains("\nhardlink_dedup_bytes_saved 26\n"));
+    assert!(metrics.contains("\nhardlink_dedup_files_linked 2\n"));
+    assert!(metrics.contains("\nhardlink_dedup_errors_total 0\n"));
+    assert!(metrics.contains("\n# TYPE hardlink_dedup_run_duration_seconds gauge\n"));
+}
+
+#[test]
+fn skip_own_output_files() {
+    let tmp_dir = tempdir().unwrap();
+    duplicate_files(tmp_dir.path(), 2, "same contents");
+    // A previous run's statistics that happen to have the same contents.
+    let stats_file = tmp_file(tmp_dir.path(), "stats.json", "same contents");
+
+    dedup(&[
+        "--stats-file",
+        stats_file.to_str().unwrap(),
+        tmp_dir.path().to_str().unwrap(),
+    ]);
+
+    assert_eq!(metadata(&stats_file).unwrap().nlink(), 1);
+    let stats: serde_json::Value =
+        serde_json::from_str(&read_to_string(&stats_file).unwrap()).unwrap();
+    assert_eq!(stats["stats"]["files_scanned"], 2);
+}
+
+#[test]
+fn dry_run_separates_already_linked_bytes() {
+    let tmp_dir = tempdir().unwrap();
+    let data_dir = tmp_dir.path().join("data");
+    let a = tmp_file(&data_dir, "a", "same contents");
+    tmp_file(&data_dir, "b", "same contents");
+    hard_link(&a, data_dir.join("c")).unwrap();
+    tmp_file(&data_dir, "x", "diff contents");
+    let y = tmp_file(&data_dir, "y", "diff contents");
+    // Replacing y frees nothing while this link outside the paths keeps its contents around. By
+    // default y would be kept for its links.
+    hard_link(&y, tmp_dir.path().join("y")).unwrap();
+    let data_path = data_dir.to_str().unwrap();
+    let summary = |args: &[&str]| -> serde_json::Value {
+        let args = [&["--prefer", "first-path"], args].concat();
+        let output = dedup(&args).get_output().stdout.clone();
+        serde_json::from_slice::<serde_json::Value>(&output).unwrap()["summary"].clone()
+    };
+
+    let dry_run = summary(&["--dry-run", "--output", "json", data_path]);
+    assert_eq!(dry_run["bytes_already_linked"], 13);
+    assert_eq!(dry_run["bytes_deduped"], 13);
+
+    let real_run = summary(&["--output", "json", data_path]);
+    assert_eq!(real_run["bytes_deduped"], dry_run["bytes_deduped"]);
+
+    let after = summary(&["--dry-run", "--output", "json", data_path]);
+    assert_eq!(after["bytes_already_linked"], 39);
+    assert_eq!(after["bytes_deduped"], 0);
+    dedup(&["--dry-run", data_path]).stdout(predicates::str::contains(
+        "Bytes already saved by existing hardlinks: 39 B\n",
+    ));
+}
+
+#[test]
+fn report_linked_and_freed_bytes() {
+    let tmp_dir = tempdir().unwrap();
+    let data_dir = tmp_dir.path().join("data");
+    tmp_file(&data_dir, "a", "same contents");
+    let b = tmp_file(&data_dir, "b", "same contents");
+    hard_link(&b, tmp_dir.path().join("b")).unwrap();
+    tmp_file(&data_dir, "x", "diff contents");
+    tmp_file(&data_dir, "y", "diff contents");
+    let data_path = data_dir.to_str().unwrap();
+
+    let output = dedup(&["--prefer", "first-path", "--output", "json", data_path])
+        .get_output()
+        .stdout
+        .clone();
+    let summary = &serde_json::from_slice::<serde_json::Value>(&output).unwrap()["summary"];
+    assert_eq!(summary["bytes_linked"], 26);
+    assert_eq!(summary["bytes_deduped"], 13);
+    assert!(same(&data_dir.join("a"), &b));
+    dedup(&["--dry-run", data_path]).stdout(predicates::str::contains("Bytes hardlinked: 0 B\n"));
+}
+
+#[test]
+fn report_resource_usage() {
+    let tmp_dir = tempdir().unwrap();
+    duplicate_files(tmp_dir.path(), 2, "same contents");
+    let path = tmp_dir.path().to_str().unwrap();
+
+    let output = dedup(&["--output", "json", path])
+        .get_output()
+        .stdout
+        .clone();
+    let document: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert!(
+        document["summary"]["resources"]["max_rss_bytes"]
+            .as_u64()
+            .unwrap()
+            > 0
+    );
+    dedup(&[path]).stdout(predicates::str::contains("Resource usage: "));
+}
+
+#[test]
+fn version_as_json() {
+    dedup(&["--version", "--json"])
+        .success()
+        .stdout(predicates::str::contains(format!(
+            r#""version":"{}""#,
+            env!("CARGO_PKG_VERSION")
+        )))
+        .stdout(predicates::str::contains(r#""hardlink":true"#));
+}
+
+#[test]
+fn json_log_format() {
+    let tmp_dir = tempdir().unwrap();
+    tmp_file(&tmp_dir.path().join("dir1"), "file1", "same contents");
+    tmp_file(&tmp_dir.path().join("dir2"), "file2", "same contents");
+
+    dedup(&[
+        "-v",
+        "--log-format",
+        "json",
+        tmp_dir.path().to_str().unwrap(),
+    ])
+    .success()
+    .stderr(predicates::str::contains(r#""level":"INFO""#))
+    .stderr(predicates::str::contains(r#""name":"scan""#));
+}
+
+#[test]
+fn dedup_only_same_permissions() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
+    let old_file1_mode = metadata(&file1).unwrap().permissions().mode();
+    let mut file2_permissions = metadata(&file2).unwrap().permissions();
+    file2_permissions.set_mode(0o100750);
+    set_permissions(&file2, file2_permissions).expect("could not set permissions");
+
+    dedup(&[tmp_dir.path().to_str().unwrap()]).success();
+
+    assert_eq!(metadata(&file2).unwrap().permissions().mode(), 0o100750);
+    assert_eq!(
+        metadata(&file1).unwrap().permissions().mode(),
+        old_file1_mode
+    );
+    assert!(!same(&file1, &file2));
+}
+
+#[test]
+fn dedup_different_permissions_with_ignore_mode() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
+    let file1_mode = metadata(&file1).unwrap().permissions().mode();
+    let mut file2_permissions = metadata(&file2).unwrap().permissions();
+    file2_permissions.set_mode(0o100750);
+    set_permissions(&file2, file2_permissions).expect("could not set permissions");
+
+    dedup(&["--ignore-mode", tmp_dir.path().to_str().unwrap()]).stderr(predicates::str::contains(
+        format!(
+            "{:?} now has the owner {}, group {} and mode {:o} of {:?}.",
+            file2,
+            metadata(&file1).unwrap().uid(),
+            metadata(&file1).unwrap().gid(),
+            file1_mode & 0o7777,
+            file1
+        ),
+    ));
+
+    assert!(same(&file1, &file2));
+    assert_eq!(metadata(&file2).unwrap().permissions().mode(), file1_mode);
+}
+
+#[test]
+fn dedup_only_same_mtime_when_required() {
+    let tmp_dir = tempdir().unwrap();
+    let files = duplicate_files(&tmp_dir.path().join("snapshot"), 2, "same content");
+    let rewritten = tmp_file(&tmp_dir.path().join("live"), "file", "same content");
+    for file in &files {
+        set_modified(file, UNIX_EPOCH + Duration::from_secs(1000));
+    }
+
+    dedup(&["--require-same-mtime", tmp_dir.path().to_str().unwrap()]);
+
+    assert!(all_same(&files));
+    assert!(!same(&files[0], &rewritten));
+}
+
+#[test]
+fn dedup_only_same_xattrs() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
+    if xattr::set(&file2, "user.origin", b"camera").is_err() {
+        // The temporary directory's filesystem doesn't support user attributes.
+        return;
+    }
+
+    dedup(&[tmp_dir.path().to_str().unwrap()]);
+
+    assert!(!same(&file1, &file2));
+    assert_eq!(
+        xattr::get(&file2, "user.origin").unwrap(),
+        Some(b"camera".to_vec())
+    );
+
+    dedup(&["--ignore-xattrs", tmp_dir.path().to_str().unwrap()]);
+
+    assert!(same(&file1, &file2));
+}
+
+#[test]
+fn dedup_only_same_selinux_context() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
+    if xattr::set(&file2, "security.selinux", b"system_u:object_r:tmp_t:s0\0").is_err() {
+        // Setting contexts needs privileges or SELinux allowing it.
+        return;
+    }
+
+    dedup(&["--ignore-xattrs", tmp_dir.path().to_str().unwrap()]);
+
+    assert!(!same(&file1, &file2));
+
+    dedup(&["--ignore-selinux", tmp_dir.path().to_str().unwrap()]);
+
+    assert!(same(&file1, &file2));
+}
+
+#[test]
+fn dedup_only_same_gid() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
+    let old_file1_gid = Gid::from_raw(metadata(&file2).unwrap().gid());
+    let old_file2_gid = Gid::from_raw(metadata(&file2).unwrap().gid());
+    let new_gid = getgroups()
+        .unwrap()
+        .into_iter()
+        .find(|gid| gid != &old_file2_gid)
+        .expect("Could not find another group to use for testing.");
+    chown(&file2, None, Some(new_gid)).expect("could not change group");
+
+    dedup(&[tmp_dir.path().to_str().unwrap()]).success();
+
+    assert_eq!(Gid::from_raw(metadata(&file2).unwrap().gid()), new_gid);
+    assert_eq!(
+        Gid::from_raw(metadata(&file1).unwrap().gid()),
+        old_file1_gid
+    );
+    assert!(!same(&file1, &file2));
+}
+
+#[test]
+fn dedup_different_gid_with_ignore_owner() {
+    let tmp_dir = tempdir().unwrap();
+    let file1 = tmp_file(&tmp_dir.path().join("dir1"), "file1", "same content");
+    let file2 = tmp_file(&tmp_dir.path().join("dir2"), "file2", "same content");
+    let file1_gid = metadata(&file1).unwrap().gid();
+    let new_gid = getgroups()
+        .unwrap()
+        .into_iter()
+        .find(|gid| gid.as_raw() != file1_gid)
+        .expect("Could not find another group to use for testing.");
+    chown(&file2, None, Some(new_gid)).expect("could not change group");
+
+    dedup(&["--ignore-owner", tmp_dir.path().to_str().unwrap()]);
+
+    assert!(same(&file1, &file2));
+    assert_eq!(metadata(&file2).unwrap().gid(), file1_gid);
+}
+
+fn dedup(paths: &[&str]) -> assert_cmd::assert::Assert {
+    dedup_with_any_exit_code(paths).success()
+}
+
+fn dedup_with_any_exit_code(paths: &[&str]) -> assert_cmd::assert::Assert {
     let mut cmd = Command::cargo_bin("hardlink-dedup").unwrap();
     let cmd_with_args = cmd.args(paths);
     println!("Running cmd: {:?}", cmd_with_args);
-    let output = cmd_with_args.unwrap();
+    let output = cmd_with_args.output().unwrap();
     println!("Output: {:?}", output);
-    return output.assert();
+    output.assert()
 }